@@ -338,6 +338,10 @@ impl D1 {
         }
 
         loop {
+            // Note how many ISRs have run *before* ticking, so that we can tell
+            // whether one of them might have woken a task once we decide to sleep.
+            let isrs = kernel::isr::Isr::entries();
+
             // Tick the scheduler
             let tick = k.tick();
 
@@ -345,30 +349,46 @@ impl D1 {
             let turn = k.timer().turn();
 
             // If there is nothing else scheduled, and we didn't just wake something up,
-            // sleep for some amount of time
+            // sleep until the next timer deadline, or until some interrupt occurs.
             if turn.expired == 0 && !tick.has_remaining {
-                // TODO(AJM): Sometimes there is no "next" in the timer wheel, even though there should
-                // be. Don't take lack of timer wheel presence as the ONLY heuristic of whether we
-                // should just wait for SOME interrupt to occur. For now, force a max sleep of 100ms
-                // which is still probably wrong.
-                let amount = turn.ticks_to_next_deadline().unwrap_or(100 * 1000 * 3); // 3 ticks per us, 1000 us per ms, 100ms sleep
-
-                // Don't sleep for too long until james figures out wrapping timers
-                let amount = amount.min(0x4000_0000) as u32;
-                let _ = timer1.get_and_clear_interrupt();
-                unsafe {
-                    plic.activate(Interrupt::TIMER1, Priority::P1).unwrap();
+                // If the timer wheel has no deadline, no task is sleeping, and
+                // everything else is waiting on a device interrupt, which will
+                // knock us out of WFI on its own. So only arm TIMER1 if there's
+                // a deadline to wake up for.
+                let deadline = turn.ticks_to_next_deadline();
+                if let Some(amount) = deadline {
+                    // Don't sleep for too long until james figures out wrapping timers.
+                    // If the deadline is further out than this, we'll just wake up
+                    // early and sleep again.
+                    let amount = amount.min(0x4000_0000) as u32;
+                    let _ = timer1.get_and_clear_interrupt();
+                    unsafe {
+                        plic.activate(Interrupt::TIMER1, Priority::P1).unwrap();
+                    }
+                    timer1.set_interrupt_en(true);
+                    timer1.start_counter(amount);
                 }
-                timer1.set_interrupt_en(true);
-                timer1.start_counter(amount);
 
+                // An interrupt which fires after we ticked, but before we reach
+                // the WFI, may have woken a task, and with no upper bound on the
+                // sleep we'd never get around to polling it. Disable interrupts
+                // and check that no ISR ran in the meantime. WFI still wakes up
+                // on a pending interrupt while they are disabled, and it is taken
+                // as soon as we re-enable them.
                 unsafe {
-                    riscv::asm::wfi();
+                    riscv::interrupt::disable();
+                    if kernel::isr::Isr::entries() == isrs {
+                        riscv::asm::wfi();
+                    }
+                    riscv::interrupt::enable();
+                }
+
+                if deadline.is_some() {
+                    // Disable the timer interrupt in case that wasn't what woke us up
+                    plic.deactivate(Interrupt::TIMER1).unwrap();
+                    timer1.set_interrupt_en(false);
+                    timer1.stop();
                 }
-                // Disable the timer interrupt in case that wasn't what woke us up
-                plic.deactivate(Interrupt::TIMER1).unwrap();
-                timer1.set_interrupt_en(false);
-                timer1.stop();
 
                 // Account for time slept
                 let _turn = k.timer().turn();
@@ -133,6 +133,7 @@ impl<T: Instance> C3Uart<T> {
 
 #[interrupt]
 fn UART0() {
+    let _isr = kernel::isr::Isr::enter();
     let uart = unsafe { UART0::steal() };
 
     if uart.int_raw.read().tx_done_int_raw().bit_is_set() {
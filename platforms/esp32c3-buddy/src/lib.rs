@@ -20,8 +20,15 @@ use kernel::{daemons, maitake, mnemos_alloc::containers::Box, services, Kernel,
 
 static ALARM1: Mutex<RefCell<Option<Alarm<Target, 1>>>> = Mutex::new(RefCell::new(None));
 
+/// The longest we will sleep for at once, in timer wheel ticks.
+const MAX_SLEEP_TICKS: u64 = 1 << 40;
+
 pub fn init() -> &'static Kernel {
-    let k_settings = KernelSettings { max_drivers: 16 };
+    let k_settings = KernelSettings {
+        max_drivers: Some(16),
+        latency: Default::default(),
+        tasks: Default::default(),
+    };
     let clock = {
         // the system timer has a period of `SystemTimer::TICKS_PER_SECOND` ticks.
         // `TICKS_PER_SECOND` is 16_000_000, so the base granularity is
@@ -94,38 +101,59 @@ pub fn run(k: &'static Kernel, alarm1: Alarm<Target, 1>) -> ! {
 
     loop {
         tracing::debug!("tick");
+        // Note how many ISRs have run *before* ticking, so that we can tell
+        // whether one of them might have woken a task once we decide to sleep.
+        let isrs = kernel::isr::Isr::entries();
         let tick = k.tick();
         let turn = k.timer().turn();
 
         // If there is nothing else scheduled, and we didn't just wake something up,
-        // sleep for some amount of time
+        // sleep until the next timer deadline, or until some interrupt occurs.
         if turn.expired == 0 && !tick.has_remaining {
-            // TODO(AJM): Sometimes there is no "next" in the timer wheel, even though there should
-            // be. Don't take lack of timer wheel presence as the ONLY heuristic of whether we
-            // should just wait for SOME interrupt to occur. For now, force a max sleep of 100ms
-            // which is still probably wrong.
-            let amount = turn.ticks_to_next_deadline().unwrap_or(800_000); // 100 ms / 125 ms ticks = 800,000
-
-            // TODO(eliza): what is the max duration of the C3's timer?
-            critical_section::with(|cs| {
-                let mut alarm1 = ALARM1.borrow_ref_mut(cs);
-                let alarm1 = alarm1.as_mut().unwrap();
-                alarm1.clear_interrupt();
-                alarm1.set_target(SystemTimer::now() + (amount * 2));
-                alarm1.interrupt_enable(true);
-            });
+            // If the timer wheel has no deadline, no task is sleeping, and
+            // everything else is waiting on a device interrupt, which will
+            // knock us out of WFI on its own. So only arm the alarm if there's
+            // a deadline to wake up for.
+            let deadline = turn.ticks_to_next_deadline();
+            if let Some(amount) = deadline {
+                // The system timer counter is only 52 bits wide, so don't try
+                // to sleep for more than 2^40 ticks (about 38 hours) at once.
+                // If the deadline is further out than that, we'll just wake
+                // up early and sleep again.
+                let amount = amount.min(MAX_SLEEP_TICKS);
+                critical_section::with(|cs| {
+                    let mut alarm1 = ALARM1.borrow_ref_mut(cs);
+                    let alarm1 = alarm1.as_mut().unwrap();
+                    alarm1.clear_interrupt();
+                    alarm1.set_target(SystemTimer::now() + (amount * 2));
+                    alarm1.interrupt_enable(true);
+                });
+            }
 
+            // An interrupt which fires after we ticked, but before we reach
+            // the WFI, may have woken a task, and with no upper bound on the
+            // sleep we'd never get around to polling it. Disable interrupts
+            // and check that no ISR ran in the meantime. WFI still wakes up
+            // on a pending interrupt while they are disabled, and it is taken
+            // as soon as we re-enable them.
             unsafe {
-                riscv::asm::wfi();
+                riscv::interrupt::disable();
+                if kernel::isr::Isr::entries() == isrs {
+                    riscv::asm::wfi();
+                }
+                riscv::interrupt::enable();
+            }
+
+            if deadline.is_some() {
+                // Disable the timer interrupt in case that wasn't what woke us up
+                critical_section::with(|cs| {
+                    ALARM1
+                        .borrow_ref_mut(cs)
+                        .as_mut()
+                        .unwrap()
+                        .interrupt_enable(false);
+                });
             }
-            // Disable the timer interrupt in case that wasn't what woke us up
-            critical_section::with(|cs| {
-                ALARM1
-                    .borrow_ref_mut(cs)
-                    .as_mut()
-                    .unwrap()
-                    .interrupt_enable(false);
-            });
 
             // Account for time slept
             let _turn = k.timer().turn();
@@ -140,6 +168,7 @@ pub fn run(k: &'static Kernel, alarm1: Alarm<Target, 1>) -> ! {
 #[interrupt]
 #[allow(non_snake_case)]
 fn SYSTIMER_TARGET1() {
+    let _isr = kernel::isr::Isr::enter();
     critical_section::with(|cs| {
         ALARM1
             .borrow_ref_mut(cs)
@@ -65,7 +65,11 @@ async fn run_pomelo() {
 
 #[tracing::instrument(name = "Kernel", level = "info")]
 async fn kernel_entry() {
-    let settings = KernelSettings { max_drivers: 16 };
+    let settings = KernelSettings {
+        max_drivers: Some(16),
+        latency: Default::default(),
+        tasks: Default::default(),
+    };
 
     let clock = {
         maitake::time::Clock::new(
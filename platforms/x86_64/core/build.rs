@@ -0,0 +1,25 @@
+use std::env;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // the Limine entry point is linked into the higher half, with the
+    // sections Limine scans for requests kept together.
+    if env::var_os("CARGO_FEATURE_LIMINE").is_some() {
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=limine.ld");
+        println!("cargo:rustc-link-arg-bin=limine=-T{manifest_dir}/limine.ld");
+        println!("cargo:rustc-link-arg-bin=limine=-zmax-page-size=0x1000");
+    }
+
+    // the Multiboot2 entry point is linked at its physical load address, and
+    // its 32-bit boot trampoline uses absolute addresses, so it can't be
+    // position-independent.
+    if env::var_os("CARGO_FEATURE_MULTIBOOT2").is_some() {
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=multiboot2.ld");
+        println!("cargo:rustc-link-arg-bin=multiboot2=-T{manifest_dir}/multiboot2.ld");
+        println!("cargo:rustc-link-arg-bin=multiboot2=--no-pie");
+        println!("cargo:rustc-link-arg-bin=multiboot2=-zmax-page-size=0x1000");
+    }
+}
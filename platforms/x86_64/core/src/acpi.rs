@@ -1,8 +1,17 @@
+use crate::{
+    lapic::{self, LocalApic},
+    pci::EcamRegion,
+    stack,
+};
+use acpi::{address::AddressSpace, AcpiTable};
 pub use acpi::{AcpiError, AcpiHandler, AcpiTables};
-use core::{fmt, ptr::NonNull};
+use alloc::vec::Vec;
+use core::{fmt, mem, ptr::NonNull};
 use hal_core::{Address, PAddr};
 use hal_x86_64::mm;
 
+pub mod pm_timer;
+
 #[derive(Debug)]
 pub enum Error {
     Acpi(AcpiError),
@@ -18,9 +27,238 @@ pub(super) fn acpi_tables(
     Ok(tables)
 }
 
+/// An entry in the MCFG table, describing the ECAM region for a range of buses
+/// in a PCI segment.
+#[repr(C, packed)]
+struct McfgEntry {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+
+/// Returns the PCIe ECAM regions described by the MCFG table.
+///
+/// Returns an error if the tables have no MCFG, in which case PCI
+/// configuration space can only be reached through the legacy I/O ports.
+pub fn pci_ecam_regions(
+    tables: &AcpiTables<IdentityMappedAcpiHandler>,
+) -> Result<Vec<EcamRegion>, AcpiError> {
+    let mcfg = tables.find_table::<acpi::mcfg::Mcfg>()?;
+    let len = mcfg.header().length as usize;
+    let entries =
+        len.saturating_sub(mem::size_of::<acpi::mcfg::Mcfg>()) / mem::size_of::<McfgEntry>();
+
+    let first = unsafe {
+        // Safety: the entries follow the fixed part of the table, and the
+        // table's mapping covers its whole length.
+        mcfg.virtual_start()
+            .as_ptr()
+            .cast::<u8>()
+            .add(mem::size_of::<acpi::mcfg::Mcfg>())
+            .cast::<McfgEntry>()
+    };
+    let regions = (0..entries)
+        .map(|idx| {
+            let entry = unsafe { first.add(idx).read_unaligned() };
+            EcamRegion {
+                base: PAddr::from_u64(entry.base_address),
+                segment: entry.segment,
+                start_bus: entry.start_bus,
+                end_bus: entry.end_bus,
+            }
+        })
+        .inspect(|region| tracing::debug!(?region, "found PCIe ECAM region"))
+        .collect();
+    Ok(regions)
+}
+
+/// The registers and values used to enter the ACPI S5 ("soft off") sleep
+/// state, returned by [`soft_off`].
+#[derive(Copy, Clone, Debug)]
+pub struct SoftOff {
+    /// The I/O port of the PM1a control register.
+    pub pm1a_control: u16,
+    /// The I/O port of the PM1b control register, if there is one.
+    pub pm1b_control: Option<u16>,
+    /// The `SLP_TYPa` value from the `\_S5` package.
+    pub slp_typ_a: u8,
+    /// The `SLP_TYPb` value from the `\_S5` package.
+    pub slp_typ_b: u8,
+    /// The SMI command port, written with `acpi_enable` to switch the
+    /// firmware into ACPI mode, or 0 if the system is always in ACPI mode.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+}
+
+/// AML `NameOp`.
+const AML_NAME_OP: u8 = 0x08;
+/// AML `PackageOp`.
+const AML_PACKAGE_OP: u8 = 0x12;
+/// AML `BytePrefix`.
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+
+/// Returns the PM1 control registers from the FADT, and the S5 sleep types
+/// from the `\_S5` object in the DSDT.
+///
+/// Rather than interpreting the DSDT's AML, this looks for the `_S5_` name
+/// definition and decodes the package that follows it. Firmware almost always
+/// defines `\_S5` as a package of constants, which this handles; anything
+/// more elaborate is reported as an error.
+pub fn soft_off(tables: &AcpiTables<IdentityMappedAcpiHandler>) -> Result<SoftOff, Error> {
+    let fadt = tables.find_table::<acpi::fadt::Fadt>()?;
+    let io_port = |addr: acpi::address::GenericAddress| match addr.address_space {
+        AddressSpace::SystemIo => u16::try_from(addr.address)
+            .map_err(|_| Error::Other("PM1 control block is outside the I/O port space")),
+        _ => Err(Error::Other("PM1 control block is not in I/O space")),
+    };
+    let pm1a_control = io_port(fadt.pm1a_control_block()?)?;
+    let pm1b_control = fadt.pm1b_control_block()?.map(io_port).transpose()?;
+
+    let dsdt = tables
+        .dsdt
+        .as_ref()
+        .ok_or(Error::Other("ACPI tables have no DSDT"))?;
+    let aml = unsafe {
+        // Safety: all physical memory is mapped, and the DSDT's AML is
+        // `length` bytes long.
+        let start = mm::kernel_vaddr_of(PAddr::from_u64(dsdt.address as u64));
+        core::slice::from_raw_parts(start.as_ptr::<u8>(), dsdt.length as usize)
+    };
+    let (slp_typ_a, slp_typ_b) =
+        find_s5(aml).ok_or(Error::Other("DSDT has no \\_S5 package of constants"))?;
+
+    Ok(SoftOff {
+        pm1a_control,
+        pm1b_control,
+        slp_typ_a,
+        slp_typ_b,
+        smi_command: { fadt.smi_cmd_port } as u16,
+        acpi_enable: fadt.acpi_enable,
+    })
+}
+
+/// Find the `\_S5` package in `aml`, returning its first two elements.
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let name = aml.windows(4).enumerate().find_map(|(idx, window)| {
+        if window != b"_S5_" {
+            return None;
+        }
+        // the name may be preceded by a root prefix.
+        let prefix = aml[..idx].strip_suffix(b"\\").unwrap_or(&aml[..idx]);
+        (prefix.last() == Some(&AML_NAME_OP)).then_some(idx + 4)
+    })?;
+
+    let mut bytes = aml.get(name..)?.iter().copied();
+    if bytes.next()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // the top two bits of the first byte of the package length are the
+    // number of bytes that follow it.
+    let len_bytes = bytes.next()? >> 6;
+    for _ in 0..len_bytes {
+        bytes.next()?;
+    }
+    let _num_elements = bytes.next()?;
+
+    let mut element = || match bytes.next()? {
+        AML_BYTE_PREFIX => bytes.next(),
+        AML_ZERO_OP => Some(0),
+        AML_ONE_OP => Some(1),
+        _ => None,
+    };
+    Some((element()?, element()?))
+}
+
+/// The ACPI reset register, and the value which resets the machine when
+/// written to it, returned by [`reset_register`].
+#[derive(Copy, Clone, Debug)]
+pub enum ResetRegister {
+    Io { port: u16, value: u8 },
+    Memory { addr: PAddr, value: u8 },
+}
+
+/// The offset of the `CENTURY` field in the FADT.
+const FADT_CENTURY: usize = 108;
+// Offsets of fields in the FADT which are only present in ACPI 2.0 and later.
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+/// Set in the FADT's flags if the reset register is supported.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+// Generic address structure address space IDs.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// Returns the reset register described by the FADT.
+///
+/// Returns an error if the FADT predates the reset register, doesn't set
+/// `RESET_REG_SUP`, or puts the register somewhere other than memory or I/O
+/// space.
+pub fn reset_register(
+    tables: &AcpiTables<IdentityMappedAcpiHandler>,
+) -> Result<ResetRegister, Error> {
+    let fadt = tables.find_table::<acpi::fadt::Fadt>()?;
+    let len = fadt.header().length as usize;
+    if len <= FADT_RESET_VALUE {
+        return Err(Error::Other("FADT is too old to have a reset register"));
+    }
+    let fadt = unsafe {
+        // Safety: the table's mapping covers its whole length.
+        core::slice::from_raw_parts(fadt.virtual_start().as_ptr().cast::<u8>(), len)
+    };
+    let read_u32 = |offset: usize| u32::from_le_bytes(fadt[offset..offset + 4].try_into().unwrap());
+    let read_u64 = |offset: usize| u64::from_le_bytes(fadt[offset..offset + 8].try_into().unwrap());
+
+    if read_u32(FADT_FLAGS) & FADT_RESET_REG_SUP == 0 {
+        return Err(Error::Other("FADT does not support the reset register"));
+    }
+    // the address is 4 bytes into the generic address structure.
+    let address = read_u64(FADT_RESET_REG + 4);
+    let value = fadt[FADT_RESET_VALUE];
+    match fadt[FADT_RESET_REG] {
+        GAS_SYSTEM_IO => Ok(ResetRegister::Io {
+            port: u16::try_from(address)
+                .map_err(|_| Error::Other("reset register is outside the I/O port space"))?,
+            value,
+        }),
+        GAS_SYSTEM_MEMORY => Ok(ResetRegister::Memory {
+            addr: PAddr::from_u64(address),
+            value,
+        }),
+        _ => Err(Error::Other("reset register is not in memory or I/O space")),
+    }
+}
+
+/// Returns the index of the CMOS register holding the RTC's century, from the
+/// FADT, or `None` if the RTC has no century register.
+pub fn rtc_century_register(
+    tables: &AcpiTables<IdentityMappedAcpiHandler>,
+) -> Result<Option<u8>, Error> {
+    let fadt = tables.find_table::<acpi::fadt::Fadt>()?;
+    if (fadt.header().length as usize) <= FADT_CENTURY {
+        return Err(Error::Other("FADT is too short to have a CENTURY field"));
+    }
+    let century = unsafe {
+        // Safety: the table's mapping covers its whole length.
+        fadt.virtual_start()
+            .as_ptr()
+            .cast::<u8>()
+            .add(FADT_CENTURY)
+            .read()
+    };
+    Ok((century != 0).then_some(century))
+}
+
+/// Bring up the application processors, giving each one a stack of
+/// `stack_size` bytes.
 #[tracing::instrument(err, skip(platform))]
-pub fn bringup_smp(platform: &acpi::PlatformInfo) -> Result<(), Error> {
-    use acpi::platform::{self, interrupt::InterruptModel};
+pub fn bringup_smp(platform: &acpi::PlatformInfo, stack_size: usize) -> Result<(), Error> {
+    use acpi::platform::{self, interrupt::InterruptModel, ProcessorState};
 
     tracing::info!(?platform.power_profile);
 
@@ -58,13 +296,53 @@ pub fn bringup_smp(platform: &acpi::PlatformInfo) -> Result<(), Error> {
         application_processors.len()
     );
     tracing::debug!(?application_processors);
+
+    // the MADT's x2APIC entries may give processors 32-bit APIC IDs, which
+    // can only be sent IPIs in x2APIC mode.
+    let max_apic_id = application_processors
+        .iter()
+        .chain(core::iter::once(boot_processor))
+        .map(|processor| processor.local_apic_id)
+        .max()
+        .unwrap_or(0);
+    let x2apic = LocalApic::current().is_some_and(|lapic| lapic.is_x2apic());
+    tracing::info!(
+        max_apic_id,
+        x2apic,
+        x2apic_supported = lapic::x2apic_supported(),
+        "local APIC mode"
+    );
+    if max_apic_id > u8::MAX as u32 && !x2apic {
+        tracing::warn!(
+            max_apic_id,
+            "some processors have APIC IDs above 255, and can only be started in x2APIC mode"
+        );
+    }
+
+    // allocate every AP's stack before starting any of them, so that running
+    // out of memory doesn't leave some cores started and others not.
+    let aps = application_processors
+        .iter()
+        .filter(|processor| processor.state != ProcessorState::Disabled)
+        .map(|processor| processor.local_apic_id);
+    if let Err(error) = stack::init_ap_stacks(aps, stack_size) {
+        tracing::warn!(%error, stack_size, "can't allocate stacks, not starting app processors");
+        return Ok(());
+    }
+
+    // TODO(eliza): once application processors are started, the trampoline
+    // should switch to the AP's stack from `stack::ap_stack`, and each one
+    // should initialize its `GsLocalData`, load its own GDT and TSS with
+    // `gdt::init_core`, enable fast syscalls with `syscall::init_core`, set
+    // up its PAT with `mmio::init_core`, and initialize its local APIC, and
+    // then call `smp::run_core`.
     tracing::warn!("not starting app processors (SMP support isn't done yet)");
 
     Ok(())
 }
 
 #[derive(Clone)]
-pub(super) struct IdentityMappedAcpiHandler;
+pub struct IdentityMappedAcpiHandler;
 
 impl AcpiHandler for IdentityMappedAcpiHandler {
     unsafe fn map_physical_region<T>(
@@ -73,9 +351,11 @@ impl AcpiHandler for IdentityMappedAcpiHandler {
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
         let paddr = PAddr::from_u64(physical_address as u64);
-        let vaddr = mm::kernel_vaddr_of(paddr);
-        let vptr =
-            NonNull::new(vaddr.as_ptr()).expect("virtual address for ACPI region is not null");
+        let vptr = crate::mm::phys_to_virt(paddr)
+            .and_then(|ptr| NonNull::new(ptr.cast()))
+            .unwrap_or_else(|| {
+                panic!("ACPI region at {paddr:?} is not in the physical memory mapping")
+            });
         // we have identity mapped all physical memory, so we don't actually
         // have to map any pages --- just tell the ACPI crate that it can use
         // this region.
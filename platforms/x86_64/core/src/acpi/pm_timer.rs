@@ -0,0 +1,184 @@
+//! The ACPI power management timer.
+//!
+//! The PM timer is a free-running counter which ticks at 3.579545 MHz on
+//! every machine, whatever the CPU's frequency or power state, so it makes a
+//! good reference for calibrating other clocks. It is found through the FADT,
+//! either in I/O space or memory, and is either 24 or 32 bits wide, as given
+//! by the FADT's `TMR_VAL_EXT` flag. A 24-bit timer wraps around about every
+//! 4.7 seconds, and a 32-bit one about every 20 minutes.
+//!
+//! The timer is found by [`init`], while the ACPI tables are parsed during
+//! boot, and then returned by [`get`].
+use super::{AcpiTables, Error, IdentityMappedAcpiHandler};
+use acpi::AcpiTable;
+use core::time::Duration;
+use hal_core::{Address, PAddr, VAddr};
+use hal_x86_64::{cpu::Port, mm};
+use kernel::maitake::sync::spin::InitOnce;
+
+/// The frequency the PM timer ticks at, in Hz.
+pub const FREQUENCY_HZ: u64 = 3_579_545;
+
+/// The ACPI power management timer, returned by [`get`].
+#[derive(Copy, Clone, Debug)]
+pub struct PmTimer {
+    register: Register,
+    /// The counter's valid bits.
+    mask: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Register {
+    Io(u16),
+    Memory(VAddr),
+}
+
+static PM_TIMER: InitOnce<PmTimer> = InitOnce::uninitialized();
+
+// Offsets of fields in the FADT.
+const FADT_PM_TMR_BLK: usize = 76;
+const FADT_PM_TMR_LEN: usize = 91;
+const FADT_FLAGS: usize = 112;
+/// Only present in ACPI 2.0 and later.
+const FADT_X_PM_TMR_BLK: usize = 208;
+/// Set in the FADT's flags if the PM timer is 32 bits wide, rather than 24.
+const FADT_TMR_VAL_EXT: u32 = 1 << 8;
+
+// Generic address structure address space IDs.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+const MASK_24_BIT: u32 = 0x00ff_ffff;
+
+/// How many times to read the timer while checking that it counts. Each read
+/// is an I/O port access taking around a microsecond, and the timer ticks
+/// every ~280ns, so it should change on the first or second read.
+const COUNTING_READS: usize = 1_000;
+
+/// Find the PM timer described by the FADT, and check that it counts.
+///
+/// Returns an error if the FADT doesn't describe a PM timer, or describes one
+/// which doesn't count.
+pub fn init(tables: &AcpiTables<IdentityMappedAcpiHandler>) -> Result<&'static PmTimer, Error> {
+    let fadt = tables.find_table::<acpi::fadt::Fadt>()?;
+    let len = fadt.header().length as usize;
+    if len < FADT_FLAGS + 4 {
+        return Err(Error::Other("FADT is too short to describe the PM timer"));
+    }
+    let fadt = unsafe {
+        // Safety: the table's mapping covers its whole length.
+        core::slice::from_raw_parts(fadt.virtual_start().as_ptr().cast::<u8>(), len)
+    };
+    let read_u32 = |offset: usize| u32::from_le_bytes(fadt[offset..offset + 4].try_into().unwrap());
+    let read_u64 = |offset: usize| u64::from_le_bytes(fadt[offset..offset + 8].try_into().unwrap());
+
+    // prefer the extended address, which may be in memory, if it's set.
+    let extended = (len >= FADT_X_PM_TMR_BLK + 12)
+        .then(|| (fadt[FADT_X_PM_TMR_BLK], read_u64(FADT_X_PM_TMR_BLK + 4)))
+        .filter(|&(_, address)| address != 0);
+    let register = match extended {
+        Some((GAS_SYSTEM_IO, address)) => Register::Io(
+            u16::try_from(address)
+                .map_err(|_| Error::Other("PM timer is outside the I/O port space"))?,
+        ),
+        Some((GAS_SYSTEM_MEMORY, address)) => {
+            Register::Memory(mm::kernel_vaddr_of(PAddr::from_u64(address)))
+        }
+        Some(_) => return Err(Error::Other("PM timer is not in memory or I/O space")),
+        None => {
+            let port = read_u32(FADT_PM_TMR_BLK);
+            if port == 0 || fadt[FADT_PM_TMR_LEN] != 4 {
+                return Err(Error::Other("FADT does not describe a PM timer"));
+            }
+            Register::Io(
+                u16::try_from(port)
+                    .map_err(|_| Error::Other("PM timer is outside the I/O port space"))?,
+            )
+        }
+    };
+
+    let is_32_bit = read_u32(FADT_FLAGS) & FADT_TMR_VAL_EXT != 0;
+    let timer = PmTimer {
+        register,
+        mask: if is_32_bit { u32::MAX } else { MASK_24_BIT },
+    };
+
+    // check the timer is really there, and that its width matches the FADT.
+    // the upper byte of a 24-bit timer is reserved, and should read as zero;
+    // if it doesn't, only the low 24 bits are trusted.
+    let first = timer.read_raw();
+    let counts = (0..COUNTING_READS).any(|_| timer.read_raw() != first);
+    if !counts {
+        return Err(Error::Other("PM timer is not counting"));
+    }
+    if !is_32_bit && first & !MASK_24_BIT != 0 {
+        tracing::warn!(
+            value = first,
+            "FADT says the PM timer is 24 bits wide, but its upper bits are set"
+        );
+    }
+
+    tracing::info!(
+        ?timer.register,
+        bits = if is_32_bit { 32 } else { 24 },
+        "found ACPI PM timer"
+    );
+    Ok(PM_TIMER.init(timer))
+}
+
+/// Returns the PM timer found by [`init`], or `None` if there isn't one.
+#[must_use]
+pub fn get() -> Option<&'static PmTimer> {
+    PM_TIMER.try_get()
+}
+
+// === impl PmTimer ===
+
+impl PmTimer {
+    /// Returns the current value of the counter.
+    #[must_use]
+    pub fn now(&self) -> u32 {
+        self.read_raw() & self.mask
+    }
+
+    /// Returns the number of ticks since the counter read `start`.
+    ///
+    /// This is only correct if the counter hasn't wrapped all the way around
+    /// since then.
+    #[must_use]
+    pub fn elapsed_ticks(&self, start: u32) -> u32 {
+        self.now().wrapping_sub(start) & self.mask
+    }
+
+    /// Returns the time since the counter read `start`.
+    ///
+    /// This is only correct if the counter hasn't wrapped all the way around
+    /// since then.
+    #[must_use]
+    pub fn elapsed(&self, start: u32) -> Duration {
+        ticks_to_duration(self.elapsed_ticks(start) as u64)
+    }
+
+    /// Returns the number of bits in the counter, either 24 or 32.
+    #[must_use]
+    pub fn bits(&self) -> u32 {
+        self.mask.count_ones()
+    }
+
+    fn read_raw(&self) -> u32 {
+        unsafe {
+            // Safety: the FADT says the timer is here, and reading it has no
+            // side effects.
+            match self.register {
+                Register::Io(port) => Port::at(port).readl(),
+                Register::Memory(addr) => addr.as_ptr::<u32>().read_volatile(),
+            }
+        }
+    }
+}
+
+/// Returns the time taken by `ticks` ticks of the PM timer.
+#[must_use]
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / FREQUENCY_HZ as u128) as u64)
+}
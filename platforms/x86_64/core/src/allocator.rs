@@ -1,9 +1,14 @@
+use crate::{backtrace::Backtrace, frame};
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::NonNull,
 };
 use hal_core::{mem, BootInfo, VAddr};
-use kernel::mnemos_alloc::heap::{MnemosAlloc, UnderlyingAllocator};
+#[cfg(feature = "smp-heap")]
+use hal_core::{Address, PAddr};
+#[cfg(feature = "smp-heap")]
+use kernel::mnemos_alloc::heap::{ArenaSource, SegregatedAlloc};
+use kernel::mnemos_alloc::heap::{MnemosAlloc, OomInfo, UnderlyingAllocator};
 use mycelium_alloc::{buddy, bump};
 
 /// 1k is enough for anyone.
@@ -14,6 +19,21 @@ const FREE_LISTS: usize = 32;
 
 const MIN_HEAP_SIZE: usize = 32;
 
+/// The size of the initial buddy heap arena requested from the frame
+/// allocator.
+///
+/// If physical memory is too fragmented to provide a contiguous run of this
+/// size, we'll settle for the largest power-of-two fraction of it that we can
+/// get.
+///
+/// When the "smp-heap" feature is enabled, the buddy heap is only used to
+/// allocate page tables for the HAL, and the global allocator grows its arena
+/// from the frame allocator on demand, so the buddy heap can be much smaller.
+#[cfg(not(feature = "smp-heap"))]
+const INITIAL_HEAP_SIZE: usize = 64 * 1024 * 1024;
+#[cfg(feature = "smp-heap")]
+const INITIAL_HEAP_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub struct Heap(());
 
@@ -23,7 +43,24 @@ pub static AHEAP: MnemosAlloc<Heap> = MnemosAlloc::new();
 pub(crate) static HEAP: buddy::Alloc<FREE_LISTS> = buddy::Alloc::new(MIN_HEAP_SIZE);
 static BUMP: bump::Alloc<BUMP_SIZE> = bump::Alloc::new();
 
+/// The allocator backing [`AHEAP`].
+///
+/// With the "smp-heap" feature, this is a locked segregated free-list
+/// allocator that may be used from multiple cores simultaneously, and which
+/// grows by requesting frames from the [frame allocator](crate::frame).
+#[cfg(feature = "smp-heap")]
+static GLOBAL: SegregatedAlloc<FrameSource> = SegregatedAlloc::new();
+#[cfg(not(feature = "smp-heap"))]
+static GLOBAL: &buddy::Alloc<FREE_LISTS> = &HEAP;
+
+/// An [`ArenaSource`] which grows the heap using physical frames from the
+/// [frame allocator](crate::frame).
+#[cfg(feature = "smp-heap")]
+#[derive(Debug)]
+pub struct FrameSource(());
+
 pub(crate) fn init(bootinfo: &impl BootInfo, vm_offset: VAddr) {
+    AHEAP.set_oom_handler(log_oom);
     HEAP.set_vm_offset(vm_offset);
 
     let mut regions = 0;
@@ -43,9 +80,6 @@ pub(crate) fn init(bootinfo: &impl BootInfo, vm_offset: VAddr) {
         if region.kind() == mem::RegionKind::FREE {
             free_regions += 1;
             free_bytes += size;
-            if unsafe { HEAP.add_region(region) }.is_err() {
-                tracing::warn!("bad region");
-            }
         }
     }
 
@@ -60,6 +94,60 @@ pub(crate) fn init(bootinfo: &impl BootInfo, vm_offset: VAddr) {
         free_regions,
         free_bytes,
     );
+
+    // the frame allocator owns all free physical memory, so the buddy heap's
+    // initial arena must come from it.
+    let mut size = INITIAL_HEAP_SIZE;
+    let (base, size) = loop {
+        if let Some(base) = frame::alloc_contiguous(size / frame::FRAME_SIZE) {
+            break (base, size);
+        }
+        size /= 2;
+        assert!(
+            size >= frame::FRAME_SIZE,
+            "could not allocate any frames for the kernel heap!"
+        );
+    };
+
+    let region = mem::Region::new(base, size, mem::RegionKind::FREE);
+    if unsafe { HEAP.add_region(region) }.is_err() {
+        tracing::warn!(?base, size, "bad heap region");
+    } else {
+        tracing::info!(?base, size, "initialized kernel heap");
+    }
+}
+
+/// Log a failed allocation, so that the panic which usually follows can be
+/// diagnosed.
+///
+/// The first failure logs the heap's state and a backtrace. Allocations which
+/// fail after that, while allocations are inhibited, are usually retried by
+/// async-aware containers, so they're only logged at the debug level.
+fn log_oom(info: &OomInfo) {
+    let size = info.layout.size();
+    let align = info.layout.align();
+    if info.inhibited {
+        tracing::debug!(size, align, "allocation inhibited while out of memory");
+        return;
+    }
+
+    let backtrace = Backtrace::capture();
+    tracing::error!(
+        size,
+        align,
+        total_bytes = info.total_bytes,
+        largest_free_block = ?info.largest_free_block,
+        %backtrace,
+        "out of memory!"
+    );
+    #[cfg(feature = "heap-stats")]
+    tracing::error!(
+        allocated_bytes = info.state.allocated_bytes,
+        free_bytes = info.state.free_bytes(),
+        live_allocs = info.state.live_alloc_count(),
+        failed_allocs = info.state.alloc_oom_count,
+        "heap state"
+    );
 }
 
 impl UnderlyingAllocator for Heap {
@@ -70,7 +158,7 @@ impl UnderlyingAllocator for Heap {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // first, try to allocate from the real heap.
-        let ptr = HEAP.alloc(layout);
+        let ptr = GLOBAL.alloc(layout);
 
         if ptr.is_null() {
             // heap is uninitialized, fall back to the bump region.
@@ -86,6 +174,29 @@ impl UnderlyingAllocator for Heap {
             return;
         }
 
-        HEAP.dealloc(ptr, layout);
+        GLOBAL.dealloc(ptr, layout);
+    }
+}
+
+#[cfg(feature = "smp-heap")]
+unsafe impl ArenaSource for FrameSource {
+    const INIT: Self = Self(());
+
+    unsafe fn grow(&self, layout: Layout) -> Option<NonNull<u8>> {
+        if layout.align() > frame::FRAME_SIZE {
+            return None;
+        }
+        let frames = layout.size().div_ceil(frame::FRAME_SIZE);
+        let paddr = frame::alloc_contiguous(frames)?;
+        NonNull::new(hal_x86_64::mm::kernel_vaddr_of(paddr).as_ptr())
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+        // all physical memory is mapped at a fixed offset, so the frame's
+        // physical address is just the pointer minus that offset.
+        let offset = hal_x86_64::mm::kernel_vaddr_of(PAddr::from_u64(0)).as_usize();
+        let paddr = PAddr::from_u64((ptr.as_ptr() as usize - offset) as u64);
+        let frames = layout.size().div_ceil(frame::FRAME_SIZE);
+        frame::free_contiguous(paddr, frames);
     }
 }
@@ -0,0 +1,115 @@
+//! Frame pointer backtraces.
+//!
+//! # Frame pointers are required!
+//!
+//! Backtraces are captured by walking the chain of saved frame pointers on
+//! the stack. This only works if *every* function on the stack saves `rbp`
+//! in its prologue, so the kernel (including all of its dependencies) must be
+//! built with `-C force-frame-pointers=yes`. This is set for the
+//! `x86_64-unknown-none` target in the workspace's `.cargo/config.toml`. If a
+//! function without a frame pointer is on the stack, the backtrace will end
+//! early, or skip frames.
+//!
+//! The captured addresses are not symbolicated. To find out which functions
+//! they belong to, pass them to `addr2line` along with the kernel binary:
+//!
+//! ```text
+//! addr2line -fipC -e <path to kernel binary> <addresses...>
+//! ```
+use core::{arch::asm, fmt};
+
+/// The maximum number of frames captured in a [`Backtrace`].
+pub const MAX_FRAMES: usize = 32;
+
+/// A captured backtrace.
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [usize; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Capture a backtrace of the current call stack, starting with the
+    /// caller of this function.
+    // never inline this, so that it always has its own stack frame to start
+    // walking from.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let rbp: usize;
+        unsafe {
+            // Safety: reading `rbp` has no side effects.
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        unsafe {
+            // Safety: `rbp` is this function's frame pointer.
+            Self::from_frame_pointer(rbp)
+        }
+    }
+
+    /// Walk the frame pointer chain starting at the frame pointed to by
+    /// `rbp`.
+    ///
+    /// # Safety
+    ///
+    /// `rbp` must be a frame pointer on the current stack, and the stack must
+    /// contain a valid chain of saved frame pointers.
+    pub unsafe fn from_frame_pointer(mut rbp: usize) -> Self {
+        let mut frames = [0; MAX_FRAMES];
+        let mut len = 0;
+        while len < MAX_FRAMES {
+            // a null or misaligned frame pointer is the end of the chain (or
+            // garbage).
+            if rbp == 0 || rbp % core::mem::align_of::<usize>() != 0 {
+                break;
+            }
+
+            // each frame begins with the caller's saved frame pointer,
+            // followed by the return address into the caller.
+            let frame = rbp as *const usize;
+            let next = frame.read();
+            let ret = frame.add(1).read();
+            if ret == 0 {
+                break;
+            }
+            frames[len] = ret;
+            len += 1;
+
+            // the stack grows down, so callers' frames are always at higher
+            // addresses. if not, the chain is corrupt; stop rather than loop
+            // forever.
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+
+        Self { frames, len }
+    }
+
+    /// Returns the captured return addresses, innermost first.
+    #[must_use]
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("backtrace:")?;
+        if self.len == 0 {
+            return f.write_str(" <empty> (was the kernel built with frame pointers?)");
+        }
+        for (i, addr) in self.frames().iter().enumerate() {
+            write!(f, "\n  {i:>2}: {addr:#018x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Backtrace")
+            .field("frames", &self.frames())
+            .finish()
+    }
+}
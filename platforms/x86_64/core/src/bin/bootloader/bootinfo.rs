@@ -2,6 +2,10 @@ use crate::framebuf;
 use bootloader_api::info;
 use hal_core::{boot::BootInfo, mem, PAddr, VAddr};
 use hal_x86_64::{mm, vga};
+use kernel::{
+    maitake::sync::spin::InitOnce,
+    modules::{self, BootModule},
+};
 
 #[derive(Debug)]
 pub struct BootloaderApiBootInfo {
@@ -9,6 +13,10 @@ pub struct BootloaderApiBootInfo {
     has_framebuffer: bool,
 }
 
+/// The bootloader only supports a single ramdisk, so that's the only module we
+/// ever have.
+static MODULES: InitOnce<Option<BootModule>> = InitOnce::uninitialized();
+
 type MemRegionIter = core::slice::Iter<'static, info::MemoryRegion>;
 
 impl BootInfo for BootloaderApiBootInfo {
@@ -72,8 +80,29 @@ impl BootloaderApiBootInfo {
         VAddr::from_u64(self.inner.physical_memory_offset.into_option().unwrap_or(0))
     }
 
+    /// Returns the boot modules loaded by the bootloader.
+    ///
+    /// `rust-osdev/bootloader` can load at most one module, the ramdisk, which
+    /// is named [`modules::RAMDISK`]. If no ramdisk was loaded, this is empty.
+    pub(super) fn modules(&self) -> &'static [BootModule] {
+        MODULES.try_get().map(Option::as_slice).unwrap_or(&[])
+    }
+
+    fn ramdisk(inner: &info::BootInfo) -> Option<BootModule> {
+        let addr = inner.ramdisk_addr.into_option()?;
+        let len = inner.ramdisk_len as usize;
+        if len == 0 {
+            return None;
+        }
+        // Safety: the bootloader maps the ramdisk at `ramdisk_addr`, and never
+        // reclaims that mapping.
+        let data = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+        Some(BootModule::new(modules::RAMDISK, data))
+    }
+
     pub(super) fn from_bootloader(inner: &'static mut info::BootInfo) -> Self {
         let has_framebuffer = framebuf::init(inner);
+        MODULES.init(Self::ramdisk(inner));
         Self {
             inner,
             has_framebuffer,
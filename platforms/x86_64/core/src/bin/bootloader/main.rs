@@ -9,13 +9,12 @@ compile_error!(
 extern crate alloc;
 
 use bootloader_api::config::{BootloaderConfig, Mapping};
-use hal_core::{
-    framebuffer::{Draw, RgbColor},
-    PAddr, VAddr,
-};
+use hal_core::{PAddr, VAddr};
 use hal_x86_64::cpu;
+use mnemos_x86_64::drivers::framebuf::Contrast;
 mod bootinfo;
 mod framebuf;
+mod panic;
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
@@ -23,6 +22,11 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     // the kernel is mapped into the higher half of the virtual address space.
     config.mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
     config.mappings.page_table_recursive = Some(Mapping::Dynamic);
+    // the bootloader leaves an unmapped guard page below the kernel stack, so
+    // overflowing the stack page faults (and, since the fault can't be pushed
+    // onto the overflowed stack, double faults onto the double fault stack)
+    // rather than silently corrupting whatever is mapped below it.
+    config.kernel_stack_size = 128 * 1024;
 
     config
 };
@@ -34,25 +38,45 @@ pub fn kernel_start(info: &'static mut bootloader_api::BootInfo) -> ! {
         cpu::intrinsics::cli();
     }
 
-    let cfg = {
-        let phys_offset = info
-            .physical_memory_offset
-            .into_option()
-            // TODO(eliza): does `None` here mean "physical mem is identity
-            // mapped" or "we don't know where the physical mem is mapped"?
-            // check the bootloader docs...
-            .unwrap_or(0);
-        mnemos_x86_64::PlatformConfig {
-            rsdp_addr: info.rsdp_addr.into_option().map(PAddr::from_u64),
-            physical_mem_offset: VAddr::from_u64(phys_offset),
-        }
-    };
+    let rsdp_addr = info.rsdp_addr.into_option().map(PAddr::from_u64);
+    let phys_offset = info
+        .physical_memory_offset
+        .into_option()
+        // TODO(eliza): does `None` here mean "physical mem is identity
+        // mapped" or "we don't know where the physical mem is mapped"?
+        // check the bootloader docs...
+        .unwrap_or(0);
     let bootinfo = bootinfo::BootloaderApiBootInfo::from_bootloader(info);
 
+    // TODO(eliza): `bootloader_api` doesn't pass us a kernel command line, so
+    // for now, the command line and framebuffer contrast are selected when
+    // building the kernel.
+    let cmdline =
+        mnemos_x86_64::cmdline::Cmdline::parse(option_env!("MNEMOS_CMDLINE").unwrap_or(""));
+    if let Some(contrast) = option_env!("MNEMOS_FRAMEBUF_CONTRAST").and_then(Contrast::from_arg) {
+        contrast.set_global();
+    }
+    let cfg = mnemos_x86_64::PlatformConfig {
+        rsdp_addr,
+        physical_mem_offset: VAddr::from_u64(phys_offset),
+        modules: bootinfo.modules(),
+        cmdline: cmdline.clone(),
+    };
+
     let subscriber = {
         let framebuf = (|| unsafe { framebuf::mk_framebuf() }) as fn() -> _;
-        framebuf().fill(RgbColor::BLACK);
+        // the framebuffer is cleared once the display is ready.
+        // the `trace` command line option overrides the early serial trace
+        // level chosen when building the kernel.
+        let early_level = cmdline
+            .trace_level
+            .or_else(|| {
+                option_env!("MNEMOS_EARLY_TRACE_LEVEL").and_then(|level| level.parse().ok())
+            })
+            .unwrap_or(tracing::level_filters::LevelFilter::INFO);
         mnemos_x86_64::trace::TraceSubscriber::new(framebuf)
+            .with_readiness(framebuf::is_ready)
+            .with_early_serial_level(early_level)
     };
     tracing::subscriber::set_global_default(subscriber)
         .expect("tracing subscriber should not have already been set!");
@@ -60,69 +84,3 @@ pub fn kernel_start(info: &'static mut bootloader_api::BootInfo) -> ! {
     let k = mnemos_x86_64::init(&bootinfo, cfg);
     mnemos_x86_64::run(&bootinfo, k)
 }
-
-#[cold]
-#[cfg_attr(target_os = "none", panic_handler)]
-#[allow(dead_code)]
-fn panic(panic: &core::panic::PanicInfo<'_>) -> ! {
-    use core::fmt::Write;
-
-    use embedded_graphics::{
-        mono_font::MonoTextStyleBuilder,
-        pixelcolor::{Rgb888, RgbColor as _},
-        prelude::*,
-    };
-    use mnemos_x86_64::drivers::framebuf::TextWriter;
-
-    // /!\ disable all interrupts, unlock everything to prevent deadlock /!\
-    //
-    // Safety: it is okay to do this because we are panicking and everything
-    // is going to die anyway.
-    unsafe {
-        // disable all interrupts.
-        cpu::intrinsics::cli();
-
-        // TODO(eliza): claim serial
-
-        // unlock the frame buffer
-        framebuf::force_unlock();
-    }
-
-    let mut framebuf = unsafe { framebuf::mk_framebuf() };
-
-    let mut writer = {
-        let font = &profont::PROFONT_12_POINT;
-        let char_height = font.character_size.height;
-        // write the panic message at the bottom of the framebuffer, so that we
-        // don't clobber any existing text preceeding the panic (useful for
-        // debugging).
-        let point = {
-            let height_px = framebuf.height() as u32;
-            let last_line = (height_px - char_height - 10) as i32;
-            Point::new(10, last_line)
-        };
-
-        // scroll the framebuffer up by one line of text to make space for the
-        // panic message.
-        framebuf.scroll_vert(char_height as isize);
-
-        let style = MonoTextStyleBuilder::new()
-            .font(font)
-            .text_color(Rgb888::WHITE)
-            .background_color(Rgb888::RED)
-            .build();
-        TextWriter::new(&mut framebuf, style, point)
-    };
-
-    // write the panic message
-    let _ = writer.write_str("mnemOS panicked");
-    let message = panic.message();
-    let _ = writeln!(&mut writer, ":\n  {message}");
-
-    if let Some(location) = panic.location() {
-        let _ = writeln!(&mut writer, "  at {location}");
-    }
-
-    // ...and die!
-    cpu::halt();
-}
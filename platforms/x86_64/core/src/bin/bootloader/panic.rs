@@ -0,0 +1,155 @@
+//! The kernel's panic handler.
+//!
+//! This is shared by each bootloader's binary, and draws to the framebuffer
+//! through the binary's own `framebuf` module.
+use crate::framebuf;
+use hal_core::framebuffer::Draw;
+use hal_x86_64::{cpu, serial};
+use mnemos_x86_64::drivers::framebuf::Contrast;
+
+#[cold]
+#[cfg_attr(target_os = "none", panic_handler)]
+#[allow(dead_code)]
+fn panic(panic: &core::panic::PanicInfo<'_>) -> ! {
+    use core::fmt::Write;
+
+    use embedded_graphics::{
+        mono_font::MonoTextStyleBuilder,
+        pixelcolor::{Rgb888, RgbColor as _},
+        prelude::*,
+    };
+    use mnemos_x86_64::{drivers::framebuf::TextWriter, halt, trace};
+
+    // decide this before we start halting anything.
+    let system_wide = halt::panic_is_system_wide();
+
+    // /!\ disable all interrupts, unlock everything to prevent deadlock /!\
+    //
+    // Safety: it is okay to do this because we are panicking and everything
+    // is going to die anyway.
+    unsafe {
+        // disable all interrupts.
+        cpu::intrinsics::cli();
+
+        // if the whole system is going down, stop the other CPU cores, so
+        // that they can't scribble over memory or the panic output while we're
+        // rendering it.
+        if system_wide {
+            mnemos_x86_64::ipi::halt_other_cores();
+        }
+
+        // unlock COM1, in case the panic occurred while it was locked.
+        if let Some(com1) = serial::com1() {
+            com1.force_unlock();
+        }
+
+        // unlock the frame buffer
+        framebuf::force_unlock();
+
+        // unlock the ring of recent events, in case we panicked while
+        // recording one.
+        trace::ring::force_unlock();
+    }
+
+    let backtrace = mnemos_x86_64::backtrace::Backtrace::capture();
+
+    // write the panic to the serial port first: it's much less likely to fail
+    // than drawing to the framebuffer, and is often the only output that's
+    // captured (e.g. when running headless in QEMU).
+    if let Some(com1) = serial::com1() {
+        let mut com1 = com1.lock();
+        // start on a fresh line, in case we panicked mid-line.
+        let _ = com1.write_str("\n");
+        // the serial subscriber may not have sent its queued events yet, so
+        // repeat everything we can remember.
+        let _ = com1.write_str("recent events:\n");
+        let _ = trace::ring::dump(&mut com1);
+        write_panic(&mut com1, panic);
+        let _ = writeln!(&mut com1, "{backtrace}");
+    }
+
+    // don't wait for the display to be ready, we're dying. if there's no
+    // framebuffer at all, the serial port is all we've got.
+    let font = &profont::PROFONT_12_POINT;
+    let char_height = font.character_size.height;
+    // scroll the framebuffer up by one line of text to make space for the
+    // panic message.
+    framebuf::scroll_up(char_height as usize);
+
+    if let Some(mut framebuf) = framebuf::try_mk_framebuf() {
+        let mut writer = {
+            // write the panic message at the bottom of the framebuffer, so
+            // that we don't clobber any existing text preceeding the panic
+            // (useful for debugging).
+            let point = {
+                let height_px = framebuf.height() as u32;
+                let last_line = (height_px - char_height - 10) as i32;
+                Point::new(10, last_line)
+            };
+
+            let (text, background) = Contrast::global().colors(Rgb888::WHITE, Some(Rgb888::RED));
+            let style = MonoTextStyleBuilder::new()
+                .font(font)
+                .text_color(text)
+                .background_color(background.unwrap_or(Rgb888::RED))
+                .build();
+            TextWriter::new(&mut framebuf, style, point)
+        };
+
+        // show any events that never made it to the screen, such as those
+        // from before the framebuffer was ready.
+        let _ = trace::ring::dump_unshown(&mut writer);
+        write_panic(&mut writer, panic);
+        let _ = writeln!(&mut writer, "{backtrace}");
+    }
+
+    // if we're running boot tests under QEMU, report the panic as a failure.
+    #[cfg(feature = "boot-tests")]
+    mnemos_x86_64::test_runner::exit_qemu(mnemos_x86_64::test_runner::ExitCode::Failure);
+
+    // ...and die!
+    if system_wide {
+        halt::halt_all()
+    } else {
+        halt::halt_core()
+    }
+}
+
+/// Write the panic message and location to `writer`.
+fn write_panic(writer: &mut impl core::fmt::Write, panic: &core::panic::PanicInfo<'_>) {
+    use core::fmt::Write;
+
+    let _ = writer.write_str("mnemOS panicked:\n  ");
+    let mut message = WroteAny {
+        inner: &mut *writer,
+        wrote_any: false,
+    };
+    let _ = write!(&mut message, "{}", panic.message());
+    if !message.wrote_any {
+        let _ = writer.write_str("<no panic message>");
+    }
+    let _ = writer.write_str("\n");
+
+    if let Some(location) = panic.location() {
+        let _ = writeln!(
+            writer,
+            "  at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+}
+
+/// A [`core::fmt::Write`] adapter which records whether anything was written.
+struct WroteAny<'w, W> {
+    inner: &'w mut W,
+    wrote_any: bool,
+}
+
+impl<W: core::fmt::Write> core::fmt::Write for WroteAny<'_, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.wrote_any |= !s.is_empty();
+        self.inner.write_str(s)
+    }
+}
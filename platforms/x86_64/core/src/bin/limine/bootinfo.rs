@@ -0,0 +1,231 @@
+use crate::framebuf;
+use hal_core::{boot::BootInfo, mem, PAddr, VAddr};
+use hal_x86_64::{mm, vga};
+use kernel::{
+    maitake::sync::spin::InitOnce,
+    modules::{self, BootModule},
+};
+use limine::{
+    memory_map::{Entry, EntryType},
+    request::{
+        FramebufferRequest, HhdmRequest, KernelFileRequest, MemoryMapRequest, ModuleRequest,
+        RequestsEndMarker, RequestsStartMarker, RsdpRequest, SmpRequest, StackSizeRequest,
+    },
+    BaseRevision,
+};
+
+// Limine finds the requests by scanning the `.requests` section, between the
+// start and end markers, which the linker script keeps together.
+
+#[used]
+#[link_section = ".requests_start_marker"]
+static START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
+
+#[used]
+#[link_section = ".requests"]
+static BASE_REVISION: BaseRevision = BaseRevision::new();
+
+#[used]
+#[link_section = ".requests"]
+static MEMORY_MAP: MemoryMapRequest = MemoryMapRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static HHDM: HhdmRequest = HhdmRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static FRAMEBUFFER: FramebufferRequest = FramebufferRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static RSDP: RsdpRequest = RsdpRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static SMP: SmpRequest = SmpRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static MODULES_REQUEST: ModuleRequest = ModuleRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static KERNEL_FILE: KernelFileRequest = KernelFileRequest::new();
+
+/// The same kernel stack size as the `bootloader_api` image. Limine's default
+/// is only 64 KiB.
+#[used]
+#[link_section = ".requests"]
+static STACK_SIZE: StackSizeRequest = StackSizeRequest::new().with_size(128 * 1024);
+
+#[used]
+#[link_section = ".requests_end_marker"]
+static END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
+
+/// The most boot modules which are passed on to the kernel. Any more are
+/// ignored.
+const MAX_MODULES: usize = 16;
+
+static MODULES: InitOnce<([BootModule; MAX_MODULES], usize)> = InitOnce::uninitialized();
+
+#[derive(Debug)]
+pub struct LimineBootInfo {
+    memory_map: &'static [&'static Entry],
+    hhdm_offset: u64,
+    has_framebuffer: bool,
+}
+
+type MemRegionIter = core::slice::Iter<'static, &'static Entry>;
+
+impl BootInfo for LimineBootInfo {
+    type MemoryMap = core::iter::Map<MemRegionIter, fn(&&'static Entry) -> mem::Region>;
+
+    type Writer = vga::Writer;
+
+    type Framebuffer = framebuf::FramebufWriter;
+
+    /// Returns the boot info's memory map.
+    fn memory_map(&self) -> Self::MemoryMap {
+        fn convert_region(entry: &&'static Entry) -> mem::Region {
+            let kind = match entry.entry_type {
+                EntryType::USABLE => mem::RegionKind::FREE,
+                // this holds the Limine responses (including the memory map
+                // itself), so it mustn't be handed out until we're done with
+                // them.
+                EntryType::BOOTLOADER_RECLAIMABLE => mem::RegionKind::BOOT,
+                // the kernel image, boot modules, ACPI tables, and so on.
+                _ => mem::RegionKind::UNKNOWN,
+            };
+            mem::Region::new(PAddr::from_u64(entry.base), entry.length as usize, kind)
+        }
+        self.memory_map.iter().map(convert_region)
+    }
+
+    fn writer(&self) -> Self::Writer {
+        unimplemented!()
+    }
+
+    fn framebuffer(&self) -> Option<Self::Framebuffer> {
+        if !self.has_framebuffer {
+            return None;
+        }
+
+        Some(unsafe { framebuf::mk_framebuf() })
+    }
+
+    fn bootloader_name(&self) -> &str {
+        "Limine"
+    }
+
+    fn init_paging(&self) {
+        mm::init_paging(self.vm_offset())
+    }
+}
+
+impl LimineBootInfo {
+    /// Collects the responses to the kernel's Limine requests.
+    ///
+    /// Returns an error if Limine doesn't support the requested base revision,
+    /// or didn't respond to a request the kernel can't boot without.
+    pub(super) fn from_bootloader() -> Result<Self, &'static str> {
+        if !BASE_REVISION.is_supported() {
+            return Err("Limine does not support the requested base revision");
+        }
+        let memory_map = MEMORY_MAP
+            .get_response()
+            .ok_or("Limine did not provide a memory map")?
+            .entries();
+        let hhdm_offset = HHDM
+            .get_response()
+            .ok_or("Limine did not provide a higher-half direct map")?
+            .offset();
+        let has_framebuffer = framebuf::init(FRAMEBUFFER.get_response());
+        MODULES.init(Self::modules_from(MODULES_REQUEST.get_response()));
+
+        Ok(Self {
+            memory_map,
+            hhdm_offset,
+            has_framebuffer,
+        })
+    }
+
+    /// Returns the offset at which Limine maps all of physical memory.
+    pub(super) fn vm_offset(&self) -> VAddr {
+        VAddr::from_u64(self.hhdm_offset)
+    }
+
+    /// Returns the physical address of the ACPI RSDP, if Limine found one.
+    pub(super) fn rsdp_addr(&self) -> Option<PAddr> {
+        let addr = RSDP.get_response()?.address() as u64;
+        // before base revision 3, the RSDP's address is in the higher-half
+        // direct map, rather than physical.
+        let addr = addr.checked_sub(self.hhdm_offset).unwrap_or(addr);
+        Some(PAddr::from_u64(addr))
+    }
+
+    /// Returns the kernel command line from the Limine config, or an empty
+    /// string if there isn't one.
+    pub(super) fn cmdline(&self) -> &'static str {
+        KERNEL_FILE
+            .get_response()
+            .and_then(|response| core::str::from_utf8(response.file().cmdline()).ok())
+            .unwrap_or("")
+    }
+
+    /// Returns the boot modules loaded by Limine.
+    ///
+    /// Each module is named by its command line in the Limine config, or, if it
+    /// has none, by its file name.
+    pub(super) fn modules(&self) -> &'static [BootModule] {
+        MODULES
+            .try_get()
+            .map(|(modules, len)| &modules[..*len])
+            .unwrap_or(&[])
+    }
+
+    /// Log the CPU cores Limine found.
+    ///
+    /// Limine parks the application processors until they're given somewhere
+    /// to jump to, which the kernel doesn't do yet.
+    pub(super) fn log_cpus(&self) {
+        let Some(smp) = SMP.get_response() else {
+            tracing::info!("Limine did not respond to the SMP request");
+            return;
+        };
+        tracing::info!(
+            cpus = smp.cpus().len(),
+            bsp_lapic_id = smp.bsp_lapic_id(),
+            "Limine found CPU cores"
+        );
+        for cpu in smp.cpus() {
+            tracing::debug!(cpu.id, cpu.lapic_id, "found CPU core");
+        }
+    }
+
+    fn modules_from(
+        response: Option<&'static limine::response::ModuleResponse>,
+    ) -> ([BootModule; MAX_MODULES], usize) {
+        let mut modules = [BootModule::new(modules::RAMDISK, &[]); MAX_MODULES];
+        let mut len = 0;
+        for file in response.map(|r| r.modules()).unwrap_or(&[]) {
+            if len == MAX_MODULES {
+                break;
+            }
+            let name = core::str::from_utf8(file.cmdline())
+                .ok()
+                .filter(|cmdline| !cmdline.is_empty())
+                .or_else(|| {
+                    let path = core::str::from_utf8(file.path()).ok()?;
+                    path.rsplit('/').next()
+                })
+                .unwrap_or(modules::RAMDISK);
+            // Safety: Limine maps modules in the higher-half direct map, and
+            // never reclaims them.
+            let data = unsafe { core::slice::from_raw_parts(file.addr(), file.size() as usize) };
+            modules[len] = BootModule::new(name, data);
+            len += 1;
+        }
+        (modules, len)
+    }
+}
@@ -0,0 +1,60 @@
+//! The framebuffer handed over by Limine.
+//!
+//! Limine describes a framebuffer by its pitch in bytes and the position and
+//! size of each color channel, which are translated into a [`FramebufInfo`]
+//! here.
+use limine::{framebuffer::MemoryModel, response::FramebufferResponse};
+use mnemos_x86_64::drivers::framebuf::{self, Channel, FramebufInfo, PixelFormat};
+
+pub(super) use mnemos_x86_64::drivers::framebuf::{
+    force_unlock, is_ready, mk_framebuf, scroll_up, try_mk_framebuf, FramebufWriter,
+};
+
+/// Try to initialize the framebuffer from Limine's response to the
+/// framebuffer request.
+///
+/// Returns `true` if the framebuffer is available, or `false` if Limine found
+/// no framebuffer, or only ones with pixel formats we can't draw to.
+pub(super) fn init(response: Option<&'static FramebufferResponse>) -> bool {
+    if framebuf::is_initialized() {
+        return true;
+    }
+
+    // use the first framebuffer we know how to draw to.
+    let Some((cfg, buf)) = response
+        .into_iter()
+        .flat_map(|r| r.framebuffers())
+        .find_map(|fb| {
+            if fb.memory_model() != MemoryModel::RGB {
+                return None;
+            }
+            let channel = |shift, size| Channel { shift, size };
+            let format = PixelFormat::from_channels(
+                fb.bpp(),
+                channel(fb.red_mask_shift(), fb.red_mask_size()),
+                channel(fb.green_mask_shift(), fb.green_mask_size()),
+                channel(fb.blue_mask_shift(), fb.blue_mask_size()),
+            )?;
+            let cfg = FramebufInfo {
+                height: fb.height() as usize,
+                width: fb.width() as usize,
+                stride: fb.pitch() as usize,
+                format,
+            };
+            let len = (fb.pitch() * fb.height()) as usize;
+            if !cfg.fits(len) {
+                return None;
+            }
+            // Safety: Limine maps the framebuffer for us, and it is `pitch`
+            // bytes long for each line.
+            let buf = unsafe { core::slice::from_raw_parts_mut(fb.addr(), len) };
+            Some((cfg, buf))
+        })
+    else {
+        return false;
+    };
+
+    // Limine has finished setting the display mode by the time it jumps to
+    // the kernel, so there's no need to wait for the display to settle.
+    framebuf::init(cfg, buf, 0)
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+
+#[cfg(not(feature = "limine"))]
+compile_error!(
+    "building the `mnemos-x86_64-limine` binary requires the \
+    'limine' Cargo feature to be enabled",
+);
+extern crate alloc;
+
+use hal_x86_64::{cpu, serial};
+use mnemos_x86_64::drivers::framebuf::Contrast;
+mod bootinfo;
+mod framebuf;
+#[path = "../bootloader/panic.rs"]
+mod panic;
+
+/// The kernel's entry point, named by `ENTRY` in `limine.ld`.
+#[no_mangle]
+unsafe extern "C" fn kernel_start() -> ! {
+    unsafe {
+        cpu::intrinsics::cli();
+    }
+
+    let bootinfo = match bootinfo::LimineBootInfo::from_bootloader() {
+        Ok(bootinfo) => bootinfo,
+        Err(error) => {
+            // there's no tracing yet, so this is our only chance of saying
+            // why we didn't boot.
+            if let Some(com1) = serial::com1() {
+                use core::fmt::Write;
+                let _ = writeln!(com1.lock(), "mnemOS can't boot: {error}");
+            }
+            cpu::halt();
+        }
+    };
+
+    let cmdline = mnemos_x86_64::cmdline::Cmdline::parse(bootinfo.cmdline());
+    // TODO(eliza): the framebuffer contrast is still selected when building
+    // the kernel, rather than on the command line.
+    if let Some(contrast) = option_env!("MNEMOS_FRAMEBUF_CONTRAST").and_then(Contrast::from_arg) {
+        contrast.set_global();
+    }
+    let cfg = mnemos_x86_64::PlatformConfig {
+        rsdp_addr: bootinfo.rsdp_addr(),
+        physical_mem_offset: bootinfo.vm_offset(),
+        modules: bootinfo.modules(),
+        cmdline: cmdline.clone(),
+    };
+
+    let subscriber = {
+        let framebuf = (|| unsafe { framebuf::mk_framebuf() }) as fn() -> _;
+        // the `trace` command line option overrides the early serial trace
+        // level chosen when building the kernel.
+        let early_level = cmdline
+            .trace_level
+            .or_else(|| {
+                option_env!("MNEMOS_EARLY_TRACE_LEVEL").and_then(|level| level.parse().ok())
+            })
+            .unwrap_or(tracing::level_filters::LevelFilter::INFO);
+        mnemos_x86_64::trace::TraceSubscriber::new(framebuf)
+            .with_readiness(framebuf::is_ready)
+            .with_early_serial_level(early_level)
+    };
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("tracing subscriber should not have already been set!");
+    bootinfo.log_cpus();
+
+    let k = mnemos_x86_64::init(&bootinfo, cfg);
+    mnemos_x86_64::run(&bootinfo, k)
+}
@@ -0,0 +1,187 @@
+//! The Multiboot2 header, and the trampoline from the 32-bit protected mode
+//! which Multiboot2 loaders jump to, into long mode.
+//!
+//! Before calling [`kernel_start`](super::kernel_start), the trampoline:
+//!
+//! - checks that the loader is Multiboot2-compliant and that the CPU supports
+//!   long mode, halting if not,
+//! - identity-maps the first [`IDENTITY_MAPPED`] bytes of physical memory,
+//!   using 2 MiB pages, so that the physical memory offset is zero,
+//! - enables long mode, the NX bit (if supported), and write protection,
+//! - switches to a 64-bit GDT and a 128 KiB stack in `.bss`.
+//!
+//! The boot page tables and stack are part of the kernel image, so they are
+//! never handed out by the frame allocator.
+use core::arch::global_asm;
+
+/// How much physical memory the trampoline identity-maps. Memory above this is
+/// not mapped, and so is never used.
+///
+/// This must be kept in sync with the number of page directories in the
+/// assembly below.
+pub(super) const IDENTITY_MAPPED: u64 = 4 << 30;
+
+/// The value in `eax` when a Multiboot2-compliant loader jumps to the kernel.
+pub(super) const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+global_asm!(
+    // === the Multiboot2 header ===
+    //
+    // this must be 8-byte aligned, and within the first 32 KiB of the image.
+    ".section .multiboot2_header, \"a\"",
+    ".align 8",
+    "mb2_header_start:",
+    // magic
+    ".long 0xe85250d6",
+    // architecture: 32-bit protected mode i386
+    ".long 0",
+    ".long mb2_header_end - mb2_header_start",
+    // checksum: the magic, architecture, length and checksum sum to zero.
+    ".long 0x100000000 - (0xe85250d6 + (mb2_header_end - mb2_header_start))",
+    // framebuffer tag: ask for a linear framebuffer, with no preference for
+    // its size or depth. this is optional, so the loader may still leave us in
+    // text mode, in which case only the serial port is used.
+    ".align 8",
+    ".short 5",
+    ".short 1",
+    ".long 20",
+    ".long 0",
+    ".long 0",
+    ".long 32",
+    // end tag
+    ".align 8",
+    ".short 0",
+    ".short 0",
+    ".long 8",
+    "mb2_header_end:",
+    //
+    // === the 32-bit entry point ===
+    ".section .text.boot, \"ax\"",
+    ".code32",
+    ".global _start",
+    "_start:",
+    "cli",
+    "cld",
+    "mov esp, offset boot_stack_top",
+    // the magic and info pointer are the first two arguments to
+    // `kernel_start`, which are passed in `rdi` and `rsi`.
+    "mov edi, eax",
+    "mov esi, ebx",
+    // `BOOTLOADER_MAGIC`
+    "cmp edi, 0x36d76289",
+    "jne 90f",
+    // check for long mode support. `cpuid` clobbers ebx, but the info pointer
+    // has already been saved.
+    "mov eax, 0x80000000",
+    "cpuid",
+    "cmp eax, 0x80000001",
+    "jb 90f",
+    "mov eax, 0x80000001",
+    "cpuid",
+    "test edx, 1 << 29",
+    "jz 90f",
+    // remember whether the NX bit is supported.
+    "mov ebp, edx",
+    // PML4[0] -> the PDPT
+    "mov eax, offset boot_pdpt",
+    "or eax, 0x3",
+    "mov [boot_pml4], eax",
+    // PDPT[0..4] -> the four page directories
+    "mov eax, offset boot_pds",
+    "or eax, 0x3",
+    "xor ecx, ecx",
+    "2:",
+    "mov [boot_pdpt + ecx * 8], eax",
+    "add eax, 4096",
+    "inc ecx",
+    "cmp ecx, 4",
+    "jne 2b",
+    // each page directory entry maps a present, writable, 2 MiB page.
+    "xor ecx, ecx",
+    "3:",
+    "mov eax, ecx",
+    "shl eax, 21",
+    "or eax, 0x83",
+    "mov [boot_pds + ecx * 8], eax",
+    "inc ecx",
+    "cmp ecx, 4 * 512",
+    "jne 3b",
+    "mov eax, offset boot_pml4",
+    "mov cr3, eax",
+    // CR4.PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    // EFER.LME, and EFER.NXE if the NX bit is supported.
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, 1 << 8",
+    "test ebp, 1 << 20",
+    "jz 4f",
+    "or eax, 1 << 11",
+    "4:",
+    "wrmsr",
+    // CR0.PG and CR0.WP
+    "mov eax, cr0",
+    "or eax, (1 << 31) | (1 << 16)",
+    "mov cr0, eax",
+    // we're now in compatibility mode. load a GDT with a 64-bit code segment,
+    // and far return into it.
+    "lgdt [boot_gdt_ptr]",
+    "push 0x08",
+    "push offset 5f",
+    "retf",
+    "90:",
+    "cli",
+    "hlt",
+    "jmp 90b",
+    //
+    // === the 64-bit entry point ===
+    ".code64",
+    "5:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    // the upper halves of these registers are undefined after switching into
+    // long mode.
+    "mov esp, offset boot_stack_top",
+    "mov edi, edi",
+    "mov esi, esi",
+    "call kernel_start",
+    "6:",
+    "cli",
+    "hlt",
+    "jmp 6b",
+    //
+    // === the boot GDT ===
+    ".section .rodata.boot, \"a\"",
+    ".align 16",
+    "boot_gdt:",
+    ".quad 0",
+    // 0x08: 64-bit code
+    ".quad 0x00af9a000000ffff",
+    // 0x10: data
+    ".quad 0x00cf92000000ffff",
+    "boot_gdt_end:",
+    "boot_gdt_ptr:",
+    ".short boot_gdt_end - boot_gdt - 1",
+    ".quad boot_gdt",
+    //
+    // === the boot page tables and stack ===
+    ".section .bss.boot, \"aw\", @nobits",
+    ".align 4096",
+    "boot_pml4:",
+    ".skip 4096",
+    "boot_pdpt:",
+    ".skip 4096",
+    "boot_pds:",
+    ".skip 4 * 4096",
+    "boot_stack_bottom:",
+    ".skip 128 * 1024",
+    "boot_stack_top:",
+    ".section .text",
+);
@@ -0,0 +1,386 @@
+use crate::{boot, framebuf};
+use hal_core::{boot::BootInfo, mem, PAddr, VAddr};
+use hal_x86_64::{mm, vga};
+use kernel::{
+    maitake::sync::spin::InitOnce,
+    modules::{self, BootModule},
+};
+
+// Multiboot2 information structure tag types.
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_RSDP_V1: u32 = 14;
+const TAG_RSDP_V2: u32 = 15;
+
+/// The Multiboot2 memory map entry type for usable RAM.
+const MEMORY_AVAILABLE: u32 = 1;
+
+/// The most regions in the memory map passed on to the kernel, after the
+/// kernel image, boot information, and modules are carved out of it. Any more
+/// are ignored.
+const MAX_REGIONS: usize = 128;
+
+/// The most boot modules which are passed on to the kernel. Any more are
+/// ignored.
+const MAX_MODULES: usize = 16;
+
+static MEMORY_MAP: InitOnce<([MemRegion; MAX_REGIONS], usize)> = InitOnce::uninitialized();
+
+static MODULES: InitOnce<([BootModule; MAX_MODULES], usize)> = InitOnce::uninitialized();
+
+extern "C" {
+    // defined by `multiboot2.ld`.
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+#[derive(Debug)]
+pub struct Multiboot2BootInfo {
+    memory_map: &'static [MemRegion],
+    cmdline: &'static str,
+    bootloader_name: &'static str,
+    rsdp_addr: Option<PAddr>,
+    has_framebuffer: bool,
+}
+
+/// A framebuffer described by the Multiboot2 framebuffer tag.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct FramebufferTag {
+    pub(super) addr: u64,
+    pub(super) pitch: u32,
+    pub(super) width: u32,
+    pub(super) height: u32,
+    pub(super) bpp: u8,
+    /// `0` for indexed color, `1` for direct RGB color, and `2` for EGA text.
+    pub(super) kind: u8,
+    /// The bit position and size of the red, green, and blue channels, for
+    /// direct RGB color framebuffers.
+    pub(super) channels: [(u8, u8); 3],
+}
+
+#[derive(Copy, Clone, Debug)]
+struct MemRegion {
+    base: u64,
+    len: u64,
+    kind: mem::RegionKind,
+}
+
+/// A tag in the Multiboot2 information structure.
+#[derive(Copy, Clone, Debug)]
+struct Tag {
+    kind: u32,
+    /// The whole tag, including its type and size.
+    bytes: &'static [u8],
+}
+
+struct Tags {
+    bytes: &'static [u8],
+}
+
+type MemRegionIter = core::slice::Iter<'static, MemRegion>;
+
+impl BootInfo for Multiboot2BootInfo {
+    type MemoryMap = core::iter::Map<MemRegionIter, fn(&MemRegion) -> mem::Region>;
+
+    type Writer = vga::Writer;
+
+    type Framebuffer = framebuf::FramebufWriter;
+
+    /// Returns the boot info's memory map.
+    fn memory_map(&self) -> Self::MemoryMap {
+        fn convert_region(region: &MemRegion) -> mem::Region {
+            mem::Region::new(
+                PAddr::from_u64(region.base),
+                region.len as usize,
+                region.kind,
+            )
+        }
+        self.memory_map.iter().map(convert_region)
+    }
+
+    fn writer(&self) -> Self::Writer {
+        unimplemented!()
+    }
+
+    fn framebuffer(&self) -> Option<Self::Framebuffer> {
+        if !self.has_framebuffer {
+            return None;
+        }
+
+        Some(unsafe { framebuf::mk_framebuf() })
+    }
+
+    fn bootloader_name(&self) -> &str {
+        self.bootloader_name
+    }
+
+    fn init_paging(&self) {
+        mm::init_paging(self.vm_offset())
+    }
+}
+
+impl Multiboot2BootInfo {
+    /// Parses the Multiboot2 information structure at `info_addr`.
+    ///
+    /// Returns an error if the kernel wasn't booted by a Multiboot2-compliant
+    /// loader, or the loader didn't provide a memory map.
+    ///
+    /// # Safety
+    ///
+    /// `info_addr` must be the address of the information structure passed by
+    /// the loader, which must be identity-mapped.
+    pub(super) unsafe fn from_bootloader(
+        magic: u32,
+        info_addr: usize,
+    ) -> Result<Self, &'static str> {
+        if magic != boot::BOOTLOADER_MAGIC {
+            return Err("not booted by a Multiboot2-compliant loader");
+        }
+        if info_addr == 0 || info_addr % 8 != 0 {
+            return Err("the Multiboot2 information structure is misaligned");
+        }
+        let total_size = (info_addr as *const u32).read() as usize;
+        // Safety: the loader promises that the whole information structure is
+        // `total_size` bytes long.
+        let info: &'static [u8] = core::slice::from_raw_parts(info_addr as *const u8, total_size);
+        let tags = || Tags {
+            bytes: info.get(8..).unwrap_or(&[]),
+        };
+        let find = |kind| tags().find(|tag: &Tag| tag.kind == kind);
+
+        let memory_map = find(TAG_MEMORY_MAP).ok_or("the loader did not provide a memory map")?;
+        let cmdline = find(TAG_CMDLINE)
+            .and_then(|tag| tag.str_at(8))
+            .unwrap_or("");
+        let bootloader_name = find(TAG_BOOTLOADER_NAME)
+            .and_then(|tag| tag.str_at(8))
+            .filter(|name| !name.is_empty())
+            .unwrap_or("Multiboot2");
+        // the RSDP tags hold a copy of the RSDP, rather than its address, so
+        // the copy is used in place of the original. prefer the ACPI 2.0 RSDP,
+        // which has the address of the XSDT.
+        let rsdp_addr = find(TAG_RSDP_V2)
+            .or_else(|| find(TAG_RSDP_V1))
+            .map(|tag| PAddr::from_u64(tag.bytes.as_ptr() as u64 + 8));
+        let has_framebuffer = framebuf::init(find(TAG_FRAMEBUFFER).and_then(Tag::framebuffer));
+
+        MODULES.init(Self::modules_from(
+            tags().filter(|tag| tag.kind == TAG_MODULE),
+        ));
+        // everything the kernel keeps referring to after boot: the kernel
+        // image (including the boot page tables and stack), the information
+        // structure, and the modules.
+        let mut reserved = [(0, 0); MAX_MODULES + 2];
+        reserved[0] = (
+            core::ptr::addr_of!(__kernel_start) as u64,
+            core::ptr::addr_of!(__kernel_end) as u64,
+        );
+        reserved[1] = (info_addr as u64, (info_addr + total_size) as u64);
+        let mut nreserved = 2;
+        for tag in tags()
+            .filter(|tag| tag.kind == TAG_MODULE)
+            .take(MAX_MODULES)
+        {
+            if let (Some(start), Some(end)) = (tag.u32_at(8), tag.u32_at(12)) {
+                reserved[nreserved] = (start as u64, end as u64);
+                nreserved += 1;
+            }
+        }
+        let reserved = &mut reserved[..nreserved];
+        reserved.sort_unstable();
+        MEMORY_MAP.init(Self::memory_map_from(memory_map, reserved));
+        let memory_map = MEMORY_MAP
+            .try_get()
+            .map(|(regions, len)| &regions[..*len])
+            .unwrap_or(&[]);
+
+        Ok(Self {
+            memory_map,
+            cmdline,
+            bootloader_name,
+            rsdp_addr,
+            has_framebuffer,
+        })
+    }
+
+    /// Returns the offset at which physical memory is mapped. The boot
+    /// trampoline identity-maps physical memory, so this is always zero.
+    pub(super) fn vm_offset(&self) -> VAddr {
+        VAddr::from_u64(0)
+    }
+
+    /// Returns the address of the ACPI RSDP, if the loader found one.
+    pub(super) fn rsdp_addr(&self) -> Option<PAddr> {
+        self.rsdp_addr
+    }
+
+    /// Returns the kernel command line, or an empty string if the loader
+    /// didn't pass one.
+    pub(super) fn cmdline(&self) -> &'static str {
+        self.cmdline
+    }
+
+    /// Returns the boot modules loaded by the loader.
+    ///
+    /// Each module is named by its command line (the arguments after its file
+    /// name, in GRUB's `module2` command), or, if it has none,
+    /// [`modules::RAMDISK`].
+    pub(super) fn modules(&self) -> &'static [BootModule] {
+        MODULES
+            .try_get()
+            .map(|(modules, len)| &modules[..*len])
+            .unwrap_or(&[])
+    }
+
+    fn modules_from(tags: impl Iterator<Item = Tag>) -> ([BootModule; MAX_MODULES], usize) {
+        let mut modules = [BootModule::new(modules::RAMDISK, &[]); MAX_MODULES];
+        let mut len = 0;
+        for tag in tags.take(MAX_MODULES) {
+            let (Some(start), Some(end)) = (tag.u32_at(8), tag.u32_at(12)) else {
+                continue;
+            };
+            if end < start {
+                continue;
+            }
+            let name = tag
+                .str_at(16)
+                .filter(|cmdline| !cmdline.is_empty())
+                .unwrap_or(modules::RAMDISK);
+            // Safety: modules are loaded below 4 GiB, which is identity
+            // mapped, and are reserved in the memory map.
+            let data = unsafe {
+                core::slice::from_raw_parts(start as usize as *const u8, (end - start) as usize)
+            };
+            modules[len] = BootModule::new(name, data);
+            len += 1;
+        }
+        (modules, len)
+    }
+
+    /// Converts the loader's memory map, carving the `reserved` ranges (which
+    /// must be sorted) out of its available regions, and dropping anything
+    /// that isn't identity-mapped.
+    fn memory_map_from(tag: Tag, reserved: &[(u64, u64)]) -> ([MemRegion; MAX_REGIONS], usize) {
+        let mut regions = [MemRegion {
+            base: 0,
+            len: 0,
+            kind: mem::RegionKind::UNKNOWN,
+        }; MAX_REGIONS];
+        let entry_size = tag.u32_at(8).unwrap_or(0) as usize;
+        if entry_size < 24 {
+            return (regions, 0);
+        }
+
+        let mut len = 0;
+        let mut push = |base: u64, end: u64, kind| {
+            if end > base && len < MAX_REGIONS {
+                regions[len] = MemRegion {
+                    base,
+                    len: end - base,
+                    kind,
+                };
+                len += 1;
+            }
+        };
+        let entries = tag.bytes.get(16..).unwrap_or(&[]);
+        for entry in entries.chunks_exact(entry_size) {
+            // each entry is a 64-bit base address and length, followed by a
+            // 32-bit type.
+            let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let length = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let kind = u32::from_le_bytes(entry[16..20].try_into().unwrap());
+            let start = base.min(boot::IDENTITY_MAPPED);
+            let end = base.saturating_add(length).min(boot::IDENTITY_MAPPED);
+            if kind != MEMORY_AVAILABLE {
+                push(start, end, mem::RegionKind::UNKNOWN);
+                continue;
+            }
+
+            let mut start = start;
+            for &(res_start, res_end) in reserved {
+                if res_end <= start || res_start >= end {
+                    continue;
+                }
+                push(start, res_start.max(start), mem::RegionKind::FREE);
+                push(
+                    res_start.max(start),
+                    res_end.min(end),
+                    mem::RegionKind::BOOT,
+                );
+                start = res_end.min(end);
+            }
+            push(start, end, mem::RegionKind::FREE);
+        }
+        (regions, len)
+    }
+}
+
+// === impl Tag ===
+
+impl Tag {
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn u64_at(&self, offset: usize) -> Option<u64> {
+        let bytes = self.bytes.get(offset..offset + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.bytes.get(offset).copied()
+    }
+
+    /// Returns the NUL-terminated string starting at `offset`.
+    fn str_at(&self, offset: usize) -> Option<&'static str> {
+        let bytes = self.bytes.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    fn framebuffer(self) -> Option<FramebufferTag> {
+        Some(FramebufferTag {
+            addr: self.u64_at(8)?,
+            pitch: self.u32_at(16)?,
+            width: self.u32_at(20)?,
+            height: self.u32_at(24)?,
+            bpp: self.u8_at(28)?,
+            kind: self.u8_at(29)?,
+            // for direct RGB color, each channel is described by its
+            // position and then its size, starting at offset 32.
+            channels: [32, 34, 36].map(|offset| {
+                (
+                    self.u8_at(offset).unwrap_or(0),
+                    self.u8_at(offset + 1).unwrap_or(0),
+                )
+            }),
+        })
+    }
+}
+
+// === impl Tags ===
+
+impl Iterator for Tags {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        let header = self.bytes.get(..8)?;
+        let kind = u32::from_le_bytes(header[..4].try_into().ok()?);
+        let size = u32::from_le_bytes(header[4..].try_into().ok()?) as usize;
+        if kind == TAG_END || size < 8 {
+            return None;
+        }
+        let tag = Tag {
+            kind,
+            bytes: self.bytes.get(..size)?,
+        };
+        // tags are padded to 8-byte alignment.
+        self.bytes = self.bytes.get(size.next_multiple_of(8)..).unwrap_or(&[]);
+        Some(tag)
+    }
+}
@@ -0,0 +1,51 @@
+//! The framebuffer described by the Multiboot2 framebuffer tag.
+//!
+//! Loaders aren't required to set up a framebuffer at all, and may leave the
+//! display in EGA text mode. If there's no direct RGB color framebuffer, the
+//! kernel only writes to the serial port.
+use crate::{boot, bootinfo::FramebufferTag};
+use mnemos_x86_64::drivers::framebuf::{self, Channel, FramebufInfo, PixelFormat};
+
+pub(super) use mnemos_x86_64::drivers::framebuf::{
+    force_unlock, is_ready, mk_framebuf, scroll_up, try_mk_framebuf, FramebufWriter,
+};
+
+/// Try to initialize the framebuffer from the Multiboot2 framebuffer tag.
+///
+/// Returns `true` if the framebuffer is available, or `false` if there is no
+/// framebuffer tag, or the framebuffer has a pixel format we can't draw to or
+/// isn't identity-mapped.
+pub(super) fn init(tag: Option<FramebufferTag>) -> bool {
+    if framebuf::is_initialized() {
+        return true;
+    }
+
+    let Some(fb) = tag else {
+        return false;
+    };
+    // only direct RGB color framebuffers are supported.
+    if fb.kind != 1 {
+        return false;
+    }
+    let len = fb.pitch as u64 * fb.height as u64;
+    if fb.addr.saturating_add(len) > boot::IDENTITY_MAPPED {
+        return false;
+    }
+    let [red, green, blue] = fb.channels.map(|(shift, size)| Channel { shift, size });
+    let Some(format) = PixelFormat::from_channels(fb.bpp.into(), red, green, blue) else {
+        return false;
+    };
+    let cfg = FramebufInfo {
+        height: fb.height as usize,
+        width: fb.width as usize,
+        stride: fb.pitch as usize,
+        format,
+    };
+    // Safety: the framebuffer is identity-mapped, and is `pitch` bytes long
+    // for each line.
+    let buf = unsafe { core::slice::from_raw_parts_mut(fb.addr as usize as *mut u8, len as usize) };
+
+    // the loader has finished setting the display mode by the time it jumps
+    // to the kernel, so there's no need to wait for the display to settle.
+    framebuf::init(cfg, buf, 0)
+}
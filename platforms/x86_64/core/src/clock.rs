@@ -0,0 +1,211 @@
+//! A monotonic clock, independent of the hardware it's read from.
+//!
+//! There are several ways to tell the time on x86_64, and which are usable
+//! depends on the machine. The [`Clock`] trait hides that choice from the
+//! rest of the kernel: [`init`] picks the best available source once the
+//! hardware has been probed, and everything else reads it with [`now`].
+//!
+//! # Sources
+//!
+//! - [`TscClock`] reads the timestamp counter, which has nanosecond-scale
+//!   granularity. It is used if the CPU has an invariant TSC, and
+//!   [`delay::init`] was able to measure its frequency.
+//! - Otherwise, [`TickClock`] counts periodic timer interrupts, so its
+//!   granularity is the `timer_granularity` set on the command line.
+//!
+//! The ACPI PM timer wraps around every few seconds, so it's only suitable
+//! for measuring short intervals, and isn't offered as a clock.
+//!
+//! Before [`init`] is called, [`now`] reads the [`TickClock`]. When [`init`]
+//! switches to the TSC, the TSC clock starts at the tick clock's current
+//! time, so [`Instant`]s taken before and after the switch can be compared.
+//!
+//! [`delay::init`]: crate::delay::init
+use crate::{delay, interrupt};
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use mycelium_util::sync::InitOnce;
+
+/// A source of monotonic time.
+pub trait Clock: Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns the smallest interval the clock can measure.
+    fn granularity(&self) -> Duration;
+
+    /// Returns a name for the clock, for diagnostics.
+    fn name(&self) -> &'static str;
+}
+
+/// A point in time, measured by a [`Clock`] from when it started.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+/// A [`Clock`] counting periodic timer interrupts.
+#[derive(Debug)]
+pub struct TickClock {
+    /// The interval between timer interrupts, in nanoseconds.
+    interval_nanos: AtomicU64,
+}
+
+/// A [`Clock`] reading the invariant timestamp counter.
+#[derive(Debug)]
+pub struct TscClock {
+    hz: u64,
+    /// The TSC value when the clock started.
+    base: u64,
+    /// The time when the clock started.
+    start: Instant,
+}
+
+static TICKS: TickClock = TickClock {
+    interval_nanos: AtomicU64::new(interrupt::TIMER_INTERVAL.as_nanos() as u64),
+};
+
+static TSC: InitOnce<TscClock> = InitOnce::uninitialized();
+
+static CLOCK: InitOnce<&'static dyn Clock> = InitOnce::uninitialized();
+
+/// Select the clock, given timer interrupts `timer_interval` apart.
+///
+/// This must be called once, after [`delay::init`](crate::delay::init).
+pub fn init(timer_interval: Duration) {
+    TICKS
+        .interval_nanos
+        .store(timer_interval.as_nanos() as u64, Ordering::Relaxed);
+    let clock: &'static dyn Clock = match delay::tsc_hz() {
+        Some(hz) => {
+            TSC.init(TscClock {
+                hz,
+                base: rdtsc(),
+                start: TICKS.now(),
+            });
+            TSC.get()
+        }
+        None => &TICKS,
+    };
+    CLOCK.init(clock);
+    tracing::info!(
+        clock = clock.name(),
+        granularity = ?clock.granularity(),
+        "selected clock"
+    );
+}
+
+/// Returns the selected clock, or the [`TickClock`] if [`init`] hasn't been
+/// called.
+#[must_use]
+pub fn current() -> &'static dyn Clock {
+    CLOCK.try_get().copied().unwrap_or(&TICKS)
+}
+
+/// Returns the interval between timer interrupts, as passed to [`init`].
+#[must_use]
+pub fn timer_interval() -> Duration {
+    Duration::from_nanos(TICKS.interval_nanos.load(Ordering::Relaxed))
+}
+
+/// Returns the current time, according to the [`current`] clock.
+#[must_use]
+pub fn now() -> Instant {
+    current().now()
+}
+
+// === impl Instant ===
+
+impl Instant {
+    /// The time when the clock started.
+    pub const ZERO: Self = Self(Duration::ZERO);
+
+    /// Returns the time elapsed since the clock started.
+    #[must_use]
+    pub fn since_start(self) -> Duration {
+        self.0
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, or zero if
+    /// `earlier` is later than `self`.
+    #[must_use]
+    pub fn duration_since(self, earlier: Self) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Returns the time elapsed since `self`, according to the [`current`]
+    /// clock.
+    #[must_use]
+    pub fn elapsed(self) -> Duration {
+        now() - self
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    /// Returns the time elapsed from `rhs` to `self`, saturating to zero.
+    fn sub(self, rhs: Self) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+// === impl TickClock ===
+
+impl Clock for TickClock {
+    fn now(&self) -> Instant {
+        let nanos =
+            interrupt::timer_ticks().saturating_mul(self.interval_nanos.load(Ordering::Relaxed));
+        Instant(Duration::from_nanos(nanos))
+    }
+
+    fn granularity(&self) -> Duration {
+        Duration::from_nanos(self.interval_nanos.load(Ordering::Relaxed))
+    }
+
+    fn name(&self) -> &'static str {
+        "timer ticks"
+    }
+}
+
+// === impl TscClock ===
+
+impl Clock for TscClock {
+    fn now(&self) -> Instant {
+        let cycles = rdtsc().wrapping_sub(self.base) as u128;
+        let nanos = cycles * 1_000_000_000 / self.hz as u128;
+        self.start + Duration::from_nanos(nanos as u64)
+    }
+
+    fn granularity(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000u64.div_ceil(self.hz))
+    }
+
+    fn name(&self) -> &'static str {
+        "TSC"
+    }
+}
+
+#[inline(always)]
+fn rdtsc() -> u64 {
+    unsafe {
+        // Safety: reading the TSC has no side effects.
+        core::arch::x86_64::_rdtsc()
+    }
+}
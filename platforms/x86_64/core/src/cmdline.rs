@@ -0,0 +1,244 @@
+//! Parsing the kernel command line.
+//!
+//! The command line is a whitespace-separated list of `key=value` options:
+//!
+//! | key                 | value                                  | default  |
+//! |---------------------|----------------------------------------|----------|
+//! | `max_drivers`       | a number of driver services, or `unlimited` | `unlimited` |
+//! | `timer_granularity` | a duration, such as `10ms` or `500us`  | `10ms`   |
+//! | `timesource`        | `auto`, `pit`, or `apic`               | `auto`   |
+//! | `trace`             | `off`, `error`, `warn`, `info`, `debug`, or `trace` | unset |
+//! | `smp`               | `on` or `off`                          | `on`     |
+//! | `ap_stack_size`     | a size, such as `64k` or `1m`          | `64k`    |
+//! | `watchdog`          | a duration, or `off`                   | `5s`     |
+//! | `sysrq`             | a key combination, such as `ctrl+alt+f12`, or `off` | `ctrl+alt+printscreen` |
+//!
+//! Durations without a unit are in milliseconds, and sizes without a unit
+//! are in bytes. Unknown keys are ignored,
+//! and malformed values leave the option at its default. Since the command
+//! line is parsed before tracing is set up, these problems are only logged
+//! once [`Cmdline::warn_invalid`] is called.
+use crate::{stack, sysrq};
+use core::{fmt, time::Duration};
+use kernel::tracing::level_filters::LevelFilter;
+
+/// Options parsed from the kernel command line.
+///
+/// See the [module-level documentation](self) for details.
+#[derive(Clone, Debug)]
+pub struct Cmdline {
+    raw: &'static str,
+    /// The maximum number of driver services which may be registered, or
+    /// `None` for no limit.
+    pub max_drivers: Option<usize>,
+    pub timer_granularity: Duration,
+    pub time_source: TimeSource,
+    /// The most verbose level to trace, if one was given.
+    pub trace_level: Option<LevelFilter>,
+    /// Whether to bring up the application processors.
+    pub smp: bool,
+    /// The size of each application processor's stack, in bytes.
+    pub ap_stack_size: usize,
+    /// How long a core's run loop may go without making progress before the
+    /// [watchdog](crate::watchdog) warns about it, or `None` to disable it.
+    pub watchdog: Option<Duration>,
+    /// The key combination which [dumps the kernel's state](crate::sysrq),
+    /// or `None` to disable it.
+    pub sysrq: Option<sysrq::Trigger>,
+}
+
+/// Which hardware timer drives the kernel's timer wheel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeSource {
+    /// Use the local APIC timer if the ACPI tables describe an APIC, and the
+    /// PIT otherwise.
+    #[default]
+    Auto,
+    /// Use the legacy PIT. The HAL only drives the PIT through the legacy
+    /// PIC, so this also disables the I/O APIC.
+    Pit,
+    /// Use the local APIC timer.
+    LocalApic,
+}
+
+/// The default [`Cmdline::watchdog`] threshold.
+const DEFAULT_WATCHDOG: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum Problem<'a> {
+    UnknownKey(&'a str),
+    Malformed { key: &'a str, value: &'a str },
+    MissingValue(&'a str),
+}
+
+// === impl Cmdline ===
+
+impl Cmdline {
+    /// Parse `raw`, ignoring any unknown or malformed options.
+    #[must_use]
+    pub fn parse(raw: &'static str) -> Self {
+        Self::parse_inner(raw, |_| {})
+    }
+
+    /// Returns the command line as it was passed to the kernel.
+    #[must_use]
+    pub fn raw(&self) -> &'static str {
+        self.raw
+    }
+
+    /// Log a warning for each unknown or malformed option.
+    pub fn warn_invalid(&self) {
+        Self::parse_inner(self.raw, |problem| match problem {
+            Problem::UnknownKey(key) => {
+                tracing::warn!(key, "ignoring unknown kernel command line option")
+            }
+            Problem::Malformed { key, value } => {
+                tracing::warn!(
+                    key,
+                    value,
+                    "malformed kernel command line option, using default"
+                )
+            }
+            Problem::MissingValue(key) => {
+                tracing::warn!(
+                    key,
+                    "kernel command line option has no value, using default"
+                )
+            }
+        });
+    }
+
+    fn parse_inner(raw: &'static str, mut report: impl FnMut(Problem<'_>)) -> Self {
+        let mut cmdline = Self {
+            raw,
+            ..Self::default()
+        };
+
+        for opt in raw.split_whitespace() {
+            let Some((key, value)) = opt.split_once('=') else {
+                report(Problem::MissingValue(opt));
+                continue;
+            };
+            let ok = match key {
+                "max_drivers" => match value {
+                    "unlimited" => {
+                        cmdline.max_drivers = None;
+                        Some(())
+                    }
+                    _ => value
+                        .parse()
+                        .ok()
+                        .filter(|&max| max > 0)
+                        .map(|max| cmdline.max_drivers = Some(max)),
+                },
+                "timer_granularity" => {
+                    parse_duration(value).map(|granularity| cmdline.timer_granularity = granularity)
+                }
+                "timesource" => TimeSource::parse(value).map(|source| cmdline.time_source = source),
+                "trace" => value
+                    .parse()
+                    .ok()
+                    .map(|level| cmdline.trace_level = Some(level)),
+                "smp" => parse_bool(value).map(|smp| cmdline.smp = smp),
+                "ap_stack_size" => parse_size(value).map(|size| cmdline.ap_stack_size = size),
+                "watchdog" => match value {
+                    "off" | "0" => {
+                        cmdline.watchdog = None;
+                        Some(())
+                    }
+                    _ => parse_duration(value).map(|threshold| cmdline.watchdog = Some(threshold)),
+                },
+                "sysrq" => match value {
+                    "off" => {
+                        cmdline.sysrq = None;
+                        Some(())
+                    }
+                    _ => sysrq::Trigger::parse(value).map(|trigger| cmdline.sysrq = Some(trigger)),
+                },
+                _ => {
+                    report(Problem::UnknownKey(key));
+                    continue;
+                }
+            };
+            if ok.is_none() {
+                report(Problem::Malformed { key, value });
+            }
+        }
+
+        cmdline
+    }
+}
+
+impl Default for Cmdline {
+    fn default() -> Self {
+        Self {
+            raw: "",
+            max_drivers: None,
+            timer_granularity: crate::interrupt::TIMER_INTERVAL,
+            time_source: TimeSource::Auto,
+            trace_level: None,
+            smp: true,
+            ap_stack_size: stack::DEFAULT_AP_STACK_SIZE,
+            watchdog: Some(DEFAULT_WATCHDOG),
+            sysrq: Some(sysrq::Trigger::default()),
+        }
+    }
+}
+
+impl fmt::Display for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.raw)
+    }
+}
+
+// === impl TimeSource ===
+
+impl TimeSource {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Self::Auto),
+            "pit" => Some(Self::Pit),
+            "apic" | "lapic" => Some(Self::LocalApic),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a non-zero duration with an optional `s`, `ms`, or `us` suffix.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (digits, unit): (_, fn(u64) -> Duration) = if let Some(digits) = s.strip_suffix("ms") {
+        (digits, Duration::from_millis)
+    } else if let Some(digits) = s.strip_suffix("us") {
+        (digits, Duration::from_micros)
+    } else if let Some(digits) = s.strip_suffix('s') {
+        (digits, Duration::from_secs)
+    } else {
+        (s, Duration::from_millis)
+    };
+    let duration = unit(digits.parse().ok()?);
+    (!duration.is_zero()).then_some(duration)
+}
+
+/// Parse a non-zero size with an optional `k` or `m` suffix.
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, scale) = if let Some(digits) = s.strip_suffix(['k', 'K']) {
+        (digits, 1024)
+    } else if let Some(digits) = s.strip_suffix(['m', 'M']) {
+        (digits, 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+    digits
+        .parse::<usize>()
+        .ok()?
+        .checked_mul(scale)
+        .filter(|&size| size > 0)
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,9 @@
+//! CPU identification.
+//!
+//! This complements the HAL's `cpu` module, which provides the low-level
+//! instructions, with information about what the CPU we're running on
+//! supports, and [`simd`] routines which use its vector instructions.
+pub mod features;
+pub mod simd;
+
+pub use self::features::{features, CpuFeatures};
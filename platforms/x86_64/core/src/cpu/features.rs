@@ -0,0 +1,435 @@
+//! CPU feature detection.
+//!
+//! The CPUID leaves describing optional features are read once, into a
+//! [`CpuFeatures`], which is returned by [`features`]. Code that depends on
+//! an optional feature should check it here, rather than executing `CPUID`
+//! itself.
+//!
+//! The features are those of the boot processor. Application processors in
+//! the same system are assumed to support the same features.
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, __get_cpuid_max, CpuidResult},
+    fmt,
+};
+use mycelium_util::sync::Lazy;
+
+/// The features supported by the CPU, read from CPUID.
+#[derive(Clone)]
+pub struct CpuFeatures {
+    max_leaf: u32,
+    max_extended_leaf: u32,
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    /// The family, model, and stepping, from leaf 1's EAX.
+    signature: u32,
+    leaf1: CpuidResult,
+    /// Leaf 5, the monitor line sizes and `MWAIT` C-states, if supported.
+    leaf5: CpuidResult,
+    /// Leaf 6, the thermal and power management flags, if supported.
+    leaf6: CpuidResult,
+    /// Leaf 7 subleaf 0, the structured extended feature flags, if supported.
+    leaf7: CpuidResult,
+    /// Leaf `0x8000_0001`, the extended feature flags, if supported.
+    ext1: CpuidResult,
+    /// Leaf `0x8000_0007`, the advanced power management flags, if supported.
+    ext7: CpuidResult,
+}
+
+static FEATURES: Lazy<CpuFeatures> = Lazy::new(CpuFeatures::detect);
+
+// CPUID.01H:ECX
+const LEAF1_ECX_SSE3: u32 = 1 << 0;
+const LEAF1_ECX_MONITOR: u32 = 1 << 3;
+const LEAF1_ECX_SSSE3: u32 = 1 << 9;
+const LEAF1_ECX_PCID: u32 = 1 << 17;
+const LEAF1_ECX_SSE4_1: u32 = 1 << 19;
+const LEAF1_ECX_SSE4_2: u32 = 1 << 20;
+const LEAF1_ECX_X2APIC: u32 = 1 << 21;
+const LEAF1_ECX_TSC_DEADLINE: u32 = 1 << 24;
+const LEAF1_ECX_XSAVE: u32 = 1 << 26;
+const LEAF1_ECX_AVX: u32 = 1 << 28;
+const LEAF1_ECX_RDRAND: u32 = 1 << 30;
+const LEAF1_ECX_HYPERVISOR: u32 = 1 << 31;
+
+// CPUID.01H:EDX
+const LEAF1_EDX_TSC: u32 = 1 << 4;
+const LEAF1_EDX_APIC: u32 = 1 << 9;
+const LEAF1_EDX_SSE: u32 = 1 << 25;
+const LEAF1_EDX_SSE2: u32 = 1 << 26;
+const LEAF1_EDX_HTT: u32 = 1 << 28;
+
+// CPUID.05H:ECX
+const LEAF5_ECX_EMX: u32 = 1 << 0;
+const LEAF5_ECX_IBE: u32 = 1 << 1;
+
+// CPUID.06H:EAX
+const LEAF6_EAX_ARAT: u32 = 1 << 2;
+
+// CPUID.(EAX=07H,ECX=0):EBX
+const LEAF7_EBX_FSGSBASE: u32 = 1 << 0;
+const LEAF7_EBX_AVX2: u32 = 1 << 5;
+const LEAF7_EBX_ERMS: u32 = 1 << 9;
+const LEAF7_EBX_INVPCID: u32 = 1 << 10;
+
+// CPUID.80000001H:EDX
+const EXT1_EDX_NX: u32 = 1 << 20;
+const EXT1_EDX_1G_PAGES: u32 = 1 << 26;
+const EXT1_EDX_RDTSCP: u32 = 1 << 27;
+
+// CPUID.80000007H:EDX
+const EXT7_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+const EMPTY: CpuidResult = CpuidResult {
+    eax: 0,
+    ebx: 0,
+    ecx: 0,
+    edx: 0,
+};
+
+/// Read the CPU's features, and log them.
+///
+/// This is called early in boot. [`features`] may be called before this, in
+/// which case it reads them without logging.
+pub fn init() {
+    let features = features();
+    tracing::info!(
+        vendor = features.vendor(),
+        brand = features.brand(),
+        family = features.family(),
+        model = features.model(),
+        stepping = features.stepping(),
+        "CPU identified"
+    );
+    tracing::debug!(?features);
+}
+
+/// Returns the features supported by the CPU.
+#[must_use]
+pub fn features() -> &'static CpuFeatures {
+    &FEATURES
+}
+
+// === impl CpuFeatures ===
+
+macro_rules! feature {
+    ($(#[$meta:meta])* $name:ident => $reg:ident.$field:ident & $bit:ident) => {
+        $(#[$meta])*
+        #[must_use]
+        pub fn $name(&self) -> bool {
+            self.$reg.$field & $bit != 0
+        }
+    };
+}
+
+impl CpuFeatures {
+    fn detect() -> Self {
+        // Safety: CPUID is always available in 64-bit mode, and the leaves
+        // below are only read if the CPU reports supporting them.
+        unsafe {
+            let leaf0 = __cpuid(0);
+            let max_leaf = leaf0.eax;
+            let mut vendor = [0; 12];
+            vendor[..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+            vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+            vendor[8..].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+            let leaf = |leaf: u32| {
+                if max_leaf >= leaf {
+                    __cpuid(leaf)
+                } else {
+                    EMPTY
+                }
+            };
+            let (max_extended_leaf, _) = __get_cpuid_max(0x8000_0000);
+            let ext = |leaf: u32| {
+                if max_extended_leaf >= leaf {
+                    __cpuid(leaf)
+                } else {
+                    EMPTY
+                }
+            };
+
+            let mut brand = [0; 48];
+            for (i, chunk) in brand.chunks_exact_mut(16).enumerate() {
+                let regs = ext(0x8000_0002 + i as u32);
+                for (dst, reg) in chunk
+                    .chunks_exact_mut(4)
+                    .zip([regs.eax, regs.ebx, regs.ecx, regs.edx])
+                {
+                    dst.copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+
+            let leaf1 = leaf(1);
+            Self {
+                max_leaf,
+                max_extended_leaf,
+                vendor,
+                brand,
+                signature: leaf1.eax,
+                leaf1,
+                leaf5: leaf(5),
+                leaf6: leaf(6),
+                leaf7: if max_leaf >= 7 {
+                    __cpuid_count(7, 0)
+                } else {
+                    EMPTY
+                },
+                ext1: ext(0x8000_0001),
+                ext7: ext(0x8000_0007),
+            }
+        }
+    }
+
+    /// Returns the highest basic CPUID leaf.
+    #[must_use]
+    pub fn max_leaf(&self) -> u32 {
+        self.max_leaf
+    }
+
+    /// Returns the highest extended CPUID leaf.
+    #[must_use]
+    pub fn max_extended_leaf(&self) -> u32 {
+        self.max_extended_leaf
+    }
+
+    /// Returns the vendor ID string, such as `GenuineIntel` or
+    /// `AuthenticAMD`.
+    #[must_use]
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("<invalid>")
+    }
+
+    /// Returns the processor brand string, or an empty string if the CPU
+    /// doesn't have one.
+    #[must_use]
+    pub fn brand(&self) -> &str {
+        let len = self
+            .brand
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.brand.len());
+        core::str::from_utf8(&self.brand[..len])
+            .unwrap_or("<invalid>")
+            .trim()
+    }
+
+    /// Returns the processor family, including the extended family.
+    #[must_use]
+    pub fn family(&self) -> u32 {
+        let family = (self.signature >> 8) & 0xf;
+        if family == 0xf {
+            family + ((self.signature >> 20) & 0xff)
+        } else {
+            family
+        }
+    }
+
+    /// Returns the processor model, including the extended model.
+    #[must_use]
+    pub fn model(&self) -> u32 {
+        let model = (self.signature >> 4) & 0xf;
+        let family = (self.signature >> 8) & 0xf;
+        if family == 0x6 || family == 0xf {
+            model | ((self.signature >> 16) & 0xf) << 4
+        } else {
+            model
+        }
+    }
+
+    /// Returns the processor stepping.
+    #[must_use]
+    pub fn stepping(&self) -> u32 {
+        self.signature & 0xf
+    }
+
+    /// Returns the smallest and largest monitor line sizes in bytes, if
+    /// `MONITOR`/`MWAIT` are supported and CPUID reports them.
+    #[must_use]
+    pub fn monitor_line_sizes(&self) -> Option<(usize, usize)> {
+        if !self.has_monitor() {
+            return None;
+        }
+        let smallest = (self.leaf5.eax & 0xffff) as usize;
+        let largest = (self.leaf5.ebx & 0xffff) as usize;
+        (smallest != 0 && largest != 0).then_some((smallest, largest))
+    }
+
+    /// Returns the number of `MWAIT` sub-states of C-state `cstate` (0 to
+    /// 7), or 0 if CPUID doesn't enumerate them.
+    #[must_use]
+    pub fn mwait_substates(&self, cstate: u8) -> u8 {
+        if !self.has_monitor() || self.leaf5.ecx & LEAF5_ECX_EMX == 0 || cstate > 7 {
+            return 0;
+        }
+        ((self.leaf5.edx >> (u32::from(cstate) * 4)) & 0xf) as u8
+    }
+
+    feature! {
+        /// Returns `true` if `MWAIT` can be woken by an interrupt while
+        /// interrupts are disabled.
+        has_mwait_interrupt_break => leaf5.ecx & LEAF5_ECX_IBE
+    }
+
+    feature! {
+        /// Returns `true` if the local APIC timer keeps running in every
+        /// C-state.
+        has_arat => leaf6.eax & LEAF6_EAX_ARAT
+    }
+
+    feature! {
+        /// Returns `true` if the CPU has a time stamp counter.
+        has_tsc => leaf1.edx & LEAF1_EDX_TSC
+    }
+
+    feature! {
+        /// Returns `true` if the TSC ticks at a constant rate in every P-,
+        /// C-, and T-state.
+        has_invariant_tsc => ext7.edx & EXT7_EDX_INVARIANT_TSC
+    }
+
+    feature! {
+        /// Returns `true` if the `RDTSCP` instruction is supported.
+        has_rdtscp => ext1.edx & EXT1_EDX_RDTSCP
+    }
+
+    feature! {
+        /// Returns `true` if the CPU has a local APIC.
+        has_apic => leaf1.edx & LEAF1_EDX_APIC
+    }
+
+    feature! {
+        /// Returns `true` if the local APIC supports x2APIC mode.
+        has_x2apic => leaf1.ecx & LEAF1_ECX_X2APIC
+    }
+
+    feature! {
+        /// Returns `true` if the local APIC timer supports TSC-deadline mode.
+        has_tsc_deadline => leaf1.ecx & LEAF1_ECX_TSC_DEADLINE
+    }
+
+    feature! {
+        /// Returns `true` if the `MONITOR` and `MWAIT` instructions are
+        /// supported.
+        has_monitor => leaf1.ecx & LEAF1_ECX_MONITOR
+    }
+
+    feature! {
+        /// Returns `true` if leaf 1 reports the number of logical processors
+        /// per package.
+        has_htt => leaf1.edx & LEAF1_EDX_HTT
+    }
+
+    feature! {
+        /// Returns `true` if the kernel is running under a hypervisor.
+        has_hypervisor => leaf1.ecx & LEAF1_ECX_HYPERVISOR
+    }
+
+    feature! {
+        /// Returns `true` if SSE is supported.
+        has_sse => leaf1.edx & LEAF1_EDX_SSE
+    }
+
+    feature! {
+        /// Returns `true` if SSE2 is supported.
+        has_sse2 => leaf1.edx & LEAF1_EDX_SSE2
+    }
+
+    feature! {
+        /// Returns `true` if SSE3 is supported.
+        has_sse3 => leaf1.ecx & LEAF1_ECX_SSE3
+    }
+
+    feature! {
+        /// Returns `true` if SSSE3 is supported.
+        has_ssse3 => leaf1.ecx & LEAF1_ECX_SSSE3
+    }
+
+    feature! {
+        /// Returns `true` if SSE4.1 is supported.
+        has_sse4_1 => leaf1.ecx & LEAF1_ECX_SSE4_1
+    }
+
+    feature! {
+        /// Returns `true` if SSE4.2 is supported.
+        has_sse4_2 => leaf1.ecx & LEAF1_ECX_SSE4_2
+    }
+
+    feature! {
+        /// Returns `true` if the `XSAVE` family of instructions is supported.
+        has_xsave => leaf1.ecx & LEAF1_ECX_XSAVE
+    }
+
+    feature! {
+        /// Returns `true` if AVX is supported by the CPU. The OS must also
+        /// enable it in `XCR0` before it can be used.
+        has_avx => leaf1.ecx & LEAF1_ECX_AVX
+    }
+
+    feature! {
+        /// Returns `true` if AVX2 is supported by the CPU. The OS must also
+        /// enable AVX in `XCR0` before it can be used.
+        has_avx2 => leaf7.ebx & LEAF7_EBX_AVX2
+    }
+
+    feature! {
+        /// Returns `true` if `REP MOVSB` and `REP STOSB` are fast.
+        has_erms => leaf7.ebx & LEAF7_EBX_ERMS
+    }
+
+    feature! {
+        /// Returns `true` if the `RDRAND` instruction is supported.
+        has_rdrand => leaf1.ecx & LEAF1_ECX_RDRAND
+    }
+
+    feature! {
+        /// Returns `true` if the `RDFSBASE` family of instructions is
+        /// supported.
+        has_fsgsbase => leaf7.ebx & LEAF7_EBX_FSGSBASE
+    }
+
+    feature! {
+        /// Returns `true` if process-context identifiers are supported.
+        has_pcid => leaf1.ecx & LEAF1_ECX_PCID
+    }
+
+    feature! {
+        /// Returns `true` if the `INVPCID` instruction is supported.
+        has_invpcid => leaf7.ebx & LEAF7_EBX_INVPCID
+    }
+
+    feature! {
+        /// Returns `true` if pages can be marked non-executable.
+        has_nx => ext1.edx & EXT1_EDX_NX
+    }
+
+    feature! {
+        /// Returns `true` if 1 GiB pages are supported.
+        has_1g_pages => ext1.edx & EXT1_EDX_1G_PAGES
+    }
+}
+
+impl fmt::Debug for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CpuFeatures")
+            .field("vendor", &self.vendor())
+            .field("brand", &self.brand())
+            .field("max_leaf", &format_args!("{:#x}", self.max_leaf))
+            .field(
+                "max_extended_leaf",
+                &format_args!("{:#x}", self.max_extended_leaf),
+            )
+            .field("invariant_tsc", &self.has_invariant_tsc())
+            .field("x2apic", &self.has_x2apic())
+            .field("tsc_deadline", &self.has_tsc_deadline())
+            .field("monitor", &self.has_monitor())
+            .field("arat", &self.has_arat())
+            .field("sse4_2", &self.has_sse4_2())
+            .field("avx2", &self.has_avx2())
+            .field("erms", &self.has_erms())
+            .field("nx", &self.has_nx())
+            .field("1g_pages", &self.has_1g_pages())
+            .field("hypervisor", &self.has_hypervisor())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,357 @@
+//! Vector-accelerated memory fills and copies.
+//!
+//! The kernel is built for a target without SSE, so the compiler never emits
+//! vector instructions, and nothing saves vector registers on interrupts or
+//! context switches. The routines here use SSE2, or AVX2 where CPUID reports
+//! it, from inline assembly, for filling and copying large buffers such as
+//! the framebuffer. Each one saves the vector registers it uses on the stack
+//! first, and restores them afterwards, so an interrupt handler which runs
+//! one in the middle of another doesn't corrupt it.
+//!
+//! The CPU faults on vector instructions until they're enabled in `CR0` and
+//! `CR4` (and, for AVX, `XCR0`), which [`init`] does for the current core.
+//! Until [`init`] has been called, and on CPUs without SSE2, every routine
+//! falls back to a scalar loop.
+use core::{
+    arch::asm,
+    ops::Range,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// The widest vector instructions the routines here use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// No vector instructions.
+    Scalar = 0,
+    /// 16-byte SSE2 loads and stores.
+    Sse2 = 1,
+    /// 32-byte AVX2 loads and stores.
+    Avx2 = 2,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Scalar as u8);
+
+// CR0
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+
+// CR4
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+// XCR0
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// The number of bytes a fill writes per loop iteration at each level.
+///
+/// This is three vector registers, which is a multiple of every pixel size
+/// from 1 to 4 bytes, so the same registers can be stored over and over.
+const FILL_BLOCK: usize = 96;
+const SSE2_FILL_STEP: usize = 48;
+const AVX2_FILL_STEP: usize = 96;
+
+/// The number of bytes a copy moves per loop iteration at each level.
+const SSE2_COPY_STEP: usize = 64;
+const AVX2_COPY_STEP: usize = 128;
+
+/// Enable vector instructions on the current core, and select the widest
+/// the CPU supports.
+///
+/// This must be called on every core before it uses the routines here with
+/// vector instructions; the boot processor calls it during `init`, and
+/// application processors when they start their run loop.
+pub fn init() {
+    let features = super::features();
+    if !features.has_sse2() {
+        tracing::warn!("CPU does not support SSE2, using scalar framebuffer copies");
+        return;
+    }
+    let avx2 = features.has_avx2() && features.has_avx() && features.has_xsave();
+
+    unsafe {
+        // Safety: these only enable features the CPU reports supporting, and
+        // the compiler never emits instructions which depend on them.
+        let cr0 = read_cr0();
+        write_cr0((cr0 & !CR0_EM) | CR0_MP);
+        let mut cr4 = read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if avx2 {
+            cr4 |= CR4_OSXSAVE;
+        }
+        write_cr4(cr4);
+        if avx2 {
+            write_xcr0(read_xcr0() | XCR0_X87 | XCR0_SSE | XCR0_AVX);
+        }
+    }
+
+    let level = if avx2 { Level::Avx2 } else { Level::Sse2 };
+    LEVEL.store(level as u8, Ordering::Release);
+    tracing::debug!(?level, "enabled vector instructions");
+}
+
+/// Returns the widest vector instructions in use.
+#[must_use]
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Acquire) {
+        2 => Level::Avx2,
+        1 => Level::Sse2,
+        _ => Level::Scalar,
+    }
+}
+
+/// Fill `dst` with repeated copies of `pattern`, such as a pixel.
+///
+/// Patterns whose length doesn't evenly divide 96 bytes (any pixel size from
+/// 1 to 4 bytes does) are always filled with a scalar loop. If `dst`'s length
+/// isn't a multiple of the pattern's, the last copy is cut short.
+pub fn fill(dst: &mut [u8], pattern: &[u8]) {
+    if pattern.is_empty() {
+        return;
+    }
+    if FILL_BLOCK % pattern.len() != 0 {
+        for chunk in dst.chunks_mut(pattern.len()) {
+            chunk.copy_from_slice(&pattern[..chunk.len()]);
+        }
+        return;
+    }
+
+    let mut block = [0u8; FILL_BLOCK];
+    for (i, byte) in block.iter_mut().enumerate() {
+        *byte = pattern[i % pattern.len()];
+    }
+    let step = match level() {
+        Level::Scalar => 0,
+        Level::Sse2 => SSE2_FILL_STEP,
+        Level::Avx2 => AVX2_FILL_STEP,
+    };
+    let bulk = if step == 0 {
+        0
+    } else {
+        dst.len() / step * step
+    };
+
+    if bulk > 0 {
+        let ptr = dst.as_mut_ptr();
+        unsafe {
+            // Safety: `bulk` is a nonzero multiple of `step`, and no larger
+            // than `dst`. vector instructions are enabled, as the level isn't
+            // `Scalar`.
+            if step == AVX2_FILL_STEP {
+                asm!(
+                    "sub rsp, 96",
+                    "vmovdqu [rsp], ymm0",
+                    "vmovdqu [rsp + 32], ymm1",
+                    "vmovdqu [rsp + 64], ymm2",
+                    "vmovdqu ymm0, [{block}]",
+                    "vmovdqu ymm1, [{block} + 32]",
+                    "vmovdqu ymm2, [{block} + 64]",
+                    "2:",
+                    "vmovdqu [{dst}], ymm0",
+                    "vmovdqu [{dst} + 32], ymm1",
+                    "vmovdqu [{dst} + 64], ymm2",
+                    "add {dst}, 96",
+                    "sub {len}, 96",
+                    "jnz 2b",
+                    "vmovdqu ymm0, [rsp]",
+                    "vmovdqu ymm1, [rsp + 32]",
+                    "vmovdqu ymm2, [rsp + 64]",
+                    "add rsp, 96",
+                    block = in(reg) block.as_ptr(),
+                    dst = inout(reg) ptr => _,
+                    len = inout(reg) bulk => _,
+                );
+            } else {
+                asm!(
+                    "sub rsp, 48",
+                    "movdqu [rsp], xmm0",
+                    "movdqu [rsp + 16], xmm1",
+                    "movdqu [rsp + 32], xmm2",
+                    "movdqu xmm0, [{block}]",
+                    "movdqu xmm1, [{block} + 16]",
+                    "movdqu xmm2, [{block} + 32]",
+                    "2:",
+                    "movdqu [{dst}], xmm0",
+                    "movdqu [{dst} + 16], xmm1",
+                    "movdqu [{dst} + 32], xmm2",
+                    "add {dst}, 48",
+                    "sub {len}, 48",
+                    "jnz 2b",
+                    "movdqu xmm0, [rsp]",
+                    "movdqu xmm1, [rsp + 16]",
+                    "movdqu xmm2, [rsp + 32]",
+                    "add rsp, 48",
+                    block = in(reg) block.as_ptr(),
+                    dst = inout(reg) ptr => _,
+                    len = inout(reg) bulk => _,
+                );
+            }
+        }
+    }
+
+    // `bulk` is a multiple of the pattern's length, so the rest of the block
+    // lines up with the rest of `dst`.
+    for chunk in dst[bulk..].chunks_mut(FILL_BLOCK) {
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
+
+/// Copy `buf[src]` to `buf[dest..]`, like [`slice::copy_within`].
+///
+/// The ranges may overlap, as when scrolling.
+///
+/// # Panics
+///
+/// If either range is out of bounds.
+pub fn copy_within(buf: &mut [u8], src: Range<usize>, dest: usize) {
+    assert!(
+        src.start <= src.end && src.end <= buf.len(),
+        "source out of bounds"
+    );
+    let len = src.end - src.start;
+    assert!(dest <= buf.len() - len, "destination out of bounds");
+
+    let step = match level() {
+        Level::Scalar => 0,
+        Level::Sse2 => SSE2_COPY_STEP,
+        Level::Avx2 => AVX2_COPY_STEP,
+    };
+    if step == 0 || len < step || dest == src.start {
+        buf.copy_within(src, dest);
+        return;
+    }
+
+    let bulk = len / step * step;
+    let rest = len - bulk;
+    let base = buf.as_mut_ptr();
+    // each iteration loads a whole step before storing any of it, so copying
+    // forwards is safe when the destination is before the source, and
+    // backwards when it's after.
+    let (first, stride, rest_at) = if dest < src.start {
+        (0, step as isize, bulk)
+    } else {
+        (len - step, -(step as isize), 0)
+    };
+    unsafe {
+        // Safety: the ranges are in bounds, and the loop copies `bulk /
+        // step` whole steps within them. vector instructions are enabled, as
+        // the level isn't `Scalar`.
+        let src_ptr = base.add(src.start + first);
+        let dst_ptr = base.add(dest + first);
+        let count = bulk / step;
+        if step == AVX2_COPY_STEP {
+            asm!(
+                "sub rsp, 128",
+                "vmovdqu [rsp], ymm0",
+                "vmovdqu [rsp + 32], ymm1",
+                "vmovdqu [rsp + 64], ymm2",
+                "vmovdqu [rsp + 96], ymm3",
+                "2:",
+                "vmovdqu ymm0, [{src}]",
+                "vmovdqu ymm1, [{src} + 32]",
+                "vmovdqu ymm2, [{src} + 64]",
+                "vmovdqu ymm3, [{src} + 96]",
+                "vmovdqu [{dst}], ymm0",
+                "vmovdqu [{dst} + 32], ymm1",
+                "vmovdqu [{dst} + 64], ymm2",
+                "vmovdqu [{dst} + 96], ymm3",
+                "add {src}, {stride}",
+                "add {dst}, {stride}",
+                "dec {count}",
+                "jnz 2b",
+                "vmovdqu ymm0, [rsp]",
+                "vmovdqu ymm1, [rsp + 32]",
+                "vmovdqu ymm2, [rsp + 64]",
+                "vmovdqu ymm3, [rsp + 96]",
+                "add rsp, 128",
+                src = inout(reg) src_ptr => _,
+                dst = inout(reg) dst_ptr => _,
+                count = inout(reg) count => _,
+                stride = in(reg) stride,
+            );
+        } else {
+            asm!(
+                "sub rsp, 64",
+                "movdqu [rsp], xmm0",
+                "movdqu [rsp + 16], xmm1",
+                "movdqu [rsp + 32], xmm2",
+                "movdqu [rsp + 48], xmm3",
+                "2:",
+                "movdqu xmm0, [{src}]",
+                "movdqu xmm1, [{src} + 16]",
+                "movdqu xmm2, [{src} + 32]",
+                "movdqu xmm3, [{src} + 48]",
+                "movdqu [{dst}], xmm0",
+                "movdqu [{dst} + 16], xmm1",
+                "movdqu [{dst} + 32], xmm2",
+                "movdqu [{dst} + 48], xmm3",
+                "add {src}, {stride}",
+                "add {dst}, {stride}",
+                "dec {count}",
+                "jnz 2b",
+                "movdqu xmm0, [rsp]",
+                "movdqu xmm1, [rsp + 16]",
+                "movdqu xmm2, [rsp + 32]",
+                "movdqu xmm3, [rsp + 48]",
+                "add rsp, 64",
+                src = inout(reg) src_ptr => _,
+                dst = inout(reg) dst_ptr => _,
+                count = inout(reg) count => _,
+                stride = in(reg) stride,
+            );
+        }
+    }
+
+    // the bytes the loop didn't cover are at the end of a forward copy, and
+    // the start of a backward one, and haven't been overwritten either way.
+    if rest > 0 {
+        buf.copy_within(
+            src.start + rest_at..src.start + rest_at + rest,
+            dest + rest_at,
+        );
+    }
+}
+
+unsafe fn read_cr0() -> u64 {
+    let cr0: u64;
+    asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    cr0
+}
+
+unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+}
+
+unsafe fn read_cr4() -> u64 {
+    let cr4: u64;
+    asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    cr4
+}
+
+unsafe fn write_cr4(cr4: u64) {
+    asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+}
+
+unsafe fn read_xcr0() -> u64 {
+    let (lo, hi): (u32, u32);
+    asm!(
+        "xgetbv",
+        in("ecx") 0,
+        out("eax") lo,
+        out("edx") hi,
+        options(nomem, nostack, preserves_flags),
+    );
+    ((hi as u64) << 32) | lo as u64
+}
+
+unsafe fn write_xcr0(xcr0: u64) {
+    asm!(
+        "xsetbv",
+        in("ecx") 0,
+        in("eax") xcr0 as u32,
+        in("edx") (xcr0 >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
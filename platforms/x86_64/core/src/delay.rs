@@ -0,0 +1,255 @@
+//! Short busy-wait delays.
+//!
+//! [`udelay`] and [`ndelay`] spin for at least the requested time, for
+//! drivers that need delays too short to sleep on the timer wheel. They never
+//! sleep or yield, so they may be called with interrupts disabled, and from
+//! any core.
+//!
+//! # Accuracy
+//!
+//! If the CPU has an invariant TSC (one which ticks at a constant rate in
+//! every P-, C-, and T-state), [`init`] measures its frequency against the
+//! [ACPI PM timer], or against the PIT if there isn't one, and delays spin on
+//! the TSC. The measurement is accurate to within about 0.1%, and a delay
+//! overshoots by the time it takes to read the TSC (tens of nanoseconds), plus
+//! any interrupts or SMIs that arrive while spinning.
+//!
+//! Otherwise, delays spin on the ACPI PM timer, which ticks every ~280ns.
+//! Delays are rounded up to a whole number of ticks, and each read of the PM
+//! timer is an I/O port access taking around a microsecond, so delays shorter
+//! than a few microseconds may take several times longer than requested.
+//!
+//! Without a PM timer, or before [`init`] is called, delays count down on PIT
+//! channel 2, which ticks every ~838ns. This is as imprecise as the PM timer,
+//! and only one core can use the PIT at a time, so concurrent delays on other
+//! cores wait for it, too.
+//!
+//! [ACPI PM timer]: crate::acpi::pm_timer
+use crate::{acpi::pm_timer, cpu, interrupt};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hal_x86_64::cpu::Port;
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+
+/// The frequency of the PIT's input clock, in Hz.
+const PIT_HZ: u64 = 1_193_182;
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// The PC speaker control port, which also gates PIT channel 2, and reports
+/// its output.
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Select channel 2, write the low and then high byte of the count, and
+/// count down once (mode 0, "interrupt on terminal count").
+const PIT_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+/// Set in the speaker control port to enable PIT channel 2's gate.
+const SPEAKER_GATE: u8 = 1 << 0;
+/// Set in the speaker control port to connect PIT channel 2 to the speaker.
+const SPEAKER_DATA: u8 = 1 << 1;
+/// Set in the speaker control port when PIT channel 2's output is high, which
+/// it is once a one-shot count has finished.
+const SPEAKER_OUT2: u8 = 1 << 5;
+
+/// How long each TSC calibration run lasts.
+const CALIBRATION_MICROS: u64 = 10_000;
+/// How many calibration runs to make. Against the PIT, the shortest is used,
+/// as runs can only be lengthened by interrupts and SMIs. Against the PM
+/// timer, both clocks are read at the start and end of each run, and the
+/// median is used.
+const CALIBRATION_RUNS: usize = 3;
+
+/// The TSC's frequency in Hz, or 0 if it hasn't been measured.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Serializes access to PIT channel 2.
+static PIT: Mutex<(), Spinlock> = Mutex::new_with_raw_mutex((), Spinlock::new());
+
+/// Measure the TSC's frequency, if it is invariant, so that delays spin on
+/// the TSC rather than the PM timer or PIT.
+///
+/// This should be called once the ACPI tables have been parsed, so that the
+/// PM timer can be used as the reference.
+pub fn init() {
+    if !cpu::features().has_invariant_tsc() {
+        match pm_timer::get() {
+            Some(_) => tracing::info!("TSC is not invariant, delays will use the PM timer"),
+            None => tracing::info!("TSC is not invariant, delays will use the PIT"),
+        }
+        return;
+    }
+
+    let (hz, reference) = match pm_timer::get() {
+        Some(pm) => (calibrate_pm_timer(pm), "PM timer"),
+        None => (calibrate_pit(), "PIT"),
+    };
+    if hz == 0 {
+        tracing::warn!(
+            reference,
+            "TSC calibration failed, delays will not use the TSC"
+        );
+        return;
+    }
+    tracing::info!(
+        tsc.mhz = hz / 1_000_000,
+        reference,
+        "calibrated the TSC for delays"
+    );
+    TSC_HZ.store(hz, Ordering::Release);
+}
+
+/// Returns the TSC's frequency in Hz, if [`init`] has measured it.
+#[must_use]
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Acquire) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Spin for at least `micros` microseconds.
+///
+/// See [the module-level documentation](self#accuracy) for how precise this
+/// is.
+pub fn udelay(micros: u64) {
+    ndelay(micros.saturating_mul(1_000))
+}
+
+/// Spin for at least `nanos` nanoseconds.
+///
+/// See [the module-level documentation](self#accuracy) for how precise this
+/// is.
+pub fn ndelay(nanos: u64) {
+    if nanos == 0 {
+        return;
+    }
+
+    match (tsc_hz(), pm_timer::get()) {
+        (Some(hz), _) => {
+            let cycles = ticks_for(nanos, hz);
+            let start = rdtsc();
+            while (rdtsc().wrapping_sub(start) as u128) < cycles {
+                core::hint::spin_loop();
+            }
+        }
+        (None, Some(pm)) => {
+            // the counter may wrap around in a long delay, so count the
+            // elapsed ticks in parts, each well under its period.
+            let mut remaining = ticks_for(nanos, pm_timer::FREQUENCY_HZ);
+            let mut start = pm.now();
+            while remaining > 0 {
+                let elapsed = pm.elapsed_ticks(start);
+                if elapsed as u128 >= remaining || elapsed >= 1 << 23 {
+                    remaining = remaining.saturating_sub(elapsed as u128);
+                    start = start.wrapping_add(elapsed);
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        (None, None) => {
+            let mut ticks = ticks_for(nanos, PIT_HZ);
+            pit_countdown(|countdown| {
+                // the PIT's counter is only 16 bits, so long delays are
+                // counted down in several parts.
+                while ticks > 0 {
+                    let part = ticks.min(u16::MAX as u128);
+                    countdown(part as u16);
+                    ticks -= part;
+                }
+            })
+        }
+    }
+}
+
+/// Returns the TSC's frequency measured against the PM timer, or 0 if it
+/// couldn't be measured.
+fn calibrate_pm_timer(pm: &pm_timer::PmTimer) -> u64 {
+    let pm_ticks = (CALIBRATION_MICROS * pm_timer::FREQUENCY_HZ / 1_000_000) as u32;
+    let mut runs = [0; CALIBRATION_RUNS];
+    for run in &mut runs {
+        *run = interrupt::without_interrupts(|| {
+            let start_pm = pm.now();
+            let start_tsc = rdtsc();
+            let elapsed = loop {
+                let elapsed = pm.elapsed_ticks(start_pm);
+                if elapsed >= pm_ticks {
+                    break elapsed;
+                }
+                core::hint::spin_loop();
+            };
+            let cycles = rdtsc().wrapping_sub(start_tsc);
+            cycles * pm_timer::FREQUENCY_HZ / elapsed as u64
+        });
+    }
+    runs.sort_unstable();
+    runs[CALIBRATION_RUNS / 2]
+}
+
+/// Returns the TSC's frequency measured against the PIT, or 0 if it couldn't
+/// be measured.
+fn calibrate_pit() -> u64 {
+    let pit_ticks = CALIBRATION_MICROS * PIT_HZ / 1_000_000;
+    let cycles = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            pit_countdown(|countdown| {
+                let start = rdtsc();
+                countdown(pit_ticks as u16);
+                rdtsc().wrapping_sub(start)
+            })
+        })
+        .min()
+        .unwrap_or(0);
+    cycles * PIT_HZ / pit_ticks
+}
+
+/// Returns the number of ticks of a `hz` clock in `nanos` nanoseconds,
+/// rounded up.
+fn ticks_for(nanos: u64, hz: u64) -> u128 {
+    (nanos as u128 * hz as u128).div_ceil(1_000_000_000)
+}
+
+/// Call `f` with exclusive access to PIT channel 2, passing it a function
+/// which counts down the given number of PIT ticks.
+fn pit_countdown<T>(f: impl FnOnce(&mut dyn FnMut(u16)) -> T) -> T {
+    // interrupts are disabled so that an interrupt handler on this core
+    // can't deadlock waiting for the PIT, and so that calibration runs aren't
+    // lengthened by interrupts.
+    interrupt::without_interrupts(|| {
+        let _pit = PIT.lock();
+        let speaker = Port::at(SPEAKER_CONTROL);
+        let command = Port::at(PIT_COMMAND);
+        let channel2 = Port::at(PIT_CHANNEL2);
+        unsafe {
+            // Safety: we hold the PIT lock, and leave the speaker disconnected.
+            let saved = speaker.readb();
+            let mut countdown = |ticks: u16| {
+                // disable the gate while the count is written, then enable it
+                // to start counting down.
+                let control = speaker.readb() & !(SPEAKER_GATE | SPEAKER_DATA);
+                speaker.writeb(control);
+                command.writeb(PIT_CHANNEL2_ONESHOT);
+                let [lo, hi] = ticks.to_le_bytes();
+                channel2.writeb(lo);
+                channel2.writeb(hi);
+                speaker.writeb(control | SPEAKER_GATE);
+                while speaker.readb() & SPEAKER_OUT2 == 0 {
+                    core::hint::spin_loop();
+                }
+            };
+            let result = f(&mut countdown);
+            speaker.writeb(saved);
+            result
+        }
+    })
+}
+
+#[inline(always)]
+fn rdtsc() -> u64 {
+    unsafe {
+        // Safety: reading the TSC has no side effects.
+        _rdtsc()
+    }
+}
@@ -0,0 +1,270 @@
+//! A scrolling text console drawn on the framebuffer.
+//!
+//! A [`Console`] keeps a grid of character cells. Writing to it (with its
+//! [`fmt::Write`] implementation) only updates the grid; nothing is drawn
+//! until [`Console::flush`] is called with the framebuffer. This means the
+//! framebuffer only needs to be locked while flushing, and only the cells
+//! which changed since the last flush are redrawn.
+//!
+//! When the cursor moves past the bottom row, the grid scrolls up by a row.
+//! On the next flush, the framebuffer's existing contents are blitted up by
+//! the number of rows scrolled, so only the newly exposed rows need to be
+//! drawn, rather than the whole screen.
+use alloc::vec::Vec;
+use core::fmt;
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::Point,
+    mono_font::{MonoFont, MonoTextStyleBuilder},
+    pixelcolor::{Rgb888, RgbColor},
+    text::{Baseline, Text},
+    Drawable,
+};
+use hal_core::framebuffer::{self, Draw};
+
+use super::framebuf::Contrast;
+
+/// Space left blank around the edges of the screen, in pixels.
+const MARGIN: usize = 10;
+
+/// Tab stops are every this many columns.
+const TAB_WIDTH: usize = 8;
+
+/// The font and colors used by a [`Console`].
+#[derive(Copy, Clone, Debug)]
+pub struct ConsoleStyle {
+    pub font: &'static MonoFont<'static>,
+    /// The initial text color. This can be changed while writing with
+    /// [`Console::set_color`].
+    pub text: Rgb888,
+    pub background: Rgb888,
+}
+
+/// A scrolling text console.
+///
+/// See the [module-level documentation](self) for details.
+#[derive(Debug)]
+pub struct Console {
+    style: ConsoleStyle,
+    color: Rgb888,
+    cols: usize,
+    rows: usize,
+    /// `rows * cols` cells, in row-major order.
+    cells: Vec<Cell>,
+    /// For each row, the range of columns changed since the last flush.
+    dirty: Vec<Dirty>,
+    /// The column and row the next character is written to.
+    cursor: (usize, usize),
+    /// The number of rows scrolled since the last flush.
+    scrolled: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    color: Rgb888,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Dirty {
+    start: usize,
+    end: usize,
+}
+
+// === impl ConsoleStyle ===
+
+impl Default for ConsoleStyle {
+    fn default() -> Self {
+        Self {
+            font: &profont::PROFONT_12_POINT,
+            text: Rgb888::new(128, 128, 128),
+            background: Rgb888::BLACK,
+        }
+    }
+}
+
+// === impl Console ===
+
+impl Console {
+    /// Returns a new console covering a framebuffer of `width` by `height`
+    /// pixels, or `None` if its cells could not be allocated.
+    ///
+    /// Allocation is fallible so that a console can be attempted before the
+    /// heap is fully set up.
+    #[must_use]
+    pub fn try_new(width: usize, height: usize, style: ConsoleStyle) -> Option<Self> {
+        let size = style.font.character_size;
+        let cols = (width.saturating_sub(MARGIN * 2) / size.width as usize).max(1);
+        let rows = (height.saturating_sub(MARGIN * 2) / size.height as usize).max(1);
+        let blank = Cell {
+            ch: ' ',
+            color: style.text,
+        };
+
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(cols * rows).ok()?;
+        cells.resize(cols * rows, blank);
+        let mut dirty = Vec::new();
+        dirty.try_reserve_exact(rows).ok()?;
+        dirty.resize(rows, Dirty::default());
+
+        Some(Self {
+            style,
+            color: style.text,
+            cols,
+            rows,
+            cells,
+            dirty,
+            cursor: (0, 0),
+            scrolled: 0,
+        })
+    }
+
+    /// Returns the number of columns and rows of text in the console.
+    #[must_use]
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Set the color of text written from now on.
+    pub fn set_color(&mut self, color: Rgb888) {
+        self.color = color;
+    }
+
+    /// Draw everything written since the last flush to `target`.
+    pub fn flush<D>(&mut self, target: &mut D) -> fmt::Result
+    where
+        D: Draw,
+        for<'a> framebuffer::DrawTarget<&'a mut D>: DrawTarget<Color = Rgb888>,
+    {
+        let char_height = self.style.font.character_size.height as usize;
+        match self.scrolled {
+            0 => {}
+            // everything on screen has scrolled off, so blitting would just
+            // move text that's about to be overwritten.
+            scrolled if scrolled >= self.rows => self.mark_all_dirty(),
+            scrolled => target.scroll_vert((scrolled * char_height) as isize),
+        }
+        self.scrolled = 0;
+
+        let mut target = target.as_draw_target();
+        for row in 0..self.rows {
+            let Dirty { start, end } = core::mem::take(&mut self.dirty[row]);
+            for col in start..end {
+                let cell = self.cells[row * self.cols + col];
+                let (color, background) =
+                    Contrast::global().colors(cell.color, Some(self.style.background));
+                let style = MonoTextStyleBuilder::new()
+                    .font(self.style.font)
+                    .text_color(color)
+                    .background_color(background.unwrap_or(self.style.background))
+                    .build();
+                let mut buf = [0; 4];
+                Text::with_baseline(
+                    cell.ch.encode_utf8(&mut buf),
+                    self.point(col, row),
+                    style,
+                    Baseline::Top,
+                )
+                .draw(&mut target)
+                .map_err(|_| fmt::Error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn point(&self, col: usize, row: usize) -> Point {
+        let size = self.style.font.character_size;
+        Point {
+            x: (MARGIN + col * size.width as usize) as i32,
+            y: (MARGIN + row * size.height as usize) as i32,
+        }
+    }
+
+    fn put(&mut self, ch: char) {
+        if self.cursor.0 >= self.cols {
+            self.newline();
+        }
+
+        let (col, row) = self.cursor;
+        let cell = Cell {
+            ch,
+            color: self.color,
+        };
+        let idx = row * self.cols + col;
+        if self.cells[idx] != cell {
+            self.cells[idx] = cell;
+            self.dirty[row].add(col);
+        }
+        self.cursor.0 += 1;
+    }
+
+    fn newline(&mut self) {
+        self.cursor.0 = 0;
+        if self.cursor.1 + 1 < self.rows {
+            self.cursor.1 += 1;
+            return;
+        }
+
+        // scroll the grid up by a row, and clear the new bottom row.
+        self.cells.rotate_left(self.cols);
+        self.dirty.rotate_left(1);
+        let bottom = (self.rows - 1) * self.cols;
+        for cell in &mut self.cells[bottom..] {
+            cell.ch = ' ';
+        }
+        // the framebuffer will be blitted up, so the new bottom row still
+        // shows the row that was there before. redraw all of it.
+        self.dirty[self.rows - 1] = Dirty {
+            start: 0,
+            end: self.cols,
+        };
+        self.scrolled += 1;
+    }
+
+    fn mark_all_dirty(&mut self) {
+        for dirty in &mut self.dirty {
+            *dirty = Dirty {
+                start: 0,
+                end: self.cols,
+            };
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            match ch {
+                '\n' => self.newline(),
+                '\r' => self.cursor.0 = 0,
+                '\t' => {
+                    let next = (self.cursor.0 / TAB_WIDTH + 1) * TAB_WIDTH;
+                    while self.cursor.0 < next.min(self.cols) {
+                        self.put(' ');
+                    }
+                }
+                ch if ch.is_control() => {}
+                ch => self.put(ch),
+            }
+        }
+        Ok(())
+    }
+}
+
+// === impl Dirty ===
+
+impl Dirty {
+    fn add(&mut self, col: usize) {
+        if self.start >= self.end {
+            *self = Self {
+                start: col,
+                end: col + 1,
+            };
+        } else {
+            self.start = self.start.min(col);
+            self.end = self.end.max(col + 1);
+        }
+    }
+}
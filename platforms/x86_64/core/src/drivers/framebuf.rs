@@ -1,16 +1,320 @@
-use core::fmt;
+//! Drawing to framebuffers.
+//!
+//! Bootloaders describe the framebuffer they hand over in different ways,
+//! and real hardware doesn't always use 32-bit RGB pixels: a framebuffer may
+//! store its channels in BGR order, use 16 or 24 bits per pixel, or pad each
+//! row out past the end of its last pixel. Each boot binary translates its
+//! loader's description into a [`FramebufInfo`], and a [`Framebuffer`]
+//! converts colors to that native layout as it draws, so everything that
+//! draws through [`Draw`], including the panic handler, works on any of them.
+use crate::cpu::simd;
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
 use embedded_graphics::{
     draw_target::DrawTarget,
     geometry::Point,
     mono_font::MonoTextStyle,
-    pixelcolor::PixelColor,
+    pixelcolor::{PixelColor, Rgb888, RgbColor},
     text::{self, Text},
     Drawable,
 };
-use hal_core::framebuffer::{self, Draw};
+use hal_core::framebuffer::{self, Draw, RgbColor as HalColor};
+use kernel::maitake::sync::{
+    blocking::{Mutex, MutexGuard},
+    spin::{InitOnce, Spinlock},
+};
 
 // TODO(eliza): add emb_display_service implementation!
 
+/// How text drawn to the framebuffer is colored.
+///
+/// Some displays make the default colors hard to read (e.g. white text on a
+/// red background, or dark blue text on black). In [`Contrast::High`] mode,
+/// all text is drawn white-on-black instead. These are the brightest and
+/// darkest colors in every pixel format, so they remain distinguishable even
+/// on displays with limited bit depth, such as grayscale framebuffers, where
+/// many distinct colors map to similar shades.
+///
+/// The contrast setting is global, and is read without taking any locks, so
+/// it is also honored when drawing panic messages.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Contrast {
+    /// Draw text in its usual colors. This is the default.
+    #[default]
+    Normal,
+    /// Draw all text white-on-black.
+    High,
+}
+
+static HIGH_CONTRAST: AtomicBool = AtomicBool::new(false);
+
+impl Contrast {
+    /// Parse a contrast setting from the value of a boot argument, either
+    /// `"normal"` or `"high"`.
+    #[must_use]
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg.trim() {
+            "normal" => Some(Self::Normal),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    /// Returns the current global contrast setting.
+    #[must_use]
+    pub fn global() -> Self {
+        if HIGH_CONTRAST.load(Ordering::Relaxed) {
+            Self::High
+        } else {
+            Self::Normal
+        }
+    }
+
+    /// Set the global contrast setting.
+    pub fn set_global(self) {
+        HIGH_CONTRAST.store(self == Self::High, Ordering::Relaxed);
+    }
+
+    /// Returns the text and background colors to draw with in this mode,
+    /// given the colors that would be used by default.
+    #[must_use]
+    pub fn colors(self, text: Rgb888, background: Option<Rgb888>) -> (Rgb888, Option<Rgb888>) {
+        match self {
+            Self::Normal => (text, background),
+            Self::High => (Rgb888::WHITE, Some(Rgb888::BLACK)),
+        }
+    }
+}
+
+/// The position and width of a color channel in a pixel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    /// The position of the channel's lowest bit, in a little-endian pixel.
+    pub shift: u8,
+    /// The number of bits in the channel.
+    pub size: u8,
+}
+
+/// How a framebuffer stores the color of a pixel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Each pixel is `bytes_per_pixel` bytes, read as a little-endian
+    /// integer, with each color channel at its own bit position. Any other
+    /// bits are padding, and are written as zero.
+    Rgb {
+        bytes_per_pixel: u8,
+        red: Channel,
+        green: Channel,
+        blue: Channel,
+    },
+    /// Each pixel is `bytes_per_pixel` bytes, the first of which is its
+    /// brightness.
+    Gray { bytes_per_pixel: u8 },
+}
+
+/// The layout of a framebuffer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FramebufInfo {
+    /// The width of the framebuffer, in pixels.
+    pub width: usize,
+    /// The height of the framebuffer, in pixels.
+    pub height: usize,
+    /// The length of a row, in bytes, including any padding after its last
+    /// pixel.
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+/// A framebuffer in its native pixel format.
+///
+/// `B` is the framebuffer's memory, such as a guard for the lock protecting
+/// it. Drawing doesn't allocate, so a `Framebuffer` can be used by the panic
+/// handler.
+#[derive(Debug)]
+pub struct Framebuffer<B> {
+    info: FramebufInfo,
+    buf: B,
+}
+
+/// Scroll the contents of a raw framebuffer up by `lines` rows of pixels, and
+/// clear the rows exposed at the bottom to black.
+///
+/// `stride` is the length of a row in bytes. This uses [`simd`] copies, which
+/// are much faster on framebuffer memory than copying a pixel at a time, and
+/// doesn't allocate, so it can be used by the panic handler.
+pub fn scroll_up_raw(buf: &mut [u8], stride: usize, lines: usize) {
+    let len = buf.len();
+    let shift = lines.saturating_mul(stride).min(len);
+    simd::copy_within(buf, shift..len, 0);
+    simd::fill(&mut buf[len - shift..], &[0]);
+}
+
+/// Locks the framebuffer the bootloader handed over.
+///
+/// See [`init`].
+#[derive(Debug)]
+pub struct FramebufGuard(MutexGuard<'static, &'static mut [u8], Spinlock>);
+
+/// A [`Framebuffer`] drawing to the framebuffer the bootloader handed over.
+pub type FramebufWriter = Framebuffer<FramebufGuard>;
+
+/// The framebuffer the bootloader handed over, shared by every CPU core.
+struct GlobalFramebuf {
+    info: FramebufInfo,
+    settle_spins: usize,
+    buf: Mutex<&'static mut [u8], Spinlock>,
+}
+
+static FRAMEBUFFER: InitOnce<GlobalFramebuf> = InitOnce::uninitialized();
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Initialize the framebuffer the bootloader handed over.
+///
+/// Each boot binary translates its loader's description of the framebuffer
+/// into a [`FramebufInfo`] and calls this; everything else is shared.
+/// `settle_spins` is the number of spin loop iterations to wait before the
+/// first write to the framebuffer (see [`is_ready`]).
+///
+/// Returns `false` if `info` doesn't [fit](FramebufInfo::fits) in `buf`.
+///
+/// # Panics
+///
+/// If the framebuffer has already been initialized.
+pub fn init(info: FramebufInfo, buf: &'static mut [u8], settle_spins: usize) -> bool {
+    if !info.fits(buf.len()) {
+        return false;
+    }
+    FRAMEBUFFER.init(GlobalFramebuf {
+        info,
+        settle_spins,
+        buf: Mutex::new_with_raw_mutex(buf, Spinlock::new()),
+    });
+    true
+}
+
+/// Returns `true` if [`init`] has been called.
+#[must_use]
+pub fn is_initialized() -> bool {
+    FRAMEBUFFER.try_get().is_some()
+}
+
+/// Locks the framebuffer and returns a [`FramebufWriter`].
+///
+/// # Safety
+///
+/// In release mode, this function *assumes* the frame buffer has been
+/// initialized by [`init`]. If this is ever called before [`init`] has been
+/// called and returned `true`, this may read uninitialized memory!
+pub unsafe fn mk_framebuf() -> FramebufWriter {
+    let fb = unsafe {
+        // Safety: see above.
+        FRAMEBUFFER.get_unchecked()
+    };
+    Framebuffer::new(&fb.info, FramebufGuard(fb.buf.lock()))
+}
+
+/// Locks the framebuffer and returns a [`FramebufWriter`], or `None` if the
+/// framebuffer was never initialized.
+#[must_use]
+pub fn try_mk_framebuf() -> Option<FramebufWriter> {
+    let fb = FRAMEBUFFER.try_get()?;
+    Some(Framebuffer::new(&fb.info, FramebufGuard(fb.buf.lock())))
+}
+
+/// Returns `true` if the framebuffer is ready to be written to.
+///
+/// On some hardware, the display mode is not fully set up at the instant the
+/// bootloader hands us the framebuffer, and anything written to it right away
+/// comes out garbled. Loaders which may do this pass a nonzero
+/// `settle_spins` to [`init`], and the framebuffer is only considered ready
+/// once that many iterations of a spin loop have elapsed. The first time
+/// this returns `true`, the screen is cleared.
+///
+/// The panic handler doesn't wait for this, and writes to the framebuffer
+/// immediately.
+#[must_use]
+pub fn is_ready() -> bool {
+    if READY.load(Ordering::Acquire) {
+        return true;
+    }
+
+    let Some(fb) = FRAMEBUFFER.try_get() else {
+        return false;
+    };
+    let mut buf = fb.buf.lock();
+    // another CPU core may have finished settling while we waited for the lock.
+    if READY.load(Ordering::Acquire) {
+        return true;
+    }
+
+    for _ in 0..fb.settle_spins {
+        core::hint::spin_loop();
+    }
+    // black is all zeroes in every pixel format.
+    simd::fill(&mut buf, &[0]);
+    READY.store(true, Ordering::Release);
+    true
+}
+
+/// Scroll the framebuffer's contents up by `lines` rows of pixels, clearing
+/// the rows exposed at the bottom.
+///
+/// This copies the raw framebuffer with vector instructions, so it's much
+/// faster than [`Draw::scroll_vert`]. Does nothing if the framebuffer was
+/// never initialized.
+pub fn scroll_up(lines: usize) {
+    let Some(fb) = FRAMEBUFFER.try_get() else {
+        return;
+    };
+    let mut buf = fb.buf.lock();
+    let visible = fb.info.byte_len().min(buf.len());
+    scroll_up_raw(&mut buf[..visible], fb.info.stride, lines);
+}
+
+/// Forcibly unlock the framebuffer mutex.
+///
+/// # Safety
+///
+/// This forcibly unlocks a potentially-locked mutex, violating mutual
+/// exclusion! This should only be called in conditions where no other CPU core
+/// will *ever* attempt to access the framebuffer again (such as while oopsing).
+pub unsafe fn force_unlock() {
+    if let Some(fb) = FRAMEBUFFER.try_get() {
+        fb.buf.force_unlock();
+    }
+}
+
+/// A copy of the framebuffer in normal RAM, which can be drawn to instead of
+/// the real framebuffer.
+///
+/// Framebuffer memory is slow, especially to read from, so scrolling it means
+/// reading back the whole screen. A `BackBuffer` is drawn to and scrolled in
+/// RAM, and [`BackBuffer::present`] then writes only the pixels which changed
+/// since the last time it was called to the real framebuffer.
+///
+/// Changed pixels are tracked as a dirty span of columns for each row, so
+/// that, for example, redrawing a line of text only copies the rectangle that
+/// line covers. Scrolling dirties the whole screen.
+///
+/// Creating a back buffer allocates a whole screen's worth of pixels, so it
+/// mustn't be used by the panic handler, which draws straight to the
+/// framebuffer.
+#[derive(Debug)]
+pub struct BackBuffer {
+    width: usize,
+    height: usize,
+    /// `width * height` pixels, in row-major order, packed as `0x00RRGGBB`.
+    pixels: Vec<u32>,
+    /// For each row, the range of columns changed since the last
+    /// [`present`](Self::present).
+    dirty: Vec<(usize, usize)>,
+}
+
 #[derive(Debug)]
 pub struct TextWriter<'style, 'target, D, C> {
     target: framebuffer::DrawTarget<&'target mut D>,
@@ -131,8 +435,15 @@ where
 
                 // if the line is longer than the remaining space on the current
                 // line, wrap the line.
-                let rem = self.px_to_len(self.width_px - (self.point.x as u32));
+                let rem = self.px_to_len(self.width_px.saturating_sub(self.point.x as u32));
                 if line.len() > rem {
+                    // don't split a multi-byte character across lines --- if
+                    // we're writing a panic message, panicking again on a bad
+                    // char boundary would be quite unfortunate.
+                    let mut rem = rem;
+                    while !line.is_char_boundary(rem) {
+                        rem -= 1;
+                    }
                     let (curr, next) = line.split_at(rem);
                     line = next;
                     chunk = curr;
@@ -160,3 +471,386 @@ where
         Ok(())
     }
 }
+
+// === impl BackBuffer ===
+
+impl BackBuffer {
+    /// Returns a new back buffer of `width` by `height` pixels, or `None` if
+    /// its pixels could not be allocated.
+    ///
+    /// The buffer starts out black, and entirely dirty, so that the first
+    /// [`present`](Self::present) draws the whole screen.
+    #[must_use]
+    pub fn try_new(width: usize, height: usize) -> Option<Self> {
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(width.checked_mul(height)?).ok()?;
+        pixels.resize(width * height, 0);
+        let mut dirty = Vec::new();
+        dirty.try_reserve_exact(height).ok()?;
+        dirty.resize(height, (0, width));
+        Some(Self {
+            width,
+            height,
+            pixels,
+            dirty,
+        })
+    }
+
+    /// Copy every pixel changed since the last call to `present` to `target`.
+    ///
+    /// `target` should be the same size as this buffer; any pixels outside of
+    /// it are skipped.
+    pub fn present<D: Draw>(&mut self, target: &mut D) {
+        let width = self.width.min(target.width());
+        let height = self.height.min(target.height());
+        for (y, dirty) in self.dirty.iter_mut().enumerate() {
+            let (start, end) = core::mem::take(dirty);
+            if y >= height {
+                continue;
+            }
+            let row = &self.pixels[y * self.width..][..self.width];
+            for (x, &packed) in row.iter().enumerate().take(end.min(width)).skip(start) {
+                target.set_pixel(x, y, unpack(packed));
+            }
+        }
+    }
+
+    /// Returns `true` if anything has been drawn since the last call to
+    /// [`present`](Self::present).
+    #[must_use]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.iter().any(|&(start, end)| start < end)
+    }
+
+    fn mark_all_dirty(&mut self) {
+        let width = self.width;
+        self.dirty.fill((0, width));
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.pixels.len() * 4;
+        unsafe {
+            // Safety: any bytes are a valid `u32`, and `u8` has no alignment
+            // requirement.
+            core::slice::from_raw_parts_mut(self.pixels.as_mut_ptr().cast::<u8>(), len)
+        }
+    }
+}
+
+// === impl Channel ===
+
+impl Channel {
+    /// Returns a channel of 8 bits at bit `shift`.
+    #[must_use]
+    pub const fn byte(shift: u8) -> Self {
+        Self { shift, size: 8 }
+    }
+
+    /// Returns the bits for `value`, an 8-bit intensity, in this channel.
+    #[must_use]
+    pub fn encode(self, value: u8) -> u32 {
+        let value = u32::from(value);
+        let bits = match self.size {
+            0 => 0,
+            size @ 1..=8 => value >> (8 - size),
+            // scale up, filling the low bits with the high bits, so that
+            // full intensity stays full intensity.
+            size => {
+                let extra = u32::from(size - 8).min(24);
+                (value << extra) | (value >> 8u32.saturating_sub(extra))
+            }
+        };
+        bits.checked_shl(u32::from(self.shift)).unwrap_or(0)
+    }
+
+    fn fits(self, bits: u32) -> bool {
+        u32::from(self.shift) + u32::from(self.size) <= bits
+    }
+}
+
+// === impl PixelFormat ===
+
+impl PixelFormat {
+    /// Red, green, and blue bytes, in that order in memory, padded to
+    /// `bytes_per_pixel`.
+    #[must_use]
+    pub const fn rgb(bytes_per_pixel: u8) -> Self {
+        Self::Rgb {
+            bytes_per_pixel,
+            red: Channel::byte(0),
+            green: Channel::byte(8),
+            blue: Channel::byte(16),
+        }
+    }
+
+    /// Blue, green, and red bytes, in that order in memory, padded to
+    /// `bytes_per_pixel`.
+    #[must_use]
+    pub const fn bgr(bytes_per_pixel: u8) -> Self {
+        Self::Rgb {
+            bytes_per_pixel,
+            red: Channel::byte(16),
+            green: Channel::byte(8),
+            blue: Channel::byte(0),
+        }
+    }
+
+    /// Returns the format of `bits_per_pixel`-bit pixels with the given
+    /// channels, or `None` if we can't draw to it.
+    ///
+    /// Pixels must be a whole number of bytes, at most 32 bits, and every
+    /// channel must fit in a pixel.
+    #[must_use]
+    pub fn from_channels(
+        bits_per_pixel: u16,
+        red: Channel,
+        green: Channel,
+        blue: Channel,
+    ) -> Option<Self> {
+        let bits = u32::from(bits_per_pixel);
+        if bits == 0 || bits % 8 != 0 || bits > 32 {
+            return None;
+        }
+        if ![red, green, blue].iter().all(|channel| channel.fits(bits)) {
+            return None;
+        }
+        Some(Self::Rgb {
+            bytes_per_pixel: (bits / 8) as u8,
+            red,
+            green,
+            blue,
+        })
+    }
+
+    /// Returns the number of bytes in a pixel.
+    #[must_use]
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            Self::Rgb {
+                bytes_per_pixel, ..
+            }
+            | Self::Gray { bytes_per_pixel } => usize::from(bytes_per_pixel),
+        }
+    }
+
+    /// Returns `color` in this format, as a little-endian pixel.
+    ///
+    /// Only the first [`bytes_per_pixel`](Self::bytes_per_pixel) bytes of
+    /// the result are part of the pixel.
+    #[must_use]
+    pub fn encode(&self, color: HalColor) -> [u8; 4] {
+        match *self {
+            Self::Rgb {
+                red, green, blue, ..
+            } => (red.encode(color.red) | green.encode(color.green) | blue.encode(color.blue))
+                .to_le_bytes(),
+            Self::Gray { .. } => {
+                // the ITU-R BT.601 luma weights, out of 256.
+                let luma = (u32::from(color.red) * 77
+                    + u32::from(color.green) * 150
+                    + u32::from(color.blue) * 29)
+                    >> 8;
+                [luma as u8, 0, 0, 0]
+            }
+        }
+    }
+}
+
+// === impl FramebufInfo ===
+
+impl FramebufInfo {
+    /// Returns `true` if the framebuffer has some pixels, its rows are long
+    /// enough for its width, and all of its rows fit in `len` bytes.
+    #[must_use]
+    pub fn fits(&self, len: usize) -> bool {
+        let px_bytes = self.format.bytes_per_pixel();
+        let row = self.width.checked_mul(px_bytes);
+        let size = self.stride.checked_mul(self.height);
+        self.width > 0
+            && self.height > 0
+            && (1..=4).contains(&px_bytes)
+            && row.is_some_and(|row| row <= self.stride)
+            && size.is_some_and(|size| size <= len)
+    }
+
+    /// Returns the number of bytes covered by the framebuffer's rows.
+    #[must_use]
+    pub fn byte_len(&self) -> usize {
+        self.stride * self.height
+    }
+
+    /// Returns the offset of the pixel at (`x`, `y`).
+    fn offset(&self, x: usize, y: usize) -> usize {
+        y * self.stride + x * self.format.bytes_per_pixel()
+    }
+}
+
+// === impl FramebufGuard ===
+
+impl Deref for FramebufGuard {
+    type Target = [u8];
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for FramebufGuard {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+// === impl Framebuffer ===
+
+impl<B> Framebuffer<B>
+where
+    B: Deref<Target = [u8]> + DerefMut,
+{
+    /// Returns a framebuffer drawing to `buf`, laid out as described by
+    /// `info`.
+    ///
+    /// If `buf` is too short for `info`, pixels past its end are skipped.
+    #[must_use]
+    pub fn new(info: &FramebufInfo, buf: B) -> Self {
+        Self { info: *info, buf }
+    }
+
+    /// Returns the framebuffer's layout.
+    #[must_use]
+    pub fn info(&self) -> &FramebufInfo {
+        &self.info
+    }
+
+    /// Returns the bytes covered by the framebuffer's rows.
+    fn rows_mut(&mut self) -> &mut [u8] {
+        let len = self.info.byte_len().min(self.buf.len());
+        &mut self.buf[..len]
+    }
+}
+
+impl<B> Draw for Framebuffer<B>
+where
+    B: Deref<Target = [u8]> + DerefMut,
+{
+    #[inline]
+    fn width(&self) -> usize {
+        self.info.width
+    }
+
+    #[inline]
+    fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: HalColor) -> &mut Self {
+        if x >= self.info.width || y >= self.info.height {
+            return self;
+        }
+        let px_bytes = self.info.format.bytes_per_pixel();
+        let start = self.info.offset(x, y);
+        let pixel = self.info.format.encode(color);
+        if let Some(dst) = self.buf.get_mut(start..start + px_bytes) {
+            dst.copy_from_slice(&pixel[..px_bytes]);
+        }
+        self
+    }
+
+    fn fill(&mut self, color: HalColor) -> &mut Self {
+        let px_bytes = self.info.format.bytes_per_pixel();
+        let pixel = self.info.format.encode(color);
+        let (width, stride) = (self.info.width, self.info.stride);
+        for row in self.rows_mut().chunks_mut(stride) {
+            let len = (width * px_bytes).min(row.len());
+            simd::fill(&mut row[..len], &pixel[..px_bytes]);
+        }
+        self
+    }
+
+    fn scroll_vert(&mut self, amount: isize) -> &mut Self {
+        let lines = amount.unsigned_abs().min(self.info.height);
+        if lines == 0 {
+            return self;
+        }
+        let stride = self.info.stride;
+        let rows = self.rows_mut();
+        if amount > 0 {
+            scroll_up_raw(rows, stride, lines);
+        } else {
+            let len = rows.len();
+            let shift = (lines * stride).min(len);
+            simd::copy_within(rows, 0..len - shift, shift);
+            simd::fill(&mut rows[..shift], &[0]);
+        }
+        self
+    }
+}
+
+fn pack(color: HalColor) -> u32 {
+    u32::from_be_bytes([0, color.red, color.green, color.blue])
+}
+
+fn unpack(packed: u32) -> HalColor {
+    let [_, red, green, blue] = packed.to_be_bytes();
+    HalColor { red, green, blue }
+}
+
+impl Draw for BackBuffer {
+    #[inline]
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: HalColor) -> &mut Self {
+        if x >= self.width || y >= self.height {
+            return self;
+        }
+        let pixel = &mut self.pixels[y * self.width + x];
+        let color = pack(color);
+        if *pixel != color {
+            *pixel = color;
+            let dirty = &mut self.dirty[y];
+            *dirty = if dirty.0 >= dirty.1 {
+                (x, x + 1)
+            } else {
+                (dirty.0.min(x), dirty.1.max(x + 1))
+            };
+        }
+        self
+    }
+
+    fn fill(&mut self, color: HalColor) -> &mut Self {
+        let color = pack(color);
+        simd::fill(self.bytes_mut(), &color.to_ne_bytes());
+        self.mark_all_dirty();
+        self
+    }
+
+    fn scroll_vert(&mut self, amount: isize) -> &mut Self {
+        let lines = amount.unsigned_abs().min(self.height);
+        if lines == 0 {
+            return self;
+        }
+        let shift = lines * self.width * 4;
+        let bytes = self.bytes_mut();
+        let len = bytes.len();
+        if amount > 0 {
+            // move everything up, and clear the rows exposed at the bottom.
+            simd::copy_within(bytes, shift..len, 0);
+            simd::fill(&mut bytes[len - shift..], &[0]);
+        } else {
+            // move everything down, and clear the rows exposed at the top.
+            simd::copy_within(bytes, 0..len - shift, shift);
+            simd::fill(&mut bytes[..shift], &[0]);
+        }
+        self.mark_all_dirty();
+        self
+    }
+}
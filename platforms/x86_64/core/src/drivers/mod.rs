@@ -1 +1,5 @@
+pub mod console;
 pub mod framebuf;
+pub mod ps2_keyboard;
+pub mod rtc;
+pub mod uart;
@@ -0,0 +1,124 @@
+//! A driver for PS/2 keyboards.
+//!
+//! The keyboard interrupt handler passes each scancode read from port `0x60`
+//! to [`handle_scancode`], which pushes it onto a ring. A task drains the
+//! ring, decodes the scancodes into [`KeyEvent`]s, and publishes them to the
+//! [`KeyboardMuxService`], where anything reading keyboard input (such as a
+//! console) can subscribe to them.
+//!
+//! [`KeyEvent`]: kernel::services::keyboard::KeyEvent
+//! [`KeyboardMuxService`]: kernel::services::keyboard::mux::KeyboardMuxService
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use kernel::{
+    comms::bbq::{new_spsc_channel, Consumer, SpscProducer},
+    mnemos_alloc::containers::Box,
+    registry,
+    services::keyboard::{
+        mux::{KeyboardMuxClient, KeyboardMuxService},
+        scancode::Decoder,
+    },
+    Kernel,
+};
+use tracing::Level;
+
+static SCANCODES: AtomicPtr<SpscProducer> = AtomicPtr::new(null_mut());
+/// Scancodes discarded because the ring was full, or the driver wasn't
+/// running yet.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Ps2Keyboard {
+    _x: (),
+}
+
+#[derive(Debug)]
+pub struct Ps2KeyboardSettings {
+    /// The number of scancodes which may be buffered before they are
+    /// decoded.
+    pub buffer_capacity: usize,
+}
+
+impl Default for Ps2KeyboardSettings {
+    fn default() -> Self {
+        Self {
+            buffer_capacity: 64,
+        }
+    }
+}
+
+/// Handle a scancode received by the keyboard interrupt handler.
+pub fn handle_scancode(scancode: u8) {
+    let prod = SCANCODES.load(Ordering::Acquire);
+    if prod.is_null() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let prod = unsafe { &*prod };
+    match prod.send_grant_exact_sync(1) {
+        Some(mut wgr) => {
+            wgr[0] = scancode;
+            wgr.commit(1);
+        }
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the number of scancodes discarded because they couldn't be
+/// buffered.
+#[must_use]
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+impl Ps2Keyboard {
+    #[tracing::instrument(
+        name = "Ps2Keyboard::register",
+        level = Level::INFO,
+        skip(k, settings)
+        ret(Debug),
+        err(Debug),
+    )]
+    pub async fn register(
+        k: &'static Kernel,
+        settings: Ps2KeyboardSettings,
+    ) -> Result<(), registry::ConnectError<KeyboardMuxService>> {
+        tracing::info!(?settings, "Starting PS/2 keyboard driver");
+
+        let keymux = KeyboardMuxClient::from_registry(k).await?;
+        let (prod, cons) = new_spsc_channel(settings.buffer_capacity).await;
+
+        let boxed_prod = Box::new(prod).await;
+        let leaked_prod = Box::into_raw(boxed_prod);
+        let old = SCANCODES.swap(leaked_prod, Ordering::AcqRel);
+        assert_eq!(old, null_mut());
+
+        let _decode_hdl = k.spawn(Self::decoding(cons, keymux)).await;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Ps2Keyboard::decoding", level = Level::INFO, skip_all)]
+    async fn decoding(cons: Consumer, mut keymux: KeyboardMuxClient) {
+        let mut decoder = Decoder::new();
+        loop {
+            let rgr = cons.read_grant().await;
+            let len = rgr.len();
+            for &scancode in rgr.iter() {
+                let Some(event) = decoder.feed(scancode) else {
+                    continue;
+                };
+                tracing::trace!(scancode, ?event, "decoded key event");
+                if let Err(error) = keymux.publish_key(event).await {
+                    tracing::warn!(?error, "failed to publish key event");
+                }
+            }
+            rgr.release(len);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! A driver for the CMOS real-time clock (RTC).
+//!
+//! The RTC keeps the wall-clock date and time while the machine is off. It is
+//! read with [`wall_clock_now`], which returns the current time as a
+//! [`UnixTime`].
+//!
+//! The RTC may store its fields in BCD or binary, and its hours in 12- or
+//! 24-hour format, depending on its status register B. It only stores a
+//! two-digit year; the century is read from the CMOS register named by the
+//! FADT's `CENTURY` field, if there is one, and otherwise the 21st century is
+//! assumed.
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use hal_x86_64::cpu::Port;
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+
+use crate::interrupt;
+
+/// The CMOS index port. Bit 7 of the index disables NMIs.
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const NMI_DISABLE: u8 = 1 << 7;
+
+// RTC registers.
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_D: u8 = 0x0d;
+
+/// Set in status register A while the RTC is updating its fields, which may
+/// be inconsistent until it clears.
+const STATUS_A_UPDATING: u8 = 1 << 7;
+/// Set in status register B if hours are in 24-hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Set in status register B if fields are binary, rather than BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hours register in 12-hour format if the time is PM.
+const HOURS_PM: u8 = 1 << 7;
+
+/// How many times to read the fields before accepting a read that didn't
+/// match the previous one.
+const MAX_READS: usize = 8;
+
+/// The CMOS register holding the century, or 0 if there isn't one.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// Serializes access to the CMOS index and data ports.
+static CMOS: Mutex<(), Spinlock> = Mutex::new_with_raw_mutex((), Spinlock::new());
+
+/// A wall-clock time, as seconds since 1970-01-01 00:00:00 UTC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UnixTime {
+    secs: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Fields {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+/// Record the CMOS register holding the century, from the FADT.
+///
+/// Until this is called, the 21st century is assumed.
+pub fn init(century_register: Option<u8>) {
+    tracing::debug!(?century_register, "RTC century register");
+    CENTURY_REGISTER.store(century_register.unwrap_or(0), Ordering::Release);
+}
+
+/// Returns the current wall-clock time, read from the RTC.
+///
+/// The RTC is assumed to be set to UTC.
+#[must_use]
+pub fn wall_clock_now() -> UnixTime {
+    let (fields, status_b) = interrupt::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        // the fields are read until two reads in a row agree, in case an
+        // update started while reading them.
+        let mut last = read_fields();
+        for _ in 0..MAX_READS {
+            let fields = read_fields();
+            if fields == last {
+                break;
+            }
+            last = fields;
+        }
+        (last, read(REG_STATUS_B))
+    });
+
+    UnixTime::from_fields(fields, status_b)
+}
+
+/// Read the RTC's fields once the current update (if any) has finished.
+///
+/// The CMOS lock must be held with interrupts disabled.
+fn read_fields() -> Fields {
+    while read(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+
+    let century = match CENTURY_REGISTER.load(Ordering::Acquire) {
+        0 => None,
+        reg => Some(read(reg)),
+    };
+    Fields {
+        seconds: read(REG_SECONDS),
+        minutes: read(REG_MINUTES),
+        hours: read(REG_HOURS),
+        day: read(REG_DAY),
+        month: read(REG_MONTH),
+        year: read(REG_YEAR),
+        century,
+    }
+}
+
+fn read(reg: u8) -> u8 {
+    unsafe {
+        // Safety: reading RTC registers has no side effects, and NMIs are
+        // disabled while selecting them, as the CMOS expects.
+        Port::at(CMOS_INDEX).writeb(NMI_DISABLE | reg);
+        let value = Port::at(CMOS_DATA).readb();
+        // re-enable NMIs, so that hardware errors are still reported.
+        Port::at(CMOS_INDEX).writeb(REG_STATUS_D);
+        value
+    }
+}
+
+// === impl UnixTime ===
+
+impl UnixTime {
+    /// Returns a `UnixTime` `secs` seconds after the Unix epoch.
+    #[must_use]
+    pub const fn from_secs(secs: u64) -> Self {
+        Self { secs }
+    }
+
+    /// Returns the number of seconds since the Unix epoch.
+    #[must_use]
+    pub const fn as_secs(self) -> u64 {
+        self.secs
+    }
+
+    fn from_fields(fields: Fields, status_b: u8) -> Self {
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let decode = |value: u8| {
+            if binary {
+                value
+            } else {
+                (value >> 4) * 10 + (value & 0x0f)
+            }
+        };
+
+        // in 12-hour mode, the PM flag is the top bit of the hours register,
+        // regardless of whether the rest is BCD.
+        let mut hours = decode(fields.hours & !HOURS_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            let pm = fields.hours & HOURS_PM != 0;
+            // 12 AM is midnight, and 12 PM is noon.
+            hours %= 12;
+            if pm {
+                hours += 12;
+            }
+        }
+
+        let year = decode(fields.year) as u64;
+        let year = match fields.century.map(decode) {
+            // some firmware claims to have a century register, but leaves it
+            // zeroed. ignore implausible centuries.
+            Some(century @ 19..=99) => century as u64 * 100 + year,
+            _ => 2000 + year,
+        };
+
+        let days = days_since_epoch(year, decode(fields.month), decode(fields.day));
+        let secs = days * 86_400
+            + hours as u64 * 3600
+            + decode(fields.minutes) as u64 * 60
+            + decode(fields.seconds) as u64;
+        Self { secs }
+    }
+}
+
+impl fmt::Display for UnixTime {
+    /// Formats the time as an ISO 8601 date and time, in UTC.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.secs / 86_400);
+        let secs = self.secs % 86_400;
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+        )
+    }
+}
+
+/// Returns the number of days between 1970-01-01 and the given date, which
+/// must not be earlier.
+///
+/// This is Howard Hinnant's `days_from_civil` algorithm, restricted to dates
+/// after the epoch. Out of range months and days are clamped, so that a
+/// garbled RTC can't cause an overflow.
+fn days_since_epoch(year: u64, month: u8, day: u8) -> u64 {
+    let month = month.clamp(1, 12) as u64;
+    let day = day.clamp(1, 31) as u64;
+    // count years from March, so that leap days are at the end of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).saturating_sub(719_468)
+}
+
+/// The inverse of [`days_since_epoch`], returning the year, month, and day.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,502 @@
+//! A driver for the 16550 UART on COM1.
+//!
+//! Received bytes are read by [`handle_interrupt`], which drains the UART's
+//! receive FIFO into a [`bbq`](kernel::comms::bbq) ring, and transmitted bytes
+//! are written by a task which waits for the transmit FIFO to empty. The port
+//! is registered as a [`SimpleSerialService`], so that the serial mux (and,
+//! through it, userspace) can use it.
+//!
+//! COM1's IRQ 4 is routed through the I/O APIC to a dynamically registered
+//! vector, whose handler is [`handle_interrupt`]. Without an I/O APIC,
+//! [`handle_interrupt`] is instead called from the timer interrupt, and checks
+//! whether the UART has an interrupt pending. This limits transmission to one
+//! FIFO's worth of bytes per timer tick.
+//!
+//! Received bytes are also decoded as terminal input, and published to the
+//! [`KeyboardMuxService`] as [`KeyEvent`]s, just like PS/2 keyboard input. This
+//! lets a console read the keyboard the same way whether it's running on a
+//! machine with a keyboard, or under QEMU with only a serial port. See
+//! [`terminal`] for how bytes are decoded.
+//!
+//! Until the driver is registered, tracing output is written to COM1 directly
+//! by [`crate::trace`]. Registering the driver ends that, since the two would
+//! otherwise interleave their output.
+//!
+//! [`KeyEvent`]: kernel::services::keyboard::KeyEvent
+//! [`KeyboardMuxService`]: kernel::services::keyboard::mux::KeyboardMuxService
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::interrupt::{self, ioapic};
+
+use hal_x86_64::cpu::Port;
+use kernel::{
+    comms::bbq::{new_bidi_channel, new_spsc_channel, BidiHandle, Consumer, SpscProducer},
+    maitake::sync::WaitCell,
+    mnemos_alloc::containers::Box,
+    registry,
+    services::{
+        keyboard::{mux::KeyboardMuxClient, terminal},
+        simple_serial::{Request, Response, SimpleSerialError, SimpleSerialService},
+    },
+    Kernel,
+};
+use tracing::Level;
+
+/// The base I/O port of COM1.
+const COM1: u16 = 0x3f8;
+
+/// COM1's ISA IRQ.
+const IRQ: u8 = 4;
+/// The vector COM1's IRQ is routed to.
+const VECTOR: u8 = interrupt::vector::FIRST_VECTOR;
+
+/// The UART's input clock divided by 16, which is the fastest supported baud
+/// rate.
+const MAX_BAUD: u32 = 115_200;
+
+/// The size of the 16550's transmit FIFO.
+const TX_FIFO_LEN: usize = 16;
+
+/// How long to wait for the rest of an escape sequence before deciding that
+/// a lone `ESC` was the Escape key.
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
+
+// Register offsets from the base port.
+/// Receive buffer (read) and transmit holding (write) registers, or the low
+/// byte of the baud rate divisor when DLAB is set.
+const DATA: u16 = 0;
+/// Interrupt enable register, or the high byte of the baud rate divisor when
+/// DLAB is set.
+const IER: u16 = 1;
+/// Interrupt identification (read) and FIFO control (write) registers.
+const IIR_FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+
+// IER bits.
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+// IIR values, with the FIFO status bits masked off.
+const IIR_MASK: u8 = 0x0f;
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+/// Bytes are in the receive FIFO, but fewer than the trigger level, and none
+/// have arrived for a while.
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+
+/// Enable and clear both FIFOs, interrupting once 14 bytes have been received.
+///
+/// A high trigger level means fewer interrupts for bulk input; a trailing
+/// partial FIFO is picked up by the receive timeout interrupt.
+const FCR_ENABLE_14: u8 = 0xc7;
+/// 8 data bits, no parity, one stop bit.
+const LCR_8N1: u8 = 0x03;
+/// Divisor latch access bit.
+const LCR_DLAB: u8 = 1 << 7;
+/// DTR, RTS, and OUT2, which gates the UART's interrupt line.
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+
+// LSR bits.
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+static TX_READY: WaitCell = WaitCell::new();
+static UART_RX: AtomicPtr<SpscProducer> = AtomicPtr::new(null_mut());
+/// Received bytes waiting to be decoded into key events.
+static KEY_RX: AtomicPtr<SpscProducer> = AtomicPtr::new(null_mut());
+/// Bytes lost because the receive FIFO overflowed before it was drained.
+static OVERRUNS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes discarded because the receive ring was full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Bytes not decoded into key events because the key ring was full.
+static KEYS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Whether COM1's IRQ has been routed to [`handle_interrupt`].
+static IRQ_ROUTED: AtomicBool = AtomicBool::new(false);
+
+pub struct Uart16550 {
+    _x: (),
+}
+
+#[derive(Debug)]
+pub struct UartSettings {
+    pub baud_rate: u32,
+    pub capacity_in: usize,
+    pub capacity_out: usize,
+    pub request_capacity: usize,
+    /// The number of received bytes which may be buffered before they are
+    /// decoded into key events, or `None` to not produce key events.
+    pub key_capacity: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    Registry(registry::RegistrationError),
+    /// The requested baud rate can't be produced by the UART's clock.
+    InvalidBaudRate(u32),
+}
+
+impl Default for UartSettings {
+    fn default() -> Self {
+        Self {
+            baud_rate: MAX_BAUD,
+            capacity_in: 256,
+            capacity_out: 1024,
+            request_capacity: 4,
+            key_capacity: Some(64),
+        }
+    }
+}
+
+/// Handle an interrupt from COM1.
+///
+/// This drains the receive FIFO into the receive ring, records overruns, and
+/// wakes the transmit task if the transmit FIFO has emptied. It's cheap to
+/// call when the UART has nothing to report.
+pub fn handle_interrupt() {
+    loop {
+        let iir = unsafe { reg(IIR_FCR).readb() } & IIR_MASK;
+        match iir {
+            _ if iir & IIR_NONE_PENDING != 0 => return,
+            IIR_LINE_STATUS => {
+                // reading the LSR clears the interrupt.
+                if unsafe { reg(LSR).readb() } & LSR_OVERRUN != 0 {
+                    OVERRUNS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => drain_rx(),
+            IIR_TX_EMPTY => {
+                // reading the IIR cleared the interrupt. stop asking for it
+                // until the transmit task waits again.
+                unsafe { set_ier(IER_RX_AVAILABLE | IER_LINE_STATUS) };
+                TX_READY.wake();
+            }
+            IIR_MODEM_STATUS => {
+                // reading the MSR clears the interrupt. we don't use flow
+                // control, so ignore it.
+                let _ = unsafe { reg(MSR).readb() };
+            }
+            _ => {
+                tracing::warn!(iir, "unexpected COM1 interrupt");
+                return;
+            }
+        }
+    }
+}
+
+/// Returns `true` if COM1's IRQ is routed to [`handle_interrupt`], so it
+/// doesn't need to be polled.
+#[must_use]
+pub fn irq_routed() -> bool {
+    IRQ_ROUTED.load(Ordering::Acquire)
+}
+
+/// Returns the number of bytes lost to receive FIFO overruns.
+#[must_use]
+pub fn overruns() -> usize {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of received bytes discarded because the receive ring
+/// was full.
+#[must_use]
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Returns the number of received bytes which weren't decoded into key
+/// events, because they couldn't be buffered.
+#[must_use]
+pub fn keys_dropped() -> usize {
+    KEYS_DROPPED.load(Ordering::Relaxed)
+}
+
+fn drain_rx() {
+    let prod = UART_RX.load(Ordering::Acquire);
+    let data_ready = || unsafe { reg(LSR).readb() } & LSR_DATA_READY != 0;
+
+    if !prod.is_null() {
+        let prod = unsafe { &*prod };
+
+        while let Some(mut wgr) = prod.send_grant_max_sync(64) {
+            let mut used = 0;
+            for b in wgr.iter_mut() {
+                if !data_ready() {
+                    break;
+                }
+                *b = unsafe { reg(DATA).readb() };
+                used += 1;
+            }
+
+            push_keys(&wgr[..used]);
+            let full = used == wgr.len();
+            wgr.commit(used);
+            if !full || !data_ready() {
+                return;
+            }
+        }
+    }
+
+    // either there's no one to receive the bytes, or the ring is full. the
+    // interrupt won't clear until the FIFO drops below the trigger level, so
+    // discard what's left.
+    while data_ready() {
+        let byte = unsafe { reg(DATA).readb() };
+        push_keys(&[byte]);
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Copy received bytes into the key ring, if key events are enabled.
+fn push_keys(mut bytes: &[u8]) {
+    let prod = KEY_RX.load(Ordering::Acquire);
+    if prod.is_null() {
+        return;
+    }
+
+    let prod = unsafe { &*prod };
+    while !bytes.is_empty() {
+        let Some(mut wgr) = prod.send_grant_max_sync(bytes.len()) else {
+            KEYS_DROPPED.fetch_add(bytes.len(), Ordering::Relaxed);
+            return;
+        };
+        let len = wgr.len();
+        wgr.copy_from_slice(&bytes[..len]);
+        wgr.commit(len);
+        bytes = &bytes[len..];
+    }
+}
+
+impl Uart16550 {
+    #[tracing::instrument(
+        name = "Uart16550::register",
+        level = Level::INFO,
+        skip(k, settings)
+        ret(Debug),
+        err(Debug),
+    )]
+    pub async fn register(
+        k: &'static Kernel,
+        settings: UartSettings,
+    ) -> Result<(), RegistrationError> {
+        tracing::info!(?settings, "Starting COM1 UART service");
+
+        let UartSettings {
+            baud_rate,
+            capacity_in,
+            capacity_out,
+            request_capacity,
+            key_capacity,
+        } = settings;
+        let divisor = match MAX_BAUD.checked_div(baud_rate) {
+            Some(divisor @ 1..=0xffff) if MAX_BAUD % baud_rate == 0 => divisor as u16,
+            _ => return Err(RegistrationError::InvalidBaudRate(baud_rate)),
+        };
+
+        let (fifo_a, fifo_b) = new_bidi_channel(capacity_in, capacity_out).await;
+
+        let reqs = k
+            .registry()
+            .bind_konly::<SimpleSerialService>(request_capacity)
+            .await
+            .map_err(RegistrationError::Registry)?
+            .into_request_stream(request_capacity)
+            .await;
+
+        let _server_hdl = k.spawn(Self::serial_server(fifo_b, reqs)).await;
+
+        let (prod, cons) = fifo_a.split();
+        let boxed_prod = Box::new(prod).await;
+        let leaked_prod = Box::into_raw(boxed_prod);
+        let old = UART_RX.swap(leaked_prod, Ordering::AcqRel);
+        assert_eq!(old, null_mut());
+
+        if let Some(capacity) = key_capacity {
+            let (prod, cons) = new_spsc_channel(capacity).await;
+            let boxed_prod = Box::new(prod).await;
+            let leaked_prod = Box::into_raw(boxed_prod);
+            let old = KEY_RX.swap(leaked_prod, Ordering::AcqRel);
+            assert_eq!(old, null_mut());
+            let _keys_hdl = k.spawn(Self::decoding(k, cons)).await;
+        }
+
+        crate::trace::end_early_serial();
+        unsafe { init(divisor) };
+        route_irq();
+        let _send_hdl = k.spawn(Self::sending(cons)).await;
+
+        Ok(())
+    }
+
+    async fn serial_server(
+        handle: BidiHandle,
+        reqs: registry::listener::RequestStream<SimpleSerialService>,
+    ) {
+        let req = reqs.next_request().await;
+        let Request::GetPort = req.msg.body;
+        let resp = req.msg.reply_with(Ok(Response::PortHandle { handle }));
+        let _ = req.reply.reply_konly(resp).await;
+
+        // And deny all further requests after the first
+        loop {
+            let req = reqs.next_request().await;
+            let Request::GetPort = req.msg.body;
+            let resp = req
+                .msg
+                .reply_with(Err(SimpleSerialError::AlreadyAssignedPort));
+            let _ = req.reply.reply_konly(resp).await;
+        }
+    }
+
+    #[tracing::instrument(name = "Uart16550::decoding", level = Level::INFO, skip_all)]
+    async fn decoding(k: &'static Kernel, cons: Consumer) {
+        // the keyboard mux is registered after the UART, so this waits for it.
+        let mut keymux = match KeyboardMuxClient::from_registry(k).await {
+            Ok(keymux) => keymux,
+            Err(error) => {
+                tracing::warn!(
+                    ?error,
+                    "can't connect to the keyboard mux, COM1 won't produce key events"
+                );
+                return;
+            }
+        };
+
+        let mut decoder = terminal::Decoder::new();
+        loop {
+            // if an escape sequence was cut short, wait a little while for
+            // the rest of it, which may arrive in a later interrupt.
+            let rgr = if decoder.is_pending() {
+                match k.timeout(ESCAPE_TIMEOUT, cons.read_grant()).await {
+                    Ok(rgr) => rgr,
+                    Err(_) => {
+                        if let Some(event) = decoder.flush() {
+                            if let Err(error) = keymux.publish_key(event).await {
+                                tracing::warn!(?error, "failed to publish key event");
+                            }
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                cons.read_grant().await
+            };
+
+            let len = rgr.len();
+            for &byte in rgr.iter() {
+                let Some(event) = decoder.feed(byte) else {
+                    continue;
+                };
+                tracing::trace!(byte, ?event, "decoded key event");
+                if let Err(error) = keymux.publish_key(event).await {
+                    tracing::warn!(?error, "failed to publish key event");
+                }
+            }
+            rgr.release(len);
+        }
+    }
+
+    #[tracing::instrument(name = "Uart16550::sending", level = Level::INFO, skip(cons))]
+    async fn sending(cons: Consumer) {
+        loop {
+            let rx = cons.read_grant().await;
+            let len = rx.len();
+
+            // whenever the transmit FIFO is empty, refill it.
+            for chunk in rx.chunks(TX_FIFO_LEN) {
+                tx_empty().await;
+                for &byte in chunk {
+                    unsafe { reg(DATA).writeb(byte) };
+                }
+            }
+
+            rx.release(len);
+        }
+    }
+}
+
+/// Route COM1's IRQ to [`handle_interrupt`], if there's an I/O APIC to route
+/// it through.
+fn route_irq() {
+    let Some(route) = ioapic::isa_route(IRQ) else {
+        tracing::info!("no I/O APIC, polling COM1 on timer ticks");
+        return;
+    };
+
+    let guard = match interrupt::register_handler(VECTOR, |_| handle_interrupt()) {
+        Ok(guard) => guard,
+        Err(error) => {
+            tracing::warn!(%error, "failed to register COM1 interrupt handler");
+            return;
+        }
+    };
+    let cpu = ioapic::current_apic_id().unwrap_or(0);
+    if let Err(error) = ioapic::route_irq(route.gsi, VECTOR, cpu, route.trigger, route.polarity) {
+        tracing::warn!(%error, "failed to route COM1 IRQ");
+        return;
+    }
+
+    // the driver is never unregistered, so neither is its handler.
+    core::mem::forget(guard);
+    IRQ_ROUTED.store(true, Ordering::Release);
+    tracing::info!(gsi = route.gsi, vector = VECTOR, "routed COM1 IRQ");
+}
+
+/// Wait for the transmit FIFO to empty.
+async fn tx_empty() {
+    loop {
+        if unsafe { reg(LSR).readb() } & LSR_TX_EMPTY != 0 {
+            return;
+        }
+
+        // register the waiter before enabling the interrupt, so that we don't
+        // miss it if it fires immediately.
+        let wait = TX_READY.subscribe().await;
+        unsafe { set_ier(IER_RX_AVAILABLE | IER_LINE_STATUS | IER_TX_EMPTY) };
+        wait.await.expect("COM1 TX_READY WaitCell is never closed!");
+    }
+}
+
+/// # Safety
+///
+/// This reconfigures COM1, and must only be called once.
+unsafe fn init(divisor: u16) {
+    // disable interrupts while configuring the port.
+    set_ier(0);
+
+    let [lo, hi] = divisor.to_le_bytes();
+    reg(LCR).writeb(LCR_DLAB);
+    reg(DATA).writeb(lo);
+    reg(IER).writeb(hi);
+    reg(LCR).writeb(LCR_8N1);
+
+    reg(IIR_FCR).writeb(FCR_ENABLE_14);
+    reg(MCR).writeb(MCR_DTR_RTS_OUT2);
+
+    // discard anything received before we were ready.
+    while reg(LSR).readb() & LSR_DATA_READY != 0 {
+        let _ = reg(DATA).readb();
+    }
+
+    set_ier(IER_RX_AVAILABLE | IER_LINE_STATUS);
+}
+
+unsafe fn set_ier(ier: u8) {
+    reg(IER).writeb(ier);
+}
+
+#[inline]
+fn reg(offset: u16) -> Port {
+    Port::at(COM1 + offset)
+}
@@ -0,0 +1,314 @@
+//! Physical page frame allocator.
+//!
+//! This is a simple bitmap allocator over the physical memory regions reported
+//! as free by the bootloader's memory map. Each bit in the bitmap tracks a
+//! single 4KiB frame; a set bit means the frame is in use (or not usable
+//! memory at all).
+//!
+//! Only regions of kind [`mem::RegionKind::FREE`] are ever handed out. This
+//! means the kernel image, the framebuffer, and any ACPI tables (which the
+//! bootloader reports as bootloader-owned or unknown memory) are implicitly
+//! reserved, and will never be returned by [`alloc_frame`].
+use core::fmt;
+use hal_core::{boot::BootInfo, mem, Address, PAddr};
+use hal_x86_64::mm;
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+use mycelium_util::sync::InitOnce;
+
+/// The size of a physical page frame, in bytes.
+pub const FRAME_SIZE: usize = 4096;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+static FRAMES: InitOnce<Mutex<Bitmap, Spinlock>> = InitOnce::uninitialized();
+
+/// A bitmap tracking which physical frames are in use.
+struct Bitmap {
+    /// Physical address of the first frame tracked by the bitmap.
+    base: PAddr,
+    /// Total number of frames tracked by the bitmap.
+    frames: usize,
+    /// Number of frames which are currently free.
+    free: usize,
+    /// Index of the lowest frame that *may* be free, to avoid re-scanning the
+    /// start of the bitmap on every allocation.
+    hint: usize,
+    words: &'static mut [u64],
+}
+
+/// Snapshot of the frame allocator's usage.
+#[derive(Copy, Clone, Debug)]
+pub struct Stats {
+    /// Total number of frames tracked by the allocator.
+    pub total_frames: usize,
+    /// Number of frames which are currently free.
+    pub free_frames: usize,
+}
+
+/// Initialize the frame allocator from the bootloader's memory map.
+///
+/// This must be called after paging has been initialized, as the bitmap is
+/// accessed through the kernel's physical memory mapping.
+#[tracing::instrument(level = tracing::Level::DEBUG, skip(bootinfo))]
+pub(crate) fn init(bootinfo: &impl BootInfo) {
+    let is_free = |region: &mem::Region| region.kind() == mem::RegionKind::FREE;
+
+    // find the span of physical memory containing free regions.
+    let (lowest, highest) =
+        bootinfo
+            .memory_map()
+            .filter(is_free)
+            .fold((usize::MAX, 0), |(lo, hi), region| {
+                let start = region.base_addr().as_usize();
+                (lo.min(start), hi.max(start + region.size()))
+            });
+    assert!(
+        lowest < highest,
+        "no free memory regions to allocate frames from!"
+    );
+
+    let base = align_down(lowest);
+    let frames = (align_up(highest) - base) / FRAME_SIZE;
+    let bitmap_bytes = frames.div_ceil(BITS_PER_WORD) * (BITS_PER_WORD / 8);
+
+    // carve the bitmap itself out of the first free region large enough to
+    // hold it.
+    let bitmap_paddr = bootinfo
+        .memory_map()
+        .filter(is_free)
+        .find_map(|region| {
+            let start = align_up(region.base_addr().as_usize());
+            let end = region.base_addr().as_usize() + region.size();
+            // never put the bitmap in the zero page.
+            let start = start.max(FRAME_SIZE);
+            (end > start && end - start >= bitmap_bytes).then_some(start)
+        })
+        .expect("no free region large enough for the frame allocator bitmap!");
+
+    let words = unsafe {
+        // Safety: the bitmap lives in free physical memory that we are about
+        // to mark as used, so nothing else will ever hand it out.
+        let ptr = mm::kernel_vaddr_of(PAddr::from_u64(bitmap_paddr as u64)).as_ptr::<u64>();
+        core::slice::from_raw_parts_mut(ptr, bitmap_bytes / 8)
+    };
+    // everything starts out used; we then free the usable regions.
+    words.fill(u64::MAX);
+
+    let mut bitmap = Bitmap {
+        base: PAddr::from_u64(base as u64),
+        frames,
+        free: 0,
+        hint: 0,
+        words,
+    };
+
+    for region in bootinfo.memory_map().filter(is_free) {
+        // only whole frames inside the region are usable.
+        let start = align_up(region.base_addr().as_usize());
+        let end = align_down(region.base_addr().as_usize() + region.size());
+        if end > start {
+            bitmap.set_range(
+                (start - base) / FRAME_SIZE,
+                (end - start) / FRAME_SIZE,
+                false,
+            );
+        }
+    }
+
+    // reserve the zero page, so that we never hand out a frame at physical
+    // address 0, and the frames occupied by the bitmap.
+    if base == 0 {
+        bitmap.set_range(0, 1, true);
+    }
+    bitmap.set_range(
+        (bitmap_paddr - base) / FRAME_SIZE,
+        bitmap_bytes.div_ceil(FRAME_SIZE),
+        true,
+    );
+
+    tracing::info!(
+        base = ?bitmap.base,
+        frames = bitmap.frames,
+        free = bitmap.free,
+        bitmap.paddr = ?PAddr::from_u64(bitmap_paddr as u64),
+        bitmap.bytes = bitmap_bytes,
+        "frame allocator initialized"
+    );
+
+    FRAMES.init(Mutex::new_with_raw_mutex(bitmap, Spinlock::new()));
+}
+
+/// Allocate a single 4KiB physical frame.
+///
+/// Returns `None` if no free frames remain, or if the frame allocator has not
+/// been initialized.
+#[must_use]
+pub fn alloc_frame() -> Option<PAddr> {
+    alloc_contiguous(1)
+}
+
+/// Allocate `count` physically contiguous 4KiB frames, returning the address
+/// of the first frame.
+///
+/// This is intended for DMA buffers and other allocations which must be
+/// physically contiguous. Returns `None` if no run of `count` free frames
+/// exists, or if `count` is 0.
+#[must_use]
+pub fn alloc_contiguous(count: usize) -> Option<PAddr> {
+    if count == 0 {
+        return None;
+    }
+    let mut bitmap = FRAMES.try_get()?.lock();
+    let idx = bitmap.find_free_run(count)?;
+    bitmap.set_range(idx, count, true);
+    Some(bitmap.addr_of(idx))
+}
+
+/// Free a single frame previously returned by [`alloc_frame`].
+///
+/// # Panics
+///
+/// If `frame` is not page-aligned, is not tracked by the frame allocator, or
+/// is already free.
+pub fn free_frame(frame: PAddr) {
+    free_contiguous(frame, 1)
+}
+
+/// Free a run of `count` frames previously returned by [`alloc_contiguous`].
+///
+/// # Panics
+///
+/// If `start` is not page-aligned, any frame in the run is not tracked by the
+/// frame allocator, or any frame in the run is already free.
+pub fn free_contiguous(start: PAddr, count: usize) {
+    let mut bitmap = FRAMES
+        .try_get()
+        .expect("frames cannot be freed before the frame allocator is initialized")
+        .lock();
+    let idx = bitmap
+        .index_of(start)
+        .unwrap_or_else(|| panic!("{start:?} is not a frame tracked by the frame allocator"));
+    assert!(
+        idx + count <= bitmap.frames,
+        "frame run {start:?} + {count} extends past the end of physical memory"
+    );
+    for i in idx..idx + count {
+        assert!(
+            bitmap.is_used(i),
+            "double free of frame {:?}",
+            bitmap.addr_of(i)
+        );
+    }
+    bitmap.set_range(idx, count, false);
+}
+
+/// Returns a snapshot of the frame allocator's current usage, or `None` if
+/// it has not been initialized.
+#[must_use]
+pub fn stats() -> Option<Stats> {
+    let bitmap = FRAMES.try_get()?.lock();
+    Some(Stats {
+        total_frames: bitmap.frames,
+        free_frames: bitmap.free,
+    })
+}
+
+#[inline]
+const fn align_up(addr: usize) -> usize {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+#[inline]
+const fn align_down(addr: usize) -> usize {
+    addr & !(FRAME_SIZE - 1)
+}
+
+// === impl Bitmap ===
+
+impl Bitmap {
+    fn addr_of(&self, idx: usize) -> PAddr {
+        PAddr::from_u64((self.base.as_usize() + idx * FRAME_SIZE) as u64)
+    }
+
+    fn index_of(&self, addr: PAddr) -> Option<usize> {
+        let addr = addr.as_usize();
+        let base = self.base.as_usize();
+        if addr < base || addr % FRAME_SIZE != 0 {
+            return None;
+        }
+        let idx = (addr - base) / FRAME_SIZE;
+        (idx < self.frames).then_some(idx)
+    }
+
+    fn is_used(&self, idx: usize) -> bool {
+        self.words[idx / BITS_PER_WORD] & (1 << (idx % BITS_PER_WORD)) != 0
+    }
+
+    fn set_range(&mut self, start: usize, count: usize, used: bool) {
+        for idx in start..start + count {
+            let word = &mut self.words[idx / BITS_PER_WORD];
+            let bit = 1 << (idx % BITS_PER_WORD);
+            let was_used = *word & bit != 0;
+            match (was_used, used) {
+                (false, true) => {
+                    *word |= bit;
+                    self.free -= 1;
+                }
+                (true, false) => {
+                    *word &= !bit;
+                    self.free += 1;
+                }
+                _ => {}
+            }
+        }
+
+        if !used {
+            self.hint = self.hint.min(start);
+        } else if start <= self.hint {
+            self.hint = self.hint.max(start + count);
+        }
+    }
+
+    fn find_free_run(&self, count: usize) -> Option<usize> {
+        if count > self.free {
+            return None;
+        }
+
+        let mut run_start = self.hint;
+        let mut run_len = 0;
+        let mut idx = self.hint;
+        while idx < self.frames {
+            let word = self.words[idx / BITS_PER_WORD];
+            // skip entirely used words quickly.
+            if word == u64::MAX && idx % BITS_PER_WORD == 0 {
+                idx += BITS_PER_WORD;
+                run_len = 0;
+                run_start = idx;
+                continue;
+            }
+
+            if self.is_used(idx) {
+                run_len = 0;
+                run_start = idx + 1;
+            } else {
+                run_len += 1;
+                if run_len == count {
+                    return Some(run_start);
+                }
+            }
+            idx += 1;
+        }
+
+        None
+    }
+}
+
+impl fmt::Debug for Bitmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bitmap")
+            .field("base", &self.base)
+            .field("frames", &self.frames)
+            .field("free", &self.free)
+            .finish()
+    }
+}
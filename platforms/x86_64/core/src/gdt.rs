@@ -0,0 +1,251 @@
+//! Per-core Global Descriptor Tables (GDTs) and Task State Segments (TSSs).
+//!
+//! Long mode barely uses segmentation, but every core still needs a GDT with
+//! code and data segments, and a TSS. The TSS holds the core's interrupt stack
+//! table (IST): the stacks which the CPU switches to when an interrupt whose
+//! IDT entry names an IST slot fires, such as a double fault or an NMI. It will also
+//! hold the stack to switch to when an interrupt arrives in userspace.
+//!
+//! A TSS can't be shared between cores: loading it marks its descriptor as
+//! busy, and a core must never take an IST interrupt on a stack another core
+//! may be using. So each core builds its own GDT, TSS, and IST stacks in
+//! [`init_core`], and finds them again through its core-local data in `GS`.
+//!
+//! # Boot
+//!
+//! The boot processor can't allocate, or use its core-local data, until well
+//! after it needs to handle exceptions. So it first loads a static boot GDT
+//! and TSS in [`init_boot`], and then switches to its own per-core tables
+//! with [`init_core`] once its `GsLocalData` is initialized, like every
+//! application processor does.
+//!
+//! # Layout
+//!
+//! Every GDT has the same layout, so the selectors in [`Selectors`] are the
+//! same on every core. The user data segment comes before the user code
+//! segment, as `SYSRET` requires.
+use crate::LocalKey;
+use alloc::{boxed::Box, vec};
+use hal_core::{Address, VAddr};
+use hal_x86_64::{
+    cpu::Ring,
+    interrupt::Idt,
+    segment::{self, Gdt},
+    task,
+};
+use mycelium_util::{fmt, sync};
+
+/// The IST slot used by the double fault handler.
+pub const DOUBLE_FAULT_IST: usize = Idt::DOUBLE_FAULT_IST_OFFSET;
+
+/// The IST slot used by the [NMI handler](crate::interrupt::nmi).
+///
+/// An NMI may arrive at any instruction, including while the kernel stack is
+/// being switched or has overflowed, so it always gets a stack of its own.
+pub const NMI_IST: usize = DOUBLE_FAULT_IST + 1;
+
+/// The size of each IST stack, in bytes.
+///
+/// chosen by fair dice roll, guaranteed to be random
+pub const IST_STACK_SIZE: usize = 8 * 4096;
+
+/// A core's GDT and TSS.
+#[derive(Debug)]
+pub struct Descriptors {
+    gdt: &'static Gdt,
+    tss: &'static task::StateSegment,
+    selectors: Selectors,
+}
+
+/// The segment selectors in a core's GDT.
+#[derive(Copy, Clone, Debug)]
+pub struct Selectors {
+    pub kernel_code: segment::Selector,
+    pub kernel_data: segment::Selector,
+    pub user_data: segment::Selector,
+    pub user_code: segment::Selector,
+    pub tss: segment::Selector,
+}
+
+static CURRENT: LocalKey<&'static Descriptors> = LocalKey::new(Descriptors::new_current);
+
+/// Stack used by ISRs during a double fault on the boot processor, until it
+/// switches to its per-core tables.
+///
+/// Double faults are handled on their own stack (an interrupt stack table, or
+/// IST, entry in the TSS), since the most common cause of a double fault is a
+/// kernel stack overflow: the page fault caused by touching the guard page
+/// below the stack can't be handled on the overflowed stack, so it escalates
+/// to a double fault. Without a separate stack, that would triple fault, and
+/// the machine would silently reset.
+///
+/// /!\ EXTREMELY SERIOUS WARNING: this has to be `static mut` or else it
+///     will go in `.bss` and we'll all die or something.
+static mut BOOT_DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+/// Stack used by the NMI handler on the boot processor, until it switches to
+/// its per-core tables.
+///
+/// /!\ this has to be `static mut` too, for the same reason.
+static mut BOOT_NMI_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+static BOOT_TSS: sync::Lazy<task::StateSegment> = sync::Lazy::new(|| {
+    tracing::trace!("initializing boot TSS..");
+    // safety: the boot double fault and NMI stacks are only ever used by
+    // their handlers on the boot processor.
+    let (double_fault, nmi) = unsafe {
+        (
+            stack_top(core::ptr::addr_of!(BOOT_DOUBLE_FAULT_STACK) as usize),
+            stack_top(core::ptr::addr_of!(BOOT_NMI_STACK) as usize),
+        )
+    };
+    let tss = new_tss(double_fault, nmi);
+    tracing::debug!(?tss, "boot TSS initialized");
+    tss
+});
+
+static BOOT_GDT: sync::InitOnce<(Gdt, Selectors)> = sync::InitOnce::uninitialized();
+
+/// Load the boot processor's static boot GDT and TSS.
+///
+/// This must be called once, on the boot processor, before the IDT is
+/// loaded. The boot processor must call [`init_core`] once its core-local
+/// data is initialized.
+#[tracing::instrument(level = tracing::Level::DEBUG)]
+pub fn init_boot() {
+    BOOT_GDT.init(new_gdt(&BOOT_TSS));
+    let (gdt, selectors) = BOOT_GDT.get();
+    // Safety: the boot GDT and TSS are never freed.
+    unsafe { load(gdt, selectors) };
+    tracing::debug!("boot GDT loaded");
+}
+
+/// Build and load the current core's GDT and TSS.
+///
+/// Each core must call this once its `GsLocalData` is initialized. Calling it
+/// again does nothing.
+pub fn init_core() {
+    let _ = current();
+}
+
+/// Returns the current core's GDT and TSS, building and loading them if this
+/// is the first time they've been used on this core.
+///
+/// The core's local data must have been initialized.
+#[must_use]
+pub fn current() -> &'static Descriptors {
+    CURRENT.with(|descriptors| *descriptors)
+}
+
+/// Returns the segment selectors, which are the same on every core.
+///
+/// This doesn't require the current core's tables to have been built.
+#[must_use]
+pub fn selectors() -> Selectors {
+    BOOT_GDT.get().1
+}
+
+// === impl Descriptors ===
+
+impl Descriptors {
+    /// Returns the segment selectors in this core's GDT.
+    #[must_use]
+    pub fn selectors(&self) -> Selectors {
+        self.selectors
+    }
+
+    /// Returns this core's GDT.
+    #[must_use]
+    pub fn gdt(&self) -> &'static Gdt {
+        self.gdt
+    }
+
+    /// Returns the top of the stack in this core's IST slot `ist`, or `None`
+    /// if that slot has no stack.
+    ///
+    /// An interrupt handler may be installed with this IST slot once every
+    /// core that may take it has called [`init_core`].
+    #[must_use]
+    pub fn ist_stack(&self, ist: usize) -> Option<VAddr> {
+        let stack = *self.tss.interrupt_stacks.get(ist)?;
+        (stack.as_usize() != 0).then_some(stack)
+    }
+
+    #[tracing::instrument(level = tracing::Level::DEBUG, name = "gdt::init_core")]
+    fn new_current() -> &'static Self {
+        // allocate the stacks on the heap directly, rather than moving them
+        // there from our (much smaller) stack.
+        let new_stack = || {
+            let stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+            // safety: each of this core's IST stacks is only ever used by the
+            // handler for its slot.
+            unsafe { stack_top(stack.as_ptr() as usize) }
+        };
+        let tss: &'static _ = Box::leak(Box::new(new_tss(new_stack(), new_stack())));
+        let (gdt, selectors) = new_gdt(tss);
+        let gdt: &'static _ = Box::leak(Box::new(gdt));
+        // Safety: the per-core GDT and TSS are leaked, and so are never freed.
+        unsafe { load(gdt, &selectors) };
+        tracing::debug!(GDT = ?gdt, "per-core GDT loaded");
+        Box::leak(Box::new(Self {
+            gdt,
+            tss,
+            selectors,
+        }))
+    }
+}
+
+/// Returns the address of the top of an IST stack starting at `base`. Stacks
+/// grow down, so the IST entry points at the *end* of the stack.
+///
+/// # Safety
+///
+/// `base` must be the start of an [`IST_STACK_SIZE`] byte allocation.
+unsafe fn stack_top(base: usize) -> VAddr {
+    VAddr::from_usize_unchecked(base + IST_STACK_SIZE)
+}
+
+fn new_tss(double_fault_stack: VAddr, nmi_stack: VAddr) -> task::StateSegment {
+    let mut tss = task::StateSegment::empty();
+    tss.interrupt_stacks[DOUBLE_FAULT_IST] = double_fault_stack;
+    tss.interrupt_stacks[NMI_IST] = nmi_stack;
+    tss
+}
+
+fn new_gdt(tss: &'static task::StateSegment) -> (Gdt, Selectors) {
+    let mut gdt = Gdt::new();
+    let kernel_code = gdt.add_segment(segment::Descriptor::code().with_ring(Ring::Ring0));
+    let kernel_data = gdt.add_segment(segment::Descriptor::data().with_ring(Ring::Ring0));
+    let user_data = gdt.add_segment(segment::Descriptor::data().with_ring(Ring::Ring3));
+    let user_code = gdt.add_segment(segment::Descriptor::code().with_ring(Ring::Ring3));
+    let tss = gdt.add_sys_segment(segment::SystemDescriptor::tss(tss));
+    let selectors = Selectors {
+        kernel_code,
+        kernel_data,
+        user_data,
+        user_code,
+        tss,
+    };
+    tracing::trace!(selectors = ?fmt::alt(selectors), "built GDT");
+    (gdt, selectors)
+}
+
+/// Load `gdt`, switch to its kernel code segment, and load its TSS.
+///
+/// # Safety
+///
+/// `gdt` and its TSS must never be freed or modified.
+unsafe fn load(gdt: &'static Gdt, selectors: &Selectors) {
+    gdt.load();
+    selectors.kernel_code.set_cs();
+
+    // in protected mode and long mode, the code segment, stack segment,
+    // data segment, and extra segment must all have base address 0 and
+    // limit `2^64`, since actual segmentation is not used in those modes.
+    // therefore, we must zero the SS, DS, and ES registers.
+    segment::Selector::null().set_ss();
+    segment::Selector::null().set_ds();
+    segment::Selector::null().set_es();
+
+    task::StateSegment::load_tss(selectors.tss);
+}
@@ -0,0 +1,107 @@
+//! Halting CPU cores.
+//!
+//! A fault may be *core-local*, in which case only the faulting core is
+//! stopped with [`halt_core`], and the rest of the system continues running
+//! on the remaining cores, or *system-wide*, in which case every core is
+//! stopped with [`halt_all`].
+//!
+//! Panics are treated as system-wide if any of the following are true (see
+//! [`panic_is_system_wide`]):
+//!
+//! - The panicking core is the bootstrap processor (BSP). The BSP runs the
+//!   kernel's scheduler and timer, and owns the boot-time devices, so no other
+//!   core can take over its work.
+//! - The panicking core is the only core online, so there is nothing left to
+//!   continue running.
+//! - Another core is already halting the system.
+//!
+//! Otherwise (a panic on an application processor (AP) while other cores are
+//! running), the panic is core-local.
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hal_x86_64::cpu::{self, msr::Msr};
+
+use crate::ipi;
+
+/// The `IA32_APIC_BASE` MSR.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Set in `IA32_APIC_BASE` if the current core is the bootstrap processor.
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+/// The number of cores currently online, including the BSP.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Record that an application processor has come online.
+///
+/// This should be called by each AP once it has finished initializing.
+pub fn core_online() {
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Record that an application processor is going offline.
+///
+/// Returns `false`, leaving the count unchanged, if this is the last core
+/// online.
+pub fn core_offline() -> bool {
+    ONLINE
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |online| {
+            (online > 1).then(|| online - 1)
+        })
+        .is_ok()
+}
+
+/// Returns the number of cores currently online.
+#[must_use]
+pub fn online_cores() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Returns `true` if the current core is the bootstrap processor.
+#[must_use]
+pub fn is_bsp() -> bool {
+    // Safety: reading `IA32_APIC_BASE` has no side effects.
+    unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_BSP != 0 }
+}
+
+/// Returns `true` if a panic on the current core should stop the whole
+/// system, rather than just the current core.
+///
+/// See the [module-level documentation](self) for details.
+#[must_use]
+pub fn panic_is_system_wide() -> bool {
+    is_bsp() || online_cores() <= 1 || ipi::is_halting()
+}
+
+/// Stop the current core, leaving the other cores running.
+///
+/// The current core is marked offline before it halts. If the current core is
+/// the BSP, or is the last core online, nothing else could continue running,
+/// so this stops every core with [`halt_all`] instead.
+///
+/// Interrupts should be disabled before calling this.
+pub fn halt_core() -> ! {
+    if is_bsp() {
+        halt_all();
+    }
+
+    // never mark the last core offline; it will halt everything instead.
+    if !core_offline() {
+        halt_all();
+    }
+
+    // TODO(eliza): once APs run their own schedulers, send the other cores
+    // an IPI so that they can reschedule any work owned by this core. For now,
+    // only the BSP runs tasks, so there's nothing to hand off.
+    cpu::halt()
+}
+
+/// Stop every core, including the current one.
+///
+/// Interrupts should be disabled before calling this.
+pub fn halt_all() -> ! {
+    unsafe {
+        // Safety: we're about to halt, so it doesn't matter what the other
+        // cores were doing.
+        ipi::halt_other_cores();
+    }
+    cpu::halt()
+}
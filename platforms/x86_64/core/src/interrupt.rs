@@ -1,31 +1,50 @@
 use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use hal_core::{interrupt, VAddr};
+use hal_core::interrupt;
+use hal_x86_64::cpu::intrinsics;
 pub use hal_x86_64::interrupt::*;
-use hal_x86_64::{
-    cpu::{intrinsics, Ring},
-    segment::{self, Gdt},
-    task,
-};
 use kernel::maitake::time;
-use mycelium_util::{fmt, sync};
+
+pub mod gpf;
+pub mod idle;
+pub mod ioapic;
+#[cfg(feature = "irq-latency")]
+pub mod latency;
+pub mod nmi;
+pub mod spurious;
+pub mod stats;
+pub mod vector;
+
+pub use self::vector::{allocate_handler, register_handler, IrqGuard, RegisterError};
 
 #[tracing::instrument]
 pub fn enable_exceptions() {
-    init_gdt();
+    crate::gdt::init_boot();
     tracing::info!("GDT initialized!");
 
     Controller::init::<InterruptHandlers>();
+    nmi::init();
+    gpf::init();
     tracing::info!("IDT initialized!");
 }
 
+/// Enable hardware interrupts, and start a periodic timer interrupt every
+/// `timer_interval`, which must match the interval of the [`clock`] passed to
+/// the kernel.
 #[tracing::instrument(skip(acpi))]
-pub fn enable_hardware_interrupts(acpi: Option<&acpi::InterruptModel>) {
+pub fn enable_hardware_interrupts(
+    acpi: Option<&acpi::InterruptModel>,
+    timer_interval: time::Duration,
+) {
     let controller = Controller::enable_hardware_interrupts(acpi, &crate::allocator::HEAP);
-    controller
-        .start_periodic_timer(TIMER_INTERVAL)
-        .expect("10ms should be a reasonable interval for the PIT or local APIC timer...");
-    tracing::info!(granularity = ?TIMER_INTERVAL, "global timer initialized")
+    if let Some(acpi::InterruptModel::Apic(apic)) = acpi {
+        ioapic::init(apic);
+    }
+    spurious::init();
+    controller.start_periodic_timer(timer_interval).expect(
+        "timer_granularity should be a reasonable interval for the PIT or local APIC timer...",
+    );
+    tracing::info!(granularity = ?timer_interval, "global timer initialized")
 }
 
 /// Wait for an interrupt in a spin loop.
@@ -35,51 +54,137 @@ pub fn enable_hardware_interrupts(acpi: Option<&acpi::InterruptModel>) {
 /// thread. This should be called on each iteration of a loop that waits on a condition
 /// set by an interrupt handler.
 ///
-/// This function will execute one [`intrinsics::sti`] instruction to enable interrupts
-/// followed by one [`intrinsics::hlt`] instruction to halt the CPU.
+/// This function enables interrupts and idles the CPU until one arrives, using
+/// `MWAIT` if [`idle::init`] found it to be supported, or [`intrinsics::hlt`]
+/// otherwise. When idling with `MWAIT`, the core may also be woken by another
+/// core with [`idle::wake`].
+///
+/// Since the wait is expected to be short, this only ever enters
+/// [`CState::C1`](idle::CState::C1).
 #[inline(always)]
 pub(crate) fn wait_for_interrupt() {
+    idle::wait(time::Duration::ZERO);
+}
+
+/// Disable interrupts, and then wait for an interrupt only if `confirm`
+/// returns `true`, in a C-state chosen for `expected_idle` (see
+/// [`idle::wait`]). Returns the C-state the CPU waited in, or `None` if it
+/// didn't wait.
+///
+/// Since `confirm` runs with interrupts disabled, an interrupt which arrives
+/// after it returns is held pending until the wait begins, and then wakes the
+/// CPU immediately, so it can never be missed. Interrupts are enabled when
+/// this returns.
+pub(crate) fn wait_for_interrupt_if(
+    expected_idle: time::Duration,
+    confirm: impl FnOnce() -> bool,
+) -> Option<idle::CState> {
     unsafe {
-        intrinsics::sti();
-        intrinsics::hlt();
+        intrinsics::cli();
+    }
+    if confirm() {
+        Some(idle::wait(expected_idle))
+    } else {
+        unsafe {
+            intrinsics::sti();
+        }
+        None
     }
 }
 
-// TODO(eliza): put this somewhere good.
-type StackFrame = [u8; 4096];
+/// Returns the number of times an interrupt handler or another core may have
+/// woken a task or changed a timer deadline.
+///
+/// This is the run loop's [`RunLoopDriver::wakeups`] counter.
+///
+/// [`RunLoopDriver::wakeups`]: kernel::runloop::RunLoopDriver::wakeups
+#[must_use]
+pub fn wakeups() -> u64 {
+    WAKEUPS.load(Ordering::Acquire)
+}
 
-// chosen by fair dice roll, guaranteed to be random
-const DOUBLE_FAULT_STACK_SIZE: usize = 8;
+/// Record that a task may have been woken, or a timer deadline changed, so
+/// that the run loop doesn't go to sleep without ticking first.
+///
+/// Every interrupt handler that may do either must call this.
+pub(crate) fn note_wakeup() {
+    WAKEUPS.fetch_add(1, Ordering::Release);
+}
 
-/// Stack used by ISRs during a double fault.
+/// Run `f` with interrupts disabled on the current CPU core.
+///
+/// The current interrupt flag is saved before disabling interrupts, and is
+/// restored once `f` returns, rather than unconditionally re-enabling
+/// interrupts. This means that calls to `without_interrupts` may be nested:
+/// only the outermost call will re-enable interrupts, and only if they were
+/// enabled when it was called.
 ///
-/// /!\ EXTREMELY SERIOUS WARNING: this has to be `static mut` or else it
-///     will go in `.bss` and we'll all die or something.
-static mut DOUBLE_FAULT_STACK: [StackFrame; DOUBLE_FAULT_STACK_SIZE] =
-    [[0; 4096]; DOUBLE_FAULT_STACK_SIZE];
-
-static TSS: sync::Lazy<task::StateSegment> = sync::Lazy::new(|| {
-    tracing::trace!("initializing TSS..");
-    let mut tss = task::StateSegment::empty();
-    tss.interrupt_stacks[Idt::DOUBLE_FAULT_IST_OFFSET] = unsafe {
-        // safety: asdf
-        VAddr::from_usize_unchecked(core::ptr::addr_of!(DOUBLE_FAULT_STACK) as usize)
-            .offset(DOUBLE_FAULT_STACK_SIZE as i32)
-    };
-    tracing::debug!(?tss, "TSS initialized");
-    tss
-});
-
-pub(crate) static GDT: sync::InitOnce<Gdt> = sync::InitOnce::uninitialized();
+/// The interrupt flag is restored by a drop guard, so it is also restored if
+/// `f` panics and the panic unwinds.
+#[inline]
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        #[inline]
+        fn drop(&mut self) {
+            if self.0 {
+                unsafe {
+                    // Safety: interrupts were enabled when we disabled them.
+                    intrinsics::sti();
+                }
+            }
+        }
+    }
+
+    let _restore = Restore(interrupts_enabled());
+    unsafe {
+        // Safety: the previous state is restored when `_restore` is dropped.
+        intrinsics::cli();
+    }
+    f()
+}
+
+/// Returns `true` if interrupts are enabled on the current CPU core.
+#[inline]
+#[must_use]
+pub fn interrupts_enabled() -> bool {
+    /// The interrupt enable flag (IF) in RFLAGS.
+    const RFLAGS_IF: u64 = 1 << 9;
+
+    let rflags: u64;
+    unsafe {
+        // Safety: reading RFLAGS has no side effects.
+        core::arch::asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
 
+/// The default interval of the periodic timer interrupt.
 pub const TIMER_INTERVAL: time::Duration = time::Duration::from_millis(10);
 
-pub const IDIOTIC_CLOCK: time::Clock = time::Clock::new(TIMER_INTERVAL, || {
+/// Returns a clock which counts periodic timer interrupts, each of which is
+/// `timer_interval` apart.
+#[must_use]
+pub const fn clock(timer_interval: time::Duration) -> time::Clock {
+    time::Clock::new(timer_interval, || {
+        IDIOTIC_CLOCK_TICKS.load(Ordering::Relaxed)
+    })
+    .named("CLOCK_IDIOTIC")
+}
+
+/// Returns the number of periodic timer interrupts so far.
+#[must_use]
+pub(crate) fn timer_ticks() -> u64 {
     IDIOTIC_CLOCK_TICKS.load(Ordering::Relaxed)
-})
-.named("CLOCK_IDIOTIC");
+}
+
+/// Faults below this address are reported as likely null pointer
+/// dereferences. The first page is never mapped.
+const NULL_PAGE_SIZE: usize = 4096;
 
 static IDIOTIC_CLOCK_TICKS: AtomicU64 = AtomicU64::new(0);
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
 static TEST_INTERRUPT_WAS_FIRED: AtomicUsize = AtomicUsize::new(0);
 
 pub(crate) struct InterruptHandlers;
@@ -95,11 +200,36 @@ impl hal_core::interrupt::Handlers<Registers> for InterruptHandlers {
     where
         C: interrupt::Context<Registers = Registers> + hal_core::interrupt::ctx::PageFault,
     {
+        // the faulting address is read from CR2, and the error code is
+        // decoded into its present, write, user, reserved bit, and
+        // instruction fetch flags by the HAL.
         let fault_vaddr = cx.fault_vaddr();
         let code = cx.display_error_code();
-
-        // TODO: add a nice fault handler
-        panic!("page fault at {fault_vaddr:?}\n{code}");
+        let registers = cx.registers();
+        let null_deref = fault_vaddr.as_usize() < NULL_PAGE_SIZE;
+
+        tracing::error!(
+            fault.addr = ?fault_vaddr,
+            fault.rip = ?registers.instruction_ptr,
+            fault.rsp = ?registers.stack_ptr,
+            fault.code = %code,
+            fault.null_deref = null_deref,
+            "page fault"
+        );
+
+        // TODO: once there's demand paging, this is where it would
+        // happen. for now, every page fault is fatal.
+        if null_deref {
+            panic!(
+                "page fault at {fault_vaddr:?} (null pointer dereference?)\n\
+                 rip: {:?}\n{code}",
+                registers.instruction_ptr
+            );
+        }
+        panic!(
+            "page fault at {fault_vaddr:?}\nrip: {:?}\n{code}",
+            registers.instruction_ptr
+        );
     }
 
     fn code_fault<C>(cx: C)
@@ -113,21 +243,41 @@ impl hal_core::interrupt::Handlers<Registers> for InterruptHandlers {
         };
     }
 
-    fn double_fault<C>(_cx: C)
+    fn double_fault<C>(cx: C)
     where
         C: hal_core::interrupt::Context<Registers = Registers>,
     {
-        // TODO: add a nice fault handler
-        panic!("double fault");
+        // we're on the double fault stack, so the panic handler can still run,
+        // and will write the fault to serial and the framebuffer before
+        // halting. a double fault is most often a kernel stack overflow.
+        panic!(
+            "double fault (possible kernel stack overflow)\n{:#?}",
+            cx.registers()
+        );
     }
 
     fn timer_tick() {
-        IDIOTIC_CLOCK_TICKS.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "irq-latency")]
+        latency::interrupt_fired();
+        stats::count_source(stats::Source::Timer);
+        let now = IDIOTIC_CLOCK_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        note_wakeup();
+        crate::watchdog::check(now);
+        nmi::report_unexpected();
+
+        // if COM1's IRQ couldn't be routed to its own vector, check for UART
+        // interrupts on every tick instead.
+        if !crate::drivers::uart::irq_routed() {
+            crate::drivers::uart::handle_interrupt();
+        }
     }
 
     fn ps2_keyboard(scancode: u8) {
-        // TODO(eliza): add a keyboard driver
-        tracing::info!(scancode, "keyoard interrupt!!!");
+        #[cfg(feature = "irq-latency")]
+        latency::interrupt_fired();
+        stats::count_source(stats::Source::Ps2Keyboard);
+        crate::drivers::ps2_keyboard::handle_scancode(scancode);
+        note_wakeup();
     }
 
     fn test_interrupt<C>(cx: C)
@@ -138,59 +288,3 @@ impl hal_core::interrupt::Handlers<Registers> for InterruptHandlers {
         tracing::info!(registers = ?cx.registers(), fired, "lol im in ur test interrupt");
     }
 }
-
-#[inline]
-#[tracing::instrument(level = tracing::Level::DEBUG)]
-pub(super) fn init_gdt() {
-    tracing::trace!("initializing GDT...");
-    let mut gdt = Gdt::new();
-
-    // add one kernel code segment
-    let code_segment = segment::Descriptor::code().with_ring(Ring::Ring0);
-    let code_selector = gdt.add_segment(code_segment);
-    tracing::debug!(
-        descriptor = ?fmt::alt(code_segment),
-        selector = ?fmt::alt(code_selector),
-        "added code segment"
-    );
-
-    // add the TSS.
-
-    let tss = segment::SystemDescriptor::tss(&TSS);
-    let tss_selector = gdt.add_sys_segment(tss);
-    tracing::debug!(
-        tss.descriptor = ?fmt::alt(tss),
-        tss.selector = ?fmt::alt(tss_selector),
-        "added TSS"
-    );
-
-    // all done! long mode barely uses this thing lol.
-    GDT.init(gdt);
-
-    // load the GDT
-    let gdt = GDT.get();
-    tracing::debug!(GDT = ?gdt, "GDT initialized");
-    gdt.load();
-
-    tracing::trace!("GDT loaded");
-
-    // set new segment selectors
-    let code_selector = segment::Selector::current_cs();
-    tracing::trace!(code_selector = ?fmt::alt(code_selector));
-    unsafe {
-        // set the code segment selector
-        code_selector.set_cs();
-
-        // in protected mode and long mode, the code segment, stack segment,
-        // data segment, and extra segment must all have base address 0 and
-        // limit `2^64`, since actual segmentation is not used in those modes.
-        // therefore, we must zero the SS, DS, and ES registers.
-        segment::Selector::null().set_ss();
-        segment::Selector::null().set_ds();
-        segment::Selector::null().set_es();
-
-        task::StateSegment::load_tss(tss_selector);
-    }
-
-    tracing::debug!("segment selectors set");
-}
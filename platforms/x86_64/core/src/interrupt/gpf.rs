@@ -0,0 +1,159 @@
+//! General protection faults (#GP).
+//!
+//! Most bring-up bugs that aren't page faults end up here: loading a bad
+//! segment selector, executing a privileged instruction from the wrong ring,
+//! or using a non-canonical address. The HAL reports a #GP as a generic code
+//! fault, without its error code, so this module replaces its IDT entry with
+//! a handler which decodes the error code and panics with the result. The
+//! panic handler writes it to the serial port and the framebuffer, and halts.
+//!
+//! If the fault was caused by a segment selector, the error code is a
+//! [`SelectorErrorCode`] naming it. Otherwise it's zero, and the faulting
+//! instruction is the only clue.
+use super::{stats, vector, Registers};
+use core::fmt;
+
+/// The #GP vector.
+pub const GP_VECTOR: u8 = 13;
+
+/// The error code pushed by a general protection fault.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+/// The descriptor table a [`SelectorErrorCode`]'s index refers to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Table {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// Install the #GP handler.
+///
+/// The IDT is shared by every core, so this is called once, by
+/// [`enable_exceptions`](super::enable_exceptions).
+pub(super) fn init() {
+    let installed = unsafe {
+        // Safety: `handle_gpf` is an interrupt handler, and doesn't switch
+        // stacks.
+        vector::set_handler(GP_VECTOR, handle_gpf as usize as u64, None)
+    };
+    if installed {
+        tracing::debug!("general protection fault handler installed");
+    } else {
+        tracing::warn!("IDT has no entry for general protection faults");
+    }
+}
+
+extern "x86-interrupt" fn handle_gpf(registers: Registers, code: u64) {
+    stats::count_vector(GP_VECTOR);
+    let code = SelectorErrorCode(code);
+
+    if code.is_selector() {
+        tracing::error!(
+            fault.rip = ?registers.instruction_ptr,
+            fault.rsp = ?registers.stack_ptr,
+            fault.code = code.bits(),
+            fault.selector.table = %code.table(),
+            fault.selector.index = code.index(),
+            fault.selector.external = code.is_external(),
+            "general protection fault"
+        );
+    } else {
+        tracing::error!(
+            fault.rip = ?registers.instruction_ptr,
+            fault.rsp = ?registers.stack_ptr,
+            "general protection fault"
+        );
+    }
+
+    // like every exception, this is never acknowledged with an EOI.
+    panic!(
+        "general protection fault\nrip: {:?}, rsp: {:?}, rflags: {:#x}\n{code}",
+        registers.instruction_ptr, registers.stack_ptr, registers.cpu_flags
+    );
+}
+
+// === impl SelectorErrorCode ===
+
+impl SelectorErrorCode {
+    const EXTERNAL: u64 = 1 << 0;
+    const IDT: u64 = 1 << 1;
+    const LDT: u64 = 1 << 2;
+    const INDEX_SHIFT: u32 = 3;
+    const INDEX_MASK: u64 = 0x1fff;
+
+    /// Returns the raw error code.
+    #[must_use]
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if the fault was caused by a segment selector. If it
+    /// wasn't, the error code is zero.
+    #[must_use]
+    pub fn is_selector(self) -> bool {
+        self.0 != 0
+    }
+
+    /// Returns `true` if the fault happened while delivering an event from
+    /// outside the CPU, such as a hardware interrupt.
+    #[must_use]
+    pub fn is_external(self) -> bool {
+        self.0 & Self::EXTERNAL != 0
+    }
+
+    /// Returns the descriptor table the selector indexes.
+    #[must_use]
+    pub fn table(self) -> Table {
+        if self.0 & Self::IDT != 0 {
+            Table::Idt
+        } else if self.0 & Self::LDT != 0 {
+            Table::Ldt
+        } else {
+            Table::Gdt
+        }
+    }
+
+    /// Returns the selector's index into its [`table`](Self::table).
+    ///
+    /// For the IDT, this is the vector being delivered.
+    #[must_use]
+    pub fn index(self) -> u16 {
+        ((self.0 >> Self::INDEX_SHIFT) & Self::INDEX_MASK) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_selector() {
+            return f.write_str(
+                "not caused by a selector (privileged instruction, \
+                 non-canonical address, or similar)",
+            );
+        }
+        write!(
+            f,
+            "selector {:#x}: {} index {}",
+            self.0,
+            self.table(),
+            self.index()
+        )?;
+        if self.is_external() {
+            f.write_str(" (while delivering an external event)")?;
+        }
+        Ok(())
+    }
+}
+
+// === impl Table ===
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Gdt => "GDT",
+            Self::Idt => "IDT",
+            Self::Ldt => "LDT",
+        })
+    }
+}
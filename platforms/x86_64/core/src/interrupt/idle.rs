@@ -0,0 +1,277 @@
+//! Idling CPU cores with `MONITOR`/`MWAIT`.
+//!
+//! When the CPU supports it, an idle core arms the monitor on its own idle
+//! flag with `MONITOR`, and then waits with `MWAIT`, rather than halting with
+//! `HLT`. The core wakes up when an interrupt arrives (including an IPI), or
+//! when another core writes to the monitored flag with [`wake`], so a core can
+//! be woken without an IPI at all.
+//!
+//! Each idle flag sits alone in a [`IDLE_LINE`]-byte aligned block, so that
+//! writes to unrelated data never wake the core. If CPUID leaf 5 reports a
+//! monitor line larger than that, or doesn't report one at all, `MWAIT` isn't
+//! used, and idle cores fall back to `HLT`.
+//!
+//! # C-states
+//!
+//! `HLT` always enters [`CState::C1`]. `MWAIT` can enter deeper C-states,
+//! which save more power, but take longer to wake up from. The run loop says
+//! how long it expects to be idle (see [`expected_idle`]), and [`wait`] picks
+//! the deepest C-state whose target residency that covers, so that a short
+//! wait never pays for the exit latency of a deep state.
+//!
+//! The C-states available are those CPUID leaf 5 reports `MWAIT` sub-states
+//! for. ACPI's `_CST` objects describe them more precisely, but reading them
+//! needs an AML interpreter, which we don't have, so the target residencies
+//! are conservative guesses instead. If the local APIC timer may stop in deep
+//! C-states (the CPU doesn't report ARAT), only `C1` and `C2` are used, so
+//! that the periodic timer interrupt always wakes the core.
+use crate::{clock, cpu, lapic::LocalApic};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Duration,
+};
+use hal_x86_64::cpu::intrinsics;
+
+/// The largest monitor line size that idle flags are padded to.
+pub const IDLE_LINE: usize = 256;
+
+/// The number of cores with an idle flag, indexed by local APIC ID. Cores
+/// with higher IDs idle with `HLT`.
+const MAX_CORES: usize = 16;
+
+/// The shortest expected idle time worth entering each C-state for, in
+/// microseconds, indexed by C-state.
+const TARGET_RESIDENCY_MICROS: [u64; CState::COUNT] =
+    [0, 0, 1_000, 2_000, 4_000, 4_000, 8_000, 8_000];
+
+/// The deepest C-state used if the local APIC timer may stop in deeper ones.
+const DEEPEST_WITHOUT_ARAT: u8 = 2;
+
+/// A C-state that an idle core waits in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CState(u8);
+
+// Idle flag states.
+const BUSY: u8 = 0;
+const IDLE: u8 = 1;
+const WOKEN: u8 = 2;
+
+static USE_MWAIT: AtomicBool = AtomicBool::new(false);
+
+/// The C-states `MWAIT` may enter, as a bitmap indexed by C-state.
+static CSTATES: AtomicU8 = AtomicU8::new(1 << CState::C1.0);
+
+static IDLE_FLAGS: [IdleFlag; MAX_CORES] = [const { IdleFlag(AtomicU8::new(BUSY)) }; MAX_CORES];
+
+#[repr(C, align(256))]
+struct IdleFlag(AtomicU8);
+
+const _: () = assert!(core::mem::size_of::<IdleFlag>() == IDLE_LINE);
+
+/// Detect whether `MONITOR`/`MWAIT` can be used to idle, and which C-states
+/// it can enter.
+///
+/// Until this is called, idle cores use `HLT`.
+pub fn init() {
+    let enabled = match monitor_line() {
+        Ok((smallest, largest)) => {
+            let cstates = available_cstates();
+            CSTATES.store(cstates, Ordering::Release);
+            tracing::info!(
+                smallest,
+                largest,
+                cstates = %format_args!("{cstates:#010b}"),
+                deepest = %CState::deepest(cstates),
+                "idling with MWAIT"
+            );
+            true
+        }
+        Err(reason) => {
+            tracing::info!(reason, "idling with HLT");
+            false
+        }
+    };
+    USE_MWAIT.store(enabled, Ordering::Release);
+}
+
+/// Returns how long a core can expect to stay idle, given the time until the
+/// next timer deadline, if it knows it.
+///
+/// The periodic timer interrupt wakes the core at least once per
+/// [timer interval](clock::timer_interval), so that bounds the idle time.
+#[must_use]
+pub fn expected_idle(next_deadline: Option<Duration>) -> Duration {
+    let interval = clock::timer_interval();
+    next_deadline.map_or(interval, |deadline| deadline.min(interval))
+}
+
+/// Wait until an interrupt arrives, or until another core calls [`wake`] for
+/// this core, in the deepest C-state worth entering for `expected_idle`.
+///
+/// Returns the C-state the core waited in. Interrupts are enabled when this
+/// returns.
+pub(crate) fn wait(expected_idle: Duration) -> CState {
+    let flag = match current_flag() {
+        Some(flag) if USE_MWAIT.load(Ordering::Acquire) => flag,
+        _ => unsafe {
+            // `sti` delays interrupts until after the next instruction, so if
+            // interrupts were disabled, none can be handled before the `hlt`.
+            core::arch::asm!("sti", "hlt", options(nomem, nostack));
+            return CState::C1;
+        },
+    };
+    let cstate = CState::select(CSTATES.load(Ordering::Acquire), expected_idle);
+
+    unsafe {
+        // interrupts are usually already disabled by the run loop (see
+        // `wait_for_interrupt_if`). with interrupts disabled, an interrupt
+        // arriving after the monitor is armed is held pending until the
+        // `sti`, and `sti` delays interrupts until after the following
+        // instruction, so it always breaks out of the `mwait` rather than
+        // being handled before it.
+        intrinsics::cli();
+        flag.store(IDLE, Ordering::SeqCst);
+        monitor(flag);
+        // if we were woken before the monitor was armed, the write won't wake
+        // the `mwait`, so check for it now.
+        if flag.load(Ordering::SeqCst) == IDLE {
+            core::arch::asm!(
+                "sti",
+                "mwait",
+                in("eax") cstate.hint(),
+                in("ecx") 0,
+                options(nomem, nostack),
+            );
+        } else {
+            intrinsics::sti();
+        }
+    }
+    flag.store(BUSY, Ordering::Release);
+    cstate
+}
+
+/// Wake the core with local APIC ID `apic_id`, if it is idle.
+///
+/// Returns `true` if the core was waiting on its idle flag, in which case the
+/// write wakes it. Otherwise, the core is either busy (and will notice any new
+/// work before it idles again), or is halted with `HLT`, and an IPI must be
+/// sent to wake it.
+pub fn wake(apic_id: u32) -> bool {
+    // make sure the core ticks again if it's just about to go idle.
+    super::note_wakeup();
+    let Some(IdleFlag(flag)) = IDLE_FLAGS.get(apic_id as usize) else {
+        return false;
+    };
+    // only cores idling with `MWAIT` ever set their flag to `IDLE`.
+    flag.compare_exchange(IDLE, WOKEN, Ordering::SeqCst, Ordering::Relaxed)
+        .is_ok()
+}
+
+fn current_flag() -> Option<&'static AtomicU8> {
+    // without a local APIC, only the boot processor is running.
+    let id = LocalApic::current().map_or(0, |lapic| lapic.id());
+    IDLE_FLAGS.get(id as usize).map(|IdleFlag(flag)| flag)
+}
+
+/// Returns the smallest and largest monitor line sizes, if `MWAIT` is
+/// supported and idle flags are padded to the largest.
+fn monitor_line() -> Result<(usize, usize), &'static str> {
+    let features = cpu::features();
+    if !features.has_monitor() {
+        return Err("MONITOR/MWAIT is not supported");
+    }
+    let (smallest, largest) = features
+        .monitor_line_sizes()
+        .ok_or("CPUID leaf 5 reports no monitor line size")?;
+    if largest > IDLE_LINE {
+        return Err("monitor line is larger than the idle flags");
+    }
+
+    Ok((smallest, largest))
+}
+
+/// Returns a bitmap of the C-states `MWAIT` may enter.
+fn available_cstates() -> u8 {
+    let features = cpu::features();
+    let deepest = if features.has_arat() {
+        7
+    } else {
+        DEEPEST_WITHOUT_ARAT
+    };
+    // C1 is always available, with a hint of 0, even if CPUID doesn't
+    // enumerate sub-states.
+    (2..=deepest)
+        .filter(|&cstate| features.mwait_substates(cstate) > 0)
+        .fold(1 << CState::C1.0, |cstates, cstate| cstates | (1 << cstate))
+}
+
+/// # Safety
+///
+/// `MONITOR` must be supported.
+unsafe fn monitor(addr: &AtomicU8) {
+    core::arch::asm!(
+        "monitor",
+        in("rax") addr.as_ptr(),
+        in("ecx") 0,
+        in("edx") 0,
+        options(nostack, preserves_flags),
+    );
+}
+
+// === impl CState ===
+
+impl CState {
+    /// The shallowest idle state, entered by `HLT`.
+    pub const C1: Self = Self(1);
+
+    /// The number of C-states `MWAIT` can name, including `C0`.
+    pub const COUNT: usize = 8;
+
+    /// Returns the C-state with number `n`, if `MWAIT` can name it.
+    #[must_use]
+    pub fn new(n: u8) -> Option<Self> {
+        (1..Self::COUNT as u8).contains(&n).then_some(Self(n))
+    }
+
+    /// Returns the C-state's number.
+    #[must_use]
+    pub fn number(self) -> u8 {
+        self.0
+    }
+
+    /// Returns the shortest expected idle time worth entering this C-state
+    /// for.
+    #[must_use]
+    pub fn target_residency(self) -> Duration {
+        Duration::from_micros(TARGET_RESIDENCY_MICROS[self.0 as usize])
+    }
+
+    /// Returns the deepest of the C-states in `cstates` whose target
+    /// residency is no longer than `expected_idle`.
+    fn select(cstates: u8, expected_idle: Duration) -> Self {
+        (Self::C1.0..Self::COUNT as u8)
+            .rev()
+            .map(Self)
+            .find(|cstate| {
+                cstates & (1 << cstate.0) != 0 && cstate.target_residency() <= expected_idle
+            })
+            .unwrap_or(Self::C1)
+    }
+
+    /// Returns the deepest of the C-states in `cstates`.
+    fn deepest(cstates: u8) -> Self {
+        Self::select(cstates, Duration::MAX)
+    }
+
+    /// Returns the `MWAIT` hint for the C-state's first sub-state.
+    fn hint(self) -> u32 {
+        u32::from(self.0 - 1) << 4
+    }
+}
+
+impl fmt::Display for CState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "C{}", self.0)
+    }
+}
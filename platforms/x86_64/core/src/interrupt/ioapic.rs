@@ -0,0 +1,311 @@
+//! I/O APIC interrupt routing.
+//!
+//! The HAL programs the I/O APIC entries for the interrupts it handles itself
+//! (the timer and the PS/2 keyboard), but gives drivers no way to route any
+//! other device interrupt to a vector. This module programs individual
+//! redirection table entries on the I/O APICs described by the ACPI MADT.
+//!
+//! Device interrupts are identified by their global system interrupt (GSI)
+//! number. ISA IRQs are usually identity-mapped to GSIs, but the MADT may
+//! override this (most commonly, the PIT's IRQ 0 is connected to GSI 2), and
+//! may give an ISA IRQ a non-default trigger mode or polarity. Use
+//! [`isa_route`] to find the GSI and signalling for an ISA IRQ.
+//!
+//! Interrupts routed through the I/O APIC must be acknowledged with
+//! [`end_of_interrupt`] at the end of their handler. For level-triggered
+//! interrupts, the local APIC forwards the EOI to the I/O APIC, which will not
+//! deliver the interrupt again until then.
+use crate::lapic::LocalApic;
+use alloc::vec::Vec;
+use core::fmt;
+use hal_core::{Address, PAddr, VAddr};
+use hal_x86_64::mm;
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+use mycelium_util::sync::InitOnce;
+
+static ROUTING: InitOnce<Routing> = InitOnce::uninitialized();
+
+/// Register selector, written with the index of the register to access.
+const IOREGSEL: usize = 0x00;
+/// Register window, through which the selected register is accessed.
+const IOWIN: usize = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+/// The low half of the first redirection table entry. Each entry occupies
+/// two registers.
+const REG_REDIRECTION_BASE: u32 = 0x10;
+
+const ENTRY_POLARITY_LOW: u32 = 1 << 13;
+const ENTRY_TRIGGER_LEVEL: u32 = 1 << 15;
+const ENTRY_MASKED: u32 = 1 << 16;
+
+/// Vectors below this are reserved for CPU exceptions.
+const MIN_VECTOR: u8 = 32;
+
+/// How an interrupt is signalled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Which level of an interrupt line is asserted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// The GSI and signalling of an ISA IRQ, returned by [`isa_route`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub trigger: Trigger,
+    pub polarity: Polarity,
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    /// [`init`] hasn't been called, or the system has no I/O APIC.
+    NotInitialized,
+    /// No I/O APIC handles this GSI.
+    NoSuchGsi(u32),
+    /// The vector is reserved for CPU exceptions.
+    ReservedVector(u8),
+}
+
+struct Routing {
+    io_apics: Vec<Mutex<IoApic, Spinlock>>,
+    isa_routes: [IsaRoute; 16],
+}
+
+#[derive(Debug)]
+struct IoApic {
+    base: VAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+/// Record the I/O APICs and interrupt source overrides in the MADT's
+/// interrupt model.
+///
+/// This must be called after the HAL has enabled hardware interrupts, which
+/// maps the local and I/O APICs' registers.
+pub fn init(apic: &acpi::platform::interrupt::Apic) {
+    use acpi::platform::interrupt::{Polarity as AcpiPolarity, TriggerMode};
+
+    let mut isa_routes = core::array::from_fn(|irq| IsaRoute {
+        gsi: irq as u32,
+        trigger: Trigger::Edge,
+        polarity: Polarity::ActiveHigh,
+    });
+    for o in &apic.interrupt_source_overrides {
+        let Some(route) = isa_routes.get_mut(o.isa_source as usize) else {
+            tracing::warn!(?o, "interrupt source override for a non-ISA IRQ");
+            continue;
+        };
+        // "same as bus" means the ISA bus defaults: edge-triggered and
+        // active-high.
+        *route = IsaRoute {
+            gsi: o.global_system_interrupt,
+            trigger: match o.trigger_mode {
+                TriggerMode::Level => Trigger::Level,
+                _ => Trigger::Edge,
+            },
+            polarity: match o.polarity {
+                AcpiPolarity::ActiveLow => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            },
+        };
+        tracing::debug!(irq = o.isa_source, route = ?*route, "ISA IRQ overridden");
+    }
+
+    let io_apics = apic
+        .io_apics
+        .iter()
+        .map(|io| {
+            let io_apic = unsafe {
+                IoApic::new(
+                    PAddr::from_u64(io.address as u64),
+                    io.global_system_interrupt_base,
+                )
+            };
+            tracing::debug!(io.id, io_apic = ?io_apic, "found I/O APIC");
+            Mutex::new_with_raw_mutex(io_apic, Spinlock::new())
+        })
+        .collect();
+
+    ROUTING.init(Routing {
+        io_apics,
+        isa_routes,
+    });
+}
+
+/// Returns the GSI, trigger mode, and polarity of an ISA IRQ, honoring any
+/// interrupt source overrides in the MADT.
+///
+/// Returns `None` if `irq` isn't an ISA IRQ (0-15), or [`init`] hasn't been
+/// called.
+#[must_use]
+pub fn isa_route(irq: u8) -> Option<IsaRoute> {
+    ROUTING.try_get()?.isa_routes.get(irq as usize).copied()
+}
+
+/// Route a GSI to `vector` on the CPU core whose local APIC ID is `cpu`, and
+/// unmask it.
+///
+/// I/O APIC destinations are 8 bits wide, so interrupts can only be routed to
+/// cores with APIC IDs of 255 or less.
+pub fn route_irq(
+    gsi: u32,
+    vector: u8,
+    cpu: u8,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> Result<(), RouteError> {
+    if vector < MIN_VECTOR {
+        return Err(RouteError::ReservedVector(vector));
+    }
+
+    let mut low = vector as u32;
+    if trigger == Trigger::Level {
+        low |= ENTRY_TRIGGER_LEVEL;
+    }
+    if polarity == Polarity::ActiveLow {
+        low |= ENTRY_POLARITY_LOW;
+    }
+    let high = (cpu as u32) << 24;
+
+    with_entry(gsi, |io_apic, entry| {
+        // mask the entry while it's changed, so that a half-written entry is
+        // never delivered.
+        io_apic.write_entry(entry, ENTRY_MASKED, high);
+        io_apic.write_entry(entry, low, high);
+    })?;
+    tracing::debug!(gsi, vector, cpu, ?trigger, ?polarity, "routed IRQ");
+    Ok(())
+}
+
+/// Stop a GSI from being delivered, without changing its routing.
+pub fn mask(gsi: u32) -> Result<(), RouteError> {
+    with_entry(gsi, |io_apic, entry| {
+        let (low, high) = io_apic.read_entry(entry);
+        io_apic.write_entry(entry, low | ENTRY_MASKED, high);
+    })
+}
+
+/// Resume delivering a GSI masked by [`mask`].
+pub fn unmask(gsi: u32) -> Result<(), RouteError> {
+    with_entry(gsi, |io_apic, entry| {
+        let (low, high) = io_apic.read_entry(entry);
+        io_apic.write_entry(entry, low & !ENTRY_MASKED, high);
+    })
+}
+
+/// Signal the end of an interrupt routed with [`route_irq`] to the current
+/// CPU core's local APIC.
+///
+/// This must be called once at the end of every such interrupt's handler.
+pub fn end_of_interrupt() {
+    if let Some(lapic) = LocalApic::current() {
+        lapic.end_of_interrupt();
+    }
+}
+
+/// Signal the end of interrupt `vector` to the current CPU core's local APIC,
+/// if it is in service.
+///
+/// Unlike [`end_of_interrupt`], this is safe to call from a handler which may
+/// also be reached by a software `int` instruction, which is never in service
+/// and so must not be acknowledged.
+pub fn end_of_interrupt_for(vector: u8) {
+    if let Some(lapic) = LocalApic::current() {
+        if lapic.is_in_service(vector) {
+            lapic.end_of_interrupt();
+        }
+    }
+}
+
+/// Returns the local APIC ID of the current CPU core, for use as the `cpu`
+/// argument to [`route_irq`], or `None` if the local APIC is disabled, or its
+/// ID is too large to route interrupts to.
+#[must_use]
+pub fn current_apic_id() -> Option<u8> {
+    u8::try_from(LocalApic::current()?.id()).ok()
+}
+
+fn with_entry(gsi: u32, f: impl FnOnce(&mut IoApic, u32)) -> Result<(), RouteError> {
+    let routing = ROUTING.try_get().ok_or(RouteError::NotInitialized)?;
+    for io_apic in &routing.io_apics {
+        let mut io_apic = io_apic.lock();
+        if let Some(entry) = gsi.checked_sub(io_apic.gsi_base) {
+            if entry < io_apic.entries {
+                f(&mut io_apic, entry);
+                return Ok(());
+            }
+        }
+    }
+
+    Err(RouteError::NoSuchGsi(gsi))
+}
+
+// === impl IoApic ===
+
+impl IoApic {
+    /// # Safety
+    ///
+    /// `addr` must be the address of an I/O APIC's registers, which must be
+    /// mapped at their kernel virtual address.
+    unsafe fn new(addr: PAddr, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base: mm::kernel_vaddr_of(addr),
+            gsi_base,
+            entries: 0,
+        };
+        // bits 16-23 of the version register are the index of the last
+        // redirection table entry.
+        io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            (self.base + IOREGSEL).as_ptr::<u32>().write_volatile(reg);
+            (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            (self.base + IOREGSEL).as_ptr::<u32>().write_volatile(reg);
+            (self.base + IOWIN).as_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    fn read_entry(&mut self, entry: u32) -> (u32, u32) {
+        let reg = REG_REDIRECTION_BASE + entry * 2;
+        (self.read(reg), self.read(reg + 1))
+    }
+
+    fn write_entry(&mut self, entry: u32, low: u32, high: u32) {
+        let reg = REG_REDIRECTION_BASE + entry * 2;
+        // write the destination first, so that unmasking the entry (in the
+        // low half) is the last thing to happen.
+        self.write(reg + 1, high);
+        self.write(reg, low);
+    }
+}
+
+// === impl RouteError ===
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInitialized => f.write_str("no I/O APIC is available"),
+            Self::NoSuchGsi(gsi) => write!(f, "no I/O APIC handles GSI {gsi}"),
+            Self::ReservedVector(vector) => {
+                write!(f, "vector {vector} is reserved for CPU exceptions")
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Interrupt latency measurement.
+//!
+//! This measures the end-to-end latency of the interrupt → wake → schedule
+//! path: the time from a hardware interrupt being handled to the scheduler
+//! next polling tasks in [`crate::run`]. Latencies are measured using the
+//! timestamp counter (TSC), and are reported in TSC cycles.
+//!
+//! When an interrupt fires, its handler calls [`interrupt_fired`], which
+//! records the current TSC value, unless an earlier interrupt is still
+//! waiting to be scheduled. The run loop then calls [`scheduler_ran`] after
+//! every scheduler tick that polled at least one task, which records the time
+//! elapsed since the oldest pending interrupt. Measuring from the *oldest*
+//! pending interrupt means that the reported latencies are worst-case.
+//! Interrupts which don't wake any tasks (such as most timer ticks) are
+//! discarded when the run loop goes idle, and aren't measured.
+//!
+//! This module requires the "irq-latency" feature flag.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// TSC value when the oldest not-yet-scheduled interrupt fired, or 0 if no
+/// interrupt is pending.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+static MIN: AtomicU64 = AtomicU64::new(u64::MAX);
+static MAX: AtomicU64 = AtomicU64::new(0);
+static SUM: AtomicU64 = AtomicU64::new(0);
+static COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupt latency statistics, in TSC cycles.
+#[derive(Copy, Clone, Debug)]
+pub struct LatencyStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: u64,
+    /// The number of latency samples recorded.
+    pub samples: u64,
+}
+
+/// Record that an interrupt has fired.
+///
+/// This should be called from interrupt handlers that may wake tasks.
+#[inline]
+pub(crate) fn interrupt_fired() {
+    let now = rdtsc();
+    // only the oldest pending interrupt is tracked.
+    let _ = PENDING.compare_exchange(0, now, Ordering::AcqRel, Ordering::Relaxed);
+}
+
+/// Record that the scheduler has polled tasks.
+#[inline]
+pub(crate) fn scheduler_ran() {
+    let fired = PENDING.swap(0, Ordering::AcqRel);
+    if fired == 0 {
+        return;
+    }
+
+    let latency = rdtsc().saturating_sub(fired);
+    MIN.fetch_min(latency, Ordering::Relaxed);
+    MAX.fetch_max(latency, Ordering::Relaxed);
+    SUM.fetch_add(latency, Ordering::Relaxed);
+    COUNT.fetch_add(1, Ordering::Release);
+}
+
+/// Discard any pending interrupt timestamp.
+///
+/// This is called when the run loop goes idle: if no tasks are ready to run,
+/// any interrupt that fired since the last tick didn't wake a task, so there's
+/// no latency to measure.
+#[inline]
+pub(crate) fn discard_pending() {
+    PENDING.store(0, Ordering::Release);
+}
+
+/// Returns the interrupt latency statistics recorded so far, or `None` if no
+/// samples have been recorded.
+#[must_use]
+pub fn stats() -> Option<LatencyStats> {
+    let samples = COUNT.load(Ordering::Acquire);
+    if samples == 0 {
+        return None;
+    }
+    Some(LatencyStats {
+        min: MIN.load(Ordering::Relaxed),
+        max: MAX.load(Ordering::Relaxed),
+        mean: SUM.load(Ordering::Relaxed) / samples,
+        samples,
+    })
+}
+
+#[inline(always)]
+fn rdtsc() -> u64 {
+    unsafe {
+        // Safety: reading the TSC has no side effects.
+        core::arch::x86_64::_rdtsc()
+    }
+}
@@ -0,0 +1,284 @@
+//! Non-maskable interrupts (NMIs).
+//!
+//! An NMI is delivered even while interrupts are disabled, so it's the only
+//! way to interrupt a core which is stuck with interrupts off. NMIs come from:
+//!
+//! - The chipset, to report hardware errors, such as a memory parity error
+//!   (`SERR#`) or an I/O channel check (`IOCHK#`). These are latched in
+//!   system control port B.
+//! - Other cores, which send an NMI IPI with [`request_dump`] to find out
+//!   what a core is doing, such as when the [watchdog](crate::watchdog) finds
+//!   that it's stopped making progress.
+//!
+//! # Handling
+//!
+//! The handler runs on its own stack, in the TSS's [`NMI_IST`] slot, so that
+//! it works even if the interrupted code's stack is broken. It captures a
+//! [`Snapshot`] of the interrupted code: its registers, a [`Backtrace`], and
+//! the [`Reason`] for the NMI.
+//!
+//! - Hardware errors are fatal, so the handler panics with the snapshot, and
+//!   the panic handler writes it to the serial port and the framebuffer.
+//! - Otherwise, the snapshot is stored for the core, to be collected with
+//!   [`take_snapshot`]. The handler doesn't log it itself, since the code it
+//!   interrupted may hold the locks that logging takes. Snapshots of NMIs
+//!   nobody asked for are logged from the timer interrupt, by
+//!   [`report_unexpected`].
+//!
+//! # Reentrancy
+//!
+//! From the time the CPU delivers an NMI until the next `IRET`, it holds off
+//! any further NMIs. But *any* `IRET` ends that, including the one returning
+//! from an exception taken inside the NMI handler, and a second NMI would
+//! then switch to the top of the same IST stack, overwriting the first
+//! handler's frame. So the handler must never fault: it only touches memory
+//! it owns, and never waits on a lock. A per-core flag also makes an NMI which
+//! arrives while the core is still handling one return immediately, rather
+//! than recursing into the snapshot it was taking.
+//!
+//! [`NMI_IST`]: crate::gdt::NMI_IST
+use super::{stats, vector, Registers};
+use crate::{
+    backtrace::Backtrace,
+    gdt,
+    lapic::{IpiDestination, LocalApic},
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use hal_core::VAddr;
+use hal_x86_64::cpu::Port;
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+
+/// The NMI's vector.
+pub const NMI_VECTOR: u8 = 2;
+
+/// The number of cores whose NMIs can be tracked, indexed by local APIC ID.
+const MAX_CORES: usize = 16;
+
+/// System control port B, which latches the chipset's NMI sources.
+const PORT_B: u16 = 0x61;
+/// Set in port B on a memory parity or system error (`SERR#`).
+const PORT_B_SERR: u8 = 1 << 7;
+/// Set in port B on an I/O channel check (`IOCHK#`).
+const PORT_B_IOCHK: u8 = 1 << 6;
+
+/// The NMI delivery mode, in the ICR.
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Why an NMI was raised.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// Another core asked for a [`Snapshot`] with [`request_dump`].
+    Requested,
+    /// The chipset reported a memory parity or system error.
+    SystemError,
+    /// The chipset reported an I/O channel check.
+    IoCheck,
+    /// The NMI came from somewhere else, such as a debugger, or a device
+    /// which doesn't report through port B.
+    Unknown,
+}
+
+/// The state of a core when it took an NMI.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// The local APIC ID of the core which took the NMI.
+    pub apic_id: u32,
+    pub reason: Reason,
+    /// The interrupted instruction pointer.
+    pub rip: VAddr,
+    /// The interrupted stack pointer.
+    pub rsp: VAddr,
+    /// The interrupted `RFLAGS`.
+    pub rflags: u64,
+    /// The interrupted code's call stack.
+    pub backtrace: Backtrace,
+}
+
+/// Set while each core is handling an NMI.
+static IN_NMI: [AtomicBool; MAX_CORES] = [const { AtomicBool::new(false) }; MAX_CORES];
+
+/// Set by [`request_dump`] before sending a core an NMI.
+static REQUESTED: [AtomicBool; MAX_CORES] = [const { AtomicBool::new(false) }; MAX_CORES];
+
+/// The last snapshot taken by each core, until it's collected.
+static SNAPSHOTS: [Mutex<Option<Snapshot>, Spinlock>; MAX_CORES] =
+    [const { Mutex::new_with_raw_mutex(None, Spinlock::new()) }; MAX_CORES];
+
+/// Install the NMI handler.
+///
+/// The IDT is shared by every core, so this is called once, by
+/// [`enable_exceptions`](super::enable_exceptions), after the boot GDT and
+/// TSS are loaded. Every other core must load a TSS with a stack in the
+/// [`NMI_IST`](gdt::NMI_IST) slot, with [`gdt::init_core`], before it can
+/// take an NMI.
+pub(super) fn init() {
+    let installed = unsafe {
+        // Safety: `handle_nmi` is an interrupt handler, and every TSS has an
+        // NMI stack.
+        vector::set_handler(NMI_VECTOR, handle_nmi as usize as u64, Some(gdt::NMI_IST))
+    };
+    if installed {
+        tracing::debug!(ist = gdt::NMI_IST, "NMI handler installed");
+    } else {
+        tracing::warn!("IDT has no entry for NMIs, NMIs will not be handled");
+    }
+}
+
+/// Send an NMI to the core with local APIC ID `apic_id`, so that it stores a
+/// [`Snapshot`] of what it's doing, to be collected with [`take_snapshot`].
+///
+/// This interrupts the core even if it has interrupts disabled. Returns
+/// `false` if the NMI couldn't be sent, such as if `apic_id` is the current
+/// core.
+pub fn request_dump(apic_id: u32) -> bool {
+    let Some(requested) = REQUESTED.get(apic_id as usize) else {
+        return false;
+    };
+    let Some(lapic) = LocalApic::current() else {
+        return false;
+    };
+    if lapic.id() == apic_id {
+        return false;
+    }
+    requested.store(true, Ordering::Release);
+    let sent = unsafe {
+        // Safety: the NMI handler only takes a snapshot, and returns.
+        lapic.send_ipi(IpiDestination::Core(apic_id), DELIVERY_NMI)
+    };
+    if !sent {
+        requested.store(false, Ordering::Release);
+    }
+    sent
+}
+
+/// Returns the last snapshot taken by the core with local APIC ID `apic_id`,
+/// if there is one which hasn't been collected yet.
+///
+/// This may be called from interrupt handlers. It returns `None`, leaving the
+/// snapshot in place, if the snapshot is being written or read elsewhere.
+pub fn take_snapshot(apic_id: u32) -> Option<Snapshot> {
+    SNAPSHOTS.get(apic_id as usize)?.try_lock()?.take()
+}
+
+/// Log the snapshots of any NMIs which weren't asked for with
+/// [`request_dump`].
+///
+/// This is called from the periodic timer interrupt.
+pub(crate) fn report_unexpected() {
+    for slot in &SNAPSHOTS {
+        let Some(mut slot) = slot.try_lock() else {
+            continue;
+        };
+        if slot
+            .as_ref()
+            .is_some_and(|snapshot| snapshot.reason == Reason::Unknown)
+        {
+            if let Some(snapshot) = slot.take() {
+                tracing::warn!(%snapshot, "unexpected NMI");
+            }
+        }
+    }
+}
+
+extern "x86-interrupt" fn handle_nmi(registers: Registers) {
+    stats::count_vector(NMI_VECTOR);
+    let apic_id = LocalApic::current().map_or(0, |lapic| lapic.id());
+    let idx = apic_id as usize;
+    if IN_NMI
+        .get(idx)
+        .is_some_and(|in_nmi| in_nmi.swap(true, Ordering::Acquire))
+    {
+        // this core is already handling an NMI.
+        return;
+    }
+
+    let requested = REQUESTED
+        .get(idx)
+        .is_some_and(|requested| requested.swap(false, Ordering::AcqRel));
+    let snapshot = Snapshot {
+        apic_id,
+        reason: Reason::current(requested),
+        rip: registers.instruction_ptr,
+        rsp: registers.stack_ptr,
+        rflags: registers.cpu_flags,
+        backtrace: Backtrace::capture(),
+    };
+    if snapshot.reason.is_hardware_error() {
+        // leave `IN_NMI` set: the panic handler runs on this stack, and
+        // doesn't return.
+        panic!("{snapshot}");
+    }
+
+    if let Some(mut slot) = SNAPSHOTS.get(idx).and_then(Mutex::try_lock) {
+        *slot = Some(snapshot);
+    }
+    if let Some(in_nmi) = IN_NMI.get(idx) {
+        in_nmi.store(false, Ordering::Release);
+    }
+}
+
+// === impl Reason ===
+
+impl Reason {
+    /// Returns the reason for the NMI being handled, given whether it was
+    /// asked for with [`request_dump`].
+    ///
+    /// Hardware errors take precedence, since they may arrive at the same
+    /// time as a requested NMI.
+    fn current(requested: bool) -> Self {
+        // Safety: reading port B has no side effects.
+        let port_b = unsafe { Port::at(PORT_B).readb() };
+        // a port that isn't there reads as all ones.
+        if port_b != 0xff {
+            if port_b & PORT_B_SERR != 0 {
+                return Self::SystemError;
+            }
+            if port_b & PORT_B_IOCHK != 0 {
+                return Self::IoCheck;
+            }
+        }
+        if requested {
+            Self::Requested
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// Returns `true` if the NMI reports a hardware error.
+    #[must_use]
+    pub fn is_hardware_error(self) -> bool {
+        matches!(self, Self::SystemError | Self::IoCheck)
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Requested => "requested by another core",
+            Self::SystemError => "memory parity or system error (SERR#)",
+            Self::IoCheck => "I/O channel check (IOCHK#)",
+            Self::Unknown => "unknown source",
+        })
+    }
+}
+
+// === impl Snapshot ===
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            apic_id,
+            reason,
+            rip,
+            rsp,
+            rflags,
+            backtrace,
+        } = self;
+        writeln!(f, "NMI on core {apic_id}: {reason}")?;
+        writeln!(f, "rip: {rip:?}, rsp: {rsp:?}, rflags: {rflags:#x}")?;
+        write!(f, "{backtrace}")
+    }
+}
@@ -0,0 +1,131 @@
+//! The local APIC's spurious interrupt vector.
+//!
+//! If an interrupt is withdrawn after the local APIC has signalled it to the
+//! CPU, but before the CPU acknowledges it (such as when it's masked in that
+//! window), the local APIC delivers a *spurious interrupt* instead, on the
+//! vector in its spurious-interrupt vector register (SIVR). A spurious
+//! interrupt never sets a bit in the local APIC's in-service register, so its
+//! handler must **not** signal an EOI: an EOI always ends the
+//! highest-priority interrupt in service, so it would end some other,
+//! genuine interrupt whose handler is still running, and that handler's own
+//! EOI would then end yet another.
+//!
+//! So the rules for acknowledging interrupts are:
+//!
+//! - Interrupts delivered by the local APIC get exactly one EOI, at the end
+//!   of their handler. The HAL does this for the timer and PS/2 keyboard, and
+//!   the [dynamic vector](super::vector) stubs do it for everything else,
+//!   but only if the vector is actually in service, so that a software `int`
+//!   on one of their vectors isn't acknowledged.
+//! - Spurious interrupts, exceptions, and [NMIs](super::nmi) are never
+//!   acknowledged.
+use super::{stats, vector, Registers};
+use crate::lapic::LocalApic;
+
+/// The vector spurious interrupts are delivered on.
+///
+/// On some older CPUs, the low four bits of the spurious vector are
+/// hardwired to ones, so it must end in `0xf`.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const _: () = assert!(SPURIOUS_VECTOR & 0xf == 0xf);
+
+/// Install the spurious interrupt handler, and point the boot processor's
+/// local APIC at it.
+///
+/// The IDT is shared by every core, so this is called once, by
+/// [`enable_hardware_interrupts`](super::enable_hardware_interrupts). Other
+/// cores must call [`init_core`] once their local APIC is enabled.
+pub(super) fn init() {
+    let installed = unsafe {
+        // Safety: `handle_spurious` is an interrupt handler, and doesn't switch
+        // stacks.
+        vector::set_handler(SPURIOUS_VECTOR, handle_spurious as usize as u64, None)
+    };
+    if !installed {
+        tracing::warn!("IDT has no entry for the spurious interrupt vector");
+        return;
+    }
+    init_core();
+}
+
+/// Point the current core's local APIC's SIVR at [`SPURIOUS_VECTOR`].
+///
+/// This also software-enables the local APIC, if it isn't already.
+pub fn init_core() {
+    let Some(lapic) = LocalApic::current() else {
+        return;
+    };
+    unsafe {
+        // Safety: `handle_spurious` is installed for the vector, and doesn't
+        // signal an EOI.
+        lapic.set_spurious_vector(SPURIOUS_VECTOR);
+    }
+    tracing::debug!(
+        core.apic_id = lapic.id(),
+        vector = SPURIOUS_VECTOR,
+        "spurious interrupt vector set"
+    );
+}
+
+/// Returns the number of spurious interrupts so far, on every core.
+#[must_use]
+pub fn count() -> u64 {
+    stats::interrupt_stats().vector(SPURIOUS_VECTOR)
+}
+
+/// Check that spurious interrupts are handled without an EOI, by raising
+/// [`SPURIOUS_VECTOR`] with an `int` instruction a few times, and then
+/// waiting for the next timer interrupt.
+///
+/// Returns `false` if a spurious interrupt wasn't counted, the local APIC
+/// was left with an interrupt in service, or no timer interrupt arrived
+/// afterwards. Interrupts must be enabled.
+pub fn smoke_test() -> bool {
+    const RAISES: u64 = 3;
+    const TIMER_WAITS: usize = 1_000;
+
+    let Some(lapic) = LocalApic::current() else {
+        // without a local APIC, there are no spurious interrupts.
+        return true;
+    };
+
+    let before = count();
+    for _ in 0..RAISES {
+        unsafe {
+            core::arch::asm!("int {}", const SPURIOUS_VECTOR);
+        }
+    }
+    let counted = count() - before;
+    if counted != RAISES {
+        tracing::warn!(
+            counted,
+            expected = RAISES,
+            "spurious interrupts not counted"
+        );
+        return false;
+    }
+    if let Some(vector) = (0..=u8::MAX).find(|&vector| lapic.is_in_service(vector)) {
+        tracing::warn!(
+            vector,
+            "interrupt left in service after spurious interrupts"
+        );
+        return false;
+    }
+
+    // a missing or extra EOI would stop the timer interrupt being delivered.
+    let ticks = super::timer_ticks();
+    for _ in 0..TIMER_WAITS {
+        if super::timer_ticks() != ticks {
+            return true;
+        }
+        super::wait_for_interrupt();
+    }
+    tracing::warn!("no timer interrupt after spurious interrupts");
+    false
+}
+
+extern "x86-interrupt" fn handle_spurious(_: Registers) {
+    // there's nothing to do, and no EOI to signal.
+    stats::count_vector(SPURIOUS_VECTOR);
+}
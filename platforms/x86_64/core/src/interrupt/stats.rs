@@ -0,0 +1,144 @@
+//! Interrupt counts, for diagnostics.
+//!
+//! Every interrupt dispatched through a [dynamic vector](super::vector) is
+//! counted by its vector. The timer and PS/2 keyboard interrupts are
+//! dispatched by the HAL, which doesn't say which vector they arrived on, so
+//! they're counted by [`Source`] instead.
+//!
+//! Counting an interrupt is a single relaxed atomic add. The counts are read
+//! with [`interrupt_stats`], and logged with [`log_stats`], which is also
+//! part of the [SysRq](crate::sysrq) dump.
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const VECTORS: usize = 256;
+
+static BY_VECTOR: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+static BY_SOURCE: [AtomicU64; Source::COUNT] = [const { AtomicU64::new(0) }; Source::COUNT];
+
+/// An interrupt handled by the HAL, whose vector isn't known.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The periodic timer interrupt, from the PIT or local APIC timer.
+    Timer,
+    /// The PS/2 keyboard interrupt.
+    Ps2Keyboard,
+}
+
+/// A snapshot of the interrupt counts.
+#[derive(Clone, Debug)]
+pub struct InterruptStats {
+    by_vector: [u64; VECTORS],
+    by_source: [u64; Source::COUNT],
+}
+
+/// Count an interrupt on `vector`.
+#[inline(always)]
+pub(crate) fn count_vector(vector: u8) {
+    BY_VECTOR[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an interrupt from `source`.
+#[inline(always)]
+pub(crate) fn count_source(source: Source) {
+    BY_SOURCE[source as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns a snapshot of the interrupt counts so far.
+///
+/// The counts are read one at a time, so interrupts that arrive while the
+/// snapshot is taken may or may not be included.
+#[must_use]
+pub fn interrupt_stats() -> InterruptStats {
+    InterruptStats {
+        by_vector: core::array::from_fn(|i| BY_VECTOR[i].load(Ordering::Relaxed)),
+        by_source: core::array::from_fn(|i| BY_SOURCE[i].load(Ordering::Relaxed)),
+    }
+}
+
+/// Log every non-zero interrupt count.
+pub fn log_stats() {
+    let stats = interrupt_stats();
+    tracing::info!(total = stats.total(), "interrupt counts");
+    for (source, count) in stats.sources() {
+        tracing::info!(?source, count);
+    }
+    for (vector, count) in stats.vectors() {
+        tracing::info!(vector, count);
+    }
+}
+
+// === impl Source ===
+
+impl Source {
+    const COUNT: usize = 2;
+    const ALL: [Self; Self::COUNT] = [Self::Timer, Self::Ps2Keyboard];
+}
+
+// === impl InterruptStats ===
+
+impl InterruptStats {
+    /// Returns the number of interrupts counted on `vector`.
+    #[must_use]
+    pub fn vector(&self, vector: u8) -> u64 {
+        self.by_vector[vector as usize]
+    }
+
+    /// Returns the number of interrupts counted from `source`.
+    #[must_use]
+    pub fn source(&self, source: Source) -> u64 {
+        self.by_source[source as usize]
+    }
+
+    /// Returns each vector with a non-zero count, and its count.
+    pub fn vectors(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        (0..=u8::MAX)
+            .zip(self.by_vector.iter().copied())
+            .filter(|&(_, count)| count > 0)
+    }
+
+    /// Returns each source with a non-zero count, and its count.
+    pub fn sources(&self) -> impl Iterator<Item = (Source, u64)> + '_ {
+        Source::ALL
+            .into_iter()
+            .zip(self.by_source.iter().copied())
+            .filter(|&(_, count)| count > 0)
+    }
+
+    /// Returns the total number of interrupts counted.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.by_vector.iter().chain(&self.by_source).sum()
+    }
+
+    /// Returns the number of interrupts counted since `earlier` was taken.
+    ///
+    /// Comparing two snapshots taken a known time apart gives interrupt
+    /// rates, which shows up interrupt storms.
+    #[must_use]
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            by_vector: core::array::from_fn(|i| {
+                self.by_vector[i].saturating_sub(earlier.by_vector[i])
+            }),
+            by_source: core::array::from_fn(|i| {
+                self.by_source[i].saturating_sub(earlier.by_source[i])
+            }),
+        }
+    }
+}
+
+impl fmt::Display for InterruptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} interrupts", self.total())?;
+        for (source, count) in self.sources() {
+            writeln!(f, "  {source:?}: {count}")?;
+        }
+        for (vector, count) in self.vectors() {
+            writeln!(f, "  vector {vector:#04x}: {count}")?;
+        }
+        Ok(())
+    }
+}
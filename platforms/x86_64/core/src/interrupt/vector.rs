@@ -0,0 +1,279 @@
+//! Dynamic interrupt vector registration.
+//!
+//! The HAL's IDT only dispatches to the fixed set of callbacks in
+//! [`hal_core::interrupt::Handlers`]. To let drivers handle other interrupts
+//! without editing the IDT by hand, this module reserves a range of vectors,
+//! [`FIRST_VECTOR`] through [`LAST_VECTOR`], to which handlers can be attached
+//! at runtime with [`register_handler`].
+//!
+//! Each vector in the range gets an entry stub in the IDT the first time a
+//! handler is registered for it. The stub looks up the vector's handler in a
+//! table, calls it, and then signals the end of the interrupt to the local
+//! APIC, so handlers must not do so themselves. The end of the interrupt is
+//! only signalled if the vector is in service, so that raising it with a
+//! software `int` (as [`smoke_test`] does) doesn't acknowledge some other
+//! interrupt instead; see [`spurious`](super::spurious) for the full rules.
+//! Vectors which the HAL has
+//! already installed a handler for are refused.
+//!
+//! Drivers which don't need a particular vector, such as those using
+//! message-signaled interrupts, can have one picked for them with
+//! [`allocate_handler`]. It hands out vectors from the top of the range down,
+//! so that it doesn't take the low vectors which built-in drivers register
+//! by number.
+//!
+//! Device interrupts are routed to a registered vector with
+//! [`ioapic::route_irq`](super::ioapic::route_irq).
+use super::{ioapic, Registers};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The first vector which handlers may be registered for.
+///
+/// Vectors below this are CPU exceptions, or the legacy PIC's IRQs.
+pub const FIRST_VECTOR: u8 = 0x30;
+/// The last vector which handlers may be registered for.
+pub const LAST_VECTOR: u8 = FIRST_VECTOR + VECTORS as u8 - 1;
+
+const VECTORS: usize = 32;
+
+/// An interrupt handler, called with the interrupted code's registers.
+pub type Handler = fn(&mut Registers);
+
+/// Registered handlers, as `fn` pointers cast to `usize`, or 0 if no handler
+/// is registered for the vector.
+static HANDLERS: [AtomicUsize; VECTORS] = [const { AtomicUsize::new(0) }; VECTORS];
+
+/// Entry stubs for each vector in the dynamic range.
+static STUBS: [extern "x86-interrupt" fn(Registers); VECTORS] = {
+    macro_rules! stubs {
+        ($($n:literal)*) => { [$(stub::<$n>),*] };
+    }
+    stubs!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31)
+};
+
+/// Unregisters a handler registered with [`register_handler`] when dropped.
+#[must_use = "the handler is unregistered when the guard is dropped"]
+#[derive(Debug)]
+pub struct IrqGuard {
+    vector: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    /// A handler is already registered for this vector, or the HAL handles it.
+    AlreadyRegistered(u8),
+    /// The vector is outside [`FIRST_VECTOR`]..=[`LAST_VECTOR`].
+    OutOfRange(u8),
+    /// Every vector in the range has a handler registered.
+    NoFreeVectors,
+}
+
+/// Register `handler` to be called when `vector` fires.
+///
+/// The handler is unregistered when the returned [`IrqGuard`] is dropped.
+pub fn register_handler(vector: u8, handler: Handler) -> Result<IrqGuard, RegisterError> {
+    if !(FIRST_VECTOR..=LAST_VECTOR).contains(&vector) {
+        return Err(RegisterError::OutOfRange(vector));
+    }
+
+    let idx = (vector - FIRST_VECTOR) as usize;
+    HANDLERS[idx]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| RegisterError::AlreadyRegistered(vector))?;
+
+    let installed = super::without_interrupts(|| unsafe { install_stub(vector, idx) });
+    if !installed {
+        HANDLERS[idx].store(0, Ordering::Release);
+        return Err(RegisterError::AlreadyRegistered(vector));
+    }
+
+    tracing::debug!(vector, "registered interrupt handler");
+    Ok(IrqGuard { vector })
+}
+
+/// Register `handler` for any free vector in the dynamic range.
+///
+/// The vector it was registered for is returned by [`IrqGuard::vector`], and
+/// the handler is unregistered when the guard is dropped.
+pub fn allocate_handler(handler: Handler) -> Result<IrqGuard, RegisterError> {
+    (FIRST_VECTOR..=LAST_VECTOR)
+        .rev()
+        .find_map(|vector| register_handler(vector, handler).ok())
+        .ok_or(RegisterError::NoFreeVectors)
+}
+
+extern "x86-interrupt" fn stub<const N: usize>(mut registers: Registers) {
+    super::stats::count_vector(FIRST_VECTOR + N as u8);
+    let handler = HANDLERS[N].load(Ordering::Acquire);
+    if handler != 0 {
+        // Safety: only `Handler`s are ever stored in the table.
+        let handler = unsafe { core::mem::transmute::<usize, Handler>(handler) };
+        handler(&mut registers);
+    } else {
+        // the handler was unregistered after the interrupt was raised.
+        tracing::trace!(vector = FIRST_VECTOR as usize + N, "no handler for vector");
+    }
+
+    super::note_wakeup();
+    ioapic::end_of_interrupt_for(FIRST_VECTOR + N as u8);
+}
+
+/// A gate descriptor in the IDT.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    attrs: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+
+/// The operand of the `sidt` instruction.
+#[repr(C, packed)]
+struct IdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// Present, ring 0, 64-bit interrupt gate.
+const GATE_INTERRUPT: u8 = 0x8e;
+const GATE_PRESENT: u8 = 1 << 7;
+
+/// Point the IDT entry for `vector` at its stub, unless another handler is
+/// already installed there. Returns `false` if one is.
+///
+/// # Safety
+///
+/// This must be called with interrupts disabled, after the IDT has been
+/// loaded.
+unsafe fn install_stub(vector: u8, idx: usize) -> bool {
+    let Some(gate) = gate(vector) else {
+        return false;
+    };
+    let stub = STUBS[idx] as usize as u64;
+    let existing = gate.read_volatile();
+    if existing.attrs & GATE_PRESENT != 0 {
+        // this is either our stub, from an earlier registration, or a handler
+        // installed by the HAL.
+        let offset = existing.offset_low as u64
+            | (existing.offset_mid as u64) << 16
+            | (existing.offset_high as u64) << 32;
+        return offset == stub;
+    }
+
+    write_gate(gate, stub, 0);
+    true
+}
+
+/// Point the IDT entry for `vector` at `handler`, replacing any existing
+/// handler, and switching to the stack in `ist` if it's `Some`.
+///
+/// Returns `false` if the loaded IDT is too short to have an entry for
+/// `vector`.
+///
+/// # Safety
+///
+/// `handler` must be an `extern "x86-interrupt"` function, and if `ist` is
+/// `Some`, every core's TSS must have a stack in that IST slot.
+pub(super) unsafe fn set_handler(vector: u8, handler: u64, ist: Option<usize>) -> bool {
+    let Some(gate) = gate(vector) else {
+        return false;
+    };
+    // the gate's IST field is 1-based, with 0 meaning no stack switch.
+    write_gate(gate, handler, ist.map_or(0, |ist| ist as u8 + 1));
+    true
+}
+
+/// Returns the loaded IDT's entry for `vector`, if it has one.
+unsafe fn gate(vector: u8) -> Option<*mut Gate> {
+    let mut idtr = IdtPointer { limit: 0, base: 0 };
+    core::arch::asm!("sidt [{}]", in(reg) &mut idtr, options(nostack, preserves_flags));
+    let limit = idtr.limit as usize;
+    if (vector as usize + 1) * core::mem::size_of::<Gate>() - 1 > limit {
+        return None;
+    }
+    Some((idtr.base as *mut Gate).add(vector as usize))
+}
+
+unsafe fn write_gate(gate: *mut Gate, handler: u64, ist: u8) {
+    let cs: u16;
+    core::arch::asm!("mov {:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+    gate.write_volatile(Gate {
+        offset_low: handler as u16,
+        selector: cs,
+        ist,
+        attrs: GATE_INTERRUPT,
+        offset_mid: (handler >> 16) as u16,
+        offset_high: (handler >> 32) as u32,
+        _reserved: 0,
+    });
+}
+
+/// Check that dynamically registered handlers are dispatched, by registering
+/// a handler for [`LAST_VECTOR`] and raising it with an `int` instruction.
+///
+/// Returns `false` if the handler couldn't be registered, or wasn't called
+/// exactly once.
+pub fn smoke_test() -> bool {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    fn handler(_: &mut Registers) {
+        FIRED.fetch_add(1, Ordering::Release);
+    }
+
+    let guard = match register_handler(LAST_VECTOR, handler) {
+        Ok(guard) => guard,
+        Err(error) => {
+            tracing::warn!(%error, "failed to register smoke test handler");
+            return false;
+        }
+    };
+    unsafe {
+        core::arch::asm!("int {}", const LAST_VECTOR);
+    }
+    drop(guard);
+
+    FIRED.load(Ordering::Acquire) == 1
+}
+
+// === impl IrqGuard ===
+
+impl IrqGuard {
+    /// Returns the vector the handler is registered for.
+    #[must_use]
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        // the stub stays installed, and ignores the vector until a new handler
+        // is registered.
+        HANDLERS[(self.vector - FIRST_VECTOR) as usize].store(0, Ordering::Release);
+        tracing::debug!(vector = self.vector, "unregistered interrupt handler");
+    }
+}
+
+// === impl RegisterError ===
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyRegistered(vector) => {
+                write!(f, "a handler is already registered for vector {vector}")
+            }
+            Self::OutOfRange(vector) => write!(
+                f,
+                "vector {vector} is outside the dynamic range {FIRST_VECTOR}..={LAST_VECTOR}"
+            ),
+            Self::NoFreeVectors => f.write_str("no free vectors in the dynamic range"),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Inter-processor interrupts (IPIs).
+//!
+//! This module sends IPIs by writing directly to the local APIC's interrupt
+//! command register (ICR), in either xAPIC or x2APIC mode (see
+//! [`crate::lapic`]). It deliberately avoids taking any locks or allocating,
+//! so that it can be used from the panic handler.
+//!
+//! # Rescheduling
+//!
+//! When a core wakes a task that runs on another core, the other core may be
+//! idle in [`wait_for_interrupt`](crate::interrupt::wait_for_interrupt).
+//! [`wake_core`] and [`wake_all_cores`] get it to run its scheduler again:
+//!
+//! - They always count a wakeup first (see the run loop's [lost wakeups]
+//!   invariant), so a core which is just about to go idle ticks again instead.
+//! - A core idling with `MWAIT` is woken by writing to its idle flag, without
+//!   an interrupt.
+//! - Otherwise, a core that is waiting for an interrupt is sent a
+//!   [`RESCHEDULE_VECTOR`] IPI. Its handler does nothing else: returning from
+//!   the interrupt is enough for the core to leave its idle loop.
+//! - An [offline](crate::smp#offlining) core is never woken. Its wakeups go to
+//!   the core which took over its tasks instead.
+//!
+//! [lost wakeups]: kernel::runloop#lost-wakeups
+use crate::{
+    interrupt::{self, idle, vector},
+    lapic::{IpiDestination, LocalApic},
+    smp,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel::maitake::sync::spin::InitOnce;
+
+/// The vector of the IPI which makes a core run its scheduler again.
+pub const RESCHEDULE_VECTOR: u8 = vector::LAST_VECTOR - 1;
+
+// ICR fields.
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Set once some core has begun halting the others.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+static RESCHEDULE_HANDLER: InitOnce<vector::IrqGuard> = InitOnce::uninitialized();
+
+/// Register the handler for [`RESCHEDULE_VECTOR`].
+///
+/// This must be called once the IDT is set up, and before any core waits for
+/// a reschedule IPI.
+pub fn init() {
+    // the vector stub already counts the wakeup, which is all there is to do.
+    fn on_reschedule(_: &mut interrupt::Registers) {}
+
+    match vector::register_handler(RESCHEDULE_VECTOR, on_reschedule) {
+        Ok(guard) => RESCHEDULE_HANDLER.init(guard),
+        Err(error) => tracing::warn!(?error, "failed to register the reschedule IPI handler"),
+    }
+}
+
+/// Wake the core with local APIC ID `apic_id`, so that it runs its scheduler
+/// again.
+///
+/// Busy cores aren't interrupted, as they always tick again before going
+/// idle. See [the module-level documentation](self#rescheduling) for details.
+pub fn wake_core(apic_id: u32) {
+    let apic_id = smp::core(apic_id).map_or(apic_id, |core| core.wake_target());
+    // `idle::wake` counts the wakeup even if the core isn't waiting on its
+    // idle flag, and does so before checking whether it's sleeping below.
+    if idle::wake(apic_id) {
+        return;
+    }
+    let Some(lapic) = LocalApic::current() else {
+        // no local APIC, so no other cores have been started.
+        return;
+    };
+    if lapic.id() == apic_id {
+        return;
+    }
+    // both this load and the core's store to its sleeping flag are
+    // sequentially consistent with the wakeup counter, so either the core sees
+    // the wakeup before sleeping, or we see that it's sleeping.
+    if smp::core(apic_id).is_some_and(|core| core.is_sleeping()) {
+        unsafe {
+            // Safety: a fixed IPI to a vector with a handler is harmless.
+            lapic.send_ipi(IpiDestination::Core(apic_id), RESCHEDULE_VECTOR as u32);
+        }
+    }
+}
+
+/// Wake every core other than the current one, so that they run their
+/// schedulers again.
+///
+/// Unlike [`wake_core`], this broadcasts a [`RESCHEDULE_VECTOR`] IPI to every
+/// other core, whether or not it is sleeping.
+pub fn wake_all_cores() {
+    interrupt::note_wakeup();
+    let Some(lapic) = LocalApic::current() else {
+        return;
+    };
+    let current = lapic.id();
+    // wake any cores idling with `MWAIT`, which may not be interrupted by an
+    // IPI until they leave the C-state.
+    for core in smp::online_cores().filter(|core| core.apic_id() != current) {
+        idle::wake(core.apic_id());
+    }
+    unsafe {
+        // Safety: a fixed IPI to a vector with a handler is harmless.
+        lapic.send_ipi(IpiDestination::AllExcludingSelf, RESCHEDULE_VECTOR as u32);
+    }
+}
+
+/// Returns `true` if some core has begun halting the others with
+/// [`halt_other_cores`].
+#[must_use]
+pub fn is_halting() -> bool {
+    HALTING.load(Ordering::Acquire)
+}
+
+/// Halt every CPU core other than the current one.
+///
+/// This broadcasts an INIT IPI to all other cores, which resets them into the
+/// "wait-for-SIPI" state, where they execute no code. Unlike an NMI, this
+/// does not depend on the other cores having a working interrupt handler.
+///
+/// The current core then waits briefly for the local APIC to report that the
+/// IPI has been accepted. Returns `false` if the local APIC is disabled, if it
+/// didn't report delivery in time, or if another core has already called this
+/// function (in which case the current core is about to be halted, too).
+///
+/// # Safety
+///
+/// This stops all other cores wherever they are, including while they hold
+/// locks. It should only be called when the system is going down anyway (such
+/// as while panicking).
+pub unsafe fn halt_other_cores() -> bool {
+    if HALTING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    let Some(lapic) = LocalApic::current() else {
+        // no local APIC, so no other cores have been started.
+        return false;
+    };
+    lapic.send_ipi(
+        IpiDestination::AllExcludingSelf,
+        DELIVERY_INIT | LEVEL_ASSERT,
+    )
+}
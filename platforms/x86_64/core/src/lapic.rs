@@ -0,0 +1,249 @@
+//! Access to the current CPU core's local APIC.
+//!
+//! A local APIC is either in xAPIC mode, where its registers are accessed
+//! through a page of MMIO, or in x2APIC mode, where they are accessed through
+//! MSRs. x2APIC mode is faster, and extends APIC IDs from 8 to 32 bits, which
+//! is required on machines with more than 255 logical processors. The mode is
+//! read from the `IA32_APIC_BASE` MSR, so [`LocalApic::current`] works in
+//! either mode, on any core.
+//!
+//! The HAL's own local APIC driver, which runs the timer and acknowledges the
+//! interrupts it handles, only supports xAPIC mode, so x2APIC mode is only
+//! used if the firmware enabled it before boot (as it must on machines with
+//! APIC IDs above 255), or [`enable_x2apic`] is called after the HAL stops
+//! using the local APIC.
+use crate::cpu;
+use hal_core::{Address, PAddr, VAddr};
+use hal_x86_64::{cpu::msr::Msr, mm};
+
+/// The `IA32_APIC_BASE` MSR.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Set in `IA32_APIC_BASE` if the local APIC is enabled.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// Set in `IA32_APIC_BASE` if the local APIC is in x2APIC mode.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The first x2APIC MSR. Each xAPIC register at MMIO offset `n` is the MSR
+/// `X2APIC_MSR_BASE + n / 16`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets from the xAPIC's MMIO base.
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+/// The first of the eight 32-bit in-service registers, 16 bytes apart.
+const REG_ISR_BASE: usize = 0x100;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+/// Set in the spurious-interrupt vector register to software-enable the
+/// local APIC.
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const SPURIOUS_VECTOR_MASK: u32 = 0xff;
+
+/// Set in the low half of the ICR by the xAPIC while an IPI is still being
+/// delivered.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// How many times to poll the xAPIC's delivery status before giving up on an
+/// IPI being accepted.
+const ICR_SPINS: usize = 100_000;
+
+/// The current CPU core's local APIC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LocalApic {
+    /// The local APIC is in xAPIC mode, with its registers mapped at `base`.
+    XApic { base: VAddr },
+    /// The local APIC is in x2APIC mode.
+    X2Apic,
+}
+
+/// The destination of an IPI sent with [`LocalApic::send_ipi`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IpiDestination {
+    /// The core with this APIC ID. In xAPIC mode, this must be 255 or less.
+    Core(u32),
+    /// Every core other than the current one.
+    AllExcludingSelf,
+}
+
+/// Returns `true` if the CPU supports x2APIC mode.
+#[must_use]
+pub fn x2apic_supported() -> bool {
+    cpu::features().has_x2apic()
+}
+
+/// Switch the current core's local APIC into x2APIC mode, if it isn't
+/// already.
+///
+/// Returns an error if the CPU doesn't support x2APIC mode, or the local APIC
+/// is disabled.
+///
+/// # Safety
+///
+/// Nothing else may access the local APIC through its MMIO registers after
+/// this is called. Once in x2APIC mode, the local APIC can't be switched back
+/// to xAPIC mode without disabling it.
+pub unsafe fn enable_x2apic() -> Result<(), &'static str> {
+    if !x2apic_supported() {
+        return Err("the CPU does not support x2APIC mode");
+    }
+    let msr = Msr::new(IA32_APIC_BASE);
+    let base = msr.read();
+    if base & APIC_BASE_ENABLE == 0 {
+        return Err("the local APIC is disabled");
+    }
+    if base & APIC_BASE_X2APIC == 0 {
+        msr.write(base | APIC_BASE_X2APIC);
+        tracing::info!("local APIC switched to x2APIC mode");
+    }
+    Ok(())
+}
+
+// === impl LocalApic ===
+
+impl LocalApic {
+    /// Returns the current core's local APIC, or `None` if it is disabled.
+    #[must_use]
+    pub fn current() -> Option<Self> {
+        // Safety: reading `IA32_APIC_BASE` has no side effects.
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+        if base & APIC_BASE_ENABLE == 0 {
+            return None;
+        }
+        if base & APIC_BASE_X2APIC != 0 {
+            return Some(Self::X2Apic);
+        }
+        Some(Self::XApic {
+            base: mm::kernel_vaddr_of(PAddr::from_u64(base & APIC_BASE_ADDR_MASK)),
+        })
+    }
+
+    /// Returns `true` if the local APIC is in x2APIC mode.
+    #[must_use]
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self, Self::X2Apic)
+    }
+
+    /// Returns the local APIC's ID.
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        let id = unsafe { self.read(REG_ID) };
+        match self {
+            // the xAPIC ID is in the top 8 bits of the register.
+            Self::XApic { .. } => id >> 24,
+            Self::X2Apic => id,
+        }
+    }
+
+    /// Signal the end of the current interrupt.
+    pub fn end_of_interrupt(&self) {
+        unsafe {
+            // Safety: writing zero to the EOI register has no effect other
+            // than signalling the end of the interrupt.
+            self.write(REG_EOI, 0);
+        }
+    }
+
+    /// Returns `true` if `vector` is in service, meaning it has been delivered
+    /// to the core and not yet acknowledged with an
+    /// [EOI](Self::end_of_interrupt).
+    ///
+    /// Exceptions, NMIs, spurious interrupts, and software `int`s are never
+    /// in service.
+    #[must_use]
+    pub fn is_in_service(&self, vector: u8) -> bool {
+        let reg = REG_ISR_BASE + (vector as usize / 32) * 0x10;
+        // Safety: reading the ISR has no side effects.
+        let isr = unsafe { self.read(reg) };
+        isr & (1 << (vector % 32)) != 0
+    }
+
+    /// Returns the vector spurious interrupts are delivered on.
+    #[must_use]
+    pub fn spurious_vector(&self) -> u8 {
+        // Safety: reading the SIVR has no side effects.
+        (unsafe { self.read(REG_SPURIOUS) } & SPURIOUS_VECTOR_MASK) as u8
+    }
+
+    /// Set the vector spurious interrupts are delivered on, and
+    /// software-enable the local APIC.
+    ///
+    /// # Safety
+    ///
+    /// A handler must be installed for `vector`, and it must not signal an
+    /// EOI.
+    pub unsafe fn set_spurious_vector(&self, vector: u8) {
+        let sivr = self.read(REG_SPURIOUS) & !SPURIOUS_VECTOR_MASK;
+        self.write(REG_SPURIOUS, sivr | SPURIOUS_APIC_ENABLE | vector as u32);
+    }
+
+    /// Send an IPI, whose vector, delivery mode, and level are given by the
+    /// low half of the interrupt command register, `icr_low`. The destination
+    /// and shorthand fields of `icr_low` are set from `dest`.
+    ///
+    /// Returns `false` if `dest` can't be addressed in xAPIC mode, or the
+    /// xAPIC didn't report that the IPI was accepted in time. In x2APIC mode,
+    /// IPIs are always accepted as soon as they are sent.
+    ///
+    /// # Safety
+    ///
+    /// Sending IPIs can do almost anything to the other cores, such as
+    /// resetting them.
+    pub unsafe fn send_ipi(&self, dest: IpiDestination, icr_low: u32) -> bool {
+        const SHORTHAND_MASK: u32 = 0b11 << 18;
+        const SHORTHAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+        let (icr_low, dest) = match dest {
+            IpiDestination::Core(id) => (icr_low & !SHORTHAND_MASK, id),
+            IpiDestination::AllExcludingSelf => {
+                (icr_low & !SHORTHAND_MASK | SHORTHAND_ALL_EXCLUDING_SELF, 0)
+            }
+        };
+
+        match self {
+            Self::X2Apic => {
+                // in x2APIC mode, the ICR is a single 64-bit MSR, with the
+                // whole 32-bit destination in the high half.
+                let icr = (dest as u64) << 32 | icr_low as u64;
+                Msr::new(Self::msr(REG_ICR_LOW)).write(icr);
+                true
+            }
+            Self::XApic { .. } => {
+                let Ok(dest) = u8::try_from(dest) else {
+                    return false;
+                };
+                // the write to the low half is what sends the IPI, so write
+                // the destination first.
+                self.write(REG_ICR_HIGH, (dest as u32) << 24);
+                self.write(REG_ICR_LOW, icr_low);
+                for _ in 0..ICR_SPINS {
+                    if self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING == 0 {
+                        return true;
+                    }
+                    core::hint::spin_loop();
+                }
+                false
+            }
+        }
+    }
+
+    unsafe fn read(&self, reg: usize) -> u32 {
+        match self {
+            Self::XApic { base } => (*base + reg).as_ptr::<u32>().read_volatile(),
+            Self::X2Apic => Msr::new(Self::msr(reg)).read() as u32,
+        }
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        match self {
+            Self::XApic { base } => (*base + reg).as_ptr::<u32>().write_volatile(value),
+            Self::X2Apic => Msr::new(Self::msr(reg)).write(value as u64),
+        }
+    }
+
+    fn msr(reg: usize) -> u32 {
+        X2APIC_MSR_BASE + (reg / 16) as u32
+    }
+}
@@ -1,54 +1,199 @@
 #![no_std]
+#![feature(abi_x86_interrupt)]
+#![feature(asm_const)]
 extern crate alloc;
 
+use alloc::vec::Vec;
 use core::time::Duration;
-use hal_core::{boot::BootInfo, PAddr, VAddr};
+use hal_core::{boot::BootInfo, mem, PAddr, VAddr};
 use hal_x86_64::cpu::local::GsLocalData;
 pub use hal_x86_64::cpu::{local::LocalKey, wait_for_interrupt};
-use kernel::{mnemos_alloc::containers::Box, Kernel, KernelSettings};
+use kernel::{
+    memory_map::{MemoryRegion, MemoryRegionKind},
+    mnemos_alloc::containers::Box,
+    modules::BootModule,
+    runloop::{RunLoopDriver, RunLoopState, Sleep, TickSummary},
+    Kernel, KernelSettings,
+};
 
 pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
+pub mod clock;
+pub mod cmdline;
+pub mod cpu;
+pub mod delay;
 pub mod drivers;
+pub mod frame;
+pub mod gdt;
+pub mod halt;
 pub mod interrupt;
+pub mod ipi;
+pub mod lapic;
+pub mod mm;
+pub mod mmio;
+pub mod pci;
+pub mod power;
+pub mod smp;
+pub mod stack;
+pub mod syscall;
+pub mod sysrq;
+#[cfg(feature = "boot-tests")]
+pub mod test_runner;
+pub mod topology;
 pub mod trace;
+pub mod watchdog;
 
 #[derive(Debug)]
 pub struct PlatformConfig {
     pub rsdp_addr: Option<PAddr>,
     pub physical_mem_offset: VAddr,
+    /// Boot modules loaded by the bootloader, if any.
+    pub modules: &'static [BootModule],
+    /// Options from the kernel command line.
+    pub cmdline: cmdline::Cmdline,
 }
 
 pub fn init(bootinfo: &impl BootInfo, cfg: PlatformConfig) -> &'static Kernel {
+    // probe for COM1 first, so that tracing output from the rest of boot is
+    // visible over serial even if the kernel never comes up.
+    let has_serial = hal_x86_64::serial::com1().is_some();
+    if !has_serial {
+        tracing::warn!("no UART on COM1, early serial tracing is disabled");
+    }
+
+    tracing::info!(cmdline = %cfg.cmdline, "kernel command line");
+    cfg.cmdline.warn_invalid();
+
     interrupt::enable_exceptions();
+    cpu::features::init();
+    cpu::simd::init();
+    interrupt::idle::init();
+    watchdog::init(cfg.cmdline.watchdog, cfg.cmdline.timer_granularity);
     bootinfo.init_paging();
+    frame::init(bootinfo);
+    mmio::init();
     allocator::init(bootinfo, cfg.physical_mem_offset);
 
     let k = {
         let settings = KernelSettings {
-            // we are a big x86 system with lots of RAM,
-            // this can probably be an even bigger number!
-            max_drivers: 64,
+            // PCI devices are probed at runtime, so by default the registry
+            // grows as drivers are registered, with no upper bound.
+            max_drivers: cfg.cmdline.max_drivers,
+            latency: Default::default(),
+            tasks: Default::default(),
         };
+        let clock = interrupt::clock(cfg.cmdline.timer_granularity);
 
         unsafe {
-            Box::into_raw(
-                Kernel::new(settings, interrupt::IDIOTIC_CLOCK).expect("cannot initialize kernel"),
-            )
-            .as_ref()
-            .unwrap()
+            Box::into_raw(Kernel::new(settings, clock).expect("cannot initialize kernel"))
+                .as_ref()
+                .unwrap()
         }
     };
     tracing::info!("allocated kernel");
 
-    init_acpi(cfg.rsdp_addr);
-    // TODO: PCI?
+    k.set_modules(cfg.modules)
+        .expect("boot modules should not have already been set");
+    for module in cfg.modules {
+        tracing::info!(
+            module.name = module.name(),
+            module.len = module.data().len(),
+            "found boot module"
+        );
+    }
+
+    k.set_memory_map(memory_map(bootinfo))
+        .expect("memory map should not have already been set");
+
+    init_acpi(cfg.rsdp_addr, &cfg.cmdline);
+    // calibrate delays once the ACPI PM timer has been found.
+    delay::init();
+    clock::init(cfg.cmdline.timer_granularity);
+    tracing::info!(now = %drivers::rtc::wall_clock_now(), "read the wall clock");
+    if interrupt::vector::smoke_test() {
+        tracing::debug!("dynamic interrupt vectors are dispatched");
+    } else {
+        tracing::warn!("dynamic interrupt vector smoke test failed!");
+    }
+    if interrupt::spurious::smoke_test() {
+        tracing::debug!("spurious interrupts are handled without an EOI");
+    } else {
+        tracing::warn!("spurious interrupt smoke test failed!");
+    }
+    init_pci(cfg.rsdp_addr);
 
     // init boot processor's core-local data
     GsLocalData::init();
     tracing::info!("set up the boot processor's local data");
+    // switch from the boot GDT to the boot processor's own GDT and TSS, now
+    // that they can be found through its local data.
+    gdt::init_core();
+    syscall::init_core();
+    ipi::init();
+
+    #[cfg(feature = "heap-stats")]
+    k.initialize(kernel::daemons::heap_stats::heap_stats(
+        k,
+        &allocator::AHEAP,
+        Default::default(),
+    ))
+    .expect("failed to spawn heap stats daemon");
+
+    k.initialize(drivers::uart::Uart16550::register(k, Default::default()))
+        .expect("failed to spawn COM1 UART driver");
+
+    // once the UART driver and serial mux are up, switch from writing events
+    // directly to COM1 to the kernel's serial tracing subscriber.
+    if has_serial {
+        let mut settings = kernel::serial_trace::SerialTraceSettings::default();
+        if let Some(level) = cfg.cmdline.trace_level {
+            settings.initial_level = level;
+        }
+        k.initialize(async move {
+            let subscriber = kernel::serial_trace::SerialSubscriber::start(k, settings).await;
+            trace::set_serial(subscriber);
+        })
+        .expect("failed to spawn serial tracing subscriber");
+    }
+
+    k.initialize(kernel::services::serial_mux::SerialMuxServer::register(
+        k,
+        Default::default(),
+    ))
+    .expect("failed to spawn SerialMuxService initialization");
+
+    // the framebuffer belongs to the tracing subscriber, so console output
+    // only goes to the serial mux for now.
+    k.initialize(async move {
+        if let Err(error) =
+            kernel::services::console::ConsoleServer::register(k, Default::default(), None).await
+        {
+            tracing::warn!(?error, "failed to register console");
+        }
+    })
+    .expect("failed to spawn ConsoleService initialization");
+
+    k.initialize(
+        kernel::services::keyboard::mux::KeyboardMuxServer::register(k, Default::default()),
+    )
+    .expect("failed to spawn KeyboardMuxService initialization");
+
+    k.initialize(drivers::ps2_keyboard::Ps2Keyboard::register(
+        k,
+        Default::default(),
+    ))
+    .expect("failed to spawn PS/2 keyboard driver");
+
+    if let Some(trigger) = cfg.cmdline.sysrq {
+        k.initialize(sysrq::run(k, trigger))
+            .expect("failed to spawn SysRq task");
+    }
+
+    #[cfg(feature = "boot-tests")]
+    k.initialize(test_runner::run(k))
+        .expect("failed to spawn boot tests");
 
-    // TODO: spawn drivers (UART, keyboard, ...)
     k.initialize(async {
         loop {
             k.timer().sleep(Duration::from_secs(5)).await;
@@ -65,46 +210,184 @@ pub fn run(bootinfo: &impl BootInfo, kernel: &'static Kernel) -> ! {
     tracing::info!("started kernel run loop\n--------------------\n");
     kernel.set_global_timer().unwrap();
 
-    // TODO(eliza): this currently uses a periodic timer, rather than a
-    // freewheeling timer like other MnemOS kernels. The periodic timer is
-    // somewhat less efficient, as it results in us being woken every 10ms
-    // regardless of what timeouts are pending. If we used a freewheeling timer
-    // instead, we could sleep until a task is actually ready.
+    // TODO(eliza): unlike the D1 and ESP32-C3 kernels, which arm a one-shot
+    // timer for the next deadline, this still uses a periodic timer. We do
+    // track `next_deadline`, but only to pick a C-state: the core is woken
+    // every `clock::timer_interval()` regardless of what timeouts are pending
+    // (see `interrupt::idle::expected_idle`).
     //
-    // However, this would require some upstream changes to the mycelium HAL to
-    // better support freewheeling timers. For now, the simpler periodic timer
-    // runloop works fine, I guess...
+    // Going tickless here means programming a one-shot local APIC (or PIT)
+    // deadline from `next_deadline`, which needs upstream changes to the
+    // mycelium HAL, and moving the `TickClock` fallback and the watchdog off
+    // counting timer ticks.
+    let mut state = RunLoopState::new();
+    let core = smp::current();
+    let mut driver = RunLoop {
+        kernel,
+        core,
+        next_deadline: None,
+    };
     loop {
-        // drive the task scheduler
-        let tick = kernel.tick();
-
-        // turn the timer wheel if it wasn't turned recently and no one else is
-        // holding a lock, ensuring any pending timer ticks are consumed.
-        let turn = kernel.timer().turn();
-
-        // if there are no woken tasks, wait for an interrupt. otherwise,
-        // continue ticking.
-        let has_remaining = tick.has_remaining || turn.has_remaining();
-        if !has_remaining {
-            interrupt::wait_for_interrupt();
+        watchdog::feed(core.apic_id());
+        // drive the task scheduler and turn the timer wheel.
+        let tick = state.tick_phase(&mut driver);
+        core.record_tick(&tick);
+        #[cfg(feature = "irq-latency")]
+        if tick.polled > 0 {
+            interrupt::latency::scheduler_ran();
         }
 
-        // turn the timer a second time to account for time spent in WFI.
-        kernel.timer().turn();
+        // if there are no woken tasks or pending timers, wait for an
+        // interrupt. otherwise, continue ticking.
+        let slept = match state.decide_sleep() {
+            Sleep::UntilInterrupt => {
+                #[cfg(feature = "irq-latency")]
+                interrupt::latency::discard_pending();
+                // re-check for wakeups with interrupts disabled, so that an
+                // interrupt arriving since the tick isn't missed.
+                let expected_idle = interrupt::idle::expected_idle(driver.next_deadline);
+                core.sleep_if(|| {
+                    interrupt::wait_for_interrupt_if(expected_idle, || {
+                        state.confirm_sleep(&driver) == Sleep::UntilInterrupt
+                    })
+                })
+            }
+            Sleep::No => false,
+        };
+        state.account_sleep(&mut driver, slept);
     }
 }
 
-fn init_acpi(rsdp_addr: Option<PAddr>) {
+/// Drives the kernel and the boot processor's run queue, counting interrupts
+/// as wakeups.
+struct RunLoop {
+    kernel: &'static Kernel,
+    core: &'static smp::Core,
+    /// The time until the timer wheel's next deadline, as of the last time
+    /// it was turned, used to choose how deeply to idle.
+    next_deadline: Option<Duration>,
+}
+
+impl RunLoopDriver for RunLoop {
+    fn tick(&mut self) -> TickSummary {
+        let kernel = RunLoopDriver::tick(&mut self.kernel);
+        kernel.merge(self.core.tick())
+    }
+
+    fn turn_timer(&mut self) -> bool {
+        let turn = self.kernel.timer().turn();
+        self.next_deadline = turn.time_to_next_deadline();
+        turn.has_remaining()
+    }
+
+    fn wakeups(&self) -> u64 {
+        interrupt::wakeups()
+    }
+}
+
+fn init_pci(rsdp_addr: Option<PAddr>) {
+    tracing::info!("init pci");
+    let ecam = rsdp_addr
+        .map(|rsdp| acpi::acpi_tables(rsdp).and_then(|tables| acpi::pci_ecam_regions(&tables)));
+    let config = match ecam {
+        Some(Ok(regions)) if !regions.is_empty() => {
+            tracing::info!(
+                regions = regions.len(),
+                "using PCIe ECAM configuration space"
+            );
+            pci::ConfigSpace::Ecam(regions.leak())
+        }
+        ecam => {
+            let error = ecam.and_then(Result::err);
+            tracing::info!(
+                ?error,
+                "no PCIe ECAM regions, using legacy PCI configuration space"
+            );
+            pci::ConfigSpace::Legacy
+        }
+    };
+
+    let pci = pci::init(config);
+    for dev in pci.devices() {
+        tracing::info!(
+            pci.address = %dev.address,
+            pci.vendor_id = dev.vendor_id,
+            pci.device_id = dev.device_id,
+            pci.class = dev.class.class,
+            pci.subclass = dev.class.subclass,
+            "found PCI device"
+        );
+        tracing::debug!(pci.address = %dev.address, ?dev.bars);
+    }
+}
+
+/// Copy the bootloader's memory map, for the kernel to report to userspace.
+fn memory_map(bootinfo: &impl BootInfo) -> &'static [MemoryRegion] {
+    bootinfo
+        .memory_map()
+        .map(|region| MemoryRegion {
+            kind: if region.kind() == mem::RegionKind::FREE {
+                MemoryRegionKind::Usable
+            } else if region.kind() == mem::RegionKind::BOOT {
+                MemoryRegionKind::Bootloader
+            } else {
+                MemoryRegionKind::Reserved
+            },
+            base: region.base_addr().as_usize() as u64,
+            len: region.size() as u64,
+        })
+        .collect::<Vec<_>>()
+        .leak()
+}
+
+fn init_acpi(rsdp_addr: Option<PAddr>, cmdline: &cmdline::Cmdline) {
+    use cmdline::TimeSource;
+
     tracing::info!("init acpi");
+    let granularity = cmdline.timer_granularity;
     if let Some(rsdp) = rsdp_addr {
         let acpi = acpi::acpi_tables(rsdp);
+        if let Ok(ref tables) = acpi {
+            power::init(tables);
+            if let Err(error) = acpi::pm_timer::init(tables) {
+                tracing::info!(%error, "no ACPI PM timer");
+            }
+            match acpi::rtc_century_register(tables) {
+                Ok(century) => drivers::rtc::init(century),
+                Err(error) => tracing::warn!(%error, "can't find the RTC century register"),
+            }
+        }
         let platform_info = acpi.and_then(|acpi| acpi.platform_info());
         match platform_info {
             Ok(platform) => {
                 tracing::debug!("found ACPI platform info");
-                interrupt::enable_hardware_interrupts(Some(&platform.interrupt_model));
-                acpi::bringup_smp(&platform)
-                    .expect("failed to bring up application processors! this is bad news!");
+                match platform.processor_info {
+                    Some(ref processors) => topology::init(processors),
+                    None => topology::init_uniprocessor(),
+                }
+                // the HAL picks the timer from the interrupt model: the local
+                // APIC timer with an APIC, and the PIT with the legacy PIC.
+                let model = match cmdline.time_source {
+                    TimeSource::Pit => {
+                        tracing::info!("using the PIT, so ignoring the APIC");
+                        None
+                    }
+                    source => {
+                        let is_apic =
+                            matches!(platform.interrupt_model, acpi::InterruptModel::Apic(_));
+                        if source == TimeSource::LocalApic && !is_apic {
+                            tracing::warn!("no local APIC timer, using the PIT instead");
+                        }
+                        Some(&platform.interrupt_model)
+                    }
+                };
+                interrupt::enable_hardware_interrupts(model, granularity);
+                if cmdline.smp {
+                    acpi::bringup_smp(&platform, cmdline.ap_stack_size)
+                        .expect("failed to bring up application processors! this is bad news!");
+                } else {
+                    tracing::info!("SMP disabled on the kernel command line");
+                }
                 return;
             }
             Err(error) => tracing::warn!(?error, "missing ACPI platform info"),
@@ -115,5 +398,9 @@ fn init_acpi(rsdp_addr: Option<PAddr>) {
     }
 
     // no ACPI
-    interrupt::enable_hardware_interrupts(None)
+    topology::init_uniprocessor();
+    if cmdline.time_source == TimeSource::LocalApic {
+        tracing::warn!("no ACPI tables to find the local APIC, using the PIT instead");
+    }
+    interrupt::enable_hardware_interrupts(None, granularity)
 }
@@ -0,0 +1,90 @@
+//! Translating between physical and virtual addresses.
+//!
+//! The bootloader maps all of physical memory into the higher half, at the
+//! offset returned by [`mm::kernel_vaddr_of`]. [`phys_to_virt`] finds a
+//! physical address in that mapping, and [`virt_to_phys`] goes the other way
+//! by walking the current page tables, so it also works for the kernel image,
+//! the heap, and regions mapped with [`map_mmio`](crate::mmio::map_mmio).
+//!
+//! Drivers programming DMA-capable devices use [`virt_to_phys`] to find the
+//! physical address of a buffer. Note that a buffer spanning several pages
+//! is only physically contiguous if it was allocated that way, such as with
+//! [`frame::alloc_contiguous`](crate::frame::alloc_contiguous).
+use hal_core::{Address, PAddr};
+use hal_x86_64::mm;
+
+/// The size of a page, in bytes.
+pub(crate) const PAGE_SIZE: usize = 4096;
+/// The number of entries in a page table.
+pub(crate) const ENTRIES: usize = 512;
+
+/// Page table entry flags.
+pub(crate) const PRESENT: u64 = 1 << 0;
+pub(crate) const WRITABLE: u64 = 1 << 1;
+/// Set in a PDPT or PD entry which maps a 1 GiB or 2 MiB page, rather than
+/// pointing at the next level of page table.
+const HUGE_PAGE: u64 = 1 << 7;
+/// The physical address bits of a page table entry.
+pub(crate) const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Returns a pointer to the physical address `paddr` in the kernel's physical
+/// memory mapping, or `None` if the bootloader didn't map it there.
+#[must_use]
+pub fn phys_to_virt(paddr: PAddr) -> Option<*mut u8> {
+    let vaddr = mm::kernel_vaddr_of(paddr);
+    // make sure the bootloader actually mapped this far, and that the offset
+    // didn't wrap around.
+    let ptr = vaddr.as_ptr::<u8>();
+    (virt_to_phys(ptr) == Some(paddr)).then_some(ptr)
+}
+
+/// Returns the physical address that `ptr` is mapped to, or `None` if it is
+/// not mapped.
+#[must_use]
+pub fn virt_to_phys(ptr: *const u8) -> Option<PAddr> {
+    let vaddr = ptr as usize;
+    // non-canonical addresses are never mapped.
+    let upper = (vaddr as isize) >> 47;
+    if upper != 0 && upper != -1 {
+        return None;
+    }
+
+    let index = |level: u32| (vaddr >> (12 + 9 * level)) % ENTRIES;
+    let mut table = current_pml4();
+    for level in (0..4).rev() {
+        let entry = self::table(table)[index(level)];
+        if entry & PRESENT == 0 {
+            return None;
+        }
+        let addr = entry & ADDR_MASK;
+        // the last level always maps a page, and huge pages end the walk
+        // early in the PDPT (level 2) or PD (level 1).
+        if level == 0 || (level < 3 && entry & HUGE_PAGE != 0) {
+            let page_size = 1u64 << (12 + 9 * level);
+            // in a huge page entry, the PAT bit is bit 12, so mask off the
+            // low bits of the address too.
+            let base = addr & !(page_size - 1);
+            return Some(PAddr::from_u64(base + (vaddr as u64 & (page_size - 1))));
+        }
+        table = PAddr::from_u64(addr);
+    }
+    None
+}
+
+/// Returns the physical address of the current PML4.
+pub(crate) fn current_pml4() -> PAddr {
+    let cr3: u64;
+    unsafe {
+        // Safety: reading CR3 has no side effects.
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    PAddr::from_u64(cr3 & ADDR_MASK)
+}
+
+/// Returns the page table at `paddr`, through the physical memory mapping.
+///
+/// Callers must make sure nothing else modifies the parts of the table they
+/// modify.
+pub(crate) fn table(paddr: PAddr) -> &'static mut [u64; ENTRIES] {
+    unsafe { &mut *mm::kernel_vaddr_of(paddr).as_ptr::<[u64; ENTRIES]>() }
+}
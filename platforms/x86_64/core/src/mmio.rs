@@ -0,0 +1,303 @@
+//! Mapping device MMIO regions.
+//!
+//! Most of the kernel reaches physical memory through the bootloader's
+//! higher-half physical memory mapping ([`mm::phys_to_virt`]), which maps
+//! everything write-back cacheable. That's right for RAM, but device
+//! registers must not be cached, and framebuffers are much faster when
+//! writes are combined. [`map_mmio`] maps a physical range at a fresh virtual
+//! address with the requested [`Caching`], and [`unmap_mmio`] removes the
+//! mapping again.
+//!
+//! # The MMIO window
+//!
+//! Mappings are made in a window of virtual address space covered by a
+//! single top-level (PML4) page table entry, which [`init`] claims from the
+//! higher half. It picks an entry that the bootloader left empty, so the
+//! window never overlaps the kernel image or the bootloader's physical
+//! memory mapping. Since the page tables below that entry are shared by every
+//! core using the kernel's address space, mappings are visible on all cores.
+//!
+//! Virtual addresses in the window are handed out in order, and are not
+//! reused once unmapped. The window is 512 GiB, so this is not expected to
+//! run out.
+//!
+//! The window is also used for [kernel stacks](crate::stack), which are
+//! mapped with [`map_guarded`] so that an unmapped guard page sits below
+//! each one.
+//!
+//! # Cache types
+//!
+//! Page table entries select a memory type from the `IA32_PAT` MSR. The
+//! power-on PAT has no write-combining entry, so [`init_core`] sets entry 5
+//! to write-combining, which matches how Limine programs the PAT. The PAT is
+//! per-core, so every core must call it.
+use crate::{
+    frame,
+    mm::{self, ADDR_MASK, ENTRIES, PAGE_SIZE, PRESENT, WRITABLE},
+};
+use core::fmt;
+use hal_core::{Address, PAddr, VAddr};
+use hal_x86_64::cpu::msr::Msr;
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+use mycelium_util::sync::InitOnce;
+
+/// How a mapped MMIO region may be cached.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Caching {
+    /// Uncacheable (UC), for device registers. Every access goes to the
+    /// device, in program order.
+    Uncacheable,
+    /// Write-combining (WC), for framebuffers. Writes may be buffered and
+    /// combined, and reads are uncached.
+    WriteCombining,
+    /// Write-back (WB), for memory which behaves like normal RAM.
+    WriteBack,
+}
+
+/// Errors returned by [`map_mmio`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// [`init`] has not been called.
+    NotInitialized,
+    /// The requested length was zero.
+    ZeroLength,
+    /// The MMIO window has no virtual address space left.
+    WindowFull,
+    /// No physical frames were left for page tables.
+    NoFrames,
+}
+
+/// The size of the MMIO window, which is what one PML4 entry maps.
+const WINDOW_SIZE: usize = 1 << 39;
+
+/// Page-level write-through, bit 0 of the PAT index.
+const PWT: u64 = 1 << 3;
+/// Page-level cache disable, bit 1 of the PAT index.
+const PCD: u64 = 1 << 4;
+/// In a 4 KiB page table entry, bit 2 of the PAT index.
+const PAT: u64 = 1 << 7;
+const GLOBAL: u64 = 1 << 8;
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// The `IA32_PAT` MSR.
+const IA32_PAT: u32 = 0x277;
+/// The `IA32_EFER` MSR.
+const IA32_EFER: u32 = 0xc000_0080;
+/// `EFER.NXE`, set if the no-execute bit may be used.
+const EFER_NXE: u64 = 1 << 11;
+/// The PAT entry [`init_core`] sets to write-combining.
+const PAT_WC_ENTRY: u32 = 5;
+/// The write-combining PAT memory type.
+const PAT_TYPE_WC: u64 = 0x01;
+
+static WINDOW: InitOnce<Mutex<Window, Spinlock>> = InitOnce::uninitialized();
+
+struct Window {
+    /// The first virtual address in the window.
+    base: usize,
+    /// The offset into the window of the next address to hand out.
+    next: usize,
+    /// The physical address of the window's page directory pointer table.
+    pdpt: PAddr,
+    /// Flags set on every page mapped in the window.
+    flags: u64,
+}
+
+/// Claim the MMIO window, and set up the boot processor's PAT.
+///
+/// This must be called once, on the boot processor, after the frame allocator
+/// is initialized.
+#[tracing::instrument(level = tracing::Level::DEBUG)]
+pub fn init() {
+    init_core();
+
+    let pml4 = mm::table(mm::current_pml4());
+    // use the highest free entry in the higher half, skipping the last one,
+    // which is usually the kernel's.
+    let Some(index) = (ENTRIES / 2..ENTRIES - 1).rev().find(|&i| pml4[i] == 0) else {
+        tracing::warn!("no free PML4 entries for the MMIO window, MMIO mapping is disabled");
+        return;
+    };
+    let Some(pdpt) = alloc_table() else {
+        tracing::warn!("no frames for the MMIO window's page tables, MMIO mapping is disabled");
+        return;
+    };
+    pml4[index] = pdpt.as_usize() as u64 | PRESENT | WRITABLE;
+
+    // sign-extend the window's address into the higher half.
+    let base = (usize::MAX << 48) | (index << 39);
+    // Safety: reading `IA32_EFER` has no side effects.
+    let nx = unsafe { Msr::new(IA32_EFER).read() } & EFER_NXE != 0;
+    let flags = PRESENT | WRITABLE | GLOBAL | if nx { NO_EXECUTE } else { 0 };
+    WINDOW.init(Mutex::new_with_raw_mutex(
+        Window {
+            base,
+            next: 0,
+            pdpt,
+            flags,
+        },
+        Spinlock::new(),
+    ));
+    tracing::info!(base = ?VAddr::from_usize(base), index, "claimed MMIO window");
+}
+
+/// Set the current core's PAT entry for write-combining.
+///
+/// Every core must call this before using mappings made with
+/// [`Caching::WriteCombining`].
+pub fn init_core() {
+    let shift = PAT_WC_ENTRY * 8;
+    unsafe {
+        // Safety: only the write-combining entry is changed, and nothing maps
+        // pages with it until `map_mmio` is called.
+        let pat = Msr::new(IA32_PAT);
+        let value = pat.read();
+        pat.write((value & !(0xff << shift)) | (PAT_TYPE_WC << shift));
+    }
+}
+
+/// Map `len` bytes of device memory starting at `paddr`, with the given
+/// `caching`, returning a pointer to `paddr`.
+///
+/// `paddr` and `len` need not be page-aligned: every page touching the range
+/// is mapped, and the returned pointer has the same offset into its page as
+/// `paddr`.
+pub fn map_mmio(paddr: PAddr, len: usize, caching: Caching) -> Result<*mut u8, MapError> {
+    map(paddr, len, caching, 0)
+}
+
+/// Map `len` bytes of RAM starting at the page-aligned `paddr`, write-back
+/// cacheable, leaving `guard_pages` unmapped pages below it.
+///
+/// Any access to the guard pages page faults, so this is used for stacks,
+/// which grow down.
+pub(crate) fn map_guarded(
+    paddr: PAddr,
+    len: usize,
+    guard_pages: usize,
+) -> Result<*mut u8, MapError> {
+    debug_assert_eq!(paddr.as_usize() % PAGE_SIZE, 0);
+    map(paddr, len, Caching::WriteBack, guard_pages)
+}
+
+fn map(
+    paddr: PAddr,
+    len: usize,
+    caching: Caching,
+    guard_pages: usize,
+) -> Result<*mut u8, MapError> {
+    if len == 0 {
+        return Err(MapError::ZeroLength);
+    }
+    let mut window = WINDOW.try_get().ok_or(MapError::NotInitialized)?.lock();
+    let offset = paddr.as_usize() % PAGE_SIZE;
+    let first = paddr.as_usize() - offset;
+    let pages = (offset + len).div_ceil(PAGE_SIZE);
+    let guard = guard_pages * PAGE_SIZE;
+    let size = pages * PAGE_SIZE;
+    if window.next + guard + size > WINDOW_SIZE {
+        return Err(MapError::WindowFull);
+    }
+
+    // the guard pages are never mapped, so just skip over them.
+    window.next += guard;
+    let vaddr = window.base + window.next;
+    let flags = window.flags | caching.flags();
+    for page in 0..pages {
+        let entry = window.entry(vaddr + page * PAGE_SIZE)?;
+        *entry = ((first + page * PAGE_SIZE) as u64 & ADDR_MASK) | flags;
+    }
+    window.next += size;
+
+    tracing::debug!(
+        ?paddr,
+        len,
+        ?caching,
+        vaddr = ?VAddr::from_usize(vaddr),
+        "mapped MMIO region"
+    );
+    Ok((vaddr + offset) as *mut u8)
+}
+
+/// Unmap a region mapped by [`map_mmio`].
+///
+/// # Safety
+///
+/// `ptr` and `len` must be a pointer returned by [`map_mmio`] and the length
+/// passed to it, and nothing may access the region once it is unmapped.
+///
+/// Only the current core's TLB is flushed, so no other core may have
+/// accessed the region either.
+pub unsafe fn unmap_mmio(ptr: *mut u8, len: usize) {
+    let Some(window) = WINDOW.try_get() else {
+        return;
+    };
+    let mut window = window.lock();
+    let offset = ptr as usize % PAGE_SIZE;
+    let first = ptr as usize - offset;
+    let pages = (offset + len).div_ceil(PAGE_SIZE);
+    for page in 0..pages {
+        let vaddr = first + page * PAGE_SIZE;
+        if let Ok(entry) = window.entry(vaddr) {
+            *entry = 0;
+        }
+        core::arch::asm!("invlpg [{}]", in(reg) vaddr, options(nostack, preserves_flags));
+    }
+    tracing::debug!(vaddr = ?VAddr::from_usize(ptr as usize), len, "unmapped MMIO region");
+}
+
+// === impl Caching ===
+
+impl Caching {
+    /// Returns the page table entry bits selecting this memory type.
+    fn flags(self) -> u64 {
+        match self {
+            // PAT entry 3, which is uncacheable on power-on.
+            Self::Uncacheable => PCD | PWT,
+            // PAT entry 5, set by `init_core`.
+            Self::WriteCombining => PAT | PWT,
+            // PAT entry 0, which is write-back on power-on.
+            Self::WriteBack => 0,
+        }
+    }
+}
+
+// === impl MapError ===
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInitialized => f.write_str("the MMIO window has not been initialized"),
+            Self::ZeroLength => f.write_str("cannot map a zero-length MMIO region"),
+            Self::WindowFull => f.write_str("the MMIO window is full"),
+            Self::NoFrames => f.write_str("no physical frames left for page tables"),
+        }
+    }
+}
+
+// === impl Window ===
+
+impl Window {
+    /// Returns the page table entry for `vaddr`, allocating page tables as
+    /// needed.
+    fn entry(&mut self, vaddr: usize) -> Result<&'static mut u64, MapError> {
+        let index = |level: u32| (vaddr >> (12 + 9 * level)) % ENTRIES;
+        let mut table = self.pdpt;
+        for level in [2, 1] {
+            let entry = &mut mm::table(table)[index(level)];
+            if *entry & PRESENT == 0 {
+                let next = alloc_table().ok_or(MapError::NoFrames)?;
+                *entry = next.as_usize() as u64 | PRESENT | WRITABLE;
+            }
+            table = PAddr::from_u64(*entry & ADDR_MASK);
+        }
+        Ok(&mut mm::table(table)[index(0)])
+    }
+}
+
+/// Allocate a zeroed page table.
+fn alloc_table() -> Option<PAddr> {
+    let paddr = frame::alloc_frame()?;
+    mm::table(paddr).fill(0);
+    Some(paddr)
+}
@@ -0,0 +1,657 @@
+//! PCI configuration space access and bus enumeration.
+//!
+//! Configuration space is reached through a [`ConfigSpace`], which either uses
+//! the legacy `0xCF8`/`0xCFC` I/O port pair, or memory-mapped PCIe ECAM
+//! regions. The legacy mechanism can only reach the first 256 bytes of each
+//! function's configuration space, and only PCI segment 0.
+//!
+//! [`ConfigSpace::devices`] enumerates every function reachable from the host
+//! bridges, recursing into the secondary bus of each PCI-to-PCI bridge it
+//! finds, and [`ConfigSpace::find_class`] finds devices of a given class, so
+//! that drivers can locate their hardware.
+//!
+//! Devices' interrupts are set up by the [`msi`] module, using
+//! message-signaled interrupts where the device supports them.
+//!
+//! The configuration space found during boot is installed by [`init`], and
+//! returned by [`config_space`]. ECAM is used when the ACPI MCFG table
+//! describes it (see [`crate::acpi::pci_ecam_regions`]), and the legacy ports
+//! otherwise.
+use core::fmt;
+use hal_core::{Address as _, PAddr, VAddr};
+use hal_x86_64::{cpu::Port, mm};
+use kernel::maitake::sync::{blocking::Mutex, spin::Spinlock};
+use mycelium_util::sync::InitOnce;
+
+pub mod msi;
+
+static CONFIG_SPACE: InitOnce<ConfigSpace> = InitOnce::uninitialized();
+
+/// Serializes access to the legacy address and data ports, since selecting a
+/// register and reading it are separate operations.
+static LEGACY_PORTS: Mutex<(), Spinlock> = Mutex::new_with_raw_mutex((), Spinlock::new());
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// The size of the configuration space reachable through the legacy ports.
+const LEGACY_CONFIG_LEN: u16 = 256;
+/// The size of each function's configuration space through ECAM.
+const ECAM_CONFIG_LEN: u16 = 4096;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+// Configuration space register offsets common to all header types.
+const REG_VENDOR_DEVICE: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_CLASS_REVISION: u16 = 0x08;
+/// Cache line size, latency timer, header type, and BIST.
+const REG_HEADER: u16 = 0x0c;
+const REG_BAR0: u16 = 0x10;
+/// Primary, secondary, and subordinate bus numbers of a PCI-to-PCI bridge.
+const REG_BRIDGE_BUSES: u16 = 0x18;
+/// The offset of the first capability, in general and bridge headers.
+const REG_CAPABILITIES: u16 = 0x34;
+/// Interrupt line, interrupt pin, and (for general headers) min grant and max
+/// latency.
+const REG_INTERRUPT: u16 = 0x3c;
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+/// Set in the status register, the upper half of [`REG_COMMAND`], if the
+/// function has a capability list.
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+
+/// The most capabilities to follow in one function's list, in case a broken
+/// device's list loops.
+const MAX_CAPABILITIES: usize = 48;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// The vendor ID read from a function that doesn't exist.
+const NO_VENDOR: u16 = 0xffff;
+
+/// How PCI configuration space is accessed.
+#[derive(Debug)]
+pub enum ConfigSpace {
+    /// Use the `0xCF8`/`0xCFC` I/O ports.
+    Legacy,
+    /// Use memory-mapped PCIe ECAM regions.
+    Ecam(&'static [EcamRegion]),
+}
+
+/// A memory-mapped PCIe configuration space region, covering a range of buses
+/// in a PCI segment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EcamRegion {
+    /// The physical address of the configuration space of the first bus in
+    /// the region.
+    pub base: PAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// The location of a PCI function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// A PCI function found by [`ConfigSpace::devices`].
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: Class,
+    pub revision: u8,
+    /// The configuration header type, without the multi-function bit.
+    pub header_type: u8,
+    /// The function's base address registers. A 64-bit memory BAR occupies
+    /// two registers, so the entry after it is always `None`. Bridges only
+    /// have two BARs.
+    pub bars: [Option<Bar>; 6],
+}
+
+/// A PCI class code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Class {
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+/// A region decoded by a PCI function, described by a base address register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        addr: PAddr,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the BAR can be placed above 4GiB.
+        is_64_bit: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+/// An iterator over the PCI functions in a [`ConfigSpace`], returned by
+/// [`ConfigSpace::devices`].
+#[derive(Debug)]
+pub struct Devices<'config> {
+    config: &'config ConfigSpace,
+    /// The index of the ECAM region currently being scanned.
+    region: usize,
+    segment: u16,
+    /// Buses in the current segment which are waiting to be scanned.
+    pending: BusSet,
+    /// Buses in the current segment which have already been queued, so that a
+    /// misconfigured bridge can't make us scan a bus twice.
+    seen: BusSet,
+    /// The bus, device, and function to check next, or `None` if the next
+    /// pending bus should be started.
+    next: Option<(u8, u8, u8)>,
+    /// Whether the current device has multiple functions.
+    multi_function: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct BusSet([u64; 4]);
+
+/// Install the configuration space used by [`config_space`].
+///
+/// # Panics
+///
+/// If the configuration space has already been set.
+pub fn init(config: ConfigSpace) -> &'static ConfigSpace {
+    CONFIG_SPACE.init(config)
+}
+
+/// Returns the configuration space installed by [`init`], or `None` if it
+/// hasn't been set yet.
+#[must_use]
+pub fn config_space() -> Option<&'static ConfigSpace> {
+    CONFIG_SPACE.try_get()
+}
+
+/// Returns the virtual address of a memory-mapped configuration register,
+/// using the configuration space installed by [`init`].
+///
+/// Returns `None` if the configuration space hasn't been installed, or isn't
+/// memory-mapped; registers must then be accessed with
+/// [`ConfigSpace::read`] and [`ConfigSpace::write`], which fall back to the
+/// legacy I/O ports.
+#[must_use]
+pub fn config_address(
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+    offset: u16,
+) -> Option<VAddr> {
+    let addr = Address {
+        segment,
+        bus,
+        device,
+        function,
+    };
+    config_space()?.config_address(addr, offset)
+}
+
+// === impl ConfigSpace ===
+
+impl ConfigSpace {
+    /// Read the 32-bit configuration register at `offset` (rounded down to a
+    /// multiple of four) of the function at `addr`.
+    ///
+    /// Returns `None` if the register can't be reached with this access
+    /// mechanism. Reads from functions which don't exist return all ones.
+    #[must_use]
+    pub fn read(&self, addr: Address, offset: u16) -> Option<u32> {
+        match self {
+            Self::Legacy => {
+                let address = legacy_address(addr, offset)?;
+                let _lock = LEGACY_PORTS.lock();
+                unsafe {
+                    Port::at(CONFIG_ADDRESS).writel(address);
+                    Some(Port::at(CONFIG_DATA).readl())
+                }
+            }
+            Self::Ecam(regions) => {
+                let ptr = ecam_ptr(regions, addr, offset)?;
+                Some(unsafe { ptr.read_volatile() })
+            }
+        }
+    }
+
+    /// Write the 32-bit configuration register at `offset` (rounded down to a
+    /// multiple of four) of the function at `addr`.
+    ///
+    /// Returns `None` if the register can't be reached with this access
+    /// mechanism.
+    ///
+    /// # Safety
+    ///
+    /// Configuration registers control how devices decode addresses and
+    /// access memory. The caller is responsible for ensuring the write won't
+    /// let a device interfere with memory or I/O ports in use by anything
+    /// else.
+    pub unsafe fn write(&self, addr: Address, offset: u16, value: u32) -> Option<()> {
+        match self {
+            Self::Legacy => {
+                let address = legacy_address(addr, offset)?;
+                let _lock = LEGACY_PORTS.lock();
+                Port::at(CONFIG_ADDRESS).writel(address);
+                Port::at(CONFIG_DATA).writel(value);
+            }
+            Self::Ecam(regions) => {
+                let ptr = ecam_ptr(regions, addr, offset)?;
+                ptr.write_volatile(value);
+            }
+        }
+        Some(())
+    }
+
+    /// Returns the virtual address of the configuration register at `offset`
+    /// of the function at `addr`, if it is memory-mapped.
+    ///
+    /// Returns `None` with the legacy access mechanism, or if no ECAM region
+    /// covers the function.
+    #[must_use]
+    pub fn config_address(&self, addr: Address, offset: u16) -> Option<VAddr> {
+        match self {
+            Self::Legacy => None,
+            Self::Ecam(regions) => ecam_address(regions, addr, offset),
+        }
+    }
+
+    /// Returns the size of each function's configuration space with this
+    /// access mechanism.
+    #[must_use]
+    pub fn config_len(&self) -> u16 {
+        match self {
+            Self::Legacy => LEGACY_CONFIG_LEN,
+            Self::Ecam(_) => ECAM_CONFIG_LEN,
+        }
+    }
+
+    /// Returns an iterator over every PCI function reachable from the host
+    /// bridges.
+    #[must_use]
+    pub fn devices(&self) -> Devices<'_> {
+        let mut devices = Devices {
+            config: self,
+            region: 0,
+            segment: 0,
+            pending: BusSet::default(),
+            seen: BusSet::default(),
+            next: None,
+            multi_function: false,
+        };
+        match self {
+            Self::Legacy => devices.start_segment(0, 0),
+            Self::Ecam(regions) => {
+                if let Some(region) = regions.first() {
+                    devices.start_segment(region.segment, region.start_bus);
+                }
+            }
+        }
+        devices
+    }
+
+    /// Returns an iterator over the PCI functions with the given class and
+    /// subclass.
+    pub fn find_class(&self, class: u8, subclass: u8) -> impl Iterator<Item = Device> + '_ {
+        self.devices()
+            .filter(move |dev| dev.class.class == class && dev.class.subclass == subclass)
+    }
+
+    /// Returns the ID and offset of each capability in the function's
+    /// capability list.
+    pub fn capabilities(&self, addr: Address) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let has_list = self.read_or_absent(addr, REG_COMMAND) & STATUS_CAPABILITIES != 0;
+        let first = if has_list {
+            self.read_or_absent(addr, REG_CAPABILITIES) as u8 & !0x3
+        } else {
+            0
+        };
+        let mut next = first as u16;
+        core::iter::from_fn(move || {
+            // the first 64 bytes are the header, so a pointer into them ends
+            // the list.
+            if next < 0x40 {
+                return None;
+            }
+            let offset = next;
+            let [id, ptr, _, _] = self.read_or_absent(addr, offset).to_le_bytes();
+            next = (ptr & !0x3) as u16;
+            Some((id, offset))
+        })
+        .take(MAX_CAPABILITIES)
+    }
+
+    /// Returns the offset of the first capability with ID `id` in the
+    /// function's capability list.
+    #[must_use]
+    pub fn find_capability(&self, addr: Address, id: u8) -> Option<u16> {
+        self.capabilities(addr)
+            .find_map(|(cap, offset)| (cap == id).then_some(offset))
+    }
+
+    fn read_or_absent(&self, addr: Address, offset: u16) -> u32 {
+        self.read(addr, offset).unwrap_or(u32::MAX)
+    }
+
+    /// Read the function at `addr`, or return `None` if there isn't one.
+    fn device(&self, addr: Address) -> Option<Device> {
+        let id = self.read_or_absent(addr, REG_VENDOR_DEVICE);
+        let vendor_id = id as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+
+        let [revision, prog_if, subclass, class] =
+            self.read_or_absent(addr, REG_CLASS_REVISION).to_le_bytes();
+        let header_type = (self.read_or_absent(addr, REG_HEADER) >> 16) as u8 & HEADER_TYPE_MASK;
+        let bar_count = match header_type {
+            HEADER_TYPE_GENERAL => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+
+        Some(Device {
+            address: addr,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: Class {
+                class,
+                subclass,
+                prog_if,
+            },
+            revision,
+            header_type,
+            bars: self.bars(addr, bar_count),
+        })
+    }
+
+    fn bars(&self, addr: Address, count: u16) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+
+        // stop the function decoding its BARs while they're being sized, so
+        // that it doesn't briefly claim addresses belonging to something else.
+        let command = self.read_or_absent(addr, REG_COMMAND);
+        let decoding = command & (COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE);
+        unsafe {
+            self.write(addr, REG_COMMAND, command & !decoding);
+        }
+
+        let mut idx = 0;
+        while idx < count {
+            let offset = REG_BAR0 + idx * 4;
+            let (bar, used) = self.size_bar(addr, offset, idx + 1 < count);
+            bars[idx as usize] = bar;
+            idx += used;
+        }
+
+        // writing the command register also writes the status register, in
+        // which set bits are cleared, so only write back the command bits.
+        unsafe {
+            self.write(addr, REG_COMMAND, command & 0xffff);
+        }
+        bars
+    }
+
+    /// Size the BAR at `offset`, returning it and the number of registers it
+    /// occupies.
+    fn size_bar(&self, addr: Address, offset: u16, has_upper: bool) -> (Option<Bar>, u16) {
+        let size_of = |offset: u16| -> u32 {
+            let orig = self.read_or_absent(addr, offset);
+            unsafe {
+                self.write(addr, offset, u32::MAX);
+                let mask = self.read_or_absent(addr, offset);
+                self.write(addr, offset, orig);
+                mask
+            }
+        };
+
+        let lower = self.read_or_absent(addr, offset);
+        if lower & 1 == 1 {
+            let mask = size_of(offset) & !0x3;
+            let size = (!mask).wrapping_add(1) & 0xffff;
+            let bar = (mask != 0).then_some(Bar::Io {
+                port: lower & !0x3,
+                size,
+            });
+            return (bar, 1);
+        }
+
+        let prefetchable = lower & (1 << 3) != 0;
+        let is_64_bit = (lower >> 1) & 0x3 == 0x2 && has_upper;
+        let (base, mask, used) = if is_64_bit {
+            let upper = self.read_or_absent(addr, offset + 4);
+            let mask_lo = size_of(offset);
+            let mask_hi = size_of(offset + 4);
+            (
+                (upper as u64) << 32 | (lower & !0xf) as u64,
+                (mask_hi as u64) << 32 | (mask_lo & !0xf) as u64,
+                2,
+            )
+        } else {
+            let mask = size_of(offset) & !0xf;
+            ((lower & !0xf) as u64, mask as u64 | !(u32::MAX as u64), 1)
+        };
+
+        let size = (!mask).wrapping_add(1);
+        // an unimplemented BAR reads back as zero after writing all ones.
+        let implemented = mask as u32 != 0 || (is_64_bit && mask >> 32 != 0);
+        let bar = implemented.then_some(Bar::Memory {
+            addr: PAddr::from_u64(base),
+            size,
+            prefetchable,
+            is_64_bit,
+        });
+        (bar, used)
+    }
+}
+
+fn legacy_address(addr: Address, offset: u16) -> Option<u32> {
+    if addr.segment != 0 || offset >= LEGACY_CONFIG_LEN {
+        return None;
+    }
+
+    Some(
+        1 << 31
+            | (addr.bus as u32) << 16
+            | (addr.device as u32) << 11
+            | (addr.function as u32) << 8
+            | (offset & 0xfc) as u32,
+    )
+}
+
+fn ecam_ptr(regions: &[EcamRegion], addr: Address, offset: u16) -> Option<*mut u32> {
+    ecam_address(regions, addr, offset & !0x3).map(|vaddr| vaddr.as_ptr())
+}
+
+fn ecam_address(regions: &[EcamRegion], addr: Address, offset: u16) -> Option<VAddr> {
+    if offset >= ECAM_CONFIG_LEN
+        || addr.device >= DEVICES_PER_BUS
+        || addr.function >= FUNCTIONS_PER_DEVICE
+    {
+        return None;
+    }
+
+    let region = regions.iter().find(|region| {
+        region.segment == addr.segment && (region.start_bus..=region.end_bus).contains(&addr.bus)
+    })?;
+    let offset = ((addr.bus - region.start_bus) as usize) << 20
+        | (addr.device as usize) << 15
+        | (addr.function as usize) << 12
+        | offset as usize;
+    // ECAM regions are reserved in the memory map, so the bootloader's
+    // mapping of physical memory covers them.
+    Some(mm::kernel_vaddr_of(region.base + offset))
+}
+
+// === impl Devices ===
+
+impl Devices<'_> {
+    fn start_segment(&mut self, segment: u16, root_bus: u8) {
+        self.segment = segment;
+        self.pending = BusSet::default();
+        self.seen = BusSet::default();
+        self.next = None;
+        self.queue_bus(root_bus);
+
+        // if the host bridge at 00.0 is multi-function, each of its functions
+        // is a separate host controller, responsible for the bus with the
+        // same number as the function.
+        let host = Address {
+            segment,
+            bus: root_bus,
+            device: 0,
+            function: 0,
+        };
+        let header = (self.config.read_or_absent(host, REG_HEADER) >> 16) as u8;
+        if self.config.read_or_absent(host, REG_VENDOR_DEVICE) as u16 != NO_VENDOR
+            && header & HEADER_MULTI_FUNCTION != 0
+        {
+            for function in 1..FUNCTIONS_PER_DEVICE {
+                let addr = Address { function, ..host };
+                if self.config.read_or_absent(addr, REG_VENDOR_DEVICE) as u16 != NO_VENDOR {
+                    self.queue_bus(root_bus.wrapping_add(function));
+                }
+            }
+        }
+    }
+
+    /// Move on to the next ECAM region, returning `false` if there are none
+    /// left.
+    fn next_segment(&mut self) -> bool {
+        let ConfigSpace::Ecam(regions) = self.config else {
+            return false;
+        };
+
+        self.region += 1;
+        match regions.get(self.region) {
+            Some(region) => {
+                self.start_segment(region.segment, region.start_bus);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn queue_bus(&mut self, bus: u8) {
+        if !self.seen.contains(bus) {
+            self.seen.insert(bus);
+            self.pending.insert(bus);
+        }
+    }
+
+    /// Advance past the function at `(bus, device, function)`.
+    fn advance(&mut self, bus: u8, device: u8, function: u8) {
+        self.next = if self.multi_function && function + 1 < FUNCTIONS_PER_DEVICE {
+            Some((bus, device, function + 1))
+        } else if device + 1 < DEVICES_PER_BUS {
+            Some((bus, device + 1, 0))
+        } else {
+            None
+        };
+    }
+}
+
+impl Iterator for Devices<'_> {
+    type Item = Device;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (bus, device, function) = match self.next {
+                Some(next) => next,
+                None => match self.pending.pop_first() {
+                    Some(bus) => (bus, 0, 0),
+                    None if self.next_segment() => continue,
+                    None => return None,
+                },
+            };
+
+            let addr = Address {
+                segment: self.segment,
+                bus,
+                device,
+                function,
+            };
+            if function == 0 {
+                let header = (self.config.read_or_absent(addr, REG_HEADER) >> 16) as u8;
+                self.multi_function = header & HEADER_MULTI_FUNCTION != 0;
+            }
+
+            let found = self.config.device(addr);
+            if found.is_none() && function == 0 {
+                // no function 0 means no device, so skip its other functions.
+                self.multi_function = false;
+            }
+            self.advance(bus, device, function);
+
+            let Some(dev) = found else {
+                continue;
+            };
+            if dev.header_type == HEADER_TYPE_BRIDGE {
+                let [_primary, secondary, _subordinate, _] = self
+                    .config
+                    .read_or_absent(addr, REG_BRIDGE_BUSES)
+                    .to_le_bytes();
+                if secondary != 0 {
+                    self.queue_bus(secondary);
+                }
+            }
+            return Some(dev);
+        }
+    }
+}
+
+// === impl BusSet ===
+
+impl BusSet {
+    fn contains(&self, bus: u8) -> bool {
+        self.0[bus as usize / 64] & (1 << (bus % 64)) != 0
+    }
+
+    fn insert(&mut self, bus: u8) {
+        self.0[bus as usize / 64] |= 1 << (bus % 64);
+    }
+
+    fn pop_first(&mut self) -> Option<u8> {
+        for (idx, word) in self.0.iter_mut().enumerate() {
+            if *word != 0 {
+                let bit = word.trailing_zeros();
+                *word &= !(1 << bit);
+                return Some((idx * 64) as u8 + bit as u8);
+            }
+        }
+        None
+    }
+}
+
+// === impl Address ===
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
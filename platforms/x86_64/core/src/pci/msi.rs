@@ -0,0 +1,565 @@
+//! Interrupts for PCI functions.
+//!
+//! [`allocate`] sets up a PCI function's interrupts, registering a handler
+//! for each on a vector chosen by
+//! [`allocate_handler`](crate::interrupt::allocate_handler), and targeting
+//! them at a chosen CPU core. It prefers message-signaled interrupts, which
+//! the function delivers by writing to the local APIC's address, so they are
+//! never shared with other devices:
+//!
+//! - **MSI-X**, which gives each interrupt its own entry in a table in one of
+//!   the function's memory BARs. A function may have up to 2048 of them, and
+//!   each can be masked, so drivers can have one per queue.
+//! - **MSI**, which is configured in the capability structure itself. Only a
+//!   single message is used, since multiple MSI messages need a block of
+//!   contiguous, aligned vectors, all targeting the same core.
+//! - Otherwise, the function's legacy `INTx` pin, routed through the I/O APIC
+//!   (see [`ioapic`]). These interrupts may be shared with other functions,
+//!   so handlers must check whether their device actually raised them.
+//!
+//! # Legacy interrupts
+//!
+//! Which I/O APIC input a PCI interrupt pin is wired to is described by the
+//! ACPI `_PRT` methods, which we can't evaluate without an AML interpreter.
+//! Instead, the function's interrupt line register, written by the firmware,
+//! is treated as an ISA IRQ. This is correct where the firmware routes PCI
+//! interrupts through the ISA IRQs (as on QEMU's `pc` machine), but not on
+//! machines that wire them to I/O APIC inputs above 15 (as on QEMU's `q35`).
+//! Modern devices all support MSI, so this is only a fallback.
+use super::{
+    Address, Bar, ConfigSpace, Device, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE,
+    COMMAND_MEMORY_SPACE, REG_COMMAND, REG_INTERRUPT,
+};
+use crate::interrupt::{
+    self,
+    ioapic::{self, Polarity, RouteError, Trigger},
+    vector::Handler,
+    IrqGuard, RegisterError,
+};
+use alloc::vec::Vec;
+use core::fmt;
+use hal_core::{Address as _, VAddr};
+use hal_x86_64::mm;
+
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+
+// MSI message control bits.
+const MSI_ENABLE: u16 = 1 << 0;
+/// The number of messages enabled, as a power of two.
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+// MSI-X message control bits.
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+/// The offset of the table offset and BIR register in the MSI-X capability.
+const MSIX_REG_TABLE: u16 = 0x04;
+/// The offset of the PBA offset and BIR register in the MSI-X capability.
+const MSIX_REG_PBA: u16 = 0x08;
+const MSIX_BIR_MASK: u32 = 0x7;
+
+/// The size of an MSI-X table entry.
+const MSIX_ENTRY_LEN: usize = 16;
+// Offsets within an MSI-X table entry.
+const MSIX_ENTRY_ADDR_LOW: usize = 0x0;
+const MSIX_ENTRY_ADDR_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// The base of the address range decoded by the local APICs. An MSI address
+/// selects the destination APIC ID in bits 12-19.
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+/// A PCI function's interrupts, returned by [`allocate`].
+///
+/// When this is dropped, the function's interrupts are disabled and their
+/// handlers unregistered.
+#[derive(Debug)]
+pub struct Interrupts {
+    addr: Address,
+    mode: Mode,
+    guards: Vec<IrqGuard>,
+}
+
+/// How a PCI function's interrupts are delivered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// MSI-X, with a table of interrupts in the function's memory BAR.
+    MsiX {
+        /// The offset of the MSI-X capability.
+        cap: u16,
+        /// The address of the MSI-X table.
+        table: VAddr,
+        /// The address of the pending bit array.
+        pba: VAddr,
+    },
+    /// A single MSI message.
+    Msi {
+        /// The offset of the MSI capability.
+        cap: u16,
+    },
+    /// The legacy `INTx` pin, routed through the I/O APIC.
+    Legacy {
+        /// The GSI the pin is routed to.
+        gsi: u32,
+    },
+}
+
+#[derive(Debug)]
+pub enum AllocError {
+    /// No handlers were passed to [`allocate`].
+    NoHandlers,
+    /// The function has no MSI or MSI-X capability, and doesn't use an
+    /// interrupt pin, or the firmware didn't assign its pin an interrupt line.
+    NoInterrupt,
+    /// The MSI-X table or PBA is in this BAR, which isn't a memory BAR.
+    BadBar(u8),
+    /// The CPU core's APIC ID is too large to be the destination of a
+    /// message-signaled interrupt.
+    BadApicId(u32),
+    /// A handler couldn't be registered.
+    Register(RegisterError),
+    /// The legacy interrupt couldn't be routed through the I/O APIC.
+    Route(RouteError),
+}
+
+/// Set up the interrupts of the PCI function `dev`, delivering them to the
+/// CPU core with local APIC ID `apic_id`.
+///
+/// The function gets one interrupt per handler in `handlers`, if it supports
+/// MSI-X with that many table entries, and otherwise fewer; the handlers
+/// which got an interrupt are the first [`Interrupts::len`]. Interrupt `n`
+/// is raised by the function for MSI-X table entry `n`. If the function
+/// doesn't support MSI-X, only the first handler is used.
+///
+/// This also enables the function's memory decoding and bus mastering, which
+/// message-signaled interrupts need.
+pub fn allocate(
+    config: &ConfigSpace,
+    dev: &Device,
+    handlers: &[Handler],
+    apic_id: u32,
+) -> Result<Interrupts, AllocError> {
+    if handlers.is_empty() {
+        return Err(AllocError::NoHandlers);
+    }
+    let addr = dev.address;
+
+    if let Some(cap) = config.find_capability(addr, CAP_MSIX) {
+        let dest = msi_dest(apic_id)?;
+        return allocate_msix(config, dev, cap, handlers, dest);
+    }
+    if let Some(cap) = config.find_capability(addr, CAP_MSI) {
+        let dest = msi_dest(apic_id)?;
+        return allocate_msi(config, addr, cap, handlers[0], dest);
+    }
+    allocate_legacy(config, addr, handlers[0], apic_id)
+}
+
+// === impl Interrupts ===
+
+impl Interrupts {
+    /// Returns how the function's interrupts are delivered.
+    #[must_use]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Returns the number of interrupts the function was given.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    /// Returns `true` if the function was given no interrupts, which never
+    /// happens once [`allocate`] succeeds.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// Returns the vector interrupt `n` is delivered on.
+    #[must_use]
+    pub fn vector(&self, n: usize) -> Option<u8> {
+        self.guards.get(n).map(IrqGuard::vector)
+    }
+
+    /// Stop interrupt `n` from being delivered. An MSI-X interrupt raised
+    /// while masked is held pending until it's unmasked.
+    ///
+    /// Returns `false` if interrupt `n` doesn't exist, or can't be masked,
+    /// because the function uses MSI without per-vector masking.
+    pub fn mask(&self, n: usize) -> bool {
+        self.set_masked(n, true)
+    }
+
+    /// Resume delivering interrupt `n`, after it was masked with
+    /// [`Interrupts::mask`].
+    ///
+    /// Returns `false` if interrupt `n` doesn't exist, or can't be masked.
+    pub fn unmask(&self, n: usize) -> bool {
+        self.set_masked(n, false)
+    }
+
+    /// Returns whether interrupt `n` was raised while masked, and is waiting
+    /// to be delivered.
+    ///
+    /// Returns `None` if interrupt `n` doesn't exist, or the function has no
+    /// pending bits.
+    #[must_use]
+    pub fn is_pending(&self, n: usize) -> Option<bool> {
+        if n >= self.len() {
+            return None;
+        }
+        let config = super::config_space()?;
+        let pending = match self.mode {
+            Mode::MsiX { pba, .. } => unsafe {
+                // Safety: the PBA has a bit for every table entry, and we
+                // only allocated as many interrupts as there are entries.
+                let word = (pba + (n / 64) * 8).as_ptr::<u64>().read_volatile();
+                word & (1 << (n % 64)) != 0
+            },
+            Mode::Msi { cap } => {
+                let control = msi_control(config, self.addr, cap);
+                if control & MSI_PER_VECTOR_MASKING == 0 {
+                    return None;
+                }
+                config.read(self.addr, msi_mask_offset(cap, control) + 4)? & 1 != 0
+            }
+            Mode::Legacy { .. } => return None,
+        };
+        Some(pending)
+    }
+
+    fn set_masked(&self, n: usize, masked: bool) -> bool {
+        if n >= self.len() {
+            return false;
+        }
+        let Some(config) = super::config_space() else {
+            return false;
+        };
+        match self.mode {
+            Mode::MsiX { table, .. } => {
+                let control = msix_entry(table, n) + MSIX_ENTRY_CONTROL;
+                unsafe {
+                    // Safety: `n` is one of the table entries we programmed.
+                    let ptr = control.as_ptr::<u32>();
+                    let value = ptr.read_volatile();
+                    ptr.write_volatile(if masked {
+                        value | MSIX_ENTRY_MASKED
+                    } else {
+                        value & !MSIX_ENTRY_MASKED
+                    });
+                }
+                true
+            }
+            Mode::Msi { cap } => {
+                let control = msi_control(config, self.addr, cap);
+                if control & MSI_PER_VECTOR_MASKING == 0 {
+                    return false;
+                }
+                let offset = msi_mask_offset(cap, control);
+                let Some(bits) = config.read(self.addr, offset) else {
+                    return false;
+                };
+                let bits = if masked { bits | 1 } else { bits & !1 };
+                unsafe { config.write(self.addr, offset, bits) }.is_some()
+            }
+            Mode::Legacy { gsi } => {
+                let result = if masked {
+                    ioapic::mask(gsi)
+                } else {
+                    ioapic::unmask(gsi)
+                };
+                result.is_ok()
+            }
+        }
+    }
+}
+
+impl Drop for Interrupts {
+    fn drop(&mut self) {
+        // stop the function raising interrupts before the handlers are
+        // unregistered.
+        if let Some(config) = super::config_space() {
+            match self.mode {
+                Mode::MsiX { cap, .. } => {
+                    let control = msi_control(config, self.addr, cap);
+                    write_control(config, self.addr, cap, control & !MSIX_ENABLE);
+                }
+                Mode::Msi { cap } => {
+                    let control = msi_control(config, self.addr, cap);
+                    write_control(config, self.addr, cap, control & !MSI_ENABLE);
+                }
+                Mode::Legacy { gsi } => {
+                    // the line may be shared, but it was routed to this
+                    // function's vector, so no other handler would see it.
+                    let _ = ioapic::mask(gsi);
+                }
+            }
+        }
+        tracing::debug!(pci.address = %self.addr, mode = ?self.mode, "freed PCI interrupts");
+    }
+}
+
+// === impl AllocError ===
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoHandlers => f.write_str("no interrupt handlers were given"),
+            Self::NoInterrupt => f.write_str("the function has no interrupts"),
+            Self::BadBar(bar) => write!(f, "MSI-X structures are in BAR {bar}, which isn't memory"),
+            Self::BadApicId(apic_id) => {
+                write!(
+                    f,
+                    "APIC ID {apic_id} can't receive message-signaled interrupts"
+                )
+            }
+            Self::Register(error) => write!(f, "failed to register a handler: {error}"),
+            Self::Route(error) => write!(f, "failed to route the legacy interrupt: {error}"),
+        }
+    }
+}
+
+impl From<RegisterError> for AllocError {
+    fn from(error: RegisterError) -> Self {
+        Self::Register(error)
+    }
+}
+
+impl From<RouteError> for AllocError {
+    fn from(error: RouteError) -> Self {
+        Self::Route(error)
+    }
+}
+
+fn allocate_msix(
+    config: &ConfigSpace,
+    dev: &Device,
+    cap: u16,
+    handlers: &[Handler],
+    dest: u32,
+) -> Result<Interrupts, AllocError> {
+    let addr = dev.address;
+    let control = msi_control(config, addr, cap);
+    let table_len = (control & MSIX_TABLE_SIZE) as usize + 1;
+    let table = msix_structure(config, dev, cap + MSIX_REG_TABLE)?;
+    let pba = msix_structure(config, dev, cap + MSIX_REG_PBA)?;
+
+    let guards = handlers
+        .iter()
+        .take(table_len)
+        .map(|&handler| interrupt::allocate_handler(handler))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    enable_messages(config, addr);
+    // mask the whole function while its table is written, so that it never
+    // sends a half-written message.
+    write_control(
+        config,
+        addr,
+        cap,
+        control | MSIX_ENABLE | MSIX_FUNCTION_MASK,
+    );
+    for n in 0..table_len {
+        let entry = msix_entry(table, n);
+        unsafe {
+            // Safety: the table has `table_len` entries, in a memory BAR.
+            (entry + MSIX_ENTRY_CONTROL)
+                .as_ptr::<u32>()
+                .write_volatile(MSIX_ENTRY_MASKED);
+            let Some(guard) = guards.get(n) else {
+                continue;
+            };
+            (entry + MSIX_ENTRY_ADDR_LOW)
+                .as_ptr::<u32>()
+                .write_volatile(MSI_ADDRESS_BASE | dest);
+            (entry + MSIX_ENTRY_ADDR_HIGH)
+                .as_ptr::<u32>()
+                .write_volatile(0);
+            (entry + MSIX_ENTRY_DATA)
+                .as_ptr::<u32>()
+                .write_volatile(guard.vector() as u32);
+            (entry + MSIX_ENTRY_CONTROL)
+                .as_ptr::<u32>()
+                .write_volatile(0);
+        }
+    }
+    write_control(
+        config,
+        addr,
+        cap,
+        (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+    );
+
+    let mode = Mode::MsiX { cap, table, pba };
+    tracing::debug!(
+        pci.address = %addr,
+        vectors = guards.len(),
+        table_len,
+        "enabled MSI-X"
+    );
+    Ok(Interrupts { addr, mode, guards })
+}
+
+fn allocate_msi(
+    config: &ConfigSpace,
+    addr: Address,
+    cap: u16,
+    handler: Handler,
+    dest: u32,
+) -> Result<Interrupts, AllocError> {
+    let guard = interrupt::allocate_handler(handler)?;
+    let control = msi_control(config, addr, cap) & !(MSI_ENABLE | MSI_MULTIPLE_MESSAGE_ENABLE);
+    write_control(config, addr, cap, control);
+
+    let data_offset = if control & MSI_64_BIT != 0 {
+        unsafe {
+            config.write(addr, cap + 0x8, 0);
+        }
+        cap + 0xc
+    } else {
+        cap + 0x8
+    };
+    unsafe {
+        config.write(addr, cap + 0x4, MSI_ADDRESS_BASE | dest);
+        config.write(addr, data_offset, guard.vector() as u32);
+    }
+    if control & MSI_PER_VECTOR_MASKING != 0 {
+        unsafe {
+            config.write(addr, msi_mask_offset(cap, control), 0);
+        }
+    }
+
+    enable_messages(config, addr);
+    write_control(config, addr, cap, control | MSI_ENABLE);
+
+    tracing::debug!(pci.address = %addr, vector = guard.vector(), "enabled MSI");
+    Ok(Interrupts {
+        addr,
+        mode: Mode::Msi { cap },
+        guards: alloc::vec![guard],
+    })
+}
+
+fn allocate_legacy(
+    config: &ConfigSpace,
+    addr: Address,
+    handler: Handler,
+    apic_id: u32,
+) -> Result<Interrupts, AllocError> {
+    let [line, pin, _, _] = config
+        .read(addr, REG_INTERRUPT)
+        .ok_or(AllocError::NoInterrupt)?
+        .to_le_bytes();
+    // a pin of 0 means the function doesn't use one, and a line of 0xff means
+    // the firmware didn't connect it (see the module docs).
+    if pin == 0 {
+        return Err(AllocError::NoInterrupt);
+    }
+    let route = ioapic::isa_route(line).ok_or(AllocError::NoInterrupt)?;
+    let cpu = u8::try_from(apic_id).map_err(|_| AllocError::BadApicId(apic_id))?;
+
+    let guard = interrupt::allocate_handler(handler)?;
+    // PCI interrupts are always level-triggered and active-low, whatever the
+    // ISA defaults for the line are.
+    ioapic::route_irq(
+        route.gsi,
+        guard.vector(),
+        cpu,
+        Trigger::Level,
+        Polarity::ActiveLow,
+    )?;
+
+    unsafe {
+        let command = config.read(addr, REG_COMMAND).unwrap_or(0) & 0xffff;
+        config.write(addr, REG_COMMAND, command & !COMMAND_INTX_DISABLE);
+    }
+
+    tracing::debug!(
+        pci.address = %addr,
+        irq = line,
+        gsi = route.gsi,
+        vector = guard.vector(),
+        "routed legacy PCI interrupt"
+    );
+    Ok(Interrupts {
+        addr,
+        mode: Mode::Legacy { gsi: route.gsi },
+        guards: alloc::vec![guard],
+    })
+}
+
+/// Returns the destination field of an MSI address targeting `apic_id`.
+fn msi_dest(apic_id: u32) -> Result<u32, AllocError> {
+    // without interrupt remapping, MSI addresses only have room for 8-bit
+    // APIC IDs.
+    u8::try_from(apic_id)
+        .map(|id| (id as u32) << 12)
+        .map_err(|_| AllocError::BadApicId(apic_id))
+}
+
+/// Returns the address of an MSI-X structure, from the offset and BIR
+/// register at `reg`.
+fn msix_structure(config: &ConfigSpace, dev: &Device, reg: u16) -> Result<VAddr, AllocError> {
+    let value = config.read(dev.address, reg).unwrap_or(0);
+    let bir = (value & MSIX_BIR_MASK) as u8;
+    match dev.bars.get(bir as usize) {
+        Some(Some(Bar::Memory { addr, .. })) => {
+            // like the ECAM regions, device memory is reached through the
+            // bootloader's mapping of physical memory.
+            Ok(mm::kernel_vaddr_of(
+                *addr + (value & !MSIX_BIR_MASK) as usize,
+            ))
+        }
+        _ => Err(AllocError::BadBar(bir)),
+    }
+}
+
+fn msix_entry(table: VAddr, n: usize) -> VAddr {
+    table + n * MSIX_ENTRY_LEN
+}
+
+/// Returns the offset of the mask bits register of an MSI capability with
+/// per-vector masking. The pending bits register follows it.
+fn msi_mask_offset(cap: u16, control: u16) -> u16 {
+    if control & MSI_64_BIT != 0 {
+        cap + 0x10
+    } else {
+        cap + 0xc
+    }
+}
+
+/// Read the message control register of an MSI or MSI-X capability.
+fn msi_control(config: &ConfigSpace, addr: Address, cap: u16) -> u16 {
+    (config.read(addr, cap).unwrap_or(0) >> 16) as u16
+}
+
+/// Write the message control register of an MSI or MSI-X capability.
+fn write_control(config: &ConfigSpace, addr: Address, cap: u16, control: u16) {
+    // the capability ID and next pointer, in the low half, are read-only.
+    let header = config.read(addr, cap).unwrap_or(0) & 0xffff;
+    unsafe {
+        config.write(addr, cap, header | (control as u32) << 16);
+    }
+}
+
+/// Let the function send messages, and stop it asserting its `INTx` pin.
+fn enable_messages(config: &ConfigSpace, addr: Address) {
+    // writing the command register also writes the status register, in which
+    // set bits are cleared, so only write back the command bits.
+    let command = config.read(addr, REG_COMMAND).unwrap_or(0) & 0xffff;
+    unsafe {
+        config.write(
+            addr,
+            REG_COMMAND,
+            command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE,
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Powering off and resetting the machine.
+//!
+//! [`power_off`] enters the ACPI S5 ("soft off") sleep state, using the PM1
+//! control registers and sleep types found by [`acpi::soft_off`] during boot.
+//! If the ACPI tables didn't describe how to do that, or the write didn't turn
+//! the machine off, it falls back to the power-off ports provided by QEMU and
+//! Bochs, and then finally to just halting.
+//!
+//! [`reboot`] writes to the ACPI reset register found by
+//! [`acpi::reset_register`]. If there isn't one, or it didn't work, it falls
+//! back to pulsing the reset line of the 8042 keyboard controller, and then
+//! finally to triple faulting, which resets the CPU.
+use crate::{acpi, ipi};
+use core::sync::atomic::{AtomicBool, Ordering};
+use hal_core::Address;
+use hal_x86_64::{
+    cpu::{self, Port},
+    mm,
+};
+use mycelium_util::sync::InitOnce;
+
+static SOFT_OFF: InitOnce<acpi::SoftOff> = InitOnce::uninitialized();
+static RESET: InitOnce<acpi::ResetRegister> = InitOnce::uninitialized();
+/// Set once some core has started powering off or resetting the machine.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// `SLP_EN` in the PM1 control register.
+const SLP_EN: u16 = 1 << 13;
+/// `SLP_TYP` in the PM1 control register.
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_TYP_SHIFT: u16 = 10;
+/// `SCI_EN` in the PM1 control register, set once the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+
+/// How many times to poll for `SCI_EN` after asking the firmware to switch
+/// to ACPI mode.
+const ACPI_ENABLE_SPINS: usize = 1_000_000;
+/// How long to spin after asking for a reset, before trying something else.
+const RESET_SPINS: usize = 10_000_000;
+
+/// The 8042 keyboard controller's status and command port.
+const I8042_COMMAND: u16 = 0x64;
+/// Set in the 8042's status while it hasn't yet read the last command.
+const I8042_INPUT_FULL: u8 = 1 << 1;
+/// Pulses the 8042's output line which is wired to the CPU's reset pin.
+const I8042_PULSE_RESET: u8 = 0xfe;
+
+/// The PM1a control port of QEMU's emulated PIIX4 and ICH9 chipsets, and the
+/// value which powers them off.
+const QEMU_POWER_OFF: (u16, u16) = (0x604, 0x2000);
+/// The power-off port of Bochs, and of older versions of QEMU.
+const BOCHS_POWER_OFF: (u16, u16) = (0xb004, 0x2000);
+/// The default I/O port of QEMU's `isa-debug-exit` device.
+///
+/// Writing `value` to this port exits QEMU with the status
+/// `(value << 1) | 1`.
+pub const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Record how to enter the ACPI S5 state and reset the machine, from the ACPI
+/// tables.
+pub fn init(tables: &acpi::AcpiTables<acpi::IdentityMappedAcpiHandler>) {
+    match acpi::soft_off(tables) {
+        Ok(soft_off) => {
+            tracing::debug!(?soft_off, "found ACPI S5 sleep state");
+            SOFT_OFF.init(soft_off);
+        }
+        Err(error) => {
+            tracing::warn!(%error, "can't power off with ACPI, will try emulator ports instead");
+        }
+    }
+
+    match acpi::reset_register(tables) {
+        Ok(reset) => {
+            tracing::debug!(?reset, "found ACPI reset register");
+            RESET.init(reset);
+        }
+        Err(error) => {
+            tracing::info!(%error, "can't reset with ACPI, will use the 8042 instead");
+        }
+    }
+}
+
+/// Turn the machine off.
+///
+/// Every other CPU core is halted first. If nothing manages to turn the
+/// machine off, the current core halts as well.
+pub fn power_off() -> ! {
+    begin_shutdown();
+    tracing::info!("powering off...");
+
+    if let Some(soft_off) = SOFT_OFF.try_get() {
+        unsafe {
+            enter_s5(soft_off);
+        }
+        tracing::warn!("still running after entering ACPI S5");
+    }
+
+    unsafe {
+        for (port, value) in [QEMU_POWER_OFF, BOCHS_POWER_OFF] {
+            Port::at(port).writew(value);
+        }
+        // this exits with a non-zero status, but it's better than not exiting
+        // at all.
+        Port::at(QEMU_DEBUG_EXIT_PORT).writeb(0);
+    }
+
+    tracing::error!("failed to power off, halting instead");
+    cpu::halt()
+}
+
+/// Reset the machine.
+///
+/// Every other CPU core is halted first. The reset methods are tried in turn,
+/// logging each one, until one of them works.
+pub fn reboot() -> ! {
+    begin_shutdown();
+    tracing::info!("rebooting...");
+
+    if let Some(&reset) = RESET.try_get() {
+        tracing::info!(?reset, "resetting with the ACPI reset register");
+        unsafe {
+            match reset {
+                acpi::ResetRegister::Io { port, value } => Port::at(port).writeb(value),
+                acpi::ResetRegister::Memory { addr, value } => mm::kernel_vaddr_of(addr)
+                    .as_ptr::<u8>()
+                    .write_volatile(value),
+            }
+        }
+        spin(RESET_SPINS);
+        tracing::warn!("still running after writing the ACPI reset register");
+    }
+
+    tracing::info!("resetting with the 8042 keyboard controller");
+    unsafe {
+        let i8042 = Port::at(I8042_COMMAND);
+        for _ in 0..RESET_SPINS {
+            if i8042.readb() & I8042_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        i8042.writeb(I8042_PULSE_RESET);
+    }
+    spin(RESET_SPINS);
+
+    tracing::warn!("still running after pulsing the 8042 reset line, triple faulting");
+    unsafe {
+        // with an empty IDT, the breakpoint can't be delivered, and neither
+        // can the resulting double fault, so the CPU shuts down, which resets
+        // it.
+        let idtr = [0u16; 5];
+        core::arch::asm!(
+            "lidt [{}]",
+            "int3",
+            in(reg) &idtr,
+            options(nostack),
+        );
+    }
+    cpu::halt()
+}
+
+/// Disable interrupts and halt the other cores. If another core is already
+/// shutting down, the current core just halts instead.
+fn begin_shutdown() {
+    unsafe {
+        cpu::intrinsics::cli();
+    }
+    if SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+        cpu::halt();
+    }
+    unsafe {
+        // Safety: the system is going down anyway.
+        ipi::halt_other_cores();
+    }
+}
+
+fn spin(spins: usize) {
+    for _ in 0..spins {
+        core::hint::spin_loop();
+    }
+}
+
+/// # Safety
+///
+/// `soft_off` must describe this machine's PM1 control registers.
+unsafe fn enter_s5(soft_off: &acpi::SoftOff) {
+    let pm1a = Port::at(soft_off.pm1a_control);
+    if pm1a.readw() & SCI_EN == 0 && soft_off.smi_command != 0 {
+        tracing::debug!("switching to ACPI mode");
+        Port::at(soft_off.smi_command).writeb(soft_off.acpi_enable);
+        for _ in 0..ACPI_ENABLE_SPINS {
+            if pm1a.readw() & SCI_EN != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // preserve the rest of the control register. when there are two control
+    // blocks, the sleep is only entered once both have been written.
+    let sleep = |port: Port, typ: u8| {
+        let bits = ((typ as u16) << SLP_TYP_SHIFT) & SLP_TYP_MASK;
+        port.writew((port.readw() & !SLP_TYP_MASK) | bits | SLP_EN);
+    };
+    if let Some(pm1b) = soft_off.pm1b_control {
+        sleep(Port::at(pm1b), soft_off.slp_typ_b);
+    }
+    sleep(pm1a, soft_off.slp_typ_a);
+}
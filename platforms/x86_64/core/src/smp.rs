@@ -0,0 +1,757 @@
+//! Per-core run queues.
+//!
+//! # Schedulers
+//!
+//! The kernel's own scheduler is a `LocalScheduler`, so the tasks spawned on
+//! it with `Kernel::initialize` only ever run on the boot processor. In
+//! addition, each CPU core has its own [`Core`], holding a thread-safe
+//! `Scheduler`, which is found through the core-local data in `GS` (see
+//! [`LocalKey`]). The boot processor ticks its core's scheduler alongside the
+//! kernel's in [`crate::run`], and application processors tick only their own
+//! in [`run_core`].
+//!
+//! # Timers
+//!
+//! The boot processor alone turns the kernel's timer wheel. Application
+//! processors never turn it: when a timer fires, the task waiting for it is
+//! woken like any other cross-core wakeup. This means there's a single timer
+//! wheel, which is never contended, at the cost of timers for tasks on other
+//! cores firing up to one boot processor tick late.
+//!
+//! # Utilization
+//!
+//! Each core's run loop marks when it goes idle and wakes up again, in
+//! [`Core::sleep_if`], so every core knows how long it has spent waiting
+//! for interrupts, and how long ticking its scheduler. [`Core::stats`]
+//! returns the totals since the core started, and [`cpu_utilization`]
+//! returns each core's busy and idle time since the last time it was
+//! called. Idle time is also broken down by the
+//! [C-state](interrupt::idle::CState) the core waited in.
+//!
+//! # Cross-core wakeups
+//!
+//! A core's scheduler may be woken from any core. Tasks are spawned on a
+//! particular core with [`spawn_on`], which wraps them so that waking them
+//! also wakes the core they belong to, with [`ipi::wake_core`].
+//!
+//! # Offlining
+//!
+//! An application processor can be taken offline with [`offline`], for power
+//! management or testing, and brought back with [`online`]. A core moves
+//! through these [`CoreState`]s:
+//!
+//! ```text
+//!            offline()             run loop
+//! Online ─────────────▶ Draining ──────────▶ Offline
+//!   ▲                      │                    │
+//!   │   no core to take    │                    │ online()
+//!   ├──────────────────────┘                    ▼
+//!   └───────────────────────────────────── Starting
+//!               core's run loop starts
+//! ```
+//!
+//! A draining core's own run loop hands its runnable tasks to another online
+//! core, then disables interrupts and halts. Its tasks are still wrapped to
+//! wake it, so from then on, [`ipi::wake_core`] forwards its wakeups to the
+//! core which took its tasks, and that core also picks up any of its tasks
+//! which are woken later. An offline core is never sent a reschedule IPI, and
+//! [`spawn_on`] refuses to place new work on it. If there's no other online
+//! core to take its tasks, the core refuses to go offline, and returns to
+//! `Online`.
+//!
+//! The boot processor runs the kernel's scheduler and timer wheel, so it can't
+//! be taken offline.
+//!
+//! Application processors can't be started yet (see
+//! [`acpi::bringup_smp`](crate::acpi::bringup_smp)), so for now, [`online`]
+//! always fails.
+use crate::{
+    clock, cpu, halt,
+    interrupt::{self, idle::CState},
+    ipi,
+    lapic::LocalApic,
+    topology, watchdog, LocalKey,
+};
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use hal_x86_64::cpu as hal_cpu;
+use kernel::{
+    maitake::{
+        scheduler::{Scheduler, TryStealError},
+        sync::{
+            blocking::Mutex,
+            spin::{InitOnce, Spinlock},
+        },
+        task::JoinHandle,
+    },
+    runloop::{RunLoopDriver, RunLoopState, Sleep, TickSummary},
+};
+
+/// The number of cores which may have a run queue, indexed by local APIC ID.
+const MAX_CORES: usize = 16;
+
+/// A CPU core's run queue.
+pub struct Core {
+    apic_id: u32,
+    scheduler: Scheduler,
+    /// The core's [`CoreState`].
+    state: AtomicU8,
+    /// The core whose scheduler runs this core's tasks: this core, unless it
+    /// has gone offline.
+    forward: AtomicU32,
+    /// Set while the core is (about to be) waiting for an interrupt, so that
+    /// wakeups only send an IPI when they need to.
+    sleeping: AtomicBool,
+    /// The number of times the core's run loop has ticked.
+    ticks: AtomicU64,
+    /// The number of tasks the core's run loop has polled.
+    polled: AtomicU64,
+    /// The number of times the core has waited for an interrupt.
+    sleeps: AtomicU64,
+    /// The total time the core has spent waiting for interrupts, in
+    /// nanoseconds, not counting the current wait.
+    idle_nanos: AtomicU64,
+    /// When the core's current wait for an interrupt started, in nanoseconds
+    /// since the clock started, plus one, or 0 if the core is awake.
+    idle_since: AtomicU64,
+    /// `idle_nanos`, broken down by the C-state the core waited in.
+    cstate_idle_nanos: [AtomicU64; CState::COUNT],
+    /// The C-state of the core's last wait for an interrupt, or 0 if it
+    /// hasn't waited yet.
+    last_cstate: AtomicU8,
+    /// When the core started.
+    started: clock::Instant,
+    /// The counters as of the last call to [`Core::utilization`].
+    last_sample: Mutex<Sample, Spinlock>,
+}
+
+/// Whether a core is running tasks.
+///
+/// See [the module-level documentation](self#offlining) for the transitions
+/// between states.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CoreState {
+    /// The core is running its scheduler.
+    Online = 0,
+    /// The core has been asked to go offline, and will hand its tasks to
+    /// another core the next time its run loop comes around.
+    Draining = 1,
+    /// The core has halted with interrupts disabled.
+    Offline = 2,
+    /// The core has been asked to come back online, and is being started.
+    Starting = 3,
+}
+
+/// Errors returned by [`offline`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OfflineError {
+    /// The core's scheduler hasn't started.
+    NotStarted,
+    /// The core is the boot processor, which can't go offline.
+    BootProcessor,
+    /// The core isn't online.
+    NotOnline(CoreState),
+    /// There's no other online core to take the core's tasks.
+    NoOtherCore,
+}
+
+/// Errors returned by [`online`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OnlineError {
+    /// The core's scheduler hasn't started.
+    NotStarted,
+    /// The core isn't offline.
+    NotOffline(CoreState),
+    /// Application processors can't be started yet.
+    Unsupported,
+}
+
+/// A snapshot of a core's run loop counters, returned by [`Core::stats`].
+#[derive(Copy, Clone, Debug)]
+pub struct CoreStats {
+    /// The number of times the core's run loop has ticked.
+    pub ticks: u64,
+    /// The number of tasks the core's run loop has polled.
+    pub polled: u64,
+    /// The number of times the core has waited for an interrupt.
+    pub sleeps: u64,
+    /// The total time the core has spent waiting for interrupts.
+    pub idle: Duration,
+    /// The total time the core has spent in each C-state, indexed by
+    /// C-state number, not counting the current wait.
+    pub idle_by_cstate: [Duration; CState::COUNT],
+    /// The C-state of the core's last wait for an interrupt.
+    pub last_cstate: Option<CState>,
+    /// The total time the core has spent running, rather than waiting for
+    /// interrupts.
+    pub busy: Duration,
+}
+
+/// How busy a core was over an interval, returned by [`cpu_utilization`].
+#[derive(Copy, Clone, Debug)]
+pub struct Utilization {
+    /// The core's local APIC ID.
+    pub apic_id: u32,
+    /// The length of the interval.
+    pub interval: Duration,
+    /// The time the core spent waiting for interrupts during the interval.
+    pub idle: Duration,
+    /// The number of times the core waited for an interrupt during the
+    /// interval.
+    pub sleeps: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Sample {
+    at: clock::Instant,
+    idle: Duration,
+    sleeps: u64,
+}
+
+static CURRENT: LocalKey<&'static Core> = LocalKey::new(Core::new_current);
+
+static CORES: [InitOnce<&'static Core>; MAX_CORES] =
+    [const { InitOnce::uninitialized() }; MAX_CORES];
+
+/// The number of cores which are offline, so that online cores only look for
+/// tasks to adopt while there are some.
+static OFFLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the current core's run queue, creating it if this is the first
+/// time it's been used on this core.
+///
+/// The core's local data must have been initialized.
+#[must_use]
+pub fn current() -> &'static Core {
+    CURRENT.with(|core| *core)
+}
+
+/// Returns the run queues of every core whose scheduler has started.
+pub fn cores() -> impl Iterator<Item = &'static Core> {
+    CORES.iter().filter_map(|core| core.try_get().copied())
+}
+
+/// Returns the run queue of the core with local APIC ID `apic_id`, if its
+/// scheduler has started.
+#[must_use]
+pub fn core(apic_id: u32) -> Option<&'static Core> {
+    CORES.get(apic_id as usize)?.try_get().copied()
+}
+
+/// Returns the run queues of every core which is online.
+pub fn online_cores() -> impl Iterator<Item = &'static Core> {
+    cores().filter(|core| core.state() == CoreState::Online)
+}
+
+/// Take the application processor with local APIC ID `apic_id` offline.
+///
+/// This only asks the core to go offline: it hands off its tasks and halts
+/// the next time its run loop comes around. Returns an error if the core
+/// can't be taken offline, such as if it's the boot processor, or there's no
+/// other online core to take its tasks.
+///
+/// See [the module-level documentation](self#offlining) for details.
+pub fn offline(apic_id: u32) -> Result<(), OfflineError> {
+    let core = core(apic_id).ok_or(OfflineError::NotStarted)?;
+    if topology::cpu(apic_id).is_some_and(|cpu| cpu.is_bsp) {
+        return Err(OfflineError::BootProcessor);
+    }
+    if core.migration_target().is_none() {
+        return Err(OfflineError::NoOtherCore);
+    }
+    core.transition(CoreState::Online, CoreState::Draining)
+        .map_err(OfflineError::NotOnline)?;
+    tracing::info!(apic_id, "taking core offline");
+    ipi::wake_core(apic_id);
+    Ok(())
+}
+
+/// Bring the application processor with local APIC ID `apic_id` back online,
+/// after it was taken offline with [`offline`].
+///
+/// An offline core has halted with interrupts disabled, so it can only be
+/// restarted with the INIT-SIPI-SIPI startup sequence.
+pub fn online(apic_id: u32) -> Result<(), OnlineError> {
+    let core = core(apic_id).ok_or(OnlineError::NotStarted)?;
+    let state = core.state();
+    if state != CoreState::Offline {
+        return Err(OnlineError::NotOffline(state));
+    }
+    // TODO(eliza): once `acpi::bringup_smp` can start application processors,
+    // move the core to `Starting`, reset it with an INIT IPI, and send it the
+    // startup IPIs here, with the stack from `stack::ap_stack`. when its run
+    // loop starts again, it goes back to `Online`.
+    Err(OnlineError::Unsupported)
+}
+
+/// Returns how busy each core whose scheduler has started has been since the
+/// last call to `cpu_utilization`, or since it started.
+///
+/// This is cheap: it reads a few counters per core.
+pub fn cpu_utilization() -> impl Iterator<Item = Utilization> {
+    cores().map(Core::utilization)
+}
+
+/// Spawn `future` on the core with local APIC ID `apic_id`.
+///
+/// This can be used to migrate work to another core. Returns `None` if that
+/// core's scheduler hasn't started.
+pub fn spawn_on<F>(apic_id: u32, future: F) -> Option<JoinHandle<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let core = core(apic_id)?;
+    if core.state() != CoreState::Online {
+        return None;
+    }
+    let handle = core.scheduler.spawn(OnCore {
+        future: Box::pin(future),
+        apic_id,
+        waker: None,
+    });
+    ipi::wake_core(apic_id);
+    Some(handle)
+}
+
+/// Run the current core's scheduler forever.
+///
+/// This is the run loop for application processors, which call it once their
+/// core-local data and local APIC are set up. It never turns the kernel's
+/// timer wheel (see [the module-level documentation](self#timers)).
+pub fn run_core() -> ! {
+    cpu::simd::init();
+    interrupt::spurious::init_core();
+    let core = current();
+    if core
+        .transition(CoreState::Starting, CoreState::Online)
+        .is_ok()
+    {
+        core.forward.store(core.apic_id, Ordering::Release);
+        OFFLINE.fetch_sub(1, Ordering::AcqRel);
+        halt::core_online();
+    }
+    tracing::info!(core.apic_id, "started core run loop");
+    let mut state = RunLoopState::new();
+    let mut driver = CoreRunLoop { core };
+    loop {
+        if core.state() == CoreState::Draining {
+            core.drain();
+        }
+        watchdog::feed(core.apic_id);
+        let tick = state.tick_phase(&mut driver);
+        core.record_tick(&tick);
+        let slept = match state.decide_sleep() {
+            Sleep::UntilInterrupt => core.sleep_if(|| {
+                // application processors don't turn the timer wheel, so only
+                // the periodic timer interrupt bounds how long they'll idle.
+                let expected_idle = interrupt::idle::expected_idle(None);
+                interrupt::wait_for_interrupt_if(expected_idle, || {
+                    state.confirm_sleep(&driver) == Sleep::UntilInterrupt
+                })
+            }),
+            Sleep::No => false,
+        };
+        state.account_sleep(&mut driver, slept);
+    }
+}
+
+// === impl Core ===
+
+impl fmt::Debug for Core {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Core")
+            .field("apic_id", &self.apic_id)
+            .field("state", &self.state())
+            .field("sleeping", &self.sleeping.load(Ordering::Relaxed))
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Core {
+    /// Returns the core's local APIC ID.
+    #[must_use]
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Returns the core's [`CoreState`].
+    #[must_use]
+    pub fn state(&self) -> CoreState {
+        CoreState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    /// Returns the local APIC ID of the core whose scheduler runs this core's
+    /// tasks.
+    ///
+    /// This is the core's own ID, unless it has gone offline, in which case
+    /// wakeups for its tasks should go to the returned core instead.
+    #[must_use]
+    pub fn wake_target(&self) -> u32 {
+        self.forward.load(Ordering::Acquire)
+    }
+
+    /// Returns `true` if the core is waiting for an interrupt, or is about to.
+    #[must_use]
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping.load(Ordering::SeqCst)
+    }
+
+    /// Returns a snapshot of the core's run loop counters.
+    ///
+    /// The counters are read one at a time, so they may be slightly out of
+    /// step with each other if the core is running.
+    #[must_use]
+    pub fn stats(&self) -> CoreStats {
+        let now = clock::now();
+        let idle = self.idle_time(now);
+        CoreStats {
+            ticks: self.ticks.load(Ordering::Relaxed),
+            polled: self.polled.load(Ordering::Relaxed),
+            sleeps: self.sleeps.load(Ordering::Relaxed),
+            idle,
+            idle_by_cstate: core::array::from_fn(|cstate| {
+                Duration::from_nanos(self.cstate_idle_nanos[cstate].load(Ordering::Relaxed))
+            }),
+            last_cstate: CState::new(self.last_cstate.load(Ordering::Relaxed)),
+            busy: (now - self.started).saturating_sub(idle),
+        }
+    }
+
+    /// Returns how busy the core has been since the last call to
+    /// `utilization` (or [`cpu_utilization`]), or since it started.
+    #[must_use]
+    pub fn utilization(&self) -> Utilization {
+        let now = clock::now();
+        let sample = Sample {
+            at: now,
+            idle: self.idle_time(now),
+            sleeps: self.sleeps.load(Ordering::Relaxed),
+        };
+        let last = core::mem::replace(&mut *self.last_sample.lock(), sample);
+        Utilization {
+            apic_id: self.apic_id,
+            interval: sample.at - last.at,
+            idle: sample.idle.saturating_sub(last.idle),
+            sleeps: sample.sleeps - last.sleeps,
+        }
+    }
+
+    /// Returns the total time the core has spent idle, including the current
+    /// wait for an interrupt, if it's waiting.
+    fn idle_time(&self, now: clock::Instant) -> Duration {
+        let idle = Duration::from_nanos(self.idle_nanos.load(Ordering::Acquire));
+        match self.idle_since.load(Ordering::Acquire) {
+            0 => idle,
+            since => {
+                let since = clock::Instant::ZERO + Duration::from_nanos(since - 1);
+                idle + (now - since)
+            }
+        }
+    }
+
+    /// Spawn `future` on this core.
+    pub fn spawn<F>(&'static self, future: F) -> Option<JoinHandle<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        spawn_on(self.apic_id, future)
+    }
+
+    /// Poll the core's scheduler once, first adopting any tasks woken on
+    /// cores which went offline and handed their tasks to this one.
+    pub(crate) fn tick(&self) -> TickSummary {
+        if OFFLINE.load(Ordering::Acquire) > 0 {
+            for offline in cores().filter(|core| {
+                core.state() == CoreState::Offline && core.wake_target() == self.apic_id
+            }) {
+                if let Ok(stealer) = offline.scheduler.try_steal() {
+                    stealer.spawn_n(&self.scheduler, usize::MAX);
+                }
+            }
+        }
+        self.scheduler.tick().into()
+    }
+
+    /// Hand this core's tasks to another online core, and halt.
+    ///
+    /// This is called by the core's own run loop once it's been asked to go
+    /// offline. If there's no other core to take its tasks, the core goes
+    /// back online instead, and this returns.
+    fn drain(&'static self) {
+        let Some(target) = self.migration_target() else {
+            tracing::warn!(
+                core.apic_id = self.apic_id,
+                "no other core to take this core's tasks, staying online"
+            );
+            self.state.store(CoreState::Online as u8, Ordering::Release);
+            return;
+        };
+
+        // forward wakeups first, so that a task woken while it's being moved
+        // still wakes a core which will run it.
+        self.forward.store(target.apic_id, Ordering::Release);
+        let migrated = match self.scheduler.try_steal() {
+            Ok(stealer) => stealer.spawn_n(&target.scheduler, usize::MAX),
+            Err(TryStealError::Empty) => 0,
+            Err(error) => {
+                // try again the next time around the run loop.
+                tracing::debug!(?error, "can't take this core's tasks yet");
+                self.forward.store(self.apic_id, Ordering::Release);
+                return;
+            }
+        };
+
+        OFFLINE.fetch_add(1, Ordering::AcqRel);
+        self.state.store(CoreState::Offline as u8, Ordering::SeqCst);
+        ipi::wake_core(target.apic_id);
+        tracing::info!(
+            core.apic_id = self.apic_id,
+            target = target.apic_id,
+            migrated,
+            "core offline"
+        );
+        halt::core_offline();
+        // with interrupts disabled, an offline core doesn't respond to
+        // anything but INIT and startup IPIs, so it can only be restarted by
+        // `online`.
+        unsafe {
+            // Safety: the core has no tasks left to run.
+            hal_cpu::intrinsics::cli();
+        }
+        hal_cpu::halt()
+    }
+
+    /// Returns another online core to take this core's tasks.
+    fn migration_target(&self) -> Option<&'static Self> {
+        let cores = || online_cores().filter(|core| core.apic_id != self.apic_id);
+        // prefer an application processor, so that the boot processor can
+        // keep up with the kernel's own tasks.
+        cores()
+            .find(|core| !topology::cpu(core.apic_id).is_some_and(|cpu| cpu.is_bsp))
+            .or_else(|| cores().next())
+    }
+
+    /// Move the core from state `from` to state `to`, returning the state it
+    /// was in if it wasn't `from`.
+    fn transition(&self, from: CoreState, to: CoreState) -> Result<(), CoreState> {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(CoreState::from_u8)
+    }
+
+    /// Count a tick of the core's run loop.
+    pub(crate) fn record_tick(&self, tick: &TickSummary) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.polled.fetch_add(tick.polled as u64, Ordering::Relaxed);
+    }
+
+    /// Mark the core as sleeping while calling `sleep`, which returns the
+    /// C-state the core slept in, if it slept. Returns `true` if the core
+    /// slept.
+    pub(crate) fn sleep_if(&self, sleep: impl FnOnce() -> Option<CState>) -> bool {
+        self.sleeping.store(true, Ordering::SeqCst);
+        let start = clock::now();
+        self.idle_since
+            .store(start.since_start().as_nanos() as u64 + 1, Ordering::Release);
+        let cstate = sleep();
+        // stop counting the current wait before adding it to the total, so
+        // that a concurrent reader may miss it, but never counts it twice.
+        self.idle_since.store(0, Ordering::Release);
+        if let Some(cstate) = cstate {
+            let idle = start.elapsed().as_nanos() as u64;
+            self.idle_nanos.fetch_add(idle, Ordering::Release);
+            self.cstate_idle_nanos[cstate.number() as usize].fetch_add(idle, Ordering::Relaxed);
+            self.last_cstate.store(cstate.number(), Ordering::Relaxed);
+            self.sleeps.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sleeping.store(false, Ordering::Release);
+        cstate.is_some()
+    }
+
+    fn new_current() -> &'static Self {
+        let apic_id = LocalApic::current().map_or(0, |lapic| lapic.id());
+        let started = clock::now();
+        let core = Box::leak(Box::new(Self {
+            apic_id,
+            scheduler: Scheduler::new(),
+            state: AtomicU8::new(CoreState::Online as u8),
+            forward: AtomicU32::new(apic_id),
+            sleeping: AtomicBool::new(false),
+            ticks: AtomicU64::new(0),
+            polled: AtomicU64::new(0),
+            sleeps: AtomicU64::new(0),
+            idle_nanos: AtomicU64::new(0),
+            idle_since: AtomicU64::new(0),
+            cstate_idle_nanos: [const { AtomicU64::new(0) }; CState::COUNT],
+            last_cstate: AtomicU8::new(0),
+            started,
+            last_sample: Mutex::new_with_raw_mutex(
+                Sample {
+                    at: started,
+                    idle: Duration::ZERO,
+                    sleeps: 0,
+                },
+                Spinlock::new(),
+            ),
+        }));
+        match CORES.get(apic_id as usize) {
+            Some(slot) => slot.init(core),
+            None => tracing::warn!(
+                apic_id,
+                "core's APIC ID is too high for other cores to spawn tasks on it"
+            ),
+        }
+        core
+    }
+}
+
+// === impl CoreState ===
+
+impl CoreState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => Self::Online,
+            1 => Self::Draining,
+            2 => Self::Offline,
+            3 => Self::Starting,
+            _ => unreachable!("invalid core state {state}"),
+        }
+    }
+}
+
+impl fmt::Display for CoreState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Online => "online",
+            Self::Draining => "draining",
+            Self::Offline => "offline",
+            Self::Starting => "starting",
+        })
+    }
+}
+
+// === impl OfflineError ===
+
+impl fmt::Display for OfflineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotStarted => f.write_str("the core's scheduler hasn't started"),
+            Self::BootProcessor => f.write_str("the boot processor can't go offline"),
+            Self::NotOnline(state) => write!(f, "the core is {state}, not online"),
+            Self::NoOtherCore => f.write_str("no other core is online to take the core's tasks"),
+        }
+    }
+}
+
+// === impl OnlineError ===
+
+impl fmt::Display for OnlineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotStarted => f.write_str("the core's scheduler hasn't started"),
+            Self::NotOffline(state) => write!(f, "the core is {state}, not offline"),
+            Self::Unsupported => f.write_str("application processors can't be started yet"),
+        }
+    }
+}
+
+// === impl Utilization ===
+
+impl Utilization {
+    /// Returns the time the core spent running during the interval.
+    #[must_use]
+    pub fn busy(&self) -> Duration {
+        self.interval.saturating_sub(self.idle)
+    }
+
+    /// Returns the percentage of the interval the core spent running.
+    #[must_use]
+    pub fn busy_percent(&self) -> u8 {
+        100 - self.idle_percent()
+    }
+
+    /// Returns the percentage of the interval the core spent waiting for
+    /// interrupts.
+    #[must_use]
+    pub fn idle_percent(&self) -> u8 {
+        let interval = self.interval.as_nanos().max(1);
+        (self.idle.as_nanos().min(interval) * 100 / interval) as u8
+    }
+}
+
+/// Drives an application processor's scheduler.
+struct CoreRunLoop {
+    core: &'static Core,
+}
+
+impl RunLoopDriver for CoreRunLoop {
+    fn tick(&mut self) -> TickSummary {
+        self.core.tick()
+    }
+
+    fn turn_timer(&mut self) -> bool {
+        // only the boot processor turns the timer wheel.
+        false
+    }
+
+    fn wakeups(&self) -> u64 {
+        interrupt::wakeups()
+    }
+}
+
+/// A task spawned on a particular core, whose wakers also wake that core.
+struct OnCore<F> {
+    future: Pin<Box<F>>,
+    apic_id: u32,
+    waker: Option<Arc<CoreWaker>>,
+}
+
+struct CoreWaker {
+    waker: Waker,
+    apic_id: u32,
+}
+
+impl<F: Future> Future for OnCore<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // only make a new waker when the scheduler's waker changes.
+        let waker = match this.waker {
+            Some(ref waker) if waker.waker.will_wake(cx.waker()) => waker.clone(),
+            _ => {
+                let waker = Arc::new(CoreWaker {
+                    waker: cx.waker().clone(),
+                    apic_id: this.apic_id,
+                });
+                this.waker = Some(waker.clone());
+                waker
+            }
+        };
+        let waker = Waker::from(waker);
+        this.future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+}
+
+impl Wake for CoreWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.waker.wake_by_ref();
+        ipi::wake_core(self.apic_id);
+    }
+}
@@ -0,0 +1,156 @@
+//! Kernel stacks with guard pages.
+//!
+//! Stacks allocated from the heap, like the syscall and IST stacks, sit right
+//! next to other allocations, so overflowing one silently corrupts whatever is
+//! below it. A [`KernelStack`] is instead mapped in the
+//! [MMIO window](crate::mmio) with an unmapped guard page below it, so an
+//! overflow page faults instead.
+//!
+//! Each application processor gets a `KernelStack` of the size set by the
+//! `ap_stack_size` command line option. [`init_ap_stacks`] allocates them all
+//! before any application processor is started, so that running out of
+//! memory is reported up front, rather than partway through bringing up the
+//! cores.
+use crate::{
+    frame,
+    mm::PAGE_SIZE,
+    mmio::{self, MapError},
+};
+use alloc::vec::Vec;
+use core::fmt;
+use hal_core::{Address, PAddr, VAddr};
+use mycelium_util::sync::InitOnce;
+
+/// The default size of each application processor's stack, in bytes.
+pub const DEFAULT_AP_STACK_SIZE: usize = 64 * 1024;
+
+/// A kernel stack, with an unmapped guard page below it.
+///
+/// Kernel stacks are never freed.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// The lowest mapped address of the stack.
+    base: VAddr,
+    /// The size of the stack, in bytes, not counting the guard page.
+    size: usize,
+    /// The physical address of the stack's first frame.
+    frames: PAddr,
+}
+
+/// Errors returned by [`KernelStack::alloc`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StackError {
+    /// No run of free frames was large enough for the stack.
+    NoFrames,
+    /// The stack couldn't be mapped.
+    Map(MapError),
+}
+
+static AP_STACKS: InitOnce<Vec<(u32, KernelStack)>> = InitOnce::uninitialized();
+
+/// Allocate a [`KernelStack`] of `size` bytes for each of the local APIC IDs
+/// in `apic_ids`.
+///
+/// This must be called once, on the boot processor, after
+/// [`mmio::init`](crate::mmio::init).
+pub fn init_ap_stacks(
+    apic_ids: impl IntoIterator<Item = u32>,
+    size: usize,
+) -> Result<(), StackError> {
+    let stacks = apic_ids
+        .into_iter()
+        .map(|apic_id| {
+            let stack = KernelStack::alloc(size)?;
+            tracing::info!(
+                apic_id,
+                base = ?stack.base(),
+                size = stack.size(),
+                guard = ?stack.guard_page(),
+                "allocated AP stack"
+            );
+            Ok((apic_id, stack))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    AP_STACKS.init(stacks);
+    Ok(())
+}
+
+/// Returns the stack allocated for the application processor with the local
+/// APIC ID `apic_id`, if [`init_ap_stacks`] allocated one.
+#[must_use]
+pub fn ap_stack(apic_id: u32) -> Option<&'static KernelStack> {
+    AP_STACKS
+        .try_get()?
+        .iter()
+        .find(|(id, _)| *id == apic_id)
+        .map(|(_, stack)| stack)
+}
+
+// === impl KernelStack ===
+
+impl KernelStack {
+    /// Allocate a stack of at least `size` bytes, rounded up to whole pages.
+    pub fn alloc(size: usize) -> Result<Self, StackError> {
+        let pages = size.max(1).div_ceil(PAGE_SIZE);
+        let size = pages * PAGE_SIZE;
+        let frames = frame::alloc_contiguous(pages).ok_or(StackError::NoFrames)?;
+        let base = match mmio::map_guarded(frames, size, 1) {
+            Ok(base) => base,
+            Err(error) => {
+                frame::free_contiguous(frames, pages);
+                return Err(StackError::Map(error));
+            }
+        };
+        Ok(Self {
+            base: VAddr::from_usize(base as usize),
+            size,
+            frames,
+        })
+    }
+
+    /// Returns the lowest mapped address of the stack.
+    #[must_use]
+    pub fn base(&self) -> VAddr {
+        self.base
+    }
+
+    /// Returns the address a new stack pointer should start at.
+    ///
+    /// Stacks grow down, so this is the *end* of the stack.
+    #[must_use]
+    pub fn top(&self) -> VAddr {
+        VAddr::from_usize(self.base.as_usize() + self.size)
+    }
+
+    /// Returns the size of the stack, in bytes, not counting the guard page.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the address of the unmapped guard page below the stack.
+    #[must_use]
+    pub fn guard_page(&self) -> VAddr {
+        VAddr::from_usize(self.base.as_usize() - PAGE_SIZE)
+    }
+
+    /// Returns the physical address of the stack's lowest page.
+    ///
+    /// The stack is physically contiguous, so this can be used before paging
+    /// is enabled.
+    #[must_use]
+    pub fn phys_base(&self) -> PAddr {
+        self.frames
+    }
+}
+
+// === impl StackError ===
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoFrames => f.write_str("no physical frames left for the stack"),
+            Self::Map(error) => write!(f, "couldn't map the stack: {error}"),
+        }
+    }
+}
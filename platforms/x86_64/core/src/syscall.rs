@@ -0,0 +1,208 @@
+//! The `syscall`/`sysret` fast path.
+//!
+//! Userspace talks to the kernel mostly through the mailbox rings, which carry
+//! structured requests and responses. A handful of tiny, latency-critical
+//! queries are also available as register-based fast syscalls, made with the
+//! `syscall` instruction. These complement the mailbox, rather than replacing
+//! it: a fast syscall never blocks, allocates, or touches a driver. See
+//! [`abi::syscall::fast`] for the calling convention and the list of fast
+//! syscalls. Everything else goes through the mailbox.
+//!
+//! # Entry
+//!
+//! `syscall` doesn't switch stacks, so the entry stub does that itself, using
+//! a per-core [`PerCore`] block:
+//!
+//! - While userspace runs, `IA32_KERNEL_GS_BASE` points at the core's
+//!   `PerCore` block, so that the stub can `swapgs` to find it.
+//! - The stub saves the user stack pointer there, and switches to the core's
+//!   syscall stack.
+//! - It saves the caller's registers in a [`Frame`] on that stack.
+//! - It points `GS` back at the core's `GsLocalData`, so that the dispatcher
+//!   can use core-local data like any other kernel code.
+//!
+//! On the way out, it undoes all of that, and `sysret`s back to the caller.
+//! `IA32_FMASK` clears the interrupt flag on entry, so fast syscalls run with
+//! interrupts disabled on the core. They must stay short.
+//!
+//! # SMP
+//!
+//! The syscall MSRs, and the `PerCore` block, are per-core, so every core,
+//! including the boot processor, must call [`init_core`] once its GDT is
+//! loaded.
+use crate::{clock, gdt, lapic::LocalApic};
+use alloc::{boxed::Box, vec};
+use core::{arch::global_asm, mem::offset_of};
+use hal_x86_64::cpu::msr::Msr;
+use kernel::abi::syscall::{fast, hello};
+
+/// `IA32_EFER`, the extended feature enable register.
+const IA32_EFER: u32 = 0xc000_0080;
+/// `IA32_STAR`, which holds the segment selectors for `syscall` and `sysret`.
+const IA32_STAR: u32 = 0xc000_0081;
+/// `IA32_LSTAR`, the address `syscall` jumps to in long mode.
+const IA32_LSTAR: u32 = 0xc000_0082;
+/// `IA32_FMASK`, the `RFLAGS` bits `syscall` clears.
+const IA32_FMASK: u32 = 0xc000_0084;
+/// `IA32_GS_BASE`, the current `GS` base.
+const IA32_GS_BASE: u32 = 0xc000_0101;
+/// `IA32_KERNEL_GS_BASE`, which `swapgs` exchanges with `IA32_GS_BASE`.
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// `EFER.SCE`, which enables the `syscall` instruction.
+const EFER_SCE: u64 = 1 << 0;
+
+/// The `RFLAGS` bits cleared on entry: TF, IF, DF, and AC.
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/// The size of each core's syscall stack, in bytes.
+const STACK_SIZE: usize = 4 * 4096;
+
+/// A core's syscall state, found by the entry stub through `GS` after
+/// `swapgs`.
+#[repr(C)]
+#[derive(Debug)]
+// most fields are only read by the entry stub.
+#[allow(dead_code)]
+struct PerCore {
+    /// The address of this block, so that the stub can find it again.
+    this: u64,
+    /// The top of the core's syscall stack.
+    kernel_rsp: u64,
+    /// The caller's stack pointer, while a syscall is in progress.
+    user_rsp: u64,
+    /// The address of the core's `GsLocalData`.
+    local_data: u64,
+}
+
+/// The caller's registers, as saved by the entry stub.
+#[repr(C)]
+#[derive(Debug)]
+// most fields are only restored by the entry stub.
+#[allow(dead_code)]
+struct Frame {
+    cpu: u64,
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    /// The syscall number on entry, and its result on exit.
+    rax: u64,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+    _pad: u64,
+}
+
+/// Enable fast syscalls on the current core.
+///
+/// This must be called once on each core, after its local data is initialized
+/// and [`gdt::init_core`] has loaded its GDT.
+pub fn init_core() {
+    let stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+    let cpu = Box::leak(Box::new(PerCore {
+        this: 0,
+        // stacks grow down, so start at the *end* of the stack.
+        kernel_rsp: stack.as_ptr() as u64 + STACK_SIZE as u64,
+        user_rsp: 0,
+        // Safety: reading `IA32_GS_BASE` has no side effects.
+        local_data: unsafe { Msr::new(IA32_GS_BASE).read() },
+    }));
+    cpu.this = cpu as *mut PerCore as u64;
+
+    // `syscall` loads CS from `STAR[47:32]`, and SS from the next selector.
+    // `sysret` loads SS from `STAR[63:48] + 8`, and CS from the one after
+    // that. the GDT is laid out so that both work.
+    let selectors = gdt::selectors();
+    let index = |selector: hal_x86_64::segment::Selector| u64::from(selector.bits() & !0b111);
+    debug_assert_eq!(
+        index(selectors.kernel_data),
+        index(selectors.kernel_code) + 8
+    );
+    debug_assert_eq!(index(selectors.user_code), index(selectors.user_data) + 8);
+    let star = ((index(selectors.user_data) - 8) << 48) | (index(selectors.kernel_code) << 32);
+
+    unsafe {
+        // Safety: the `PerCore` block and its stack are leaked, so they are
+        // never freed, and the entry stub matches the GDT's layout.
+        Msr::new(IA32_KERNEL_GS_BASE).write(cpu.this);
+        Msr::new(IA32_STAR).write(star);
+        Msr::new(IA32_LSTAR).write(syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(FMASK);
+        let efer = Msr::new(IA32_EFER);
+        efer.write(efer.read() | EFER_SCE);
+    }
+    tracing::debug!(?cpu, star, "fast syscalls enabled");
+}
+
+extern "C" {
+    fn syscall_entry();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // find this core's `PerCore` block, and switch to its stack.
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{kernel_rsp}]",
+    // save the caller's registers in a `Frame`. `syscall` left the caller's
+    // RIP in RCX, and its RFLAGS in R11.
+    "push 0",
+    "push qword ptr gs:[{user_rsp}]",
+    "push rcx",
+    "push r11",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    "push qword ptr gs:[{this}]",
+    // point GS at the core's local data, for the dispatcher.
+    "mov ecx, {gs_base}",
+    "mov eax, dword ptr gs:[{local_data}]",
+    "mov edx, dword ptr gs:[{local_data} + 4]",
+    "wrmsr",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    // point GS back at the `PerCore` block, and restore the caller's
+    // registers.
+    "pop rax",
+    "mov rdx, rax",
+    "shr rdx, 32",
+    "mov ecx, {gs_base}",
+    "wrmsr",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rax",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    this = const offset_of!(PerCore, this),
+    kernel_rsp = const offset_of!(PerCore, kernel_rsp),
+    user_rsp = const offset_of!(PerCore, user_rsp),
+    local_data = const offset_of!(PerCore, local_data),
+    gs_base = const IA32_GS_BASE,
+    dispatch = sym dispatch,
+);
+
+/// Handle a fast syscall, replacing the syscall number in `frame.rax` with
+/// its result.
+extern "C" fn dispatch(frame: &mut Frame) {
+    frame.rax = match frame.rax {
+        fast::ABI_VERSION => u64::from(hello::ABI_VERSION),
+        fast::UPTIME_MICROS => clock::now().since_start().as_micros() as u64,
+        fast::CORE_ID => u64::from(LocalApic::current().map_or(0, |lapic| lapic.id())),
+        _ => fast::UNKNOWN_SYSCALL,
+    };
+}
@@ -0,0 +1,260 @@
+//! A SysRq-style key for dumping the kernel's state.
+//!
+//! When debugging on real hardware, there's often no way to find out what the
+//! kernel is doing other than what it prints. [`run`] listens for a reserved
+//! key combination, the [`Trigger`], and when it's pressed, logs a snapshot
+//! of the kernel's state to the serial console:
+//!
+//! - each core's run loop counters and idle time,
+//! - heap usage (with the `heap-stats` feature) and free physical frames,
+//! - interrupt counts, and interrupt latency (with the `irq-latency`
+//!   feature).
+//!
+//! The trigger is set with the `sysrq` command line option, and defaults to
+//! Ctrl+Alt+PrintScreen. Key events come from the keyboard mux, so the
+//! trigger can be pressed on a PS/2 keyboard or sent over the serial port.
+//! PrintScreen can't be sent from a terminal, so headless machines need a
+//! different trigger, such as `sysrq=ctrl+\`.
+//!
+//! [`run`] is an ordinary task rather than an interrupt handler, so the dump
+//! can allocate and format freely. The flip side is that it can't report on
+//! a kernel whose scheduler is stuck; that's what the
+//! [watchdog](crate::watchdog) is for.
+use crate::{clock, frame, interrupt, smp};
+use core::fmt;
+use kernel::{
+    services::keyboard::{
+        key_event::{KeyCode, Kind, Modifiers},
+        KeyClient, KeyEvent,
+    },
+    Kernel,
+};
+
+/// Key names accepted by [`Trigger::parse`], other than F1-F12.
+const KEY_NAMES: [(&str, KeyCode); 23] = [
+    ("printscreen", KeyCode::PrintScreen),
+    ("prtsc", KeyCode::PrintScreen),
+    ("sysrq", KeyCode::PrintScreen),
+    ("pause", KeyCode::Pause),
+    ("break", KeyCode::Pause),
+    ("esc", KeyCode::Esc),
+    ("escape", KeyCode::Esc),
+    ("enter", KeyCode::Enter),
+    ("return", KeyCode::Enter),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("space", KeyCode::Char(' ')),
+    ("insert", KeyCode::Insert),
+    ("delete", KeyCode::Delete),
+    ("del", KeyCode::Delete),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+];
+
+/// The key combination which triggers a dump.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Trigger {
+    /// The modifiers which must be held.
+    pub modifiers: Modifiers,
+    /// The key which must be pressed.
+    pub code: KeyCode,
+}
+
+/// Listen for `trigger`, and dump the kernel's state whenever it's pressed.
+///
+/// This runs until the keyboard mux goes away.
+#[tracing::instrument(level = tracing::Level::INFO, skip(k))]
+pub async fn run(k: &'static Kernel, trigger: Trigger) {
+    let mut keys = match KeyClient::from_registry(k, Default::default()).await {
+        Ok(keys) => keys,
+        Err(error) => {
+            tracing::warn!(?error, "can't subscribe to key events, SysRq is disabled");
+            return;
+        }
+    };
+    tracing::info!(%trigger, "SysRq key enabled");
+
+    loop {
+        match keys.next().await {
+            Ok(event) if trigger.matches(&event) => dump(),
+            Ok(_) => {}
+            Err(error) => {
+                tracing::warn!(?error, "key events ended, SysRq is disabled");
+                return;
+            }
+        }
+    }
+}
+
+/// Log a snapshot of the kernel's state.
+pub fn dump() {
+    let uptime = clock::now().since_start();
+    tracing::info!(?uptime, "SysRq: dumping kernel state");
+
+    for core in smp::cores() {
+        let stats = core.stats();
+        tracing::info!(
+            core.apic_id = core.apic_id(),
+            state = %core.state(),
+            ticks = stats.ticks,
+            polled = stats.polled,
+            sleeps = stats.sleeps,
+            busy = ?stats.busy,
+            idle = ?stats.idle,
+            last_cstate = ?stats.last_cstate,
+            sleeping = core.is_sleeping(),
+            "core"
+        );
+        for (cstate, idle) in stats.idle_by_cstate.iter().enumerate() {
+            if !idle.is_zero() {
+                tracing::info!(
+                    core.apic_id = core.apic_id(),
+                    cstate,
+                    ?idle,
+                    "core idle by C-state"
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "heap-stats")]
+    {
+        let state = crate::allocator::AHEAP.state();
+        tracing::info!(
+            allocated_bytes = state.allocated_bytes,
+            free_bytes = state.free_bytes(),
+            total_bytes = state.total_bytes,
+            live_allocs = state.live_alloc_count(),
+            alloc_oom_count = state.alloc_oom_count,
+            "heap"
+        );
+    }
+    if let Some(frames) = frame::stats() {
+        tracing::info!(
+            total_frames = frames.total_frames,
+            free_frames = frames.free_frames,
+            "physical frames"
+        );
+    }
+
+    interrupt::stats::log_stats();
+    #[cfg(feature = "irq-latency")]
+    if let Some(latency) = interrupt::latency::stats() {
+        tracing::info!(?latency, "interrupt latency");
+    }
+}
+
+// === impl Trigger ===
+
+impl Trigger {
+    /// Parse a key combination such as `ctrl+alt+printscreen`.
+    ///
+    /// The combination is zero or more of the modifiers `ctrl`, `alt`,
+    /// `shift`, and `meta`, followed by a key, separated by `+`. The key is
+    /// either a single character, or the name of a key, such as `esc`,
+    /// `pause`, or `f12`. Names are case-insensitive.
+    #[must_use]
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('+');
+        let key = parts.next_back()?;
+        let mut modifiers = Modifiers::new();
+        for name in parts {
+            let is = |names: &[&str]| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+            if is(&["ctrl", "control"]) {
+                modifiers.set(Modifiers::CTRL, true);
+            } else if is(&["alt"]) {
+                modifiers.set(Modifiers::ALT, true);
+            } else if is(&["shift"]) {
+                modifiers.set(Modifiers::SHIFT, true);
+            } else if is(&["meta", "super"]) {
+                modifiers.set(Modifiers::META, true);
+            } else {
+                return None;
+            }
+        }
+        Some(Self {
+            modifiers,
+            code: key_code(key)?,
+        })
+    }
+
+    /// Returns `true` if `event` is a press of this key combination.
+    ///
+    /// Exactly the trigger's modifiers must be held, ignoring Caps Lock and
+    /// Num Lock. Characters are compared case-insensitively, since a
+    /// terminal may send Ctrl+letter combinations as either case.
+    #[must_use]
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        let held = |modifiers: Modifiers| {
+            [
+                Modifiers::CTRL,
+                Modifiers::ALT,
+                Modifiers::SHIFT,
+                Modifiers::META,
+            ]
+            .map(|modifier| modifiers.get(modifier))
+        };
+        let code = match (self.code, event.code) {
+            (KeyCode::Char(a), KeyCode::Char(b)) => a.eq_ignore_ascii_case(&b),
+            (a, b) => a == b,
+        };
+        event.kind == Kind::Pressed && code && held(self.modifiers) == held(event.modifiers)
+    }
+}
+
+impl Default for Trigger {
+    /// Ctrl+Alt+PrintScreen.
+    fn default() -> Self {
+        let mut modifiers = Modifiers::new();
+        modifiers.set(Modifiers::CTRL, true);
+        modifiers.set(Modifiers::ALT, true);
+        Self {
+            modifiers,
+            code: KeyCode::PrintScreen,
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (Modifiers::CTRL, "Ctrl+"),
+            (Modifiers::ALT, "Alt+"),
+            (Modifiers::SHIFT, "Shift+"),
+            (Modifiers::META, "Meta+"),
+        ] {
+            if self.modifiers.get(modifier) {
+                f.write_str(name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(c) => write!(f, "{c}"),
+            code => write!(f, "{code:?}"),
+        }
+    }
+}
+
+/// Returns the key named `name`.
+///
+/// This is called while parsing the command line, before the heap is set up,
+/// so it mustn't allocate.
+fn key_code(name: &str) -> Option<KeyCode> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(c));
+    }
+
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        return Some(code);
+    }
+    match name.strip_prefix(['f', 'F'])?.parse() {
+        Ok(n @ 1..=12) => Some(KeyCode::F(n)),
+        _ => None,
+    }
+}
@@ -0,0 +1,142 @@
+//! Boot-time tests, run under QEMU.
+//!
+//! When the kernel is built with the `boot-tests` feature, it runs each of
+//! the [`TESTS`] once it has booted, logs whether each one passed, and then
+//! exits QEMU through the `isa-debug-exit` device, with a status reporting
+//! whether they all passed. A panic, including one in a test, exits QEMU
+//! with the failure status.
+//!
+//! QEMU must be started with
+//! `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. Writing `value` to the
+//! device's port makes QEMU exit with the status `(value << 1) | 1`, so the
+//! [`ExitCode`]s are chosen so that neither can be mistaken for QEMU exiting
+//! normally (0), or failing on its own (1). The `test` subcommand of the
+//! x86_64 bootimager starts QEMU this way, and interprets its exit status.
+//!
+//! Without the device, writing to its port does nothing, and the kernel keeps
+//! running after the tests.
+use crate::{interrupt, power::QEMU_DEBUG_EXIT_PORT, smp, topology};
+use alloc::{boxed::Box, vec::Vec};
+use core::{future::Future, pin::Pin, time::Duration};
+use hal_x86_64::cpu::Port;
+use kernel::Kernel;
+
+/// A status to exit QEMU with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExitCode {
+    /// Every test passed. QEMU exits with status 33.
+    Success = 0x10,
+    /// A test failed, or the kernel panicked. QEMU exits with status 35.
+    Failure = 0x11,
+}
+
+/// A boot-time test.
+pub struct Test {
+    pub name: &'static str,
+    pub run: fn(&'static Kernel) -> TestFuture,
+}
+
+/// The future returned by a [`Test`], which completes with `true` if it
+/// passed.
+pub type TestFuture = Pin<Box<dyn Future<Output = bool>>>;
+
+/// The tests run by [`run`], in order.
+pub static TESTS: &[Test] = &[
+    Test {
+        name: "allocator",
+        run: |_| Box::pin(async { allocator() }),
+    },
+    Test {
+        name: "timer advances",
+        run: |k| Box::pin(timer_advances(k)),
+    },
+    Test {
+        name: "cores online",
+        run: |_| Box::pin(async { cores_online() }),
+    },
+    Test {
+        name: "dynamic interrupt vectors",
+        run: |_| Box::pin(async { interrupt::vector::smoke_test() }),
+    },
+    Test {
+        name: "spurious interrupts",
+        run: |_| Box::pin(async { interrupt::spurious::smoke_test() }),
+    },
+];
+
+/// Run every test in [`TESTS`], and exit QEMU with the result.
+///
+/// This is spawned on the kernel's scheduler during initialization, so the
+/// tests run once the kernel's run loop has started.
+pub async fn run(k: &'static Kernel) {
+    tracing::info!(tests = TESTS.len(), "running boot tests");
+    let mut failed = 0;
+    for test in TESTS {
+        if (test.run)(k).await {
+            tracing::info!(test = test.name, "boot test ok");
+        } else {
+            tracing::error!(test = test.name, "boot test FAILED");
+            failed += 1;
+        }
+    }
+
+    // give the serial tracing subscriber a chance to send the results before
+    // QEMU goes away.
+    k.sleep(Duration::from_millis(100)).await;
+
+    if failed == 0 {
+        tracing::info!(tests = TESTS.len(), "all boot tests passed");
+        exit_qemu(ExitCode::Success);
+    } else {
+        tracing::error!(failed, tests = TESTS.len(), "boot tests failed");
+        exit_qemu(ExitCode::Failure);
+    }
+    tracing::warn!("no isa-debug-exit device, continuing after boot tests");
+}
+
+/// Exit QEMU with `code`, through its `isa-debug-exit` device.
+///
+/// If QEMU wasn't started with the device (or this isn't QEMU at all), this
+/// returns.
+pub fn exit_qemu(code: ExitCode) {
+    unsafe {
+        // Safety: nothing else is at this port, unless it's the device.
+        Port::at(QEMU_DEBUG_EXIT_PORT).writeb(code as u8);
+    }
+}
+
+/// Allocations of a few sizes can be made, written, and freed.
+fn allocator() -> bool {
+    let boxed = Box::new(0xfeed_u64);
+    let mut vec = Vec::new();
+    if vec.try_reserve_exact(64 * 1024).is_err() {
+        return false;
+    }
+    vec.extend((0..64 * 1024).map(|i: u64| i));
+    let sum: u64 = vec.iter().sum();
+    *boxed == 0xfeed && sum == (64 * 1024) * (64 * 1024 - 1) / 2
+}
+
+/// The timer interrupt fires, and sleeping on the kernel's timer wakes up.
+async fn timer_advances(k: &'static Kernel) -> bool {
+    let ticks = interrupt::timer_ticks();
+    k.sleep(Duration::from_millis(50)).await;
+    interrupt::timer_ticks() > ticks
+}
+
+/// The boot processor's run queue is online, along with any application
+/// processors that have started.
+fn cores_online() -> bool {
+    let online = smp::online_cores().count();
+    let started = smp::cores().count();
+    tracing::info!(
+        online,
+        started,
+        cpus = topology::cpu_count(),
+        "cores online"
+    );
+    smp::current().state() == smp::CoreState::Online
+        && online >= 1
+        && online <= topology::cpu_count()
+}
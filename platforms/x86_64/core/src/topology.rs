@@ -0,0 +1,300 @@
+//! The CPU topology: how the logical CPUs described by the MADT map onto
+//! physical packages, cores, and SMT threads.
+//!
+//! Each logical CPU's APIC ID is made up of bit fields: the SMT thread within
+//! its core in the lowest bits, then the core within its package, and then the
+//! package. The widths of these fields are the same on every CPU in the system,
+//! so they're read once, from the CPUID topology leaves of the boot processor,
+//! and used to decompose the APIC ID of each processor in the MADT.
+//!
+//! The extended topology leaves (`0x1F`, then `0x0B`) are preferred. On older
+//! CPUs without them, the field widths are derived from the number of logical
+//! processors and cores per package reported by the legacy leaves. Any levels
+//! between cores and packages, such as modules or dies, are counted as part of
+//! the core number.
+//!
+//! The topology is discovered by [`init`], while the ACPI tables are parsed
+//! during boot. Until then, only the current CPU is known.
+use crate::{cpu, lapic::LocalApic};
+use acpi::platform::{ProcessorInfo, ProcessorState};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    fmt,
+};
+use kernel::maitake::sync::spin::InitOnce;
+
+/// A logical CPU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cpu {
+    /// The CPU's local APIC ID.
+    pub apic_id: u32,
+    /// The physical package (socket) containing the CPU.
+    pub package: u32,
+    /// The core within the package.
+    pub core: u32,
+    /// The SMT thread within the core.
+    pub thread: u32,
+    /// Whether this is the boot processor.
+    pub is_bsp: bool,
+}
+
+#[derive(Debug)]
+struct Topology {
+    cpus: Box<[Cpu]>,
+    cores: usize,
+    packages: usize,
+}
+
+/// The widths of the APIC ID bit fields.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Shifts {
+    /// The number of bits identifying the SMT thread within a core.
+    smt: u32,
+    /// The number of bits identifying the SMT thread and core within a
+    /// package.
+    package: u32,
+    /// The CPUID leaf the widths came from.
+    source: &'static str,
+}
+
+static TOPOLOGY: InitOnce<Topology> = InitOnce::uninitialized();
+
+// Extended topology level types.
+const LEVEL_INVALID: u32 = 0;
+const LEVEL_SMT: u32 = 1;
+
+/// Discover the topology of the processors in the MADT.
+///
+/// Disabled processors, which can't be started, are skipped.
+pub(crate) fn init(processors: &ProcessorInfo) {
+    let shifts = Shifts::current();
+    let processors = core::iter::once(&processors.boot_processor)
+        .chain(processors.application_processors.iter())
+        .filter(|processor| processor.state != ProcessorState::Disabled);
+    let cpus = processors
+        .map(|processor| shifts.cpu(processor.local_apic_id, !processor.is_ap))
+        .collect();
+    init_from(shifts, cpus);
+}
+
+/// Record the current CPU as the only one, when there's no MADT to find the
+/// others.
+pub(crate) fn init_uniprocessor() {
+    let shifts = Shifts::current();
+    let apic_id = LocalApic::current().map(|lapic| lapic.id()).unwrap_or(0);
+    init_from(shifts, alloc::vec![shifts.cpu(apic_id, true)]);
+}
+
+/// Returns the number of logical CPUs.
+///
+/// Before the topology is discovered, this is 1.
+#[must_use]
+pub fn cpu_count() -> usize {
+    TOPOLOGY
+        .try_get()
+        .map(|topology| topology.cpus.len())
+        .unwrap_or(1)
+}
+
+/// Returns the number of physical cores, in all packages.
+///
+/// Before the topology is discovered, this is 1.
+#[must_use]
+pub fn core_count() -> usize {
+    TOPOLOGY
+        .try_get()
+        .map(|topology| topology.cores)
+        .unwrap_or(1)
+}
+
+/// Returns the number of physical packages (sockets).
+///
+/// Before the topology is discovered, this is 1.
+#[must_use]
+pub fn package_count() -> usize {
+    TOPOLOGY
+        .try_get()
+        .map(|topology| topology.packages)
+        .unwrap_or(1)
+}
+
+/// Returns every logical CPU, starting with the boot processor.
+///
+/// Before the topology is discovered, this is empty.
+#[must_use]
+pub fn cpus() -> &'static [Cpu] {
+    TOPOLOGY
+        .try_get()
+        .map(|topology| &topology.cpus[..])
+        .unwrap_or(&[])
+}
+
+/// Returns the logical CPU with the local APIC ID `apic_id`.
+#[must_use]
+pub fn cpu(apic_id: u32) -> Option<&'static Cpu> {
+    cpus().iter().find(|cpu| cpu.apic_id == apic_id)
+}
+
+/// Returns the logical CPU this is running on.
+#[must_use]
+pub fn current() -> Option<&'static Cpu> {
+    cpu(LocalApic::current()?.id())
+}
+
+fn init_from(shifts: Shifts, mut cpus: Vec<Cpu>) {
+    if let Some(bsp) = cpus.iter().position(|cpu| cpu.is_bsp) {
+        cpus.swap(0, bsp);
+        cpus[1..].sort_unstable_by_key(|cpu| cpu.apic_id);
+    }
+    // count the distinct packages and cores. sorting by APIC ID groups
+    // threads by core, and cores by package.
+    let mut sorted = cpus.clone();
+    sorted.sort_unstable_by_key(|cpu| cpu.apic_id);
+    sorted.dedup_by_key(|cpu| (cpu.package, cpu.core));
+    let cores = sorted.len();
+    sorted.dedup_by_key(|cpu| cpu.package);
+    let packages = sorted.len();
+
+    let topology = Topology {
+        cpus: cpus.into_boxed_slice(),
+        cores,
+        packages,
+    };
+    tracing::info!(
+        cpus = topology.cpus.len(),
+        cores,
+        packages,
+        smt_bits = shifts.smt,
+        package_shift = shifts.package,
+        source = shifts.source,
+        "discovered CPU topology"
+    );
+    for cpu in &topology.cpus[..] {
+        tracing::debug!(%cpu);
+    }
+    TOPOLOGY.init(topology);
+}
+
+// === impl Cpu ===
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            apic_id,
+            package,
+            core,
+            thread,
+            is_bsp,
+        } = self;
+        write!(
+            f,
+            "CPU {apic_id}: package {package}, core {core}, thread {thread}"
+        )?;
+        if *is_bsp {
+            f.write_str(" (BSP)")?;
+        }
+        Ok(())
+    }
+}
+
+// === impl Shifts ===
+
+impl Shifts {
+    /// Read the APIC ID field widths from the current CPU.
+    fn current() -> Self {
+        Self::extended(0x1f)
+            .or_else(|| Self::extended(0x0b))
+            .unwrap_or_else(Self::legacy)
+    }
+
+    /// Read the field widths from an extended topology leaf (`0x0B` or
+    /// `0x1F`), if it's supported.
+    fn extended(leaf: u32) -> Option<Self> {
+        if cpu::features().max_leaf() < leaf {
+            return None;
+        }
+
+        let mut smt = 0;
+        let mut package = None;
+        // there are at most a handful of levels; don't trust the CPU to
+        // terminate the list.
+        for subleaf in 0..8 {
+            let regs = unsafe { __cpuid_count(leaf, subleaf) };
+            let level = (regs.ecx >> 8) & 0xff;
+            // a zero EBX at subleaf 0 means the leaf isn't supported.
+            if level == LEVEL_INVALID || (subleaf == 0 && regs.ebx == 0) {
+                break;
+            }
+            // the shift to the next level's ID.
+            let shift = regs.eax & 0x1f;
+            if level == LEVEL_SMT {
+                smt = shift;
+            }
+            package = Some(shift);
+        }
+
+        Some(Self {
+            smt,
+            package: package?,
+            source: if leaf == 0x1f {
+                "CPUID.1FH"
+            } else {
+                "CPUID.0BH"
+            },
+        })
+    }
+
+    /// Derive the field widths from the number of logical processors and
+    /// cores per package, on CPUs without the extended topology leaves.
+    fn legacy() -> Self {
+        let leaf1 = unsafe { __cpuid(1) };
+        if !cpu::features().has_htt() {
+            // one logical processor per package.
+            return Self {
+                smt: 0,
+                package: 0,
+                source: "CPUID.01H",
+            };
+        }
+        let logical = (leaf1.ebx >> 16) & 0xff;
+        let package = ceil_log2(logical);
+
+        // Intel CPUs report the cores per package in the deterministic cache
+        // parameters leaf. CPUs without it (such as older AMD CPUs, which
+        // don't have SMT) are assumed to have one thread per core.
+        let leaf4 = (cpu::features().max_leaf() >= 4)
+            .then(|| unsafe { __cpuid_count(4, 0) })
+            .filter(|regs| regs.eax & 0x1f != 0);
+        let (cores, source) = match leaf4 {
+            Some(regs) => ((regs.eax >> 26) + 1, "CPUID.04H"),
+            None => (logical, "CPUID.01H"),
+        };
+        let threads_per_core = (logical / cores.max(1)).max(1);
+        Self {
+            smt: ceil_log2(threads_per_core),
+            package: package.max(ceil_log2(cores)),
+            source,
+        }
+    }
+
+    fn cpu(&self, apic_id: u32, is_bsp: bool) -> Cpu {
+        let mask = |bits: u32| 1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1);
+        Cpu {
+            apic_id,
+            package: apic_id.checked_shr(self.package).unwrap_or(0),
+            core: (apic_id >> self.smt) & mask(self.package.saturating_sub(self.smt)),
+            thread: apic_id & mask(self.smt),
+            is_bsp,
+        }
+    }
+}
+
+/// Returns the number of bits needed to hold `n` distinct values.
+fn ceil_log2(n: u32) -> u32 {
+    if n <= 1 {
+        0
+    } else {
+        32 - (n - 1).leading_zeros()
+    }
+}
@@ -1,18 +1,23 @@
-use crate::drivers::framebuf::TextWriter;
+pub mod ring;
+
+use crate::drivers::{
+    console::{Console, ConsoleStyle},
+    framebuf::{BackBuffer, Framebuffer},
+};
 use core::{
+    fmt,
     marker::PhantomData,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicBool, Ordering},
 };
 use embedded_graphics::{
     draw_target::DrawTarget,
-    geometry::Point,
-    mono_font::{MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::{Rgb888, RgbColor},
 };
-use hal_core::framebuffer;
-use hal_x86_64::framebuffer::Framebuffer;
+use hal_core::framebuffer::{self, Draw};
+use hal_x86_64::serial;
 use kernel::{
+    maitake::sync::{blocking::Mutex, spin::Spinlock},
     serial_trace::SerialSubscriber,
     tracing::{
         level_filters::LevelFilter, span, subscriber::Interest, Event, Metadata, Subscriber,
@@ -22,15 +27,73 @@ use mycelium_util::sync::InitOnce;
 
 static SERIAL: InitOnce<SerialSubscriber> = InitOnce::uninitialized();
 
+/// Whether events are still written directly to COM1, which is the case until
+/// the [UART driver](crate::drivers::uart) takes over the port.
+static EARLY_SERIAL: AtomicBool = AtomicBool::new(true);
+
+/// The number of bytes of text which may be buffered while the framebuffer is
+/// not yet ready. Anything past this is dropped.
+const DEFERRED_CAPACITY: usize = 4096;
+
 pub struct TraceSubscriber<F>
 where
     F: Deref<Target = [u8]> + DerefMut + 'static,
 {
-    framebuf: fn() -> Framebuffer<'static, F>,
-    point: AtomicU64,
+    framebuf: fn() -> Framebuffer<F>,
+    ready: fn() -> bool,
+    deferred: Mutex<Deferred, Spinlock>,
+    /// The console events are written to, created once the framebuffer is
+    /// ready and the heap can hold its cells.
+    console: Mutex<Option<Screen>, Spinlock>,
+    style: ConsoleStyle,
+    /// Whether to draw the console to a [`BackBuffer`], rather than straight
+    /// to the framebuffer.
+    double_buffer: bool,
+    /// The most verbose level written to COM1 before the serial tracing
+    /// subscriber is up.
+    early_level: LevelFilter,
     _f: PhantomData<fn(&'static F)>,
 }
 
+/// The console, and the back buffer it's drawn to, if there is one.
+struct Screen {
+    console: Console,
+    back: Option<BackBuffer>,
+}
+
+/// Text written before the framebuffer was ready, to be flushed to the
+/// framebuffer once it becomes ready.
+struct Deferred {
+    buf: [u8; DEFERRED_CAPACITY],
+    len: usize,
+    dropped: usize,
+}
+
+/// Send events to the kernel's [`SerialSubscriber`] from now on, rather than
+/// to the framebuffer and early serial output.
+///
+/// # Panics
+///
+/// If the serial subscriber has already been set.
+pub fn set_serial(subscriber: SerialSubscriber) {
+    SERIAL.init(subscriber);
+}
+
+/// Stop writing events directly to COM1.
+///
+/// This is called by the [UART driver](crate::drivers::uart) before it
+/// reconfigures the port, and waits for any event currently being written to
+/// finish.
+pub fn end_early_serial() {
+    match serial::com1() {
+        Some(com1) => {
+            let _lock = com1.lock();
+            EARLY_SERIAL.store(false, Ordering::Release);
+        }
+        None => EARLY_SERIAL.store(false, Ordering::Release),
+    }
+}
+
 #[inline]
 fn with_serial<T>(f: impl FnOnce(&SerialSubscriber) -> T) -> Option<T> {
     SERIAL.try_get().map(f)
@@ -40,26 +103,78 @@ impl<F> TraceSubscriber<F>
 where
     F: Deref<Target = [u8]> + DerefMut + 'static,
 {
-    pub fn new(framebuf: fn() -> Framebuffer<'static, F>) -> Self {
+    pub fn new(framebuf: fn() -> Framebuffer<F>) -> Self {
         Self {
             framebuf,
-            point: AtomicU64::new(pack_point(Point { x: 10, y: 10 })),
+            ready: || true,
+            deferred: Mutex::new_with_raw_mutex(
+                Deferred {
+                    buf: [0; DEFERRED_CAPACITY],
+                    len: 0,
+                    dropped: 0,
+                },
+                Spinlock::new(),
+            ),
+            console: Mutex::new_with_raw_mutex(None, Spinlock::new()),
+            style: ConsoleStyle::default(),
+            double_buffer: true,
+            early_level: LevelFilter::INFO,
             _f: PhantomData,
         }
     }
-}
 
-fn style(color: Rgb888) -> MonoTextStyle<'static, Rgb888> {
-    MonoTextStyleBuilder::new()
-        .font(&profont::PROFONT_12_POINT)
-        .text_color(color)
-        .build()
+    /// Only write to the framebuffer once `ready` returns `true`.
+    ///
+    /// Until then, events are buffered (up to [`DEFERRED_CAPACITY`] bytes),
+    /// and are flushed to the framebuffer before the first event written once
+    /// it's ready.
+    pub fn with_readiness(self, ready: fn() -> bool) -> Self {
+        Self { ready, ..self }
+    }
+
+    /// Draw events with the given font and colors.
+    pub fn with_console_style(self, style: ConsoleStyle) -> Self {
+        Self { style, ..self }
+    }
+
+    /// Draw the console to a [`BackBuffer`] in RAM, and copy only what changed
+    /// to the framebuffer, rather than drawing straight to the framebuffer.
+    /// Defaults to `true`.
+    ///
+    /// Scrolling is much faster with a back buffer, as the framebuffer never
+    /// has to be read back. If the back buffer can't be allocated, the
+    /// console draws straight to the framebuffer anyway.
+    pub fn with_double_buffering(self, double_buffer: bool) -> Self {
+        Self {
+            double_buffer,
+            ..self
+        }
+    }
+
+    /// Write events up to `early_level` directly to COM1, if it's present,
+    /// until the UART driver takes over the port. Defaults to
+    /// [`LevelFilter::INFO`].
+    pub fn with_early_serial_level(self, early_level: LevelFilter) -> Self {
+        Self {
+            early_level,
+            ..self
+        }
+    }
+
+    fn defer(&self, lvl_str: &str, event: &Event<'_>) {
+        use core::fmt::Write;
+
+        let mut deferred = self.deferred.lock();
+        let _ = write!(&mut *deferred, "{lvl_str} {}:", event.metadata().target());
+        event.record(&mut FieldVisitor(&mut *deferred));
+        let _ = writeln!(&mut *deferred);
+    }
 }
 
 impl<F> Subscriber for TraceSubscriber<F>
 where
     F: Deref<Target = [u8]> + DerefMut + 'static,
-    for<'a> framebuffer::DrawTarget<&'a mut Framebuffer<'static, F>>: DrawTarget<Color = Rgb888>, // jesus christ...
+    for<'a> framebuffer::DrawTarget<&'a mut Framebuffer<F>>: DrawTarget<Color = Rgb888>, // jesus christ...
 {
     fn enabled(&self, metadata: &Metadata<'_>) -> bool {
         with_serial(|serial| serial.enabled(metadata)).unwrap_or(!metadata.is_span())
@@ -97,40 +212,98 @@ where
     fn event(&self, event: &Event<'_>) {
         use core::fmt::Write;
 
+        let meta = event.metadata();
+        let (lvl_color, lvl_str) = match *meta.level() {
+            tracing::Level::TRACE => (Rgb888::BLUE, "TRCE"),
+            tracing::Level::DEBUG => (Rgb888::CYAN, "DBUG"),
+            tracing::Level::INFO => (Rgb888::GREEN, "INFO"),
+            tracing::Level::WARN => (Rgb888::YELLOW, "WARN"),
+            tracing::Level::ERROR => (Rgb888::RED, "ERR!"),
+        };
+        // always keep the most recent events, for the panic handler.
+        ring::record(lvl_str, event);
+
         if with_serial(|serial| serial.event(event)).is_none() {
-            let point = unpack_point(self.point.load(Ordering::Acquire));
-            let mut framebuf = (self.framebuf)();
-            let meta = event.metadata();
-            let (lvl_color, lvl_str) = match *meta.level() {
-                tracing::Level::TRACE => (Rgb888::BLUE, "TRCE"),
-                tracing::Level::DEBUG => (Rgb888::CYAN, "DBUG"),
-                tracing::Level::INFO => (Rgb888::GREEN, "INFO"),
-                tracing::Level::WARN => (Rgb888::YELLOW, "WARN"),
-                tracing::Level::ERROR => (Rgb888::RED, "ERR!"),
+            if *meta.level() <= self.early_level && EARLY_SERIAL.load(Ordering::Acquire) {
+                if let Some(com1) = serial::com1() {
+                    let mut com1 = com1.lock();
+                    // the UART driver may have taken over the port while we
+                    // waited for the lock.
+                    if EARLY_SERIAL.load(Ordering::Acquire) {
+                        let _ = write!(&mut com1, "{lvl_str} {}:", meta.target());
+                        event.record(&mut FieldVisitor(&mut com1));
+                        let _ = writeln!(&mut com1);
+                    }
+                }
+            }
+
+            if !(self.ready)() {
+                // the display isn't ready yet, so stash the event until it is.
+                self.defer(lvl_str, event);
+                return;
+            }
+
+            let mut screen = self.console.lock();
+            if screen.is_none() {
+                let (width, height) = {
+                    let framebuf = (self.framebuf)();
+                    (framebuf.width(), framebuf.height())
+                };
+                *screen = Console::try_new(width, height, self.style).map(|console| Screen {
+                    console,
+                    back: self
+                        .double_buffer
+                        .then(|| BackBuffer::try_new(width, height))
+                        .flatten(),
+                });
+            }
+            let Some(Screen { console, back }) = screen.as_mut() else {
+                // the heap isn't ready for the console's cells yet.
+                self.defer(lvl_str, event);
+                return;
             };
 
+            // flush anything written before the framebuffer was ready.
+            {
+                let mut deferred = self.deferred.lock();
+                if deferred.len > 0 || deferred.dropped > 0 {
+                    console.set_color(self.style.text);
+                    console.write_str(deferred.as_str()).unwrap();
+                    if deferred.dropped > 0 {
+                        writeln!(
+                            console,
+                            "... {} bytes of early output dropped",
+                            deferred.dropped
+                        )
+                        .unwrap();
+                    }
+                    deferred.len = 0;
+                    deferred.dropped = 0;
+                }
+            }
+
             // write the level in the per-level color.
-            let mut writer = TextWriter::new(&mut framebuf, style(lvl_color), point);
-            writer.write_str(lvl_str).unwrap();
+            console.set_color(lvl_color);
+            console.write_str(lvl_str).unwrap();
 
-            writer.set_style(style(Rgb888::new(128, 128, 128)));
-            write!(&mut writer, " {}:", meta.target()).unwrap();
+            console.set_color(self.style.text);
+            write!(console, " {}:", meta.target()).unwrap();
 
-            writer.set_style(style(Rgb888::WHITE));
+            console.set_color(Rgb888::WHITE);
 
-            event.record(
-                &mut (|field: &tracing::field::Field, value: &'_ (dyn core::fmt::Debug + '_)| {
-                    if field.name() == "message" {
-                        write!(&mut writer, " {value:?}").unwrap();
-                    } else {
-                        write!(&mut writer, " {field}={value:?}").unwrap();
-                    }
-                }) as &mut dyn tracing::field::Visit,
-            );
-            writeln!(&mut writer).unwrap();
+            event.record(&mut FieldVisitor(&mut *console));
+            writeln!(console).unwrap();
 
-            self.point
-                .store(pack_point(writer.next_point()), Ordering::Release);
+            match back {
+                Some(back) => {
+                    let _ = console.flush(back);
+                    back.present(&mut (self.framebuf)());
+                }
+                None => {
+                    let _ = console.flush(&mut (self.framebuf)());
+                }
+            }
+            ring::mark_shown();
         }
     }
 
@@ -145,13 +318,39 @@ where
     }
 }
 
-const fn pack_point(Point { x, y }: Point) -> u64 {
-    (x as u64) << 32 | y as u64
+// === impl Deferred ===
+
+impl Deferred {
+    fn as_str(&self) -> &str {
+        // Safety: `write_str` only ever appends whole `str`s, so the buffer is
+        // always valid UTF-8.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
 }
 
-const fn unpack_point(u: u64) -> Point {
-    const Y_MASK: u64 = u32::MAX as u64;
-    let x = (u >> 32) as i32;
-    let y = (u & Y_MASK) as i32;
-    Point { x, y }
+impl fmt::Write for Deferred {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > DEFERRED_CAPACITY {
+            // drop the whole string rather than splitting a UTF-8 sequence.
+            self.dropped += s.len();
+        } else {
+            self.buf[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// Formats an event's fields to a [`fmt::Write`].
+struct FieldVisitor<'w, W>(&'w mut W);
+
+impl<W: fmt::Write> tracing::field::Visit for FieldVisitor<'_, W> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {value:?}");
+        } else {
+            let _ = write!(self.0, " {field}={value:?}");
+        }
+    }
 }
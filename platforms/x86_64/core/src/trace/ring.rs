@@ -0,0 +1,150 @@
+//! An in-memory ring buffer of recent events, for post-mortem debugging.
+//!
+//! Every event the [`TraceSubscriber`](super::TraceSubscriber) sees is
+//! formatted into a fixed-size ring buffer, whether or not the framebuffer or
+//! serial output is up yet. When the ring is full, the oldest events are
+//! overwritten. The panic handler [dumps](dump) the ring, so the events
+//! leading up to a panic can be seen even if it happens during early boot, or
+//! while the serial subscriber still had events queued.
+//!
+//! Events are recorded with [`Mutex::try_lock`], so recording an event from
+//! an interrupt handler which preempted another recording never deadlocks;
+//! the interrupt's event is dropped instead. The panic handler
+//! [force-unlocks](force_unlock) the ring before dumping it.
+use super::FieldVisitor;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use kernel::{
+    maitake::sync::{blocking::Mutex, spin::Spinlock},
+    tracing::Event,
+};
+
+/// The size of the ring, in bytes.
+pub const CAPACITY: usize = 8 * 1024;
+
+static RING: Mutex<Ring, Spinlock> = Mutex::new_with_raw_mutex(
+    Ring {
+        buf: [0; CAPACITY],
+        head: 0,
+        shown: 0,
+    },
+    Spinlock::new(),
+);
+
+/// The number of events dropped because the ring was locked.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+struct Ring {
+    buf: [u8; CAPACITY],
+    /// The total number of bytes ever written. The ring holds the last
+    /// `CAPACITY` of them.
+    head: u64,
+    /// The value of `head` when the console was last drawn, so events before
+    /// that have already been seen on the framebuffer.
+    shown: u64,
+}
+
+/// Record an event in the ring.
+pub(super) fn record(level: &str, event: &Event<'_>) {
+    let Some(mut ring) = RING.try_lock() else {
+        // don't spin here: if an interrupt handler preempted a recording on
+        // this core, that would never finish.
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        let _ = writeln!(ring, "... {dropped} events not recorded");
+    }
+    let _ = write!(ring, "{level} {}:", event.metadata().target());
+    event.record(&mut FieldVisitor(&mut *ring));
+    let _ = writeln!(ring);
+}
+
+/// Note that everything recorded so far has been drawn on the framebuffer.
+pub(super) fn mark_shown() {
+    if let Some(mut ring) = RING.try_lock() {
+        ring.shown = ring.head;
+    }
+}
+
+/// Write every event in the ring to `writer`, oldest first.
+///
+/// If the oldest event has been partly overwritten, it's skipped.
+pub fn dump(writer: &mut impl Write) -> fmt::Result {
+    RING.lock().write_since(0, writer)
+}
+
+/// Write the events in the ring which haven't been drawn on the framebuffer
+/// to `writer`, oldest first.
+///
+/// If the framebuffer console never came up, this is every event in the
+/// ring.
+pub fn dump_unshown(writer: &mut impl Write) -> fmt::Result {
+    let ring = RING.lock();
+    ring.write_since(ring.shown, writer)
+}
+
+/// Forcibly unlock the ring.
+///
+/// # Safety
+///
+/// This forcibly unlocks a potentially-locked mutex, violating mutual
+/// exclusion! This should only be called in conditions where no other CPU core
+/// will *ever* attempt to access the ring again (such as while panicking).
+pub unsafe fn force_unlock() {
+    RING.force_unlock();
+}
+
+// === impl Ring ===
+
+impl Ring {
+    fn write_since(&self, start: u64, writer: &mut impl Write) -> fmt::Result {
+        let oldest = self.head.saturating_sub(CAPACITY as u64);
+        let mut start = start.max(oldest);
+        if start == oldest && oldest > 0 {
+            // the ring has wrapped, so the oldest line is probably partial.
+            match (start..self.head).find(|&pos| self.byte(pos) == b'\n') {
+                Some(newline) => start = newline + 1,
+                None => return Ok(()),
+            }
+        }
+
+        // write the (at most two) contiguous runs of the ring between `start`
+        // and `head`.
+        let from = (start % CAPACITY as u64) as usize;
+        let len = (self.head - start) as usize;
+        let first = &self.buf[from..(from + len).min(CAPACITY)];
+        let second = &self.buf[..len - first.len()];
+        for run in [first, second] {
+            for chunk in run.utf8_chunks() {
+                writer.write_str(chunk.valid())?;
+                if !chunk.invalid().is_empty() {
+                    // a character was split where the ring wraps around.
+                    writer.write_char(char::REPLACEMENT_CHARACTER)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn byte(&self, pos: u64) -> u8 {
+        self.buf[(pos % CAPACITY as u64) as usize]
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // only the last `CAPACITY` bytes of a huge string could be kept.
+        let skip = s.len().saturating_sub(CAPACITY);
+        self.head += skip as u64;
+        for &byte in &s.as_bytes()[skip..] {
+            let at = (self.head % CAPACITY as u64) as usize;
+            self.buf[at] = byte;
+            self.head += 1;
+        }
+        Ok(())
+    }
+}
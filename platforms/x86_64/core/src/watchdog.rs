@@ -0,0 +1,120 @@
+//! A software watchdog for hung run loops.
+//!
+//! Each core's run loop [feeds](feed) the watchdog every time around, and
+//! every periodic timer interrupt [checks](check) when each core last made
+//! progress. If a core which isn't idle hasn't made progress for longer than
+//! the threshold set by the `watchdog` command line option, a warning is
+//! logged, once per stall. This catches tasks and drivers which spin or
+//! deadlock, which would otherwise just look like a frozen machine.
+//!
+//! When the stalled core is the one handling the timer interrupt, the warning
+//! includes a [`Backtrace`] of the code the interrupt preempted, which is
+//! where the core is stuck. Other stalled cores are sent an
+//! [NMI](interrupt::nmi), which interrupts them even with interrupts
+//! disabled, and the snapshot of the stuck code it takes is logged on the
+//! next check.
+//!
+//! The watchdog runs in the timer interrupt, so it can't catch a hang with
+//! interrupts disabled on the core which takes that interrupt.
+use crate::{backtrace::Backtrace, interrupt, lapic::LocalApic, smp};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The number of cores the watchdog can track, indexed by local APIC ID.
+const MAX_CORES: usize = 16;
+
+/// The stall threshold, in timer ticks, or 0 if the watchdog is disabled.
+static THRESHOLD_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The interval between timer ticks, in microseconds.
+static TICK_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// The timer tick at which each core last made progress, plus one, or 0 if
+/// the core's run loop hasn't started.
+static PROGRESS: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// The progress stamp at which each core's current stall was reported, so
+/// that each stall is only reported once.
+static REPORTED: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// Enable the watchdog, warning about cores which make no progress for
+/// `threshold`, given timer interrupts `timer_interval` apart.
+///
+/// If `threshold` is `None`, the watchdog is disabled.
+pub fn init(threshold: Option<Duration>, timer_interval: Duration) {
+    let Some(threshold) = threshold else {
+        tracing::info!("watchdog disabled");
+        return;
+    };
+    let ticks = threshold
+        .as_nanos()
+        .div_ceil(timer_interval.as_nanos().max(1))
+        .max(1) as u64;
+    TICK_MICROS.store(timer_interval.as_micros() as u64, Ordering::Relaxed);
+    THRESHOLD_TICKS.store(ticks, Ordering::Relaxed);
+    tracing::info!(?threshold, ticks, "watchdog enabled");
+}
+
+/// Record that the core with local APIC ID `apic_id` is making progress.
+///
+/// Run loops call this every time around.
+#[inline]
+pub fn feed(apic_id: u32) {
+    if let Some(progress) = PROGRESS.get(apic_id as usize) {
+        progress.store(interrupt::timer_ticks() + 1, Ordering::Relaxed);
+    }
+}
+
+/// Check every running core for stalls, at timer tick `now`.
+///
+/// This is called from the periodic timer interrupt.
+pub(crate) fn check(now: u64) {
+    let threshold = THRESHOLD_TICKS.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return;
+    }
+
+    let current = LocalApic::current().map(|lapic| lapic.id());
+    for core in smp::online_cores() {
+        let apic_id = core.apic_id();
+        if let Some(snapshot) = interrupt::nmi::take_snapshot(apic_id) {
+            tracing::warn!(core = apic_id, %snapshot, "watchdog: stalled core's state");
+        }
+        let Some(progress) = PROGRESS.get(apic_id as usize) else {
+            continue;
+        };
+        let stamp = progress.load(Ordering::Relaxed);
+        // idle cores aren't expected to make progress.
+        if stamp == 0 || core.is_sleeping() {
+            continue;
+        }
+        let stalled = now.saturating_sub(stamp - 1);
+        if stalled < threshold || REPORTED[apic_id as usize].swap(stamp, Ordering::Relaxed) == stamp
+        {
+            continue;
+        }
+
+        let stalled_ms = stalled.saturating_mul(TICK_MICROS.load(Ordering::Relaxed)) / 1_000;
+        if current == Some(apic_id) {
+            // the timer interrupt preempted the stuck code, so it's on this
+            // stack.
+            let backtrace = Backtrace::capture();
+            tracing::warn!(
+                core = apic_id,
+                stalled_ms,
+                %backtrace,
+                "watchdog: core's run loop has stopped making progress"
+            );
+        } else {
+            let nmi_sent = interrupt::nmi::request_dump(apic_id);
+            tracing::warn!(
+                core = apic_id,
+                stalled_ms,
+                nmi_sent,
+                "watchdog: core's run loop has stopped making progress"
+            );
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Integrity checks for messages sent through the mailbox rings.
+//!
+//! Each message written to the `u2k` or `k2u` ring is prefixed with a
+//! [`HEADER_LEN`]-byte header:
+//!
+//! | Bytes  | Contents                                        |
+//! |--------|-------------------------------------------------|
+//! | 0..2   | [`MAGIC`]                                       |
+//! | 2..6   | the length of the payload, little endian        |
+//! | 6..10  | the [CRC-32] of the payload, little endian      |
+//!
+//! followed by the postcard-encoded payload. The writer fills in the header
+//! with [`seal`], and the reader checks it with [`open`] before decoding the
+//! payload. A frame which was corrupted in the ring would otherwise either
+//! fail to decode, with no way to tell why, or decode as a different message.
+//!
+//! The header is part of the transport, not of the [`syscall`](crate::syscall)
+//! types, so both sides must agree on it before they can exchange anything,
+//! including the ABI version handshake.
+//!
+//! [CRC-32]: https://en.wikipedia.org/wiki/Cyclic_redundancy_check
+
+/// The first two bytes of every frame.
+pub const MAGIC: [u8; 2] = *b"MF";
+
+/// The length of a frame's header, in bytes.
+pub const HEADER_LEN: usize = 10;
+
+/// Errors returned by [`open`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// The frame is too short to hold a header.
+    TooShort {
+        /// The length of the frame, in bytes.
+        len: usize,
+    },
+    /// The frame doesn't start with [`MAGIC`].
+    BadMagic([u8; 2]),
+    /// The length in the header doesn't match the length of the payload.
+    LengthMismatch {
+        /// The payload length recorded in the header.
+        header: u32,
+        /// The actual length of the payload.
+        actual: usize,
+    },
+    /// The payload's checksum doesn't match the one in the header, so the
+    /// payload (or the header) was corrupted.
+    ChecksumMismatch {
+        /// The checksum recorded in the header.
+        header: u32,
+        /// The checksum of the payload as received.
+        actual: u32,
+    },
+}
+
+/// Returns the length of a frame holding a payload of `payload_len` bytes.
+#[must_use]
+pub const fn frame_len(payload_len: usize) -> usize {
+    HEADER_LEN + payload_len
+}
+
+/// Fill in the header of `frame`, for the payload in the rest of it.
+///
+/// # Panics
+///
+/// If `frame` is shorter than [`HEADER_LEN`].
+pub fn seal(frame: &mut [u8]) {
+    let (header, payload) = frame.split_at_mut(HEADER_LEN);
+    // frames are limited by the size of the rings, which is far below 4 GiB.
+    let len = payload.len() as u32;
+    header[0..2].copy_from_slice(&MAGIC);
+    header[2..6].copy_from_slice(&len.to_le_bytes());
+    header[6..10].copy_from_slice(&crc32(payload).to_le_bytes());
+}
+
+/// Check the header of `frame`, returning its payload if the header is valid.
+pub fn open(frame: &[u8]) -> Result<&[u8], FrameError> {
+    if frame.len() < HEADER_LEN {
+        return Err(FrameError::TooShort { len: frame.len() });
+    }
+    let (header, payload) = frame.split_at(HEADER_LEN);
+    let magic = [header[0], header[1]];
+    if magic != MAGIC {
+        return Err(FrameError::BadMagic(magic));
+    }
+    let len = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+    if len as usize != payload.len() {
+        return Err(FrameError::LengthMismatch {
+            header: len,
+            actual: payload.len(),
+        });
+    }
+    let crc = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+    let actual = crc32(payload);
+    if crc != actual {
+        return Err(FrameError::ChecksumMismatch {
+            header: crc,
+            actual,
+        });
+    }
+    Ok(payload)
+}
+
+/// Returns the CRC-32 (IEEE 802.3) checksum of `bytes`.
+#[must_use]
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The lookup table for [`crc32`], for the reflected polynomial `0xEDB88320`.
+static CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::{KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader};
+
+    /// Returns a sealed frame holding a response, as the kernel sends it.
+    fn response_frame() -> std::vec::Vec<u8> {
+        let rsp = KernelMsg::Response(KernelResponse {
+            header: KernelResponseHeader { nonce: 1 },
+            body: KernelResponseBody::Pong(0xdead_beef),
+        });
+        let mut buf = [0; 64];
+        let payload = postcard::to_slice(&rsp, &mut buf).expect("message must serialize");
+        let mut bytes = std::vec![0; frame_len(payload.len())];
+        bytes[HEADER_LEN..].copy_from_slice(payload);
+        seal(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        let mut bytes = response_frame();
+        assert!(open(&bytes).is_ok());
+
+        // a bit flipped in the payload...
+        bytes[HEADER_LEN + 3] ^= 0x10;
+        let error = open(&bytes).expect_err("corrupted frame must be rejected");
+        assert!(
+            matches!(error, FrameError::ChecksumMismatch { .. }),
+            "expected a checksum mismatch, got {error:?}"
+        );
+        bytes[HEADER_LEN + 3] ^= 0x10;
+
+        // ...or in the header's checksum is caught...
+        bytes[HEADER_LEN - 1] ^= 0x01;
+        assert!(matches!(
+            open(&bytes),
+            Err(FrameError::ChecksumMismatch { .. })
+        ));
+        bytes[HEADER_LEN - 1] ^= 0x01;
+
+        // ...and so is one in its length or magic, without reading the payload.
+        bytes[2] ^= 0x01;
+        assert!(matches!(
+            open(&bytes),
+            Err(FrameError::LengthMismatch { .. })
+        ));
+        bytes[2] ^= 0x01;
+        bytes[0] ^= 0x01;
+        assert!(matches!(open(&bytes), Err(FrameError::BadMagic(_))));
+        assert_eq!(
+            open(&bytes[..HEADER_LEN - 1]),
+            Err(FrameError::TooShort {
+                len: HEADER_LEN - 1
+            })
+        );
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        // the standard check value for CRC-32/ISO-HDLC.
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+}
@@ -0,0 +1,61 @@
+//! Writing text to the console.
+//!
+//! Userspace writes text with [`UserRequestBody::ConsoleWrite`], and the
+//! kernel copies it to each of its console sinks, such as a serial port or
+//! the framebuffer. The bytes are carried in the request itself, so each
+//! request holds at most [`CONSOLE_WRITE_MAX`] of them. Longer text is
+//! written with several requests, each resuming after the number of bytes
+//! the previous one reported as written.
+//!
+//! [`UserRequestBody::ConsoleWrite`]: super::UserRequestBody::ConsoleWrite
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of bytes in a single [`ConsoleWriteRequest`].
+///
+/// This is chosen so that a full request fits in a 128 byte mailbox message.
+pub const CONSOLE_WRITE_MAX: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ConsoleWriteRequest {
+    /// The number of bytes of `bytes` to write.
+    pub len: u8,
+    pub bytes: [u8; CONSOLE_WRITE_MAX],
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ConsoleWriteResponse {
+    /// The number of bytes written.
+    pub written: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum ConsoleError {
+    /// The platform hasn't registered a console.
+    NoConsole,
+    /// The request's `len` is more than [`CONSOLE_WRITE_MAX`].
+    TooLong,
+}
+
+impl ConsoleWriteRequest {
+    /// Returns a request writing as much of `bytes` as fits, along with the
+    /// rest of `bytes`, which must be written by later requests.
+    #[must_use]
+    pub fn new(bytes: &[u8]) -> (Self, &[u8]) {
+        let (now, rest) = bytes.split_at(bytes.len().min(CONSOLE_WRITE_MAX));
+        let mut req = Self {
+            len: now.len() as u8,
+            bytes: [0; CONSOLE_WRITE_MAX],
+        };
+        req.bytes[..now.len()].copy_from_slice(now);
+        (req, rest)
+    }
+
+    /// Returns the bytes to write, or `None` if `len` is out of range.
+    #[must_use]
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        self.bytes.get(..self.len as usize)
+    }
+}
@@ -0,0 +1,44 @@
+//! Register-based fast system calls.
+//!
+//! Most requests are sent through the mailbox rings as [`UserRequest`]s,
+//! which can carry structured data, and may be answered asynchronously. A
+//! few queries are so small and so latency-critical that serializing them
+//! through a ring costs more than the query itself. On platforms which
+//! support it (currently x86_64, using `syscall`/`sysret`), these can be made
+//! with a single trap instead, passing everything in registers:
+//!
+//! - The fast syscall number is passed in `rax`, and up to six arguments in
+//!   `rdi`, `rsi`, `rdx`, `r10`, `r8`, and `r9`.
+//! - The result is returned in `rax`. All other registers are preserved,
+//!   except `rcx` and `r11`, which the `syscall` instruction clobbers.
+//!
+//! Fast syscalls always complete immediately, and never block. Anything that
+//! may wait, allocate, or needs more than a few words of input or output goes
+//! through the mailbox.
+//!
+//! | number            | result                                        |
+//! |-------------------|-----------------------------------------------|
+//! | [`ABI_VERSION`]   | the kernel's [`hello::ABI_VERSION`]           |
+//! | [`UPTIME_MICROS`] | microseconds since the kernel's timer started |
+//! | [`CORE_ID`]       | the ID of the CPU core the caller is running on |
+//!
+//! An unknown number returns [`UNKNOWN_SYSCALL`].
+//!
+//! [`UserRequest`]: super::UserRequest
+//! [`hello::ABI_VERSION`]: super::hello::ABI_VERSION
+
+/// Returns the kernel's [`ABI_VERSION`](super::hello::ABI_VERSION).
+///
+/// This doesn't replace the [`hello`](super::hello) handshake, which
+/// userspace must still perform before using the mailbox.
+pub const ABI_VERSION: u64 = 0;
+
+/// Returns the number of microseconds since the kernel's timer started.
+pub const UPTIME_MICROS: u64 = 1;
+
+/// Returns the ID of the CPU core the caller is running on. On x86_64, this
+/// is the core's local APIC ID.
+pub const CORE_ID: u64 = 2;
+
+/// The result of a fast syscall with an unknown number.
+pub const UNKNOWN_SYSCALL: u64 = u64::MAX;
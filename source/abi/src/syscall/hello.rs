@@ -0,0 +1,49 @@
+//! The ABI version handshake.
+//!
+//! Userspace and the kernel must agree on the layout of every type in
+//! [`syscall`](super), or messages between them will fail to decode, or
+//! worse, decode as something else. Before sending anything else, userspace
+//! sends a [`UserRequestBody::Hello`] with the [`ABI_VERSION`] it was built
+//! with. The kernel responds with its own version if it supports the
+//! caller's, or with [`HelloError::AbiMismatch`] if it doesn't.
+//!
+//! The encoding of the handshake itself must never change, so that a
+//! mismatch can always be reported: `Hello` is never moved within
+//! [`UserRequestBody`] or [`KernelResponseBody`], and new variants are only
+//! ever added after it.
+//!
+//! [`UserRequestBody`]: super::UserRequestBody
+//! [`UserRequestBody::Hello`]: super::UserRequestBody::Hello
+//! [`KernelResponseBody`]: super::KernelResponseBody
+use serde::{Deserialize, Serialize};
+
+/// The version of the syscall ABI.
+///
+/// This must be incremented whenever a non-additive change is made to the
+/// types in [`syscall`](super).
+pub const ABI_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct HelloRequest {
+    /// The ABI version the caller was built with. This should generally be
+    /// [`ABI_VERSION`].
+    pub abi_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct HelloResponse {
+    /// The ABI version the kernel will use to talk to the caller.
+    pub abi_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum HelloError {
+    /// The kernel doesn't support the caller's ABI version.
+    AbiMismatch {
+        /// The ABI version the kernel supports.
+        supported: u32,
+    },
+}
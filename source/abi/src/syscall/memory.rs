@@ -0,0 +1,71 @@
+//! Querying the boot memory map.
+//!
+//! Userspace may request the memory map the kernel was booted with using
+//! [`UserRequestBody::QueryMemoryMap`]. The map may have more regions than
+//! fit in a single mailbox message, so it is returned one page of at most
+//! [`MEMORY_MAP_PAGE_LEN`] regions at a time.
+//!
+//! ## Pagination
+//!
+//! Regions are listed in the order the bootloader reported them, and the map
+//! never changes once the kernel has booted. To read the whole map:
+//!
+//! 1. Send a [`QueryMemoryMapRequest`] with a `cursor` of `0`.
+//! 2. If the [`MemoryMapPage::next`] field is `Some(cursor)`, send another
+//!    request with that `cursor`, and repeat.
+//! 3. When `next` is `None`, the map is complete.
+//!
+//! A `cursor` past the end of the map returns an empty page. If the platform
+//! didn't provide a memory map, the map is empty.
+//!
+//! [`UserRequestBody::QueryMemoryMap`]: super::UserRequestBody::QueryMemoryMap
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of regions returned in a single [`MemoryMapPage`].
+///
+/// This is chosen so that a full page fits in a 128 byte mailbox message,
+/// even when every region's base and length take their largest encoding.
+pub const MEMORY_MAP_PAGE_LEN: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct QueryMemoryMapRequest {
+    /// The index of the first region to return.
+    pub cursor: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct MemoryMapPage {
+    /// The total number of regions in the memory map.
+    pub total: u32,
+    /// The cursor to request the next page with, or `None` if this is the
+    /// last page.
+    pub next: Option<u32>,
+    /// The regions on this page. Entries after the last region on the page
+    /// are `None`.
+    pub regions: [Option<MemoryRegion>; MEMORY_MAP_PAGE_LEN],
+}
+
+/// A region of physical memory.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct MemoryRegion {
+    pub kind: MemoryRegionKind,
+    /// The physical address of the start of the region.
+    pub base: u64,
+    /// The length of the region, in bytes.
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum MemoryRegionKind {
+    /// Free memory, available to the kernel's allocators.
+    Usable,
+    /// Memory used by the bootloader, which may be reclaimed once the kernel
+    /// has booted.
+    Bootloader,
+    /// Memory which is reserved, or whose use the bootloader didn't report.
+    Reserved,
+}
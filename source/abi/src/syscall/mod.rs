@@ -14,6 +14,10 @@
 //! Care must be taken when modifying these types! Non-additive changes,
 //! including ANY field reordering **MUST** be considered a breaking change!
 //!
+//! Any breaking change must also increment [`hello::ABI_VERSION`], so that
+//! userspace built against the old types is rejected by the version
+//! handshake, rather than failing to decode messages later.
+//!
 //! I have chosen NOT to mark these enums as `#[non_exhaustive]` as
 //! Serde will already fail deserialization on an unknown enum variant.
 //!
@@ -22,7 +26,14 @@
 //! moment. If this is important to you, pin the exact `common` crate version
 //! you plan to support, or open an issue to discuss changing this policy.
 
+pub mod console;
+pub mod fast;
+pub mod hello;
+pub mod memory;
 pub mod serial;
+pub mod services;
+pub mod tasks;
+pub mod time;
 
 use serde::{Deserialize, Serialize};
 
@@ -30,9 +41,15 @@ use serde::{Deserialize, Serialize};
 // kernelspace driver. I'm not sure this is the right abstraction.
 //
 // TODO: This MUST be kept in sync with UserRequestBody!
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DriverKind {
     Serial,
+    Console,
+    Hello,
+    Memory,
+    Services,
+    Tasks,
+    Time,
 
     // I'm not sure if I actually want to keep the "driverkind" paradigm.
     Todo,
@@ -45,9 +62,20 @@ pub struct UserRequest {
     pub body: UserRequestBody,
 }
 
+/// A [`UserRequestHeader::nonce`] marking a request which expects no
+/// response.
+///
+/// The kernel processes such requests as usual, but discards any reply to
+/// them rather than sending it to userspace. Userspace must never use this
+/// nonce for a request which expects a response.
+pub const NO_RESPONSE_NONCE: u32 = u32::MAX;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct UserRequestHeader {
+    /// Identifies the request, so that its response can be matched to it.
+    ///
+    /// If this is [`NO_RESPONSE_NONCE`], no response is sent.
     pub nonce: u32,
 }
 
@@ -55,12 +83,51 @@ pub struct UserRequestHeader {
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum UserRequestBody {
     Serial(serial::SerialRequest),
+    ListServices(services::ListServicesRequest),
+    ServiceLatency(services::ServiceLatencyRequest),
+    /// Sleep for a duration. The kernel responds when it has elapsed.
+    Sleep(time::SleepRequest),
+    /// Cancel an earlier [`UserRequestBody::Sleep`].
+    ///
+    /// This expects no response, and should be sent with
+    /// [`NO_RESPONSE_NONCE`].
+    CancelSleep(time::CancelSleepRequest),
+    /// Spawn a task from one of the kernel's entry points.
+    Spawn(tasks::SpawnRequest),
+    /// Query the status of a task spawned by [`UserRequestBody::Spawn`].
+    TaskStatus(tasks::TaskStatusRequest),
+    /// Negotiate the ABI version. See [`hello`] for details.
+    Hello(hello::HelloRequest),
+    /// Read a page of the boot memory map. See [`memory`] for details.
+    QueryMemoryMap(memory::QueryMemoryMapRequest),
+    /// Check that the kernel is still responding. The kernel responds with a
+    /// [`KernelResponseBody::Pong`] carrying the same sequence number.
+    Ping(u32),
+    /// Write text to the console. See [`console`] for details.
+    ConsoleWrite(console::ConsoleWriteRequest),
 }
 
 impl UserRequest {
     pub fn driver_kind(&self) -> DriverKind {
-        match self.body {
+        self.body.driver_kind()
+    }
+}
+
+impl UserRequestBody {
+    pub fn driver_kind(&self) -> DriverKind {
+        match self {
             UserRequestBody::Serial(_) => DriverKind::Serial,
+            UserRequestBody::ListServices(_) => DriverKind::Services,
+            UserRequestBody::ServiceLatency(_) => DriverKind::Services,
+            UserRequestBody::Sleep(_) => DriverKind::Time,
+            UserRequestBody::CancelSleep(_) => DriverKind::Time,
+            UserRequestBody::Spawn(_) => DriverKind::Tasks,
+            UserRequestBody::TaskStatus(_) => DriverKind::Tasks,
+            UserRequestBody::Hello(_) => DriverKind::Hello,
+            UserRequestBody::QueryMemoryMap(_) => DriverKind::Memory,
+            // pings are answered by the kernel itself, like the handshake.
+            UserRequestBody::Ping(_) => DriverKind::Hello,
+            UserRequestBody::ConsoleWrite(_) => DriverKind::Console,
         }
     }
 }
@@ -91,6 +158,43 @@ pub struct KernelResponseHeader {
 pub enum KernelResponseBody {
     Serial(Result<serial::SerialResponse, serial::SerialError>),
     TodoLoopback,
+    ListServices(Result<services::ListServicesResponse, services::ListServicesError>),
+    ServiceLatency(Result<services::ServiceLatencyResponse, services::ServiceLatencyError>),
+    /// The kernel could not process the request at all, so it never reached
+    /// a driver.
+    Error(SysCallError),
+    /// The response to a [`UserRequestBody::Sleep`], sent once it has
+    /// elapsed or been cancelled.
+    Sleep(Result<(), time::SleepError>),
+    Spawn(Result<tasks::TaskHandle, SysCallError>),
+    TaskStatus(Result<tasks::TaskStatus, SysCallError>),
+    Hello(Result<hello::HelloResponse, hello::HelloError>),
+    QueryMemoryMap(memory::MemoryMapPage),
+    /// The response to a [`UserRequestBody::Ping`].
+    Pong(u32),
+    /// The response to a [`UserRequestBody::ConsoleWrite`].
+    ConsoleWrite(Result<console::ConsoleWriteResponse, console::ConsoleError>),
+}
+
+/// The reason the kernel rejected a request, returned as
+/// [`KernelResponseBody::Error`].
+///
+/// Errors returned by the driver which handled a request are part of that
+/// driver's response instead.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum SysCallError {
+    /// The request was malformed, or could not be decoded.
+    InvalidRequest,
+    /// The caller is not allowed to make this request.
+    PermissionDenied,
+    /// The kernel did not have the resources (such as queue space or memory)
+    /// to process the request. The request may succeed if it is retried.
+    ResourceExhausted,
+    /// The driver the request was addressed to does not exist.
+    NotFound,
+    /// Any other error, identified by a kernel-defined code.
+    Other(u32),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -0,0 +1,145 @@
+//! Service discovery and health introspection.
+//!
+//! Userspace may request a list of the driver services registered with the
+//! kernel using [`UserRequestBody::ListServices`]. Because the list may be too
+//! long to fit in a single mailbox message, it is returned one page of at most
+//! [`LIST_SERVICES_PAGE_LEN`] services at a time.
+//!
+//! ## Pagination
+//!
+//! Services are listed in the order in which they were registered. The
+//! kernel's registry is append-only, so a service's position in the list never
+//! changes once it has been registered. To list every service:
+//!
+//! 1. Send a [`ListServicesRequest`] with a `cursor` of `0`.
+//! 2. If the [`ListServicesResponse::next`] field is `Some(cursor)`, send
+//!    another request with that `cursor`, and repeat.
+//! 3. When `next` is `None`, the list is complete.
+//!
+//! Services registered while paging through the list will appear on a later
+//! page. A `cursor` past the end of the list returns an empty page.
+//!
+//! ## Versioning
+//!
+//! Every request carries the version of the response format the caller
+//! understands, and every response carries the version it was produced with.
+//! The kernel rejects requests for a version it does not support with
+//! [`ListServicesError::UnsupportedVersion`].
+//!
+//! ## Latency
+//!
+//! The kernel also tracks how long each service takes to respond to requests.
+//! [`UserRequestBody::ServiceLatency`] returns estimated latency percentiles
+//! for a single service, identified by its UUID, and may optionally reset the
+//! service's statistics.
+//!
+//! [`UserRequestBody::ListServices`]: super::UserRequestBody::ListServices
+//! [`UserRequestBody::ServiceLatency`]: super::UserRequestBody::ServiceLatency
+use serde::{Deserialize, Serialize};
+
+/// The current version of the [`ListServicesResponse`] format.
+pub const LIST_SERVICES_VERSION: u8 = 1;
+
+/// The maximum number of services returned in a single
+/// [`ListServicesResponse`].
+///
+/// This is chosen so that a full page fits in a 128 byte mailbox message.
+pub const LIST_SERVICES_PAGE_LEN: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ListServicesRequest {
+    /// The response format version understood by the caller. This should
+    /// generally be [`LIST_SERVICES_VERSION`].
+    pub version: u8,
+    /// The index of the first service to return.
+    pub cursor: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ListServicesResponse {
+    /// The version of this response's format.
+    pub version: u8,
+    /// The total number of registered services.
+    pub total: u32,
+    /// The cursor to request the next page with, or `None` if this is the
+    /// last page.
+    pub next: Option<u32>,
+    /// The services on this page. Entries after the last service on the page
+    /// are `None`.
+    pub services: [Option<ServiceInfo>; LIST_SERVICES_PAGE_LEN],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ServiceInfo {
+    /// The service's UUID.
+    pub uuid: [u8; 16],
+    /// The ID assigned to the service by the kernel when it was registered.
+    pub service_id: u32,
+    pub state: ServiceState,
+    pub stats: ServiceStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum ServiceState {
+    /// The service is accepting connections.
+    Ready,
+    /// The last connection attempt found that the service was no longer
+    /// running. This is cleared once a connection to the service succeeds.
+    Faulted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ServiceStats {
+    /// The number of connections successfully established to this service.
+    pub connections: u32,
+    /// Whether this service may be connected to from userspace.
+    pub userspace: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum ListServicesError {
+    /// The requested response version is not supported by the kernel.
+    UnsupportedVersion { supported: u8 },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ServiceLatencyRequest {
+    /// The UUID of the service to report on.
+    pub uuid: [u8; 16],
+    /// If `true`, the service's latency statistics are reset after this
+    /// response is produced.
+    pub reset: bool,
+}
+
+/// Estimated request latency percentiles for a service.
+///
+/// Percentiles are estimated from a histogram, and may overestimate the true
+/// latency by up to a factor of two. A percentile is `None` if no requests
+/// have been recorded, or if it is longer than the longest latency the
+/// histogram can measure.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ServiceLatencyResponse {
+    /// The number of requests recorded since the statistics were last reset.
+    pub samples: u32,
+    /// The median latency, in microseconds.
+    pub p50_us: Option<u32>,
+    /// The 99th percentile latency, in microseconds.
+    pub p99_us: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum ServiceLatencyError {
+    /// No service with the requested UUID is registered.
+    NotFound,
+    /// Latency tracking is disabled for this service.
+    NotTracked,
+}
@@ -0,0 +1,74 @@
+//! Spawning tasks from userspace.
+//!
+//! Userspace can't hand the kernel code to run, so it spawns tasks from a
+//! fixed set of *entry points* which the platform implementation gives the
+//! kernel during initialization. [`UserRequestBody::Spawn`] names an entry
+//! point by its index, and passes it an argument. If the task is spawned, the
+//! kernel responds with a [`TaskHandle`], which can be passed to
+//! [`UserRequestBody::TaskStatus`] to find out whether the task is still
+//! running.
+//!
+//! ## Handles
+//!
+//! The kernel can only track a limited number of spawned tasks at once.
+//! Spawning a task while that many are still running fails with
+//! [`SysCallError::ResourceExhausted`]. Once a task finishes, its slot in the
+//! kernel's task table may be reused by a later task.
+//!
+//! A [`TaskHandle`] names both the slot and the *generation* of the slot,
+//! which counts how many tasks have been spawned in it. The kernel reports a
+//! finished task as [`TaskStatus::Finished`] until its slot is reused, after
+//! which its handle is stale, and querying it fails with
+//! [`SysCallError::NotFound`]. So a stale handle never refers to the wrong
+//! task, unless the same slot is reused 2<sup>32</sup> times in between.
+//!
+//! ## Errors
+//!
+//! Both requests fail with a [`SysCallError`]:
+//!
+//! - [`SysCallError::NotFound`] if the entry point or handle doesn't exist,
+//! - [`SysCallError::ResourceExhausted`] if there is no room for another
+//!   task, either in the task table or the kernel's heap.
+//!
+//! [`UserRequestBody::Spawn`]: super::UserRequestBody::Spawn
+//! [`UserRequestBody::TaskStatus`]: super::UserRequestBody::TaskStatus
+//! [`SysCallError`]: super::SysCallError
+//! [`SysCallError::NotFound`]: super::SysCallError::NotFound
+//! [`SysCallError::ResourceExhausted`]: super::SysCallError::ResourceExhausted
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct SpawnRequest {
+    /// The index of the entry point to spawn the task from.
+    pub entry: u32,
+    /// An argument passed to the entry point.
+    pub arg: u32,
+}
+
+/// Identifies a task spawned by [`SpawnRequest`].
+///
+/// See the [module-level documentation](self) for details.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct TaskHandle {
+    /// The task's slot in the kernel's task table.
+    pub slot: u32,
+    /// The generation of the slot when the task was spawned.
+    pub generation: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct TaskStatusRequest {
+    pub handle: TaskHandle,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum TaskStatus {
+    /// The task is still running.
+    Running,
+    /// The task has finished.
+    Finished,
+}
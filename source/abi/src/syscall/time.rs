@@ -0,0 +1,40 @@
+//! Sleeping from userspace.
+//!
+//! Userspace asks the kernel to sleep for a duration by sending a
+//! [`UserRequestBody::Sleep`]. The kernel arms a timer, and only responds once it
+//! fires, so waiting for the response waits for the duration.
+//!
+//! A sleep which is no longer needed can be cancelled by sending a
+//! [`UserRequestBody::CancelSleep`] with the nonce of the request which started it.
+//! This disarms the kernel's timer, and the sleep is answered with
+//! [`SleepError::Cancelled`]. Cancelling a sleep which has already finished,
+//! or which never started, does nothing.
+//!
+//! [`UserRequestBody::Sleep`]: super::UserRequestBody::Sleep
+//! [`UserRequestBody::CancelSleep`]: super::UserRequestBody::CancelSleep
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct SleepRequest {
+    /// How long to sleep for, in microseconds.
+    pub micros: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct CancelSleepRequest {
+    /// The nonce of the [`SleepRequest`] to cancel.
+    pub nonce: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum SleepError {
+    /// The sleep was cancelled by a [`CancelSleepRequest`] before it
+    /// finished.
+    Cancelled,
+    /// The kernel could not start the sleep, because another sleep with the
+    /// same nonce is already in progress.
+    Rejected,
+}
@@ -5,7 +5,7 @@
 use core::{
     alloc::Layout,
     cell::UnsafeCell,
-    mem::MaybeUninit,
+    mem::{self, MaybeUninit},
     ops::{Deref, DerefMut},
     ptr::NonNull,
 };
@@ -391,9 +391,14 @@ impl<T> FixedVec<T> {
     ///
     /// Returns None if the allocation does not succeed immediately.
     ///
-    /// Panics if the len is zero, or large enough that creating the layout would fail
+    /// If `capacity` is zero, no allocation is performed, and the returned
+    /// FixedVec is always full.
+    ///
+    /// Panics if the len is large enough that creating the layout would fail
     pub fn try_new(capacity: usize) -> Option<Self> {
-        assert_ne!(capacity, 0, "ZST FixedVec doesn't make sense");
+        if capacity == 0 {
+            return Some(Self::empty());
+        }
         let layout = Layout::array::<T>(capacity).unwrap();
 
         unsafe {
@@ -408,9 +413,14 @@ impl<T> FixedVec<T> {
     ///
     /// Will not return until allocation succeeds.
     ///
-    /// Panics if the len is zero, or large enough that creating the layout would fail
+    /// If `capacity` is zero, no allocation is performed, and the returned
+    /// FixedVec is always full.
+    ///
+    /// Panics if the len is large enough that creating the layout would fail
     pub async fn new(capacity: usize) -> Self {
-        assert_ne!(capacity, 0, "ZST FixedVec doesn't make sense");
+        if capacity == 0 {
+            return Self::empty();
+        }
         let layout = Layout::array::<T>(capacity).unwrap();
 
         unsafe {
@@ -421,6 +431,13 @@ impl<T> FixedVec<T> {
         }
     }
 
+    fn empty() -> Self {
+        assert_ne!(mem::size_of::<T>(), 0, "ZST FixedVec doesn't make sense");
+        FixedVec {
+            inner: alloc::vec::Vec::new(),
+        }
+    }
+
     /// Attempt to push an item into the fixed vec.
     ///
     /// Returns an error if the fixed vec is full
@@ -10,7 +10,10 @@ use linked_list_allocator::Heap;
 use maitake::sync::{Mutex, WaitQueue};
 #[cfg(feature = "stats")]
 use portable_atomic::AtomicU16;
-use portable_atomic::{AtomicBool, AtomicUsize, Ordering::*};
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering::*};
+
+pub mod segregated;
+pub use self::segregated::{ArenaSource, SegregatedAlloc};
 
 /// # Mnemos Allocator
 ///
@@ -39,13 +42,19 @@ use portable_atomic::{AtomicBool, AtomicUsize, Ordering::*};
 /// By wrapping the [UnderlyingAllocator], we allow non-async-aware allocations
 /// (like those through [alloc::alloc::alloc()] or [alloc::alloc::dealloc()]) to
 /// trigger these behaviors. However, non-async-aware allocations are still subject
-/// to normal OOM handling, which typically means panicking.
+/// to normal OOM handling, which typically means panicking. To make those
+/// panics easier to diagnose, an [OomHandler] may be registered with
+/// [MnemosAlloc::set_oom_handler()], which is called whenever an allocation
+/// fails.
 pub struct MnemosAlloc<U> {
     allocator: U,
 
     /// The total size of the heap, in bytes.
     heap_size: AtomicUsize,
 
+    /// The [OomHandler] to call when an allocation fails, or null.
+    oom_handler: AtomicPtr<()>,
+
     /// Tracks heap statistics.
     #[cfg(feature = "stats")]
     stats: stats::Stats,
@@ -59,6 +68,35 @@ pub enum InitError {
     AlreadyInitialized,
 }
 
+/// A function called when a [MnemosAlloc] fails to allocate.
+///
+/// See [MnemosAlloc::set_oom_handler()].
+pub type OomHandler = fn(&OomInfo);
+
+/// Describes a failed allocation, passed to an [OomHandler].
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct OomInfo {
+    /// The layout of the allocation which failed.
+    pub layout: Layout,
+
+    /// If this is `true`, the allocation wasn't attempted, because
+    /// allocations are inhibited after an earlier one failed, until the next
+    /// deallocation.
+    pub inhibited: bool,
+
+    /// The total size of the heap, in bytes.
+    pub total_bytes: usize,
+
+    /// The size of the largest block that could be allocated, if the
+    /// underlying allocator reports it.
+    pub largest_free_block: Option<usize>,
+
+    /// A snapshot of the heap's statistics.
+    #[cfg(feature = "stats")]
+    pub state: State,
+}
+
 #[cfg(feature = "stats")]
 pub use self::stats::State;
 
@@ -75,12 +113,28 @@ impl<U: UnderlyingAllocator> MnemosAlloc<U> {
         Self {
             allocator: U::INIT,
             heap_size: AtomicUsize::new(0),
+            oom_handler: AtomicPtr::new(null_mut()),
 
             #[cfg(feature = "stats")]
             stats: stats::Stats::new(),
         }
     }
 
+    /// Register a function to be called whenever an allocation fails,
+    /// replacing any previously registered handler.
+    ///
+    /// The handler is called before the failed allocation returns a null
+    /// pointer, so for allocations which can't fail, it runs before the
+    /// allocation error panics. This makes it a good place to log the failed
+    /// layout and the state of the heap.
+    ///
+    /// The handler may allocate, but those allocations will most likely fail
+    /// too, and won't call the handler again. While one core is running the
+    /// handler, allocation failures on other cores don't call it.
+    pub fn set_oom_handler(&self, handler: OomHandler) {
+        self.oom_handler.store(handler as *mut (), Release);
+    }
+
     /// Initialize the allocator, with a heap of size `len` starting at `start`.
     ///
     /// # Returns
@@ -136,12 +190,42 @@ impl<U: UnderlyingAllocator> MnemosAlloc<U> {
     pub fn total_size(&self) -> usize {
         self.heap_size.load(Acquire)
     }
+
+    /// Returns the size of the largest block that can currently be allocated
+    /// without growing the heap, if the underlying allocator can report it.
+    ///
+    /// See [`UnderlyingAllocator::largest_free_block`].
+    #[must_use]
+    pub fn largest_free_block(&self) -> Option<usize> {
+        self.allocator.largest_free_block()
+    }
+
+    #[cold]
+    fn out_of_memory(&self, layout: Layout, inhibited: bool) {
+        let handler = self.oom_handler.load(Acquire);
+        if handler.is_null() || IN_OOM_HANDLER.swap(true, AcqRel) {
+            return;
+        }
+
+        // Safety: the only non-null values ever stored are `OomHandler`s.
+        let handler = unsafe { core::mem::transmute::<*mut (), OomHandler>(handler) };
+        handler(&OomInfo {
+            layout,
+            inhibited,
+            total_bytes: self.total_size(),
+            largest_free_block: self.largest_free_block(),
+            #[cfg(feature = "stats")]
+            state: self.state(),
+        });
+        IN_OOM_HANDLER.store(false, Release);
+    }
 }
 
 unsafe impl<U: UnderlyingAllocator> GlobalAlloc for MnemosAlloc<U> {
     #[inline(always)]
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         if INHIBIT_ALLOC.load(Acquire) {
+            self.out_of_memory(layout, true);
             return null_mut();
         }
 
@@ -155,6 +239,7 @@ unsafe impl<U: UnderlyingAllocator> GlobalAlloc for MnemosAlloc<U> {
             {
                 self.stats.alloc_oom_count.fetch_add(1, Release);
             }
+            self.out_of_memory(layout, false);
         } else {
             #[cfg(feature = "stats")]
             {
@@ -196,6 +281,10 @@ static OOM_WAITER: WaitQueue = WaitQueue::new();
 /// that *could* potentially succeed
 static INHIBIT_ALLOC: AtomicBool = AtomicBool::new(false);
 
+/// Set while an [OomHandler] is running, so that allocation failures in the
+/// handler don't call it recursively.
+static IN_OOM_HANDLER: AtomicBool = AtomicBool::new(false);
+
 /// Asynchronously allocate with the given [Layout].
 ///
 /// Analogous to [alloc::alloc::alloc()], but will never return a null pointer,
@@ -273,6 +362,15 @@ pub trait UnderlyingAllocator {
     ///
     /// The same as [GlobalAlloc::dealloc()].
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout);
+
+    /// Returns the size of the largest block that can currently be allocated
+    /// without growing the heap, or `None` if this allocator does not track
+    /// it.
+    ///
+    /// This is intended for diagnostics, and may be approximate.
+    fn largest_free_block(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// A wrapper of [linked_list_allocator::Heap] that uses [maitake::sync::Mutex].
@@ -493,11 +591,13 @@ mod stats {
         /// Returns the current amount of free space in the heap, in bytes.
         ///
         /// This is calculated by subtracting [`self.allocated_bytes`] from
-        /// [`self.total_bytes`].
+        /// [`self.total_bytes`]. If the heap's total size is not known (because
+        /// the underlying allocator was not initialized through
+        /// [`MnemosAlloc::init`]), this is 0.
         #[must_use]
         #[inline]
         pub fn free_bytes(&self) -> usize {
-            self.total_bytes - self.allocated_bytes
+            self.total_bytes.saturating_sub(self.allocated_bytes)
         }
 
         /// Returns the total number of allocation attempts that have been
@@ -550,3 +650,56 @@ mod stats {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An allocator with no memory.
+    struct Empty;
+
+    impl UnderlyingAllocator for Empty {
+        const INIT: Self = Empty;
+
+        unsafe fn init(&self, _: NonNull<u8>, _: usize) {}
+
+        unsafe fn alloc(&self, _: Layout) -> *mut u8 {
+            null_mut()
+        }
+
+        unsafe fn dealloc(&self, _: *mut u8, _: Layout) {}
+    }
+
+    static OOM_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static OOM_INHIBITED: AtomicBool = AtomicBool::new(false);
+    static OOM_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn oom_handler() {
+        static ALLOC: MnemosAlloc<Empty> = MnemosAlloc::new();
+
+        fn handler(info: &OomInfo) {
+            OOM_CALLS.fetch_add(1, SeqCst);
+            OOM_INHIBITED.store(info.inhibited, SeqCst);
+            OOM_SIZE.store(info.layout.size(), SeqCst);
+            // allocating in the handler must not call it again.
+            assert!(unsafe { ALLOC.alloc(Layout::new::<u8>()) }.is_null());
+        }
+
+        ALLOC.set_oom_handler(handler);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            assert!(ALLOC.alloc(layout).is_null());
+            assert_eq!(OOM_CALLS.load(SeqCst), 1);
+            assert!(!OOM_INHIBITED.load(SeqCst));
+            assert_eq!(OOM_SIZE.load(SeqCst), 64);
+
+            // allocations are now inhibited until the next deallocation.
+            assert!(ALLOC.alloc(layout).is_null());
+            assert_eq!(OOM_CALLS.load(SeqCst), 2);
+            assert!(OOM_INHIBITED.load(SeqCst));
+
+            ALLOC.dealloc(NonNull::dangling().as_ptr(), layout);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+//! A locked, segregated free-list allocator.
+//!
+//! Small allocations are rounded up to a power-of-two size class, and each
+//! size class has its own free list protected by its own spinlock. This means
+//! that CPU cores allocating objects of different sizes never contend with one
+//! another, and cores allocating objects of the same size only contend for the
+//! time it takes to pop a single block off a list.
+//!
+//! Free lists are refilled by carving fixed-size slabs out of an *arena*. The
+//! arena is seeded by [`UnderlyingAllocator::init`], and is grown on demand by
+//! requesting more memory from an [`ArenaSource`] (such as a physical page
+//! frame allocator). Allocations larger than the largest size class bypass
+//! the free lists entirely, and are requested directly from the
+//! [`ArenaSource`].
+//!
+//! ## Free Checking
+//!
+//! When the `free-check` feature is enabled in a debug build, freed blocks
+//! are tagged as free and the rest of the block is filled with
+//! [`FREE_POISON`]. Freeing a block which is already on its size class's free
+//! list panics with a report of the pointer, the layout it was freed with the
+//! first time, and the layout of the second free. Reading freed memory yields
+//! the poison pattern, and writes to freed memory are detected (and reported
+//! the same way) when the block is next allocated. Allocation sites are not
+//! tracked. Allocations larger than [`MAX_CLASS_SIZE`] are not checked.
+use core::{alloc::Layout, ptr::NonNull};
+
+use maitake::sync::{blocking::Mutex, spin::Spinlock};
+
+use super::UnderlyingAllocator;
+
+/// A source of memory used to grow a [`SegregatedAlloc`]'s arena.
+///
+/// # Safety
+///
+/// Implementations must ensure that every region returned by
+/// [`ArenaSource::grow`] is valid for reads and writes, satisfies the
+/// requested [`Layout`], and is not aliased by any other region until it is
+/// passed back to [`ArenaSource::release`].
+pub unsafe trait ArenaSource {
+    /// A constant initializer of the arena source.
+    const INIT: Self;
+
+    /// Returns a new region of memory satisfying `layout`, or `None` if no
+    /// more memory is available.
+    ///
+    /// # Safety
+    ///
+    /// This may be called concurrently from multiple CPU cores.
+    unsafe fn grow(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Return a region previously returned by [`ArenaSource::grow`] with the
+    /// same `layout`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by a call to [`ArenaSource::grow`] on
+    /// this source with the same `layout`, and must not be used after this
+    /// call.
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// An [`ArenaSource`] that never grows.
+///
+/// A [`SegregatedAlloc`] using this source can only allocate from the region
+/// it was initialized with, and cannot serve allocations larger than
+/// [`MAX_CLASS_SIZE`].
+#[derive(Debug)]
+pub struct NoGrow;
+
+/// The smallest size class, in bytes.
+pub const MIN_CLASS_SIZE: usize = 1 << MIN_CLASS_SHIFT;
+
+/// The largest size class, in bytes. Larger allocations are served directly by
+/// the [`ArenaSource`].
+pub const MAX_CLASS_SIZE: usize = 1 << MAX_CLASS_SHIFT;
+
+/// The size of the region requested from the [`ArenaSource`] whenever the arena
+/// is exhausted.
+pub const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of bytes carved from the arena each time a size class's free
+/// list is empty.
+const SLAB_SIZE: usize = 4096;
+
+/// The byte pattern that freed blocks are filled with when free checking is
+/// enabled.
+pub const FREE_POISON: u8 = 0xDF;
+
+/// Written to the header of freed blocks when free checking is enabled.
+const FREE_TAG: u32 = 0xF4EE_D00D;
+
+/// Whether free checking is enabled. See the [module-level
+/// documentation](self#free-checking).
+const FREE_CHECK: bool = cfg!(all(feature = "free-check", debug_assertions));
+
+const MIN_CLASS_SHIFT: u32 = 4;
+const MAX_CLASS_SHIFT: u32 = 11;
+const CLASSES: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+
+/// A locked segregated free-list allocator, which is safe to use from
+/// multiple CPU cores simultaneously.
+///
+/// See the [module-level documentation](self) for details.
+///
+/// Because this allocator uses spinlocks, it must not be used from interrupt
+/// handlers which may preempt an allocation on the same core.
+pub struct SegregatedAlloc<S> {
+    classes: [Mutex<FreeList, Spinlock>; CLASSES],
+    arena: Mutex<Arena, Spinlock>,
+    source: S,
+}
+
+struct FreeList {
+    head: Option<NonNull<Block>>,
+}
+
+/// The header of a block on a free list. This must fit in [`MIN_CLASS_SIZE`]
+/// bytes.
+struct Block {
+    next: Option<NonNull<Block>>,
+    /// [`FREE_TAG`], if free checking is enabled.
+    tag: u32,
+    /// The layout the block was freed with, if free checking is enabled.
+    size: u16,
+    align: u16,
+}
+
+/// The region that slabs are currently being carved from.
+struct Arena {
+    next: usize,
+    end: usize,
+}
+
+const _: () = assert!(core::mem::size_of::<Block>() <= MIN_CLASS_SIZE);
+
+enum Class {
+    Small(usize),
+    Large,
+}
+
+// Safety: the free list only contains pointers to blocks owned by the
+// allocator, and is only accessed while its lock is held.
+unsafe impl Send for FreeList {}
+
+// This constant is only used as an array initializer, so the interior
+// mutability is not an issue.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CLASS: Mutex<FreeList, Spinlock> =
+    Mutex::new_with_raw_mutex(FreeList { head: None }, Spinlock::new());
+
+impl<S: ArenaSource> SegregatedAlloc<S> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            classes: [EMPTY_CLASS; CLASSES],
+            arena: Mutex::new_with_raw_mutex(Arena { next: 0, end: 0 }, Spinlock::new()),
+            source: S::INIT,
+        }
+    }
+
+    /// Returns a reference to this allocator's [`ArenaSource`].
+    #[must_use]
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    fn class_of(layout: Layout) -> Class {
+        let size = layout.size().max(layout.align()).max(MIN_CLASS_SIZE);
+        if size > MAX_CLASS_SIZE {
+            return Class::Large;
+        }
+        let shift = size.next_power_of_two().trailing_zeros();
+        Class::Small((shift - MIN_CLASS_SHIFT) as usize)
+    }
+
+    /// Carve a new slab of `class_size` blocks out of the arena, growing the
+    /// arena if necessary.
+    ///
+    /// Lock ordering: this is called with a size class's lock held, and
+    /// acquires the arena lock. The arena lock is never held while acquiring a
+    /// size class lock.
+    unsafe fn refill(&self, list: &mut FreeList, class_size: usize) -> bool {
+        let mut arena = self.arena.lock();
+        let mut start = (arena.next + class_size - 1) & !(class_size - 1);
+        if start + SLAB_SIZE > arena.end {
+            // the arena is exhausted. any leftover space at the end of the
+            // current chunk is abandoned.
+            let chunk = Layout::from_size_align_unchecked(ARENA_CHUNK_SIZE, SLAB_SIZE);
+            let Some(ptr) = self.source.grow(chunk) else {
+                return false;
+            };
+            start = ptr.as_ptr() as usize;
+            arena.end = start + ARENA_CHUNK_SIZE;
+        }
+        arena.next = start + SLAB_SIZE;
+        drop(arena);
+
+        for addr in (start..start + SLAB_SIZE).step_by(class_size).rev() {
+            let block = NonNull::new_unchecked(addr as *mut Block);
+            // blocks that were never allocated are recorded as freed with
+            // their class's own layout.
+            let layout = Layout::from_size_align_unchecked(class_size, class_size);
+            Self::push(list, block, layout, class_size);
+        }
+        true
+    }
+
+    /// Push `block` onto `list`, poisoning it if free checking is enabled.
+    unsafe fn push(list: &mut FreeList, block: NonNull<Block>, freed: Layout, class_size: usize) {
+        if FREE_CHECK {
+            let header = core::mem::size_of::<Block>();
+            core::ptr::write_bytes(
+                block.as_ptr().cast::<u8>().add(header),
+                FREE_POISON,
+                class_size - header,
+            );
+        }
+        block.as_ptr().write(Block {
+            next: list.head,
+            tag: if FREE_CHECK { FREE_TAG } else { 0 },
+            // small layouts always fit, as they are at most `MAX_CLASS_SIZE`.
+            size: freed.size() as u16,
+            align: freed.align() as u16,
+        });
+        list.head = Some(block);
+    }
+
+    /// Panic if `block`, which is about to be freed with `layout`, is already
+    /// on `list`.
+    unsafe fn check_double_free(list: &FreeList, block: NonNull<Block>, layout: Layout) {
+        // a live block may happen to contain the tag, so only search the free
+        // list if it's there.
+        if block.as_ref().tag != FREE_TAG {
+            return;
+        }
+
+        let mut next = list.head;
+        while let Some(free) = next {
+            if free == block {
+                let first = block.as_ref();
+                panic!(
+                    "double free of {block:p}: first freed with (size: {}, align: {}), \
+                    freed again with {layout:?}; allocation site not tracked",
+                    first.size, first.align,
+                );
+            }
+            next = free.as_ref().next;
+        }
+    }
+
+    /// Panic if `block`, which is about to be allocated, was written to while
+    /// it was free.
+    unsafe fn check_poison(block: NonNull<Block>, class_size: usize) {
+        let header = core::mem::size_of::<Block>();
+        let body = core::slice::from_raw_parts(
+            block.as_ptr().cast::<u8>().add(header),
+            class_size - header,
+        );
+        let Some(offset) = body.iter().position(|&b| b != FREE_POISON) else {
+            return;
+        };
+        let freed = block.as_ref();
+        panic!(
+            "use after free of {block:p}: byte {} was written after it was freed \
+            with (size: {}, align: {}); allocation site not tracked",
+            header + offset,
+            freed.size,
+            freed.align,
+        );
+    }
+}
+
+impl<S: ArenaSource> Default for SegregatedAlloc<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: ArenaSource> UnderlyingAllocator for SegregatedAlloc<S> {
+    // This constant is used as an initializer, so the interior mutability is
+    // not an issue.
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self::new();
+
+    unsafe fn init(&self, start: NonNull<u8>, len: usize) {
+        let mut arena = self.arena.lock();
+        assert!(arena.end == 0, "Already initialized the heap");
+        arena.next = start.as_ptr() as usize;
+        arena.end = arena.next + len;
+    }
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let idx = match Self::class_of(layout) {
+            Class::Small(idx) => idx,
+            Class::Large => {
+                return self
+                    .source
+                    .grow(layout)
+                    .map_or(core::ptr::null_mut(), NonNull::as_ptr)
+            }
+        };
+
+        let class_size = MIN_CLASS_SIZE << idx;
+        let mut list = self.classes[idx].lock();
+        if list.head.is_none() && !self.refill(&mut list, class_size) {
+            return core::ptr::null_mut();
+        }
+
+        match list.head {
+            Some(block) => {
+                if FREE_CHECK {
+                    Self::check_poison(block, class_size);
+                    // clear the tag, so that freeing this block isn't
+                    // mistaken for a double free.
+                    (*block.as_ptr()).tag = 0;
+                }
+                list.head = block.as_ref().next;
+                block.as_ptr().cast()
+            }
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            debug_assert!(false, "Deallocating a null?");
+            return;
+        };
+
+        match Self::class_of(layout) {
+            Class::Large => self.source.release(ptr, layout),
+            Class::Small(idx) => {
+                let block = ptr.cast::<Block>();
+                let mut list = self.classes[idx].lock();
+                if FREE_CHECK {
+                    Self::check_double_free(&list, block, layout);
+                }
+                Self::push(&mut list, block, layout, MIN_CLASS_SIZE << idx);
+            }
+        }
+    }
+}
+
+unsafe impl ArenaSource for NoGrow {
+    const INIT: Self = NoGrow;
+
+    unsafe fn grow(&self, _: Layout) -> Option<NonNull<u8>> {
+        None
+    }
+
+    unsafe fn release(&self, _: NonNull<u8>, _: Layout) {
+        unreachable!("`NoGrow` never returns memory from `grow`")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{alloc::GlobalAlloc, sync::Arc, thread, vec::Vec};
+
+    struct SystemSource;
+
+    unsafe impl ArenaSource for SystemSource {
+        const INIT: Self = SystemSource;
+
+        unsafe fn grow(&self, layout: Layout) -> Option<NonNull<u8>> {
+            NonNull::new(std::alloc::System.alloc(layout))
+        }
+
+        unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+            std::alloc::System.dealloc(ptr.as_ptr(), layout)
+        }
+    }
+
+    fn init_no_grow() -> SegregatedAlloc<NoGrow> {
+        let alloc = SegregatedAlloc::<NoGrow>::new();
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let region = unsafe { std::alloc::System.alloc(layout) };
+        unsafe { alloc.init(NonNull::new(region).unwrap(), SLAB_SIZE) };
+        alloc
+    }
+
+    #[test]
+    #[cfg(all(feature = "free-check", debug_assertions))]
+    #[should_panic(expected = "double free")]
+    fn free_check_double_free() {
+        let alloc = init_no_grow();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            alloc.dealloc(ptr, layout);
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "free-check", debug_assertions))]
+    #[should_panic(expected = "use after free")]
+    fn free_check_use_after_free() {
+        let alloc = init_no_grow();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            alloc.dealloc(ptr, layout);
+            // reads of freed memory see the poison pattern...
+            assert_eq!(ptr.add(32).read(), FREE_POISON);
+            // ...and writes are caught when the block is reallocated.
+            ptr.add(32).write(1);
+            alloc.alloc(layout);
+        }
+    }
+
+    #[test]
+    fn no_grow_exhausts() {
+        let alloc = init_no_grow();
+
+        let block = Layout::from_size_align(MAX_CLASS_SIZE, 8).unwrap();
+        let a = unsafe { alloc.alloc(block) };
+        let b = unsafe { alloc.alloc(block) };
+        assert!(!a.is_null());
+        assert!(!b.is_null());
+        assert!(unsafe { alloc.alloc(block) }.is_null());
+
+        // freeing a block makes it available again.
+        unsafe { alloc.dealloc(a, block) };
+        assert_eq!(unsafe { alloc.alloc(block) }, a);
+    }
+
+    #[test]
+    fn stress() {
+        const THREADS: usize = 8;
+        const ITERS: usize = 10_000;
+        const LIVE: usize = 64;
+
+        let alloc = Arc::new(SegregatedAlloc::<SystemSource>::new());
+        let threads = (0..THREADS)
+            .map(|thread| {
+                let alloc = alloc.clone();
+                thread::spawn(move || {
+                    let mut rng = thread as u32 + 1;
+                    let mut live: Vec<(*mut u8, Layout, u8)> = Vec::with_capacity(LIVE);
+                    for i in 0..ITERS {
+                        // xorshift32
+                        rng ^= rng << 13;
+                        rng ^= rng >> 17;
+                        rng ^= rng << 5;
+
+                        if live.len() == LIVE || (rng & 1 == 0 && !live.is_empty()) {
+                            let (ptr, layout, fill) = live.swap_remove(rng as usize % live.len());
+                            let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                            assert!(
+                                bytes.iter().all(|&b| b == fill),
+                                "allocation at {ptr:p} was corrupted!"
+                            );
+                            unsafe { alloc.dealloc(ptr, layout) };
+                        } else {
+                            // mostly small allocations, with the occasional
+                            // large one.
+                            let size = 1 + (rng as usize >> 8) % (MAX_CLASS_SIZE * 2);
+                            let align = 1 << ((rng >> 4) % 5);
+                            let layout = Layout::from_size_align(size, align).unwrap();
+                            let ptr = unsafe { alloc.alloc(layout) };
+                            assert!(!ptr.is_null(), "allocation failed");
+                            assert_eq!(ptr as usize % align, 0, "allocation misaligned");
+                            let fill = (thread * ITERS + i) as u8;
+                            unsafe { ptr.write_bytes(fill, size) };
+                            live.push((ptr, layout, fill));
+                        }
+                    }
+
+                    for (ptr, layout, fill) in live {
+                        let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                        assert!(bytes.iter().all(|&b| b == fill));
+                        unsafe { alloc.dealloc(ptr, layout) };
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
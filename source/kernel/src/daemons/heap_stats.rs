@@ -0,0 +1,83 @@
+//! Heap statistics daemon
+//!
+//! Periodically logs the kernel heap's usage, so that slow memory leaks show
+//! up as steadily increasing usage in the trace log.
+//!
+//! This daemon requires the "heap-stats" feature flag, which enables the
+//! `mnemos-alloc` crate's heap statistics.
+
+use core::time::Duration;
+
+use mnemos_alloc::heap::{MnemosAlloc, UnderlyingAllocator};
+use serde::{Deserialize, Serialize};
+
+use crate::Kernel;
+
+/// Heap Stats Settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct HeapStatsSettings {
+    /// Should the heap stats daemon be enabled?
+    #[serde(default)]
+    pub enabled: bool,
+    /// Interval between log messages. Defaults to 10 seconds
+    #[serde(default = "HeapStatsSettings::default_interval")]
+    pub interval: Duration,
+}
+
+impl HeapStatsSettings {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+    const fn default_interval() -> Duration {
+        Self::DEFAULT_INTERVAL
+    }
+}
+
+impl Default for HeapStatsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: Self::DEFAULT_INTERVAL,
+        }
+    }
+}
+
+/// Spawns a heap stats logger
+///
+/// Every `interval`, logs the amount of memory allocated from `heap`, the
+/// amount that remains free, the number of live allocations, and how much
+/// allocated memory has changed since the previous log message.
+#[tracing::instrument(skip(kernel, heap))]
+pub async fn heap_stats<U: UnderlyingAllocator>(
+    kernel: &'static Kernel,
+    heap: &'static MnemosAlloc<U>,
+    settings: HeapStatsSettings,
+) {
+    let HeapStatsSettings { interval, .. } = settings;
+    tracing::info!("heap stats daemon running!");
+
+    let mut prev = heap.state();
+    loop {
+        kernel.sleep(interval).await;
+
+        // Take a snapshot of every metric *before* logging anything, so that
+        // allocations made while formatting and emitting the log message are
+        // not counted in this sample. They will have been freed by the time
+        // the next sample is taken, so they don't show up as a leak either.
+        let state = heap.state();
+        let largest_free_block = heap.largest_free_block();
+
+        let growth = state.allocated_bytes as isize - prev.allocated_bytes as isize;
+        tracing::info!(
+            allocated_bytes = state.allocated_bytes,
+            free_bytes = state.free_bytes(),
+            total_bytes = state.total_bytes,
+            largest_free_block,
+            live_allocs = state.live_alloc_count(),
+            alloc_oom_count = state.alloc_oom_count,
+            growth,
+            "heap stats"
+        );
+        prev = state;
+    }
+}
@@ -6,5 +6,7 @@
 //! Unlike [services][crate::services], daemons are not exposed as a
 //! client/server via the [registry][crate::registry].
 
+#[cfg(feature = "heap-stats")]
+pub mod heap_stats;
 pub mod sermux;
 pub mod shells;
@@ -1,6 +1,7 @@
-use portable_atomic::{AtomicU8, Ordering};
+use portable_atomic::{AtomicU8, AtomicUsize, Ordering};
 
 static IN_ISR: AtomicU8 = AtomicU8::new(0);
+static ENTRIES: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Isr(());
 
@@ -18,10 +19,24 @@ impl Isr {
     #[must_use]
     #[inline]
     pub fn enter() -> Self {
+        ENTRIES.fetch_add(1, Ordering::Release);
         IN_ISR.fetch_add(1, Ordering::Release);
         Self(())
     }
 
+    /// Returns the number of times an ISR has been [entered](Self::enter),
+    /// wrapping on overflow.
+    ///
+    /// A run loop which reads this before ticking the scheduler, and finds it
+    /// unchanged with interrupts disabled right before waiting for an
+    /// interrupt, knows that no ISR could have woken a task in between, so it
+    /// may safely sleep with no timeout.
+    #[must_use]
+    #[inline]
+    pub fn entries() -> usize {
+        ENTRIES.load(Ordering::Acquire)
+    }
+
     #[must_use]
     #[inline]
     pub fn is_in_isr() -> bool {
@@ -78,17 +78,23 @@ pub mod daemons;
 pub(crate) mod fmt;
 pub mod forth;
 pub mod isr;
+pub mod memory_map;
+pub mod modules;
 pub mod registry;
 pub mod retry;
+pub mod runloop;
 #[cfg(feature = "serial-trace")]
 pub mod serial_trace;
 pub mod services;
+pub mod tasks;
+pub mod user_sleep;
 
 #[cfg(test)]
 pub(crate) mod test_util;
 
 use core::{convert::identity, future::Future, ptr::NonNull};
 
+pub use abi;
 use abi::{
     bbqueue_ipc::BBBuffer,
     syscall::{KernelResponse, UserRequest},
@@ -103,6 +109,8 @@ use maitake::{
 };
 pub use mnemos_alloc;
 use mnemos_alloc::containers::Box;
+use modules::BootModule;
+use mycelium_util::sync::InitOnce;
 use registry::Registry;
 use serde::{Deserialize, Serialize};
 use services::{
@@ -119,7 +127,20 @@ pub struct Rings {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KernelSettings {
-    pub max_drivers: usize,
+    /// The maximum number of driver services which may be registered, or
+    /// `None` for no limit.
+    ///
+    /// The registry's storage is allocated from the heap, and grows as
+    /// services are registered, so this needn't be set for storage to be
+    /// allocated on demand. See [`Registry::try_new`].
+    #[serde(default)]
+    pub max_drivers: Option<usize>,
+    /// Settings for tracking the latency of requests to each driver service.
+    #[serde(default)]
+    pub latency: registry::LatencySettings,
+    /// Settings for tasks spawned by userspace.
+    #[serde(default)]
+    pub tasks: tasks::TaskSettings,
 }
 
 pub struct Message {
@@ -144,6 +165,18 @@ pub struct KernelInner {
 
     /// Maitake timer wheel.
     timer: Timer,
+
+    /// Read-only blobs loaded by the bootloader. See [`modules`].
+    modules: InitOnce<&'static [BootModule]>,
+
+    /// The memory map the kernel was booted with. See [`memory_map`].
+    memory_map: InitOnce<&'static [memory_map::MemoryRegion]>,
+
+    /// Sleeps requested by userspace. See [`user_sleep`].
+    user_sleeps: user_sleep::UserSleeps,
+
+    /// Tasks spawned by userspace. See [`tasks`].
+    user_tasks: tasks::UserTasks,
 }
 
 /// Settings for all services spawned by default.
@@ -173,13 +206,20 @@ impl Kernel {
         settings: KernelSettings,
         clock: maitake::time::Clock,
     ) -> Result<Box<Self>, &'static str> {
-        let registry = registry::Registry::new(settings.max_drivers);
+        let registry = registry::Registry::try_new(settings.max_drivers)
+            .ok_or("Registry allocation failed.")?
+            .with_latency_tracking(settings.latency, clock.clone());
 
         let scheduler = LocalScheduler::new();
 
         let inner = KernelInner {
             scheduler,
             timer: Timer::new(clock),
+            modules: InitOnce::uninitialized(),
+            memory_map: InitOnce::uninitialized(),
+            user_sleeps: user_sleep::UserSleeps::new(),
+            user_tasks: tasks::UserTasks::try_new(&settings.tasks)
+                .ok_or("Task table allocation failed.")?,
         };
 
         let new_kernel =
@@ -198,9 +238,10 @@ impl Kernel {
         &self.inner.timer
     }
 
-    pub fn tick(&'static self) -> maitake::scheduler::Tick {
+    /// Poll the kernel's scheduler once, returning a summary of the tick.
+    pub fn tick(&'static self) -> runloop::TickSummary {
         let inner = self.inner();
-        inner.scheduler.tick()
+        inner.scheduler.tick().into()
         // TODO: Send time to userspace?
     }
 
@@ -238,6 +279,72 @@ impl Kernel {
         &self.registry
     }
 
+    /// Provide the kernel with the [boot modules](modules) loaded by the
+    /// bootloader.
+    ///
+    /// This should be called once by the platform implementation, during
+    /// initialization. Returns an error if the modules have already been set.
+    pub fn set_modules(&self, modules: &'static [BootModule]) -> Result<(), &'static str> {
+        self.inner
+            .modules
+            .try_init(modules)
+            .map_err(|_| "Boot modules already set.")
+    }
+
+    /// Returns all [boot modules](modules), in the order they were provided.
+    ///
+    /// If the platform has not provided any modules, this is empty.
+    #[must_use]
+    pub fn modules(&self) -> &'static [BootModule] {
+        self.inner.modules.try_get().copied().unwrap_or(&[])
+    }
+
+    /// Returns the contents of the first [boot module](modules) named `name`,
+    /// or `None` if there is no such module.
+    #[must_use]
+    pub fn find_module(&self, name: &str) -> Option<&'static [u8]> {
+        self.modules()
+            .iter()
+            .find(|module| module.name() == name)
+            .map(BootModule::data)
+    }
+
+    /// Provide the kernel with the [memory map](memory_map) it was booted
+    /// with.
+    ///
+    /// This should be called once by the platform implementation, during
+    /// initialization. Returns an error if the memory map has already been
+    /// set.
+    pub fn set_memory_map(
+        &self,
+        regions: &'static [memory_map::MemoryRegion],
+    ) -> Result<(), &'static str> {
+        self.inner
+            .memory_map
+            .try_init(regions)
+            .map_err(|_| "Memory map already set.")
+    }
+
+    /// Returns the [memory map](memory_map) the kernel was booted with.
+    ///
+    /// If the platform has not provided a memory map, this is empty.
+    #[must_use]
+    pub fn memory_map(&self) -> &'static [memory_map::MemoryRegion] {
+        self.inner.memory_map.try_get().copied().unwrap_or(&[])
+    }
+
+    /// Handle a [`UserRequestBody::QueryMemoryMap`] request, returning a
+    /// single page of the [memory map](memory_map).
+    ///
+    /// [`UserRequestBody::QueryMemoryMap`]: abi::syscall::UserRequestBody::QueryMemoryMap
+    #[must_use]
+    pub fn query_memory_map(
+        &self,
+        req: abi::syscall::memory::QueryMemoryMapRequest,
+    ) -> abi::syscall::memory::MemoryMapPage {
+        memory_map::page(self.memory_map(), req)
+    }
+
     #[track_caller]
     pub fn spawn_allocated<F>(
         &'static self,
@@ -262,6 +369,129 @@ impl Kernel {
         self.inner.timer.timeout(duration, f)
     }
 
+    /// Handle a [`UserRequestBody::Sleep`] request sent with `nonce`,
+    /// completing once the requested duration has elapsed.
+    ///
+    /// The response to the request should be sent when this completes. See
+    /// [`user_sleep`] for details.
+    ///
+    /// [`UserRequestBody::Sleep`]: abi::syscall::UserRequestBody::Sleep
+    pub async fn user_sleep(
+        &'static self,
+        nonce: u32,
+        req: abi::syscall::time::SleepRequest,
+    ) -> Result<(), abi::syscall::time::SleepError> {
+        self.inner
+            .user_sleeps
+            .sleep(&self.inner.timer, nonce, req)
+            .await
+    }
+
+    /// Handle a [`UserRequestBody::CancelSleep`] request, cancelling the
+    /// userspace sleep it names.
+    ///
+    /// Returns `false` if there was no such sleep in progress.
+    ///
+    /// [`UserRequestBody::CancelSleep`]: abi::syscall::UserRequestBody::CancelSleep
+    pub fn cancel_user_sleep(&'static self, req: abi::syscall::time::CancelSleepRequest) -> bool {
+        self.inner.user_sleeps.cancel(req)
+    }
+
+    /// Handle a [`UserRequestBody::Hello`] request, checking that the caller
+    /// was built with an ABI version the kernel supports.
+    ///
+    /// The kernel only supports its own [`ABI_VERSION`]. See
+    /// [`abi::syscall::hello`] for details.
+    ///
+    /// [`UserRequestBody::Hello`]: abi::syscall::UserRequestBody::Hello
+    /// [`ABI_VERSION`]: abi::syscall::hello::ABI_VERSION
+    pub fn hello(
+        &self,
+        req: abi::syscall::hello::HelloRequest,
+    ) -> Result<abi::syscall::hello::HelloResponse, abi::syscall::hello::HelloError> {
+        use abi::syscall::hello::{HelloError, HelloResponse, ABI_VERSION};
+
+        if req.abi_version != ABI_VERSION {
+            tracing::warn!(
+                theirs = req.abi_version,
+                ours = ABI_VERSION,
+                "rejecting userspace with a mismatched ABI version"
+            );
+            return Err(HelloError::AbiMismatch {
+                supported: ABI_VERSION,
+            });
+        }
+        Ok(HelloResponse {
+            abi_version: ABI_VERSION,
+        })
+    }
+
+    /// Handle a [`UserRequestBody::Ping`] request, returning the sequence
+    /// number to respond with in a [`KernelResponseBody::Pong`].
+    ///
+    /// [`UserRequestBody::Ping`]: abi::syscall::UserRequestBody::Ping
+    /// [`KernelResponseBody::Pong`]: abi::syscall::KernelResponseBody::Pong
+    #[must_use]
+    pub fn ping(&self, seq: u32) -> u32 {
+        seq
+    }
+
+    /// Handle a [`UserRequestBody::ConsoleWrite`] request, writing its bytes
+    /// to the [console](services::console).
+    ///
+    /// This completes once every console sink has accepted the bytes. If the
+    /// platform hasn't registered a console, it fails with
+    /// [`ConsoleError::NoConsole`].
+    ///
+    /// [`UserRequestBody::ConsoleWrite`]: abi::syscall::UserRequestBody::ConsoleWrite
+    /// [`ConsoleError::NoConsole`]: abi::syscall::console::ConsoleError::NoConsole
+    pub async fn console_write(
+        &'static self,
+        req: abi::syscall::console::ConsoleWriteRequest,
+    ) -> Result<abi::syscall::console::ConsoleWriteResponse, abi::syscall::console::ConsoleError>
+    {
+        use abi::syscall::console::{ConsoleError, ConsoleWriteResponse};
+
+        let mut console = services::console::ConsoleClient::from_registry_no_retry(self)
+            .await
+            .map_err(|_| ConsoleError::NoConsole)?;
+        let written = console.write(req).await?;
+        Ok(ConsoleWriteResponse {
+            written: written as u32,
+        })
+    }
+
+    /// Provide the kernel with the [entry points](tasks) which userspace may
+    /// spawn tasks from.
+    ///
+    /// This should be called once by the platform implementation, during
+    /// initialization. Returns an error if the entry points have already been
+    /// set.
+    pub fn set_task_entries(&self, entries: &'static [tasks::Entry]) -> Result<(), &'static str> {
+        self.inner.user_tasks.set_entries(entries)
+    }
+
+    /// Handle a [`UserRequestBody::Spawn`] request, spawning a task from one
+    /// of the kernel's [entry points](tasks).
+    ///
+    /// [`UserRequestBody::Spawn`]: abi::syscall::UserRequestBody::Spawn
+    pub fn spawn_user_task(
+        &'static self,
+        req: abi::syscall::tasks::SpawnRequest,
+    ) -> Result<abi::syscall::tasks::TaskHandle, abi::syscall::SysCallError> {
+        self.inner.user_tasks.spawn(self, req)
+    }
+
+    /// Handle a [`UserRequestBody::TaskStatus`] request.
+    ///
+    /// [`UserRequestBody::TaskStatus`]: abi::syscall::UserRequestBody::TaskStatus
+    pub fn user_task_status(
+        &'static self,
+        req: abi::syscall::tasks::TaskStatusRequest,
+    ) -> Result<abi::syscall::tasks::TaskStatus, abi::syscall::SysCallError> {
+        self.inner.user_tasks.status(req)
+    }
+
     /// Initialize the default set of cross-platform kernel [`services`] that
     /// are spawned on all hardware platforms.
     ///
@@ -0,0 +1,162 @@
+//! The boot memory map.
+//!
+//! The platform implementation hands the kernel the memory map it was booted
+//! with once, during initialization, using [`Kernel::set_memory_map`].
+//! Userspace reads it a page at a time with
+//! [`UserRequestBody::QueryMemoryMap`]; see [`abi::syscall::memory`] for
+//! details on pagination.
+//!
+//! [`Kernel::set_memory_map`]: crate::Kernel::set_memory_map
+//! [`UserRequestBody::QueryMemoryMap`]: abi::syscall::UserRequestBody::QueryMemoryMap
+use abi::syscall::memory::{MemoryMapPage, QueryMemoryMapRequest, MEMORY_MAP_PAGE_LEN};
+pub use abi::syscall::memory::{MemoryRegion, MemoryRegionKind};
+
+/// Returns the page of `map` starting at `req.cursor`.
+pub(crate) fn page(map: &[MemoryRegion], req: QueryMemoryMapRequest) -> MemoryMapPage {
+    let start = (req.cursor as usize).min(map.len());
+    let end = (start + MEMORY_MAP_PAGE_LEN).min(map.len());
+
+    let mut regions = [None; MEMORY_MAP_PAGE_LEN];
+    for (slot, region) in regions.iter_mut().zip(&map[start..end]) {
+        *slot = Some(*region);
+    }
+
+    MemoryMapPage {
+        total: map.len() as u32,
+        next: (end < map.len()).then_some(end as u32),
+        regions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comms::bbq, test_util::TestKernel};
+    use abi::{
+        frame,
+        syscall::{
+            KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader, UserRequest,
+            UserRequestBody, UserRequestHeader,
+        },
+    };
+
+    /// The size of the mailbox's messages, in bytes.
+    const MAX_MSG_SIZE: usize = 128;
+
+    /// Serialize `msg` into a sealed frame, as the mailbox does.
+    fn to_frame(msg: &impl serde::Serialize) -> Vec<u8> {
+        let payload = postcard::to_stdvec(msg).expect("message must serialize");
+        let mut bytes = vec![0; frame::frame_len(payload.len())];
+        bytes[frame::HEADER_LEN..].copy_from_slice(&payload);
+        frame::seal(&mut bytes);
+        bytes
+    }
+
+    static MAP: [MemoryRegion; 10] = {
+        let mut map = [MemoryRegion {
+            kind: MemoryRegionKind::Usable,
+            base: 0,
+            len: 0,
+        }; 10];
+        let mut i = 0;
+        while i < map.len() {
+            map[i] = MemoryRegion {
+                kind: match i % 3 {
+                    0 => MemoryRegionKind::Usable,
+                    1 => MemoryRegionKind::Bootloader,
+                    _ => MemoryRegionKind::Reserved,
+                },
+                base: (i as u64) << 40,
+                len: u64::MAX - i as u64,
+            };
+            i += 1;
+        }
+        map
+    };
+
+    #[test]
+    fn reassemble_over_rings() {
+        TestKernel::run(|k| async move {
+            k.set_memory_map(&MAP).unwrap();
+            let (u2k_tx, u2k_rx) = bbq::new_spsc_channel(256).await;
+            let (k2u_tx, k2u_rx) = bbq::new_spsc_channel(256).await;
+
+            let mut regions = Vec::new();
+            let mut frames = 0;
+            let mut cursor = Some(0);
+            while let Some(next) = cursor {
+                // userspace sends a request...
+                let req = UserRequest {
+                    header: UserRequestHeader { nonce: frames },
+                    body: UserRequestBody::QueryMemoryMap(QueryMemoryMapRequest { cursor: next }),
+                };
+                let bytes = to_frame(&req);
+                let mut wgr = u2k_tx.send_grant_exact(bytes.len()).await;
+                wgr.copy_from_slice(&bytes);
+                wgr.commit(bytes.len());
+
+                // ...which the kernel answers...
+                let rgr = u2k_rx.read_grant().await;
+                let len = rgr.len();
+                let payload = frame::open(&rgr).expect("request frame must be valid");
+                let req: UserRequest = postcard::from_bytes(payload).expect("request must decode");
+                rgr.release(len);
+                let UserRequestBody::QueryMemoryMap(query) = req.body else {
+                    panic!("expected a memory map query, got {:?}", req.body);
+                };
+                let rsp = KernelMsg::Response(KernelResponse {
+                    header: KernelResponseHeader {
+                        nonce: req.header.nonce,
+                    },
+                    body: KernelResponseBody::QueryMemoryMap(k.query_memory_map(query)),
+                });
+                let bytes = to_frame(&rsp);
+                assert!(
+                    bytes.len() <= frame::frame_len(MAX_MSG_SIZE),
+                    "{} bytes",
+                    bytes.len()
+                );
+                let mut wgr = k2u_tx.send_grant_exact(bytes.len()).await;
+                wgr.copy_from_slice(&bytes);
+                wgr.commit(bytes.len());
+
+                // ...and userspace collects the page.
+                let rgr = k2u_rx.read_grant().await;
+                let len = rgr.len();
+                let payload = frame::open(&rgr).expect("response frame must be valid");
+                let rsp: KernelMsg = postcard::from_bytes(payload).expect("response must decode");
+                rgr.release(len);
+                let (header, page) = match rsp {
+                    KernelMsg::Response(KernelResponse {
+                        header,
+                        body: KernelResponseBody::QueryMemoryMap(page),
+                    }) => (header, page),
+                    rsp => panic!("expected a memory map page, got {rsp:?}"),
+                };
+                assert_eq!(header.nonce, frames);
+                assert_eq!(page.total, MAP.len() as u32);
+                regions.extend(page.regions.iter().flatten().copied());
+                cursor = page.next;
+                frames += 1;
+            }
+
+            assert_eq!(frames, 3);
+            assert_eq!(regions, MAP);
+
+            // a cursor past the end returns an empty page.
+            let page = k.query_memory_map(QueryMemoryMapRequest { cursor: 100 });
+            assert_eq!(page.next, None);
+            assert!(page.regions.iter().all(Option::is_none));
+        })
+    }
+
+    #[test]
+    fn no_memory_map() {
+        TestKernel::run(|k| async move {
+            let page = k.query_memory_map(QueryMemoryMapRequest { cursor: 0 });
+            assert_eq!(page.total, 0);
+            assert_eq!(page.next, None);
+            assert!(page.regions.iter().all(Option::is_none));
+        })
+    }
+}
@@ -0,0 +1,93 @@
+//! Boot modules.
+//!
+//! A boot module is a read-only blob of data which the bootloader loaded into
+//! memory alongside the kernel, such as an initial ramdisk. The platform
+//! implementation hands the kernel its boot modules once, during
+//! initialization, using [`Kernel::set_modules`]. After that, they can be
+//! enumerated with [`Kernel::modules`], or looked up by name with
+//! [`Kernel::find_module`].
+//!
+//! ## Naming
+//!
+//! Each module has a name, which is chosen by the platform implementation.
+//! Where the bootloader provides a name for a module (such as a Multiboot
+//! module's command line), the platform should use that name. Otherwise, it
+//! should use a fixed name describing what the module is; for example, a
+//! bootloader-provided initial ramdisk is named [`RAMDISK`].
+//!
+//! Module names are compared exactly. If more than one module has the same
+//! name, [`Kernel::find_module`] returns the first one. Modules are
+//! enumerated in the order the platform provided them, which should be the
+//! order the bootloader loaded them in.
+//!
+//! A platform with no boot modules (or that never calls
+//! [`Kernel::set_modules`]) simply has none: [`Kernel::modules`] returns an
+//! empty slice, and [`Kernel::find_module`] returns `None`.
+//!
+//! [`Kernel::set_modules`]: crate::Kernel::set_modules
+//! [`Kernel::modules`]: crate::Kernel::modules
+//! [`Kernel::find_module`]: crate::Kernel::find_module
+
+/// The name of the initial ramdisk, when the bootloader doesn't give it a
+/// name of its own.
+pub const RAMDISK: &str = "ramdisk";
+
+/// A read-only blob of data loaded by the bootloader.
+///
+/// See the [module-level documentation](self) for details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootModule {
+    name: &'static str,
+    data: &'static [u8],
+}
+
+impl BootModule {
+    #[must_use]
+    pub const fn new(name: &'static str, data: &'static [u8]) -> Self {
+        Self { name, data }
+    }
+
+    /// Returns the name of this module.
+    #[inline]
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the contents of this module.
+    #[inline]
+    #[must_use]
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TestKernel;
+
+    #[test]
+    fn find_module() {
+        static MODULES: [BootModule; 3] = [
+            BootModule::new(RAMDISK, b"first"),
+            BootModule::new("config", b"hello"),
+            BootModule::new(RAMDISK, b"second"),
+        ];
+
+        TestKernel::run(|k| async move {
+            assert!(k.modules().is_empty());
+            assert_eq!(k.find_module(RAMDISK), None);
+
+            k.set_modules(&MODULES).unwrap();
+            assert_eq!(k.modules(), &MODULES[..]);
+            assert_eq!(k.find_module("config"), Some(&b"hello"[..]));
+            // duplicate names resolve to the first module.
+            assert_eq!(k.find_module(RAMDISK), Some(&b"first"[..]));
+            assert_eq!(k.find_module("nope"), None);
+
+            // modules may only be set once.
+            assert!(k.set_modules(&[]).is_err());
+        });
+    }
+}
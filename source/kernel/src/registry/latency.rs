@@ -0,0 +1,190 @@
+//! Per-service request latency tracking.
+//!
+//! Each registered service has a [`LatencyHistogram`], which records how long
+//! requests sent with [`KernelHandle::request_oneshot`] took to be answered.
+//! The histogram is a fixed-size array of exponentially sized buckets, so
+//! recording a sample is a single atomic increment, and percentiles are
+//! estimated to within a factor of two, without storing any samples.
+//!
+//! Bucket 0 counts latencies below the configured
+//! [`LatencySettings::resolution`], bucket `n` counts latencies between
+//! `resolution * 2^(n - 1)` and `resolution * 2^n`, and the last bucket counts
+//! everything longer than that. Each bucket costs four bytes per service.
+//!
+//! [`KernelHandle::request_oneshot`]: super::KernelHandle::request_oneshot
+
+use maitake::time::{Clock, Duration, Instant};
+use mnemos_alloc::containers::FixedVec;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::{Deserialize, Serialize};
+
+/// Settings for per-service request latency tracking.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencySettings {
+    /// Whether request latency is tracked at all.
+    #[serde(default = "LatencySettings::default_enabled")]
+    pub enabled: bool,
+    /// The number of histogram buckets allocated for each service.
+    ///
+    /// More buckets can record longer latencies before they are all lumped
+    /// into the last bucket.
+    #[serde(default = "LatencySettings::default_buckets")]
+    pub buckets: usize,
+    /// The upper bound of the first histogram bucket. Smaller values give a
+    /// finer resolution for fast requests.
+    #[serde(default = "LatencySettings::default_resolution")]
+    pub resolution: Duration,
+}
+
+/// Estimated latency percentiles for a service, returned by
+/// [`LatencyHistogram::snapshot`].
+///
+/// Each percentile is the upper bound of the bucket containing it, so it may
+/// overestimate the true value by up to a factor of two. A percentile which
+/// fell in the last bucket is reported as `None`, since that bucket has no
+/// upper bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// The number of requests recorded.
+    pub samples: u32,
+    pub p50: Option<Duration>,
+    pub p99: Option<Duration>,
+}
+
+/// A histogram of request latencies for a single service.
+///
+/// See the [module-level documentation](self) for details.
+pub struct LatencyHistogram {
+    clock: Clock,
+    resolution: Duration,
+    buckets: FixedVec<AtomicU32>,
+}
+
+// === impl LatencySettings ===
+
+impl LatencySettings {
+    pub const DEFAULT_ENABLED: bool = true;
+    pub const DEFAULT_BUCKETS: usize = 16;
+    pub const DEFAULT_RESOLUTION: Duration = Duration::from_micros(10);
+
+    const fn default_enabled() -> bool {
+        Self::DEFAULT_ENABLED
+    }
+
+    const fn default_buckets() -> usize {
+        Self::DEFAULT_BUCKETS
+    }
+
+    const fn default_resolution() -> Duration {
+        Self::DEFAULT_RESOLUTION
+    }
+}
+
+impl Default for LatencySettings {
+    fn default() -> Self {
+        Self {
+            enabled: Self::DEFAULT_ENABLED,
+            buckets: Self::DEFAULT_BUCKETS,
+            resolution: Self::DEFAULT_RESOLUTION,
+        }
+    }
+}
+
+// === impl LatencyHistogram ===
+
+impl LatencyHistogram {
+    /// Returns a new histogram, or `None` if tracking is disabled, or its
+    /// buckets could not be allocated.
+    pub(crate) fn try_new(settings: &LatencySettings, clock: &Clock) -> Option<Self> {
+        if !settings.enabled || settings.buckets == 0 {
+            return None;
+        }
+
+        let mut buckets = FixedVec::try_new(settings.buckets)?;
+        for _ in 0..settings.buckets {
+            buckets.try_push(AtomicU32::new(0)).ok()?;
+        }
+        Some(Self {
+            clock: clock.clone(),
+            resolution: settings.resolution,
+            buckets,
+        })
+    }
+
+    /// Returns the current time, for measuring a request's latency.
+    pub(crate) fn start(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Record the latency of a request which started at `start`.
+    pub(crate) fn record(&self, start: Instant) {
+        let elapsed = self
+            .clock
+            .now()
+            .checked_duration_since(start)
+            .unwrap_or_default();
+        self.record_duration(elapsed);
+    }
+
+    pub(crate) fn record_duration(&self, latency: Duration) {
+        let buckets = self.buckets.as_slice();
+        let idx = self.bucket_of(latency).min(buckets.len() - 1);
+        buckets[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Estimate the latency percentiles recorded so far.
+    #[must_use]
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let buckets = self.buckets.as_slice();
+        let samples = buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .fold(0u32, u32::saturating_add);
+        LatencySnapshot {
+            samples,
+            p50: self.percentile(samples, 50),
+            p99: self.percentile(samples, 99),
+        }
+    }
+
+    /// Discard all recorded samples.
+    pub fn reset(&self) {
+        for bucket in self.buckets.as_slice() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn bucket_of(&self, latency: Duration) -> usize {
+        let resolution = self.resolution.as_nanos().max(1);
+        let ratio = latency.as_nanos() / resolution;
+        // bucket 0 holds latencies below one `resolution`, and bucket `n`
+        // holds latencies below `resolution * 2^n`.
+        (u128::BITS - ratio.leading_zeros()) as usize
+    }
+
+    fn percentile(&self, samples: u32, pct: u32) -> Option<Duration> {
+        if samples == 0 {
+            return None;
+        }
+
+        let buckets = self.buckets.as_slice();
+        // the rank of the sample at this percentile, rounding up.
+        let rank = ((samples as u64 * pct as u64 + 99) / 100).max(1);
+        let mut seen = 0u64;
+        for (idx, bucket) in buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed) as u64;
+            if seen >= rank {
+                if idx == buckets.len() - 1 {
+                    // the last bucket has no upper bound.
+                    return None;
+                }
+                return 1u32
+                    .checked_shl(idx as u32)
+                    .and_then(|scale| self.resolution.checked_mul(scale));
+            }
+        }
+
+        // samples were recorded concurrently with the snapshot.
+        None
+    }
+}
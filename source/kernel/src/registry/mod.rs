@@ -6,9 +6,21 @@ use core::{
 };
 
 use crate::comms::{kchannel, oneshot::Reusable};
-use maitake::sync::{RwLock, WaitQueue};
-use mnemos_alloc::containers::FixedVec;
-use portable_atomic::{AtomicU32, Ordering};
+use abi::syscall::{
+    services::{
+        ListServicesError, ListServicesRequest, ListServicesResponse, ServiceInfo,
+        ServiceLatencyError, ServiceLatencyRequest, ServiceLatencyResponse, ServiceState,
+        ServiceStats, LIST_SERVICES_PAGE_LEN, LIST_SERVICES_VERSION,
+    },
+    SysCallError, NO_RESPONSE_NONCE,
+};
+use alloc::vec::Vec;
+use maitake::{
+    sync::{RwLock, WaitQueue},
+    time::Clock,
+};
+use mnemos_alloc::containers::Arc;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 use postcard::experimental::max_size::MaxSize;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use spitebuf::EnqueueError;
@@ -21,7 +33,9 @@ use crate::comms::{
     oneshot::{ReusableError, Sender},
 };
 
+pub mod latency;
 pub mod listener;
+pub use self::latency::{LatencyHistogram, LatencySettings, LatencySnapshot};
 pub use self::listener::{Listener, Registration};
 
 #[cfg(test)]
@@ -45,6 +59,7 @@ pub mod known_uuids {
         pub const KEYBOARD_MUX: Uuid = uuid!("70861d1c-9f01-4e9b-89e6-ede77d8f26d8");
         pub const EMB_DISPLAY_V2: Uuid = uuid!("aa6a2af8-afd8-40e3-83c2-2c501c698aa8");
         pub const SDMMC: Uuid = uuid!("9f4f8244-c986-4212-982e-d35890260de4");
+        pub const CONSOLE: Uuid = uuid!("568b79ed-21fd-483e-a9bb-0875256052c4");
     }
 
     // In case you need to iterate over every UUID
@@ -58,6 +73,7 @@ pub mod known_uuids {
         kernel::KEYBOARD,
         kernel::KEYBOARD_MUX,
         kernel::EMB_DISPLAY_V2,
+        kernel::CONSOLE,
     ];
 }
 
@@ -121,9 +137,15 @@ pub struct RegistryType {
 
 /// The driver registry used by the kernel.
 pub struct Registry {
-    items: RwLock<FixedVec<RegistryItem>>,
+    /// The registered services. This grows as services are registered, up to
+    /// `max_items`.
+    items: RwLock<Vec<RegistryItem>>,
+    /// The maximum number of services which may be registered, if limited.
+    max_items: Option<usize>,
     counter: AtomicU32,
     service_added: WaitQueue,
+    /// Settings and clock used to track request latency, if enabled.
+    latency: Option<(LatencySettings, Clock)>,
 }
 
 // TODO: This probably goes into the ABI crate, here is fine for now
@@ -268,7 +290,16 @@ pub enum UserHandlerError {
 #[derive(Debug, Eq, PartialEq)]
 pub enum RegistrationError {
     UuidAlreadyRegistered(Uuid),
-    RegistryFull,
+    /// The registry already contains the maximum number of services it was
+    /// created with (see [`KernelSettings::max_drivers`]).
+    ///
+    /// [`KernelSettings::max_drivers`]: crate::KernelSettings::max_drivers
+    RegistryFull {
+        capacity: usize,
+    },
+    /// The registry's storage had to grow to fit the service, but the
+    /// allocation failed. Registering the service may succeed later.
+    OutOfMemory,
 }
 
 /// Errors returned by [`Registry::connect`] and [`Registry::try_connect`].
@@ -354,6 +385,7 @@ pub struct KernelHandle<RD: RegisteredDriver> {
     service_id: ServiceId,
     client_id: ClientId,
     request_ctr: u32,
+    latency: Option<Arc<LatencyHistogram>>,
 }
 
 type ErasedReqDeser = unsafe fn(
@@ -381,6 +413,20 @@ struct RegistryValue {
     conn_prod: ErasedKProducer,
     user_vtable: Option<UserVtable>,
     service_id: ServiceId,
+    health: ServiceHealth,
+    /// Request latency for the service, if tracked.
+    latency: Option<Arc<LatencyHistogram>>,
+}
+
+/// Health and statistics tracked for each registered service, reported by
+/// [`Registry::list_services`].
+#[derive(Default)]
+struct ServiceHealth {
+    /// The number of connections successfully established to the service.
+    connections: AtomicU32,
+    /// Set when a connection attempt finds that the service is no longer
+    /// running, and cleared when a connection succeeds.
+    faulted: AtomicBool,
 }
 
 /// A [virtual function pointer table][vtable] (vtable) that specifies how
@@ -413,13 +459,50 @@ impl RegistryType {
 // Registry
 
 impl Registry {
-    /// Create a new registry with room for up to `max_items` registered drivers.
-    pub fn new(max_items: usize) -> Self {
-        let items = FixedVec::try_new(max_items).unwrap();
-        Self {
+    /// The number of services the registry has room for when it is created.
+    pub const INITIAL_CAPACITY: usize = 8;
+
+    /// Create a new registry with room for up to `max_items` registered
+    /// drivers, or any number of drivers if `max_items` is `None`.
+    ///
+    /// Storage for the registry is allocated from the heap, starting with
+    /// room for [`Registry::INITIAL_CAPACITY`] drivers (or `max_items`, if
+    /// that's smaller), and growing as drivers are registered. Registering
+    /// more than `max_items` drivers fails with
+    /// [`RegistrationError::RegistryFull`]. If `max_items` is zero, no storage
+    /// is allocated, and every registration fails.
+    ///
+    /// Returns `None` if the registry's initial storage could not be
+    /// allocated.
+    pub fn try_new(max_items: Option<usize>) -> Option<Self> {
+        let mut items = Vec::new();
+        let initial = max_items.map_or(Self::INITIAL_CAPACITY, |max| {
+            max.min(Self::INITIAL_CAPACITY)
+        });
+        items.try_reserve_exact(initial).ok()?;
+        let service_added = WaitQueue::new();
+        if max_items == Some(0) {
+            // no services will ever be added.
+            service_added.close();
+        }
+        Some(Self {
             items: RwLock::new(items),
+            max_items,
             counter: AtomicU32::new(0),
-            service_added: WaitQueue::new(),
+            service_added,
+            latency: None,
+        })
+    }
+
+    /// Track the latency of requests to each service registered after this
+    /// call, measured using `clock`. See the [`latency`] module for details.
+    ///
+    /// By default, latency is not tracked.
+    #[must_use]
+    pub fn with_latency_tracking(self, settings: LatencySettings, clock: Clock) -> Self {
+        Self {
+            latency: Some((settings, clock)),
+            ..self
         }
     }
 
@@ -495,6 +578,8 @@ impl Registry {
                 conn_prod,
                 user_vtable: None,
                 service_id: ServiceId(service_id),
+                health: ServiceHealth::default(),
+                latency: self.new_latency_histogram(),
             },
         })
         .await?;
@@ -536,6 +621,8 @@ impl Registry {
                 conn_prod,
                 user_vtable: Some(UserVtable::new::<RD>()),
                 service_id: ServiceId(service_id),
+                health: ServiceHealth::default(),
+                latency: self.new_latency_histogram(),
             },
         })
         .await?;
@@ -584,7 +671,7 @@ impl Registry {
         &self,
         hello: RD::Hello,
     ) -> Result<KernelHandle<RD>, ConnectError<RD>> {
-        let (tx, service_id) = {
+        let (tx, service_id, latency) = {
             // /!\ WARNING: Load-bearing scope /!\
             //
             // We need to ensure that we only hold the lock on `self.items`
@@ -606,34 +693,43 @@ impl Registry {
                     .conn_prod
                     .clone_typed::<listener::Handshake<RD>>()
             };
-            (tx, item.value.service_id)
+            (tx, item.value.service_id, item.value.latency.clone())
         };
 
-        // TODO(eliza): it would be nice if we could reuse the oneshot receiver
-        // every time this driver is connected to? This would require type
-        // erasing it...
-        let rx = Reusable::new_async().await;
-        let reply = rx
-            .sender()
-            .await
-            .expect("we just created the oneshot, so this should never fail");
-        // send the connection request...
-        tx.enqueue_async(listener::Handshake {
-            hello,
-            accept: listener::Accept { reply }
-        }).await.map_err(|err| match err {
-            kchannel::EnqueueError::Closed(_) => ConnectError::DriverDead,
-            kchannel::EnqueueError::Full(_) => unreachable!("the channel should not be full, as we are using `enqueue_async`, which waits for capacity")
-        })?;
-        // ...and wait for a response with an established connection.
-        let prod = rx
-            .receive()
-            .await
-            // this is a `Reusable<Result<KProducer, RD::ConnectError>>>`, so
-            // the outer `Result` is the error returned by `receive()`...
-            .map_err(|_| ConnectError::DriverDead)?
-            // ...and the inner `Result` is the error returned by the driver.
-            .map_err(ConnectError::Rejected)?;
+        let prod = async {
+            // TODO(eliza): it would be nice if we could reuse the oneshot receiver
+            // every time this driver is connected to? This would require type
+            // erasing it...
+            let rx = Reusable::new_async().await;
+            let reply = rx
+                .sender()
+                .await
+                .expect("we just created the oneshot, so this should never fail");
+            // send the connection request...
+            tx.enqueue_async(listener::Handshake {
+                hello,
+                accept: listener::Accept { reply }
+            }).await.map_err(|err| match err {
+                kchannel::EnqueueError::Closed(_) => ConnectError::DriverDead,
+                kchannel::EnqueueError::Full(_) => unreachable!("the channel should not be full, as we are using `enqueue_async`, which waits for capacity")
+            })?;
+            // ...and wait for a response with an established connection.
+            rx.receive()
+                .await
+                // this is a `Reusable<Result<KProducer, RD::ConnectError>>>`, so
+                // the outer `Result` is the error returned by `receive()`...
+                .map_err(|_| ConnectError::DriverDead)?
+                // ...and the inner `Result` is the error returned by the driver.
+                .map_err(ConnectError::Rejected)
+        }
+        .await;
+        self.update_health(RD::UUID, |health| match &prod {
+            Ok(_) => health.record_connection(),
+            Err(ConnectError::DriverDead) => health.record_fault(),
+            Err(_) => {}
+        })
+        .await;
+        let prod = prod?;
 
         let client_id = self.counter.fetch_add(1, Ordering::Relaxed);
         let res = Ok(KernelHandle {
@@ -641,6 +737,7 @@ impl Registry {
             service_id,
             client_id: ClientId(client_id),
             request_ctr: 0,
+            latency,
         });
 
         info!(
@@ -822,12 +919,21 @@ impl Registry {
                 return Err(UserConnectError::DeserializationFailed(error));
             }
             // Safe to touch the out pointer!
-            Ok(Ok(())) => unsafe {
-                // Safety: `handshake_result` is guaranteed to be initialized by
-                // `erased_handshake` if and only if its future completes with
-                // an `Ok(())`. and it did!
-                handshake_result.assume_init()?.type_erase()
-            },
+            Ok(Ok(())) => {
+                let result = unsafe {
+                    // Safety: `handshake_result` is guaranteed to be initialized by
+                    // `erased_handshake` if and only if its future completes with
+                    // an `Ok(())`. and it did!
+                    handshake_result.assume_init()
+                };
+                self.update_health(RD::UUID, |health| match &result {
+                    Ok(_) => health.record_connection(),
+                    Err(UserConnectError::DriverDead) => health.record_fault(),
+                    Err(_) => {}
+                })
+                .await;
+                result?.type_erase()
+            }
         };
 
         let client_id = self.counter.fetch_add(1, Ordering::Relaxed);
@@ -847,6 +953,105 @@ impl Registry {
         })
     }
 
+    /// List the services registered with the registry, along with their
+    /// health.
+    ///
+    /// This returns a single page of at most [`LIST_SERVICES_PAGE_LEN`]
+    /// services, starting at `req.cursor`. See [`abi::syscall::services`] for
+    /// details on pagination.
+    pub async fn list_services(
+        &self,
+        req: ListServicesRequest,
+    ) -> Result<ListServicesResponse, ListServicesError> {
+        if req.version != LIST_SERVICES_VERSION {
+            return Err(ListServicesError::UnsupportedVersion {
+                supported: LIST_SERVICES_VERSION,
+            });
+        }
+
+        let items = self.items.read().await;
+        let items = items.as_slice();
+        let start = (req.cursor as usize).min(items.len());
+        let end = (start + LIST_SERVICES_PAGE_LEN).min(items.len());
+
+        let mut services: [Option<ServiceInfo>; LIST_SERVICES_PAGE_LEN] = Default::default();
+        for (slot, item) in services.iter_mut().zip(&items[start..end]) {
+            let health = &item.value.health;
+            let state = if health.faulted.load(Ordering::Acquire) {
+                ServiceState::Faulted
+            } else {
+                // services are only added to the registry once their listener
+                // exists, so a registered service is always ready to accept
+                // connections.
+                ServiceState::Ready
+            };
+            *slot = Some(ServiceInfo {
+                uuid: *item.key.as_bytes(),
+                service_id: item.value.service_id.0,
+                state,
+                stats: ServiceStats {
+                    connections: health.connections.load(Ordering::Relaxed),
+                    userspace: item.value.user_vtable.is_some(),
+                },
+            });
+        }
+
+        Ok(ListServicesResponse {
+            version: LIST_SERVICES_VERSION,
+            total: items.len() as u32,
+            next: (end < items.len()).then_some(end as u32),
+            services,
+        })
+    }
+
+    /// Report the request latency percentiles for the service with the
+    /// requested UUID, resetting its statistics if requested.
+    ///
+    /// Only requests sent with [`KernelHandle::request_oneshot`] are timed,
+    /// since other replies are not observed by the sender's handle.
+    pub async fn service_latency(
+        &self,
+        req: ServiceLatencyRequest,
+    ) -> Result<ServiceLatencyResponse, ServiceLatencyError> {
+        let uuid = Uuid::from_bytes(req.uuid);
+        let items = self.items.read().await;
+        let item = items
+            .as_slice()
+            .iter()
+            .find(|i| i.key == uuid)
+            .ok_or(ServiceLatencyError::NotFound)?;
+        let latency = item
+            .value
+            .latency
+            .as_ref()
+            .ok_or(ServiceLatencyError::NotTracked)?;
+
+        let snapshot = latency.snapshot();
+        if req.reset {
+            latency.reset();
+        }
+
+        let as_micros = |d: maitake::time::Duration| u32::try_from(d.as_micros()).ok();
+        Ok(ServiceLatencyResponse {
+            samples: snapshot.samples,
+            p50_us: snapshot.p50.and_then(as_micros),
+            p99_us: snapshot.p99.and_then(as_micros),
+        })
+    }
+
+    fn new_latency_histogram(&self) -> Option<Arc<LatencyHistogram>> {
+        let (settings, clock) = self.latency.as_ref()?;
+        let histogram = LatencyHistogram::try_new(settings, clock)?;
+        Arc::try_new(histogram).ok()
+    }
+
+    async fn update_health(&self, uuid: Uuid, f: impl FnOnce(&ServiceHealth)) {
+        let items = self.items.read().await;
+        if let Some(item) = items.as_slice().iter().find(|i| i.key == uuid) {
+            f(&item.value.health);
+        }
+    }
+
     async fn insert_item(&self, item: RegistryItem) -> Result<(), RegistrationError> {
         {
             let mut items = self.items.write().await;
@@ -854,13 +1059,27 @@ impl Registry {
                 return Err(RegistrationError::UuidAlreadyRegistered(item.key));
             }
 
-            items.try_push(item).map_err(|_| {
-                warn!("failed to insert new registry item; the registry is full!");
+            if let Some(capacity) = self.max_items.filter(|&max| items.len() >= max) {
+                warn!(
+                    capacity,
+                    "failed to insert new registry item; the registry is full!"
+                );
                 // close the "service added" waitcell, because no new services will
                 // ever be added.
                 self.service_added.close();
-                RegistrationError::RegistryFull
+                return Err(RegistrationError::RegistryFull { capacity });
+            }
+
+            // grow the registry's storage, if needed, without panicking if the
+            // allocation fails.
+            items.try_reserve(1).map_err(|_| {
+                warn!(
+                    len = items.len(),
+                    "failed to insert new registry item; could not grow the registry!"
+                );
+                RegistrationError::OutOfMemory
             })?;
+            items.push(item);
         }
 
         self.service_added.wake_all();
@@ -868,7 +1087,7 @@ impl Registry {
         Ok(())
     }
 
-    fn get<RD: RegisteredDriver>(items: &FixedVec<RegistryItem>) -> Option<&RegistryItem> {
+    fn get<RD: RegisteredDriver>(items: &[RegistryItem]) -> Option<&RegistryItem> {
         let Some(item) = items.as_slice().iter().find(|i| i.key == RD::UUID) else {
             debug!(
                 svc = %any::type_name::<RD>(),
@@ -895,6 +1114,19 @@ impl Registry {
     }
 }
 
+// ServiceHealth
+
+impl ServiceHealth {
+    fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.faulted.store(false, Ordering::Release);
+    }
+
+    fn record_fault(&self) {
+        self.faulted.store(true, Ordering::Release);
+    }
+}
+
 // UserRequest
 
 // Envelope
@@ -1026,6 +1258,11 @@ where
                 sender.send(envelope)?;
                 Ok(())
             }
+            ReplyTo::Userspace { nonce, .. } if nonce == NO_RESPONSE_NONCE => {
+                // userspace sent a fire-and-forget request, and isn't
+                // expecting a reply.
+                Ok(())
+            }
             ReplyTo::Userspace { nonce, outgoing } => {
                 let mut wgr = outgoing
                     .send_grant_exact(
@@ -1105,10 +1342,18 @@ impl<RD: RegisteredDriver> KernelHandle<RD> {
         reply: &Reusable<Envelope<Result<RD::Response, RD::Error>>>,
     ) -> Result<Envelope<Result<RD::Response, RD::Error>>, OneshotRequestError> {
         let tx = reply.sender().await.map_err(OneshotRequestError::Sender)?;
+        let start = self.latency.as_ref().map(|latency| latency.start());
         self.send(msg, ReplyTo::OneShot(tx))
             .await
             .map_err(|_| OneshotRequestError::Send)?;
-        reply.receive().await.map_err(OneshotRequestError::Receive)
+        let rsp = reply
+            .receive()
+            .await
+            .map_err(OneshotRequestError::Receive)?;
+        if let (Some(latency), Some(start)) = (&self.latency, start) {
+            latency.record(start);
+        }
+        Ok(rsp)
     }
 }
 
@@ -1272,6 +1517,15 @@ impl fmt::Display for UserHandlerError {
     }
 }
 
+impl From<UserHandlerError> for SysCallError {
+    fn from(error: UserHandlerError) -> Self {
+        match error {
+            UserHandlerError::DeserializationFailed => SysCallError::InvalidRequest,
+            UserHandlerError::QueueFull => SysCallError::ResourceExhausted,
+        }
+    }
+}
+
 // ConnectError
 
 impl<D> PartialEq for ConnectError<D>
@@ -1414,10 +1668,15 @@ where
 impl fmt::Display for RegistrationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::RegistryFull => "the registry is full".fmt(f),
+            Self::RegistryFull { capacity } => write!(
+                f,
+                "the registry is full (it has room for {capacity} services; \
+                 consider increasing `max_drivers`)"
+            ),
             Self::UuidAlreadyRegistered(uuid) => {
                 write!(f, "a service with UUID {uuid} has already been registered")
             }
+            Self::OutOfMemory => f.write_str("the registry could not grow to fit the service"),
         }
     }
 }
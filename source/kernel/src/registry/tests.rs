@@ -17,6 +17,18 @@ impl RegisteredDriver for TestService {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 struct TestMessage(usize);
 
+/// A distinct service for each `N`, for tests which register several.
+struct Numbered<const N: u128>;
+
+impl<const N: u128> RegisteredDriver for Numbered<N> {
+    type Request = TestMessage;
+    type Response = TestMessage;
+    type Error = TestMessage;
+    type Hello = TestMessage;
+    type ConnectError = TestMessage;
+    const UUID: Uuid = Uuid::from_u128(N);
+}
+
 #[test]
 fn konly_connect() {
     TestKernel::run(|k| async move {
@@ -207,3 +219,438 @@ fn user_connect() {
         assert_eq!(Ok(TestMessage(4)), rsp);
     })
 }
+
+impl MaxSize for TestMessage {
+    const POSTCARD_MAX_SIZE: usize = usize::POSTCARD_MAX_SIZE;
+}
+
+#[test]
+fn no_response_nonce() {
+    TestKernel::run(|_| async move {
+        let (user_tx, user_rx) = bbq::new_spsc_channel(256).await;
+        let user_tx = user_tx.into_mpmc_producer().await;
+
+        let envelope = |nonce| Envelope {
+            body: Ok(TestMessage(1)),
+            service_id: ServiceId(0),
+            client_id: ClientId(0),
+            request_id: RequestResponseId::new(nonce, MessageKind::Response),
+        };
+
+        // a fire-and-forget request's reply is discarded.
+        ReplyTo::<TestService>::Userspace {
+            nonce: NO_RESPONSE_NONCE,
+            outgoing: user_tx.clone(),
+        }
+        .reply(TestService::UUID, envelope(NO_RESPONSE_NONCE))
+        .await
+        .expect("reply should succeed");
+        assert!(user_rx.read_grant_sync().is_none());
+
+        // but other requests are still replied to.
+        ReplyTo::<TestService>::Userspace {
+            nonce: 1,
+            outgoing: user_tx,
+        }
+        .reply(TestService::UUID, envelope(1))
+        .await
+        .expect("reply should succeed");
+        let rgr = user_rx.read_grant_sync().expect("reply should be sent");
+        let len = rgr.len();
+        let rsp: UserResponse<TestMessage, TestMessage> =
+            postcard::from_bytes(&rgr[..]).expect("response should deserialize");
+        rgr.release(len);
+        assert_eq!(rsp.nonce, 1);
+    })
+}
+
+#[test]
+fn list_services() {
+    use abi::syscall::{
+        services::{ListServicesError, ListServicesRequest, ServiceState, LIST_SERVICES_VERSION},
+        KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader,
+    };
+
+    TestKernel::run(|k| async move {
+        let _l1 = k.registry().bind_konly::<Numbered<1>>(1).await.unwrap();
+        let _l2 = k.registry().bind::<Numbered<2>>(1).await.unwrap();
+        let _l3 = k.registry().bind_konly::<Numbered<3>>(1).await.unwrap();
+        let _l4 = k.registry().bind::<Numbered<4>>(1).await.unwrap();
+
+        let page1 = k
+            .registry()
+            .list_services(ListServicesRequest {
+                version: LIST_SERVICES_VERSION,
+                cursor: 0,
+            })
+            .await
+            .expect("listing services should succeed");
+        assert_eq!(page1.version, LIST_SERVICES_VERSION);
+        assert_eq!(page1.total, 4);
+        assert_eq!(page1.next, Some(3));
+        let uuids = page1
+            .services
+            .iter()
+            .map(|svc| Uuid::from_bytes(svc.as_ref().unwrap().uuid))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            uuids,
+            [
+                Numbered::<1>::UUID,
+                Numbered::<2>::UUID,
+                Numbered::<3>::UUID
+            ]
+        );
+        for svc in page1.services.iter().flatten() {
+            assert_eq!(svc.state, ServiceState::Ready);
+            assert_eq!(svc.stats.connections, 0);
+        }
+        assert!(!page1.services[0].as_ref().unwrap().stats.userspace);
+        assert!(page1.services[1].as_ref().unwrap().stats.userspace);
+
+        let page2 = k
+            .registry()
+            .list_services(ListServicesRequest {
+                version: LIST_SERVICES_VERSION,
+                cursor: 3,
+            })
+            .await
+            .expect("listing services should succeed");
+        assert_eq!(page2.total, 4);
+        assert_eq!(page2.next, None);
+        let svc = page2.services[0].as_ref().unwrap();
+        assert_eq!(Uuid::from_bytes(svc.uuid), Numbered::<4>::UUID);
+        assert!(page2.services[1..].iter().all(Option::is_none));
+
+        // a cursor past the end returns an empty page.
+        let page3 = k
+            .registry()
+            .list_services(ListServicesRequest {
+                version: LIST_SERVICES_VERSION,
+                cursor: 10,
+            })
+            .await
+            .expect("listing services should succeed");
+        assert_eq!(page3.next, None);
+        assert!(page3.services.iter().all(Option::is_none));
+
+        let res = k
+            .registry()
+            .list_services(ListServicesRequest {
+                version: LIST_SERVICES_VERSION + 1,
+                cursor: 0,
+            })
+            .await;
+        assert!(matches!(
+            res,
+            Err(ListServicesError::UnsupportedVersion {
+                supported: LIST_SERVICES_VERSION
+            })
+        ));
+
+        // a full page must fit in a mailbox message.
+        let msg = KernelMsg::Response(KernelResponse {
+            header: KernelResponseHeader { nonce: u32::MAX },
+            body: KernelResponseBody::ListServices(Ok(page1)),
+        });
+        let bytes = postcard::to_stdvec(&msg).expect("response must serialize");
+        assert!(bytes.len() <= 128, "{} bytes", bytes.len());
+    })
+}
+
+#[test]
+fn list_services_faulted() {
+    use abi::syscall::services::{ListServicesRequest, ServiceState, LIST_SERVICES_VERSION};
+
+    async fn state(k: &'static Kernel) -> ServiceState {
+        let page = k
+            .registry()
+            .list_services(ListServicesRequest {
+                version: LIST_SERVICES_VERSION,
+                cursor: 0,
+            })
+            .await
+            .expect("listing services should succeed");
+        page.services[0].as_ref().unwrap().state
+    }
+
+    TestKernel::run(|k| async move {
+        let listener = k.registry().bind_konly::<TestService>(2).await.unwrap();
+
+        // server: fails the first handshake by dropping it without a reply,
+        // then accepts every later one.
+        k.spawn(async move {
+            drop(listener.handshake().await);
+            loop {
+                let conn = listener.handshake().await;
+                let (tx, _rx) = comms::kchannel::KChannel::new_async(2).await.split();
+                conn.accept(tx).unwrap();
+            }
+        })
+        .await;
+
+        assert_eq!(state(k).await, ServiceState::Ready);
+
+        let res = k.registry().connect::<TestService>(TestMessage(1)).await;
+        assert!(matches!(res, Err(ConnectError::DriverDead)));
+        assert_eq!(state(k).await, ServiceState::Faulted);
+
+        // a successful connection clears the fault.
+        k.registry()
+            .connect::<TestService>(TestMessage(1))
+            .await
+            .expect("connect should succeed");
+        assert_eq!(state(k).await, ServiceState::Ready);
+    })
+}
+
+#[test]
+fn registry_capacity() {
+    TestKernel::run(|_| async move {
+        // a registry with no capacity rejects every registration.
+        let registry = Registry::try_new(Some(0)).expect("an empty registry needs no allocation");
+        let res = registry.bind_konly::<TestService>(1).await;
+        assert_eq!(
+            res.err(),
+            Some(RegistrationError::RegistryFull { capacity: 0 })
+        );
+
+        let registry = Registry::try_new(Some(1)).expect("allocating the registry should succeed");
+        let _listener = registry
+            .bind_konly::<TestService>(1)
+            .await
+            .expect("the first registration should succeed");
+        let res = registry.bind_konly::<Numbered<1>>(1).await;
+        assert_eq!(
+            res.err(),
+            Some(RegistrationError::RegistryFull { capacity: 1 })
+        );
+    })
+}
+
+#[test]
+fn registry_grows() {
+    macro_rules! bind_all {
+        ($registry:expr; $($n:literal),+) => {
+            $(
+                $registry
+                    .bind_konly::<Numbered<$n>>(1)
+                    .await
+                    .expect("registration should succeed");
+            )+
+        };
+    }
+
+    TestKernel::run(|_| async move {
+        // an unbounded registry grows past its initial capacity.
+        let registry = Registry::try_new(None).expect("allocating the registry should succeed");
+        bind_all!(registry; 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);
+        assert!(12 > Registry::INITIAL_CAPACITY);
+        assert_eq!(registry.items.read().await.len(), 12);
+
+        // a bounded registry grows up to its bound, and no further.
+        let registry = Registry::try_new(Some(10)).expect("allocating the registry should succeed");
+        bind_all!(registry; 1, 2, 3, 4, 5, 6, 7, 8, 9, 10);
+        let res = registry.bind_konly::<Numbered<11>>(1).await;
+        assert_eq!(
+            res.err(),
+            Some(RegistrationError::RegistryFull { capacity: 10 })
+        );
+    })
+}
+
+#[test]
+fn service_latency() {
+    use abi::syscall::services::{ServiceLatencyError, ServiceLatencyRequest};
+
+    TestKernel::run(|k| async move {
+        let listener = k.registry().bind_konly::<TestService>(2).await.unwrap();
+
+        // server
+        k.spawn(async move {
+            loop {
+                let conn = listener.handshake().await;
+                let (tx, rx) = crate::comms::kchannel::KChannel::new_async(2).await.split();
+                k.spawn(async move {
+                    while let Ok(Message { msg, reply }) = rx.dequeue_async().await {
+                        reply
+                            .reply_konly(
+                                msg.reply_with_body(|TestMessage(val)| Ok(TestMessage(val + 1))),
+                            )
+                            .await
+                            .unwrap();
+                    }
+                })
+                .await;
+                conn.accept(tx).unwrap();
+            }
+        })
+        .await;
+
+        let reply = comms::oneshot::Reusable::new_async().await;
+        let mut client = k
+            .registry()
+            .connect::<TestService>(TestMessage(1))
+            .await
+            .expect("connect should succeed");
+        for i in 0..4 {
+            client
+                .request_oneshot(TestMessage(i), &reply)
+                .await
+                .unwrap();
+        }
+
+        let req = |reset| ServiceLatencyRequest {
+            uuid: *TestService::UUID.as_bytes(),
+            reset,
+        };
+        let rsp = k.registry().service_latency(req(true)).await.unwrap();
+        assert_eq!(rsp.samples, 4);
+        assert!(rsp.p50_us.is_some());
+        assert!(rsp.p50_us <= rsp.p99_us);
+
+        // the previous request reset the statistics.
+        let rsp = k.registry().service_latency(req(false)).await.unwrap();
+        assert_eq!(rsp.samples, 0);
+        assert_eq!(rsp.p50_us, None);
+
+        let res = k
+            .registry()
+            .service_latency(ServiceLatencyRequest {
+                uuid: [0; 16],
+                reset: false,
+            })
+            .await;
+        assert_eq!(res, Err(ServiceLatencyError::NotFound));
+
+        // a registry without latency tracking doesn't track any services.
+        let registry = Registry::try_new(Some(1)).unwrap();
+        let _listener = registry.bind_konly::<TestService>(1).await.unwrap();
+        let res = registry.service_latency(req(false)).await;
+        assert_eq!(res, Err(ServiceLatencyError::NotTracked));
+    })
+}
+
+#[test]
+fn latency_percentiles() {
+    use maitake::time::Duration;
+
+    let settings = LatencySettings {
+        enabled: true,
+        buckets: 4,
+        resolution: Duration::from_micros(10),
+    };
+    let clock = maitake::time::Clock::new(Duration::from_micros(1), || 0);
+    let histogram = LatencyHistogram::try_new(&settings, &clock).unwrap();
+    assert_eq!(histogram.snapshot().p50, None);
+
+    // 98 fast requests, and two slow ones.
+    for _ in 0..98 {
+        histogram.record_duration(Duration::from_micros(5));
+    }
+    histogram.record_duration(Duration::from_micros(15));
+    histogram.record_duration(Duration::from_micros(15));
+    let snapshot = histogram.snapshot();
+    assert_eq!(snapshot.samples, 100);
+    assert_eq!(snapshot.p50, Some(Duration::from_micros(10)));
+    assert_eq!(snapshot.p99, Some(Duration::from_micros(20)));
+
+    // latencies past the last bucket have no upper bound.
+    for _ in 0..100 {
+        histogram.record_duration(Duration::from_secs(1));
+    }
+    assert_eq!(histogram.snapshot().p99, None);
+
+    histogram.reset();
+    assert_eq!(histogram.snapshot().samples, 0);
+}
+
+#[test]
+fn syscall_error_wire_format() {
+    use abi::syscall::{KernelMsg, KernelResponse, KernelResponseBody, SysCallError};
+
+    let cases: [(&[u8], SysCallError); 5] = [
+        (&[0], SysCallError::InvalidRequest),
+        (&[1], SysCallError::PermissionDenied),
+        (&[2], SysCallError::ResourceExhausted),
+        (&[3], SysCallError::NotFound),
+        (&[4, 0xac, 0x02], SysCallError::Other(300)),
+    ];
+
+    for (error_bytes, expected) in cases {
+        // `KernelMsg::Response`, nonce 7, `KernelResponseBody::Error`
+        let mut bytes = vec![2, 7, 4];
+        bytes.extend_from_slice(error_bytes);
+        let msg: KernelMsg = postcard::from_bytes(&bytes).expect("error should decode");
+        match msg {
+            KernelMsg::Response(KernelResponse {
+                header,
+                body: KernelResponseBody::Error(error),
+            }) => {
+                assert_eq!(header.nonce, 7);
+                assert_eq!(error, expected);
+            }
+            msg => panic!("expected an error response, got {msg:?}"),
+        }
+    }
+
+    assert_eq!(
+        SysCallError::from(UserHandlerError::DeserializationFailed),
+        SysCallError::InvalidRequest
+    );
+    assert_eq!(
+        SysCallError::from(UserHandlerError::QueueFull),
+        SysCallError::ResourceExhausted
+    );
+}
+
+#[test]
+fn abi_hello() {
+    use abi::syscall::{
+        hello::{HelloError, HelloRequest, HelloResponse, ABI_VERSION},
+        KernelMsg, KernelResponse, KernelResponseBody, UserRequest, UserRequestBody,
+        UserRequestHeader,
+    };
+
+    TestKernel::run(|k| async move {
+        assert_eq!(
+            k.hello(HelloRequest {
+                abi_version: ABI_VERSION
+            }),
+            Ok(HelloResponse {
+                abi_version: ABI_VERSION
+            })
+        );
+        assert_eq!(
+            k.hello(HelloRequest {
+                abi_version: ABI_VERSION + 1
+            }),
+            Err(HelloError::AbiMismatch {
+                supported: ABI_VERSION
+            })
+        );
+    });
+
+    // the handshake's encoding must never change, so that a mismatch can
+    // always be reported.
+    let req = UserRequest {
+        header: UserRequestHeader { nonce: 3 },
+        body: UserRequestBody::Hello(HelloRequest { abi_version: 1 }),
+    };
+    assert_eq!(postcard::to_stdvec(&req).unwrap(), [3, 7, 1]);
+
+    // `KernelMsg::Response`, nonce 3, `KernelResponseBody::Hello`, `Err`,
+    // `HelloError::AbiMismatch`, supported version 1
+    let bytes = [2, 3, 8, 1, 0, 1];
+    let msg: KernelMsg = postcard::from_bytes(&bytes).expect("hello should decode");
+    match msg {
+        KernelMsg::Response(KernelResponse {
+            header,
+            body: KernelResponseBody::Hello(res),
+        }) => {
+            assert_eq!(header.nonce, 3);
+            assert_eq!(res, Err(HelloError::AbiMismatch { supported: 1 }));
+        }
+        msg => panic!("expected a hello response, got {msg:?}"),
+    }
+}
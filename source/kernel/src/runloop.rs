@@ -0,0 +1,431 @@
+//! A platform-independent kernel run loop.
+//!
+//! Each iteration of a platform's run loop does the same things: poll the
+//! scheduler, turn the timer wheel, and, if there's no more work to do, put
+//! the CPU to sleep until an interrupt occurs. [`RunLoopState`] implements
+//! the decisions involved, in terms of a [`RunLoopDriver`], so that platform
+//! implementations only need to provide the hardware-facing parts (actually
+//! waiting for an interrupt), and so that the logic can be tested without
+//! hardware.
+//!
+//! A platform's run loop looks something like this:
+//!
+//! ```ignore
+//! let mut state = RunLoopState::new();
+//! loop {
+//!     state.tick_phase(&mut kernel);
+//!     let slept = match state.decide_sleep() {
+//!         Sleep::UntilInterrupt => {
+//!             disable_interrupts();
+//!             if state.confirm_sleep(&kernel) == Sleep::UntilInterrupt {
+//!                 // e.g. `sti; hlt` on x86.
+//!                 enable_interrupts_and_wait();
+//!                 true
+//!             } else {
+//!                 enable_interrupts();
+//!                 false
+//!             }
+//!         }
+//!         Sleep::No => false,
+//!     };
+//!     state.account_sleep(&mut kernel, slept);
+//! }
+//! ```
+//!
+//! # Lost wakeups
+//!
+//! An interrupt which wakes a task (or another core waking this one) may
+//! arrive after [`RunLoopState::tick_phase`] found no work to do, but before
+//! the CPU goes to sleep. If the platform simply slept, that task would not
+//! run until some later interrupt woke the CPU again. To avoid this, the run
+//! loop relies on the following invariant:
+//!
+//! - Every interrupt handler (or cross-core wakeup) that may wake a task or
+//!   change a timer deadline increments the counter returned by
+//!   [`RunLoopDriver::wakeups`].
+//! - The platform disables interrupts, then calls
+//!   [`RunLoopState::confirm_sleep`], which checks that the counter hasn't
+//!   changed since the last tick started.
+//! - Only if it hasn't, the platform re-enables interrupts and waits for one
+//!   as a single atomic step, so that any interrupt that arrives after the
+//!   check wakes the CPU, rather than being handled before it sleeps.
+
+use crate::Kernel;
+
+/// The parts of the kernel driven by the run loop.
+///
+/// This is implemented for [`&'static Kernel`](Kernel), and may be mocked
+/// for testing.
+pub trait RunLoopDriver {
+    /// Poll the scheduler once, returning a summary of the tick.
+    fn tick(&mut self) -> TickSummary;
+
+    /// Turn the timer wheel, returning `true` if any timers have yet to
+    /// fire.
+    fn turn_timer(&mut self) -> bool;
+
+    /// Returns a counter which is incremented whenever something outside the
+    /// run loop, such as an interrupt handler, may have woken a task or
+    /// changed a timer deadline.
+    ///
+    /// The default implementation always returns 0, in which case
+    /// [`RunLoopState::confirm_sleep`] cannot detect lost wakeups.
+    fn wakeups(&self) -> u64 {
+        0
+    }
+}
+
+/// A summary of a single scheduler tick.
+///
+/// These counts are kept by the scheduler as it runs, so producing a summary
+/// costs nothing beyond the tick itself. The scheduler doesn't count the
+/// tasks in its run queue, so the number of tasks still runnable after a
+/// tick isn't known exactly; `has_remaining` reports whether there are any.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TickSummary {
+    /// The number of tasks polled during the tick.
+    pub polled: usize,
+    /// The number of tasks which completed during the tick.
+    pub completed: usize,
+    /// The number of tasks spawned since the previous tick.
+    pub spawned: usize,
+    /// The number of tasks woken since the previous tick, whether by other
+    /// tasks on the same scheduler, or from elsewhere (such as an interrupt
+    /// handler or another core).
+    pub woken: usize,
+    /// Whether there are woken tasks which were not polled during the tick.
+    pub has_remaining: bool,
+}
+
+impl TickSummary {
+    /// Combine the summaries of two schedulers ticked during the same
+    /// iteration of a run loop.
+    #[must_use]
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            polled: self.polled + other.polled,
+            completed: self.completed + other.completed,
+            spawned: self.spawned + other.spawned,
+            woken: self.woken + other.woken,
+            has_remaining: self.has_remaining || other.has_remaining,
+        }
+    }
+}
+
+impl From<maitake::scheduler::Tick> for TickSummary {
+    fn from(tick: maitake::scheduler::Tick) -> Self {
+        Self {
+            polled: tick.polled,
+            completed: tick.completed,
+            spawned: tick.spawned,
+            woken: tick.woken_internal + tick.woken_external,
+            has_remaining: tick.has_remaining,
+        }
+    }
+}
+
+/// Whether the run loop should put the CPU to sleep.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sleep {
+    /// There is more work to do, so keep running.
+    No,
+    /// There is no work to do until an interrupt occurs.
+    UntilInterrupt,
+}
+
+/// What the run loop was doing after its last step.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// The run loop has not ticked yet.
+    Starting,
+    /// The last tick left more work to do.
+    Busy,
+    /// The last tick left no work to do.
+    Idle,
+    /// The CPU has just woken from sleeping.
+    Woken,
+}
+
+/// The state of a kernel run loop.
+///
+/// See the [module-level documentation](self) for details.
+#[derive(Debug)]
+pub struct RunLoopState {
+    phase: Phase,
+    ticks: u64,
+    sleeps: u64,
+    /// The value of [`RunLoopDriver::wakeups`] when the last tick started.
+    wakeups: u64,
+}
+
+impl RunLoopState {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            phase: Phase::Starting,
+            ticks: 0,
+            sleeps: 0,
+            wakeups: 0,
+        }
+    }
+
+    /// Drive the scheduler once, and turn the timer wheel so that any timers
+    /// which have elapsed are fired.
+    ///
+    /// The timer is turned after the scheduler, so that tasks woken by
+    /// expired timers are counted as remaining work.
+    pub fn tick_phase(&mut self, driver: &mut impl RunLoopDriver) -> TickSummary {
+        // anything that happens from here on is seen by this tick, or is
+        // caught by `confirm_sleep`.
+        self.wakeups = driver.wakeups();
+        let tick = driver.tick();
+        let timers_remaining = driver.turn_timer();
+
+        self.ticks += 1;
+        self.phase = if tick.has_remaining || timers_remaining {
+            Phase::Busy
+        } else {
+            Phase::Idle
+        };
+        tick
+    }
+
+    /// Decide whether the CPU should sleep until the next interrupt, based on
+    /// the last call to [`RunLoopState::tick_phase`].
+    #[must_use]
+    pub fn decide_sleep(&self) -> Sleep {
+        match self.phase {
+            Phase::Idle => Sleep::UntilInterrupt,
+            Phase::Starting | Phase::Busy | Phase::Woken => Sleep::No,
+        }
+    }
+
+    /// Check that nothing has happened since the last call to
+    /// [`RunLoopState::tick_phase`] which could have made work for the run
+    /// loop, immediately before sleeping.
+    ///
+    /// This must be called with interrupts disabled, after
+    /// [`RunLoopState::decide_sleep`] returned [`Sleep::UntilInterrupt`]. If
+    /// this also returns [`Sleep::UntilInterrupt`], the platform must
+    /// re-enable interrupts and wait for one in a single atomic step. If it
+    /// returns [`Sleep::No`], the platform should re-enable interrupts and
+    /// tick again instead. See [the module-level
+    /// documentation](self#lost-wakeups) for details.
+    #[must_use]
+    pub fn confirm_sleep(&self, driver: &impl RunLoopDriver) -> Sleep {
+        match self.decide_sleep() {
+            Sleep::UntilInterrupt if driver.wakeups() == self.wakeups => Sleep::UntilInterrupt,
+            _ => Sleep::No,
+        }
+    }
+
+    /// Record whether the CPU slept after the last call to
+    /// [`RunLoopState::decide_sleep`].
+    ///
+    /// This turns the timer wheel again, to account for the time spent
+    /// sleeping.
+    pub fn account_sleep(&mut self, driver: &mut impl RunLoopDriver, slept: bool) {
+        driver.turn_timer();
+        if slept {
+            self.sleeps += 1;
+            self.phase = Phase::Woken;
+        }
+    }
+
+    /// Returns what the run loop was doing after its last step.
+    #[must_use]
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Returns the number of times the scheduler has been ticked.
+    #[must_use]
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Returns the number of times the CPU has slept.
+    #[must_use]
+    pub fn sleeps(&self) -> u64 {
+        self.sleeps
+    }
+}
+
+impl Default for RunLoopState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunLoopDriver for &'static Kernel {
+    fn tick(&mut self) -> TickSummary {
+        let kernel: &'static Kernel = self;
+        kernel.tick()
+    }
+
+    fn turn_timer(&mut self) -> bool {
+        let kernel: &'static Kernel = self;
+        kernel.timer().turn().has_remaining()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A driver which returns scripted results.
+    #[derive(Default)]
+    struct MockDriver {
+        tick: TickSummary,
+        timers_remaining: bool,
+        turns: usize,
+        /// Incremented by tests to simulate an interrupt.
+        wakeups: u64,
+    }
+
+    impl RunLoopDriver for MockDriver {
+        fn tick(&mut self) -> TickSummary {
+            self.tick
+        }
+
+        fn turn_timer(&mut self) -> bool {
+            self.turns += 1;
+            self.timers_remaining
+        }
+
+        fn wakeups(&self) -> u64 {
+            self.wakeups
+        }
+    }
+
+    #[test]
+    fn idle() {
+        let mut driver = MockDriver::default();
+        let mut state = RunLoopState::new();
+        assert_eq!(state.decide_sleep(), Sleep::No);
+
+        state.tick_phase(&mut driver);
+        assert_eq!(state.phase(), Phase::Idle);
+        assert_eq!(state.decide_sleep(), Sleep::UntilInterrupt);
+    }
+
+    #[test]
+    fn busy() {
+        let mut driver = MockDriver {
+            tick: TickSummary {
+                polled: 1,
+                has_remaining: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut state = RunLoopState::new();
+
+        let tick = state.tick_phase(&mut driver);
+        assert_eq!(tick.polled, 1);
+        assert_eq!(state.phase(), Phase::Busy);
+        assert_eq!(state.decide_sleep(), Sleep::No);
+        state.account_sleep(&mut driver, false);
+        assert_eq!(state.phase(), Phase::Busy);
+        assert_eq!(state.sleeps(), 0);
+
+        // pending timers are also work to do.
+        driver.tick.has_remaining = false;
+        driver.timers_remaining = true;
+        state.tick_phase(&mut driver);
+        assert_eq!(state.decide_sleep(), Sleep::No);
+        assert_eq!(state.ticks(), 2);
+    }
+
+    #[test]
+    fn wake_from_sleep() {
+        let mut driver = MockDriver::default();
+        let mut state = RunLoopState::new();
+
+        state.tick_phase(&mut driver);
+        assert_eq!(state.decide_sleep(), Sleep::UntilInterrupt);
+        let turns = driver.turns;
+        state.account_sleep(&mut driver, true);
+        // the timer is turned again to account for the time spent asleep.
+        assert_eq!(driver.turns, turns + 1);
+        assert_eq!(state.phase(), Phase::Woken);
+        assert_eq!(state.sleeps(), 1);
+        // don't go straight back to sleep without ticking first.
+        assert_eq!(state.decide_sleep(), Sleep::No);
+
+        // an interrupt woke a task.
+        driver.tick = TickSummary {
+            polled: 1,
+            completed: 1,
+            ..Default::default()
+        };
+        state.tick_phase(&mut driver);
+        assert_eq!(state.phase(), Phase::Idle);
+        assert_eq!(state.decide_sleep(), Sleep::UntilInterrupt);
+    }
+
+    #[test]
+    fn wakeup_before_sleep() {
+        let mut driver = MockDriver::default();
+        let mut state = RunLoopState::new();
+
+        state.tick_phase(&mut driver);
+        assert_eq!(state.decide_sleep(), Sleep::UntilInterrupt);
+        assert_eq!(state.confirm_sleep(&driver), Sleep::UntilInterrupt);
+
+        // an interrupt fires after deciding to sleep, but before interrupts
+        // are disabled.
+        driver.wakeups += 1;
+        assert_eq!(state.confirm_sleep(&driver), Sleep::No);
+        state.account_sleep(&mut driver, false);
+        assert_eq!(state.sleeps(), 0);
+
+        // the next tick sees the interrupt, so sleeping is fine again.
+        state.tick_phase(&mut driver);
+        assert_eq!(state.confirm_sleep(&driver), Sleep::UntilInterrupt);
+    }
+
+    #[test]
+    fn merge_summaries() {
+        let kernel = TickSummary {
+            polled: 3,
+            completed: 1,
+            spawned: 2,
+            woken: 1,
+            has_remaining: false,
+        };
+        let core = TickSummary {
+            polled: 1,
+            woken: 4,
+            has_remaining: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            kernel.merge(core),
+            TickSummary {
+                polled: 4,
+                completed: 1,
+                spawned: 2,
+                woken: 5,
+                has_remaining: true,
+            }
+        );
+        assert_eq!(
+            kernel.merge(TickSummary::default()),
+            kernel,
+            "merging with an empty summary changes nothing"
+        );
+    }
+
+    #[test]
+    fn confirm_busy() {
+        let mut driver = MockDriver {
+            timers_remaining: true,
+            ..Default::default()
+        };
+        let mut state = RunLoopState::new();
+
+        state.tick_phase(&mut driver);
+        assert_eq!(state.confirm_sleep(&driver), Sleep::No);
+    }
+}
@@ -0,0 +1,399 @@
+//! # Console
+//!
+//! A write-only text console, which is where userspace's
+//! [`UserRequestBody::ConsoleWrite`] requests end up.
+//!
+//! The [`ConsoleServer`] copies everything written to it to each of its
+//! sinks: a [serial mux](crate::services::serial_mux) port, and/or a byte
+//! stream provided by the platform, which it drains onto its framebuffer.
+//! Neither sink is written to until it has room, so a write isn't answered
+//! until the slowest sink has accepted it. For the serial port, this means
+//! waiting for the UART driver to drain its ring as the transmitter becomes
+//! ready, rather than spinning.
+//!
+//! [`UserRequestBody::ConsoleWrite`]: abi::syscall::UserRequestBody::ConsoleWrite
+
+use crate::{
+    comms::{bbq, oneshot::Reusable},
+    registry::{self, known_uuids, Envelope, KernelHandle, Message, RegisteredDriver},
+    services::serial_mux::{self, PortHandle},
+    Kernel,
+};
+pub use abi::syscall::console::{ConsoleError, ConsoleWriteRequest, CONSOLE_WRITE_MAX};
+use serde::{Deserialize, Serialize};
+use tracing::Level;
+use uuid::Uuid;
+
+////////////////////////////////////////////////////////////////////////////////
+// Service Definition
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct ConsoleService;
+
+impl RegisteredDriver for ConsoleService {
+    type Request = Request;
+    type Response = Response;
+    type Error = ConsoleError;
+    type Hello = ();
+    type ConnectError = core::convert::Infallible;
+
+    const UUID: Uuid = known_uuids::kernel::CONSOLE;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Message and Error Types
+////////////////////////////////////////////////////////////////////////////////
+
+pub enum Request {
+    Write(ConsoleWriteRequest),
+}
+
+pub enum Response {
+    /// The number of bytes written to every sink.
+    Written(usize),
+}
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    Register(registry::RegistrationError),
+    NoSermux(registry::ConnectError<serial_mux::SerialMuxService>),
+    NoSermuxPort,
+    /// Neither a serial mux port nor a framebuffer stream was provided.
+    NoSinks,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Client Definition
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct ConsoleClient {
+    handle: KernelHandle<ConsoleService>,
+    reply: Reusable<Envelope<Result<Response, ConsoleError>>>,
+}
+
+impl ConsoleClient {
+    /// Obtain a `ConsoleClient`
+    ///
+    /// If the [`ConsoleServer`] hasn't been registered yet, we will retry until it has been
+    pub async fn from_registry(
+        kernel: &'static Kernel,
+    ) -> Result<Self, registry::ConnectError<ConsoleService>> {
+        let handle = kernel.registry().connect::<ConsoleService>(()).await?;
+
+        Ok(Self {
+            handle,
+            reply: Reusable::new_async().await,
+        })
+    }
+
+    /// Obtain a `ConsoleClient`
+    ///
+    /// Does NOT attempt to get a [`ConsoleServer`] handle more than once.
+    pub async fn from_registry_no_retry(
+        kernel: &'static Kernel,
+    ) -> Result<Self, registry::ConnectError<ConsoleService>> {
+        let handle = kernel.registry().try_connect::<ConsoleService>(()).await?;
+
+        Ok(Self {
+            handle,
+            reply: Reusable::new_async().await,
+        })
+    }
+
+    /// Write the bytes in `req` to every console sink, returning the number of
+    /// bytes written.
+    pub async fn write(&mut self, req: ConsoleWriteRequest) -> Result<usize, ConsoleError> {
+        let resp = self
+            .handle
+            .request_oneshot(Request::Write(req), &self.reply)
+            .await
+            .map_err(|_| ConsoleError::NoConsole)?;
+        let Response::Written(written) = resp.body?;
+        Ok(written)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Server Definition
+////////////////////////////////////////////////////////////////////////////////
+
+/// Server implementation for the [`ConsoleService`].
+pub struct ConsoleServer {
+    reqs: registry::listener::RequestStream<ConsoleService>,
+    sermux_port: Option<PortHandle>,
+    framebuffer: Option<bbq::SpscProducer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsoleSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "ConsoleSettings::default_request_capacity")]
+    pub request_capacity: usize,
+    #[serde(default = "ConsoleSettings::default_sermux_port")]
+    pub sermux_port: Option<u16>,
+    #[serde(default = "ConsoleSettings::default_sermux_capacity")]
+    pub sermux_capacity: usize,
+}
+
+impl ConsoleServer {
+    /// Register the `ConsoleServer`.
+    ///
+    /// If [`ConsoleSettings::with_sermux_port`] is [`Some`], this will open the
+    /// configured serial mux port, waiting for the serial mux to be registered
+    /// if it hasn't been yet. If `framebuffer` is [`Some`], everything written
+    /// to the console is also sent to it, for the platform to draw.
+    #[tracing::instrument(
+        name = "ConsoleServer::register",
+        level = Level::INFO,
+        skip(kernel, settings, framebuffer),
+        err(Debug),
+    )]
+    pub async fn register(
+        kernel: &'static Kernel,
+        settings: ConsoleSettings,
+        framebuffer: Option<bbq::SpscProducer>,
+    ) -> Result<(), RegistrationError> {
+        tracing::info!(?settings, "Registering console");
+        if settings.sermux_port.is_none() && framebuffer.is_none() {
+            return Err(RegistrationError::NoSinks);
+        }
+
+        let reqs = kernel
+            .registry()
+            .bind_konly::<ConsoleService>(settings.request_capacity)
+            .await
+            .map_err(RegistrationError::Register)?
+            .into_request_stream(settings.request_capacity)
+            .await;
+
+        let sermux_port = match settings.sermux_port {
+            Some(port) => {
+                let mut client = serial_mux::SerialMuxClient::from_registry(kernel)
+                    .await
+                    .map_err(RegistrationError::NoSermux)?;
+                tracing::info!("opening Serial Mux port {port}");
+                Some(
+                    client
+                        .open_port(port, settings.sermux_capacity)
+                        .await
+                        .ok_or(RegistrationError::NoSermuxPort)?,
+                )
+            }
+            None => None,
+        };
+
+        kernel
+            .spawn(
+                Self {
+                    reqs,
+                    sermux_port,
+                    framebuffer,
+                }
+                .run(),
+            )
+            .await;
+
+        tracing::info!("ConsoleServer registered!");
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "ConsoleServer", level = Level::INFO, skip(self))]
+    async fn run(self) {
+        loop {
+            let Message { msg, reply } = self.reqs.next_request().await;
+            let Request::Write(ref req) = msg.body;
+            let res = match req.as_bytes() {
+                Some(bytes) => {
+                    self.write(bytes).await;
+                    Ok(Response::Written(bytes.len()))
+                }
+                None => Err(ConsoleError::TooLong),
+            };
+            // the writer may have given up on the response.
+            let _ = reply.reply_konly(msg.reply_with(res)).await;
+        }
+    }
+
+    async fn write(&self, bytes: &[u8]) {
+        if let Some(port) = &self.sermux_port {
+            port.send(bytes).await;
+        }
+        if let Some(framebuffer) = &self.framebuffer {
+            let mut rest = bytes;
+            while !rest.is_empty() {
+                let mut wgr = framebuffer.send_grant_max(rest.len()).await;
+                let len = wgr.len().min(rest.len());
+                wgr[..len].copy_from_slice(&rest[..len]);
+                wgr.commit(len);
+                rest = &rest[len..];
+            }
+        }
+    }
+}
+
+impl ConsoleSettings {
+    pub const DEFAULT_REQUEST_CAPACITY: usize = 16;
+    pub const DEFAULT_SERMUX_PORT: Option<u16> = Some(serial_mux::WellKnown::Console as u16);
+    pub const DEFAULT_SERMUX_CAPACITY: usize = 256;
+
+    const fn default_request_capacity() -> usize {
+        Self::DEFAULT_REQUEST_CAPACITY
+    }
+    const fn default_sermux_port() -> Option<u16> {
+        Self::DEFAULT_SERMUX_PORT
+    }
+    const fn default_sermux_capacity() -> usize {
+        Self::DEFAULT_SERMUX_CAPACITY
+    }
+
+    /// Sets the [serial mux](crate::services::serial_mux) port console output
+    /// is sent to.
+    ///
+    /// If this is [`None`], console output is not sent over the serial mux.
+    #[must_use]
+    pub fn with_sermux_port(self, port: impl Into<Option<u16>>) -> Self {
+        Self {
+            sermux_port: port.into(),
+            ..self
+        }
+    }
+}
+
+impl Default for ConsoleSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            request_capacity: Self::DEFAULT_REQUEST_CAPACITY,
+            sermux_port: Self::DEFAULT_SERMUX_PORT,
+            sermux_capacity: Self::DEFAULT_SERMUX_CAPACITY,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestKernel;
+    use abi::{
+        frame,
+        syscall::{
+            KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader, UserRequest,
+            UserRequestBody, UserRequestHeader,
+        },
+    };
+
+    /// Serialize `msg` into a sealed frame, as the mailbox does.
+    fn to_frame(msg: &impl serde::Serialize) -> Vec<u8> {
+        let payload = postcard::to_stdvec(msg).expect("message must serialize");
+        let mut bytes = vec![0; frame::frame_len(payload.len())];
+        bytes[frame::HEADER_LEN..].copy_from_slice(&payload);
+        frame::seal(&mut bytes);
+        bytes
+    }
+
+    async fn send_frame(tx: &bbq::SpscProducer, msg: &impl serde::Serialize) {
+        let bytes = to_frame(msg);
+        let mut wgr = tx.send_grant_exact(bytes.len()).await;
+        wgr.copy_from_slice(&bytes);
+        wgr.commit(bytes.len());
+    }
+
+    async fn recv_frame<T: serde::de::DeserializeOwned>(rx: &bbq::Consumer) -> T {
+        let rgr = rx.read_grant().await;
+        let len = rgr.len();
+        let payload = frame::open(&rgr).expect("frame must be valid");
+        let msg = postcard::from_bytes(payload).expect("message must decode");
+        rgr.release(len);
+        msg
+    }
+
+    #[test]
+    fn write_over_rings() {
+        const TEXT: &[u8] = b"hello from userspace! this takes more than one request.\n";
+
+        TestKernel::run(|k| async move {
+            // a fake framebuffer sink, smaller than a single request, so that
+            // writes have to wait for it to be drained.
+            let (sink_tx, sink_rx) = bbq::new_spsc_channel(16).await;
+            let settings = ConsoleSettings::default().with_sermux_port(None);
+            ConsoleServer::register(k, settings, Some(sink_tx))
+                .await
+                .expect("console must register");
+            let drained = k
+                .spawn(async move {
+                    let mut out = Vec::new();
+                    while out.len() < TEXT.len() {
+                        let rgr = sink_rx.read_grant().await;
+                        let len = rgr.len();
+                        out.extend_from_slice(&rgr);
+                        rgr.release(len);
+                    }
+                    out
+                })
+                .await;
+
+            let (u2k_tx, u2k_rx) = bbq::new_spsc_channel(256).await;
+            let (k2u_tx, k2u_rx) = bbq::new_spsc_channel(256).await;
+            let mut rest = TEXT;
+            let mut nonce = 0;
+            while !rest.is_empty() {
+                // userspace sends a request...
+                let (req, _) = ConsoleWriteRequest::new(rest);
+                let req = UserRequest {
+                    header: UserRequestHeader { nonce },
+                    body: UserRequestBody::ConsoleWrite(req),
+                };
+                send_frame(&u2k_tx, &req).await;
+
+                // ...which the kernel answers...
+                let req: UserRequest = recv_frame(&u2k_rx).await;
+                let UserRequestBody::ConsoleWrite(write) = req.body else {
+                    panic!("expected a console write, got {:?}", req.body);
+                };
+                let rsp = KernelMsg::Response(KernelResponse {
+                    header: KernelResponseHeader {
+                        nonce: req.header.nonce,
+                    },
+                    body: KernelResponseBody::ConsoleWrite(k.console_write(write).await),
+                });
+                send_frame(&k2u_tx, &rsp).await;
+
+                // ...and userspace learns how much was written.
+                let rsp: KernelMsg = recv_frame(&k2u_rx).await;
+                let written = match rsp {
+                    KernelMsg::Response(KernelResponse {
+                        header,
+                        body: KernelResponseBody::ConsoleWrite(Ok(rsp)),
+                    }) if header.nonce == nonce => rsp.written as usize,
+                    rsp => panic!("expected a console write response, got {rsp:?}"),
+                };
+                assert_eq!(written, rest.len().min(CONSOLE_WRITE_MAX));
+                rest = &rest[written..];
+                nonce += 1;
+            }
+
+            assert_eq!(nonce, 2);
+            assert_eq!(drained.await.unwrap(), TEXT);
+        })
+    }
+
+    #[test]
+    fn bad_requests() {
+        TestKernel::run(|k| async move {
+            let (req, _) = ConsoleWriteRequest::new(b"hello");
+            assert_eq!(k.console_write(req).await, Err(ConsoleError::NoConsole));
+
+            let (sink_tx, _sink_rx) = bbq::new_spsc_channel(16).await;
+            let settings = ConsoleSettings::default().with_sermux_port(None);
+            ConsoleServer::register(k, settings, Some(sink_tx))
+                .await
+                .expect("console must register");
+            let req = ConsoleWriteRequest {
+                len: CONSOLE_WRITE_MAX as u8 + 1,
+                ..req
+            };
+            assert_eq!(k.console_write(req).await, Err(ConsoleError::TooLong));
+        })
+    }
+}
@@ -12,6 +12,11 @@
 //! as many different types of keyboard as possible. Not all keyboards will
 //! provide all of the available keyboard event types, based on what keys
 //! actually exist on the keyboard.
+//!
+//! The [`scancode`] submodule decodes the scancodes sent by PC keyboards into
+//! [`KeyEvent`]s, for use by PS/2 keyboard drivers, and the [`terminal`]
+//! submodule decodes the bytes a terminal sends into the same events, for
+//! consoles on a serial port.
 use uuid::Uuid;
 
 use crate::{
@@ -25,6 +30,8 @@ use crate::{
 
 pub mod key_event;
 pub mod mux;
+pub mod scancode;
+pub mod terminal;
 
 ////////////////////////////////////////////////////////////////////////////////
 // Service Definition
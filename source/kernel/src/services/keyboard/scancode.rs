@@ -0,0 +1,491 @@
+//! Decoding PC keyboard scancodes.
+//!
+//! PS/2 keyboards (and the PS/2 emulation provided by most PC firmware for
+//! USB keyboards) report key presses and releases as sequences of bytes
+//! called scancodes. This module decodes [scancode set 1], the set most
+//! keyboard controllers translate to by default, into [`KeyEvent`]s, using
+//! a US QWERTY layout.
+//!
+//! Most keys send a single byte when pressed (the key's "make" code), and the
+//! same byte with the high bit set when released (its "break" code). Keys
+//! added after the original PC keyboard send a `0xE0` prefix before their
+//! make and break codes, and the Pause key sends the six-byte sequence
+//! `E1 1D 45 E1 9D C5` when pressed, and nothing when released.
+//!
+//! [scancode set 1]: https://wiki.osdev.org/PS/2_Keyboard#Scan_Code_Set_1
+use super::key_event::{KeyCode, KeyEvent, Kind, MediaKeyCode, Modifiers};
+
+/// The prefix sent before the scancodes of extended keys.
+const EXTENDED: u8 = 0xe0;
+/// The sequence sent when the Pause key is pressed.
+const PAUSE: [u8; 6] = [0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5];
+/// Set in a scancode if it's a break code.
+const BREAK: u8 = 0x80;
+
+/// A scancode set 1 decoder.
+///
+/// Bytes read from the keyboard are passed to [`Decoder::feed`], which
+/// returns a [`KeyEvent`] once a complete scancode has been received.
+///
+/// The decoder tracks the state of the modifier keys, and which keys are
+/// currently held down, so that a key which is repeated while held is
+/// reported as [`Kind::Held`], rather than pressed again.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    state: State,
+    modifiers: Modifiers,
+    /// A bit for each scancode (or extended scancode) which is held down.
+    held: [u64; 4],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Start,
+    /// The last byte was [`EXTENDED`].
+    Extended,
+    /// This many bytes of the [`PAUSE`] sequence have been received.
+    Pause(usize),
+}
+
+/// What a scancode represents.
+enum Key {
+    Code(KeyCode),
+    Modifier(Modifier),
+    /// A key which toggles a modifier when pressed, and also produces a key
+    /// code of its own, if any.
+    Lock(Modifier, Option<KeyCode>),
+    /// A key with a different meaning depending on whether Num Lock is on.
+    Keypad {
+        num: char,
+        nav: KeyCode,
+    },
+}
+
+#[derive(Copy, Clone)]
+enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+    Meta,
+    CapsLock,
+    NumLock,
+}
+
+impl Decoder {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: State::Start,
+            modifiers: Modifiers::new(),
+            held: [0; 4],
+        }
+    }
+
+    /// Returns the modifier keys which are currently held down or locked.
+    #[must_use]
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Decode the next byte from the keyboard, returning a [`KeyEvent`] if
+    /// it completes a scancode for a key which produces one.
+    ///
+    /// Modifier keys and Caps Lock update [`Decoder::modifiers`], rather
+    /// than producing events of their own. Unknown scancodes, and bytes sent
+    /// by the keyboard controller rather than a key (such as
+    /// acknowledgements) are ignored.
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        match self.state {
+            State::Pause(n) if byte == PAUSE[n] => {
+                if n + 1 < PAUSE.len() {
+                    self.state = State::Pause(n + 1);
+                    return None;
+                }
+                self.state = State::Start;
+                // Pause has no break code, so it's never held.
+                return Some(self.event(Kind::Pressed, KeyCode::Pause));
+            }
+            // not a Pause sequence after all. start over with this byte.
+            State::Pause(_) => self.state = State::Start,
+            State::Extended => {
+                self.state = State::Start;
+                return self.scancode(byte, true);
+            }
+            State::Start => {}
+        }
+
+        match byte {
+            EXTENDED => {
+                self.state = State::Extended;
+                None
+            }
+            _ if byte == PAUSE[0] => {
+                self.state = State::Pause(1);
+                None
+            }
+            // acknowledgements, resend requests, and errors from the
+            // keyboard controller.
+            0x00 | 0xfa | 0xfe | 0xff => None,
+            _ => self.scancode(byte, false),
+        }
+    }
+
+    fn scancode(&mut self, byte: u8, extended: bool) -> Option<KeyEvent> {
+        let code = byte & !BREAK;
+        let released = byte & BREAK != 0;
+        let key = if extended {
+            extended_key(code)?
+        } else {
+            key(code)?
+        };
+
+        let bit = (code as usize) | ((extended as usize) << 7);
+        let (word, mask) = (bit / 64, 1u64 << (bit % 64));
+        let was_held = self.held[word] & mask != 0;
+        let kind = if released {
+            self.held[word] &= !mask;
+            Kind::Released
+        } else {
+            self.held[word] |= mask;
+            if was_held {
+                Kind::Held
+            } else {
+                Kind::Pressed
+            }
+        };
+
+        let code = match key {
+            Key::Code(code) => code,
+            Key::Modifier(modifier) => {
+                modifier.set(&mut self.modifiers, !released);
+                return None;
+            }
+            Key::Lock(modifier, code) => {
+                if kind == Kind::Pressed {
+                    let locked = modifier.get(self.modifiers);
+                    modifier.set(&mut self.modifiers, !locked);
+                }
+                code?
+            }
+            Key::Keypad { num, nav } => {
+                if self.modifiers.get(Modifiers::NUMLOCK) {
+                    KeyCode::Char(num)
+                } else {
+                    nav
+                }
+            }
+        };
+        Some(self.event(kind, self.shift(code)))
+    }
+
+    /// Apply Shift and Caps Lock to a key code.
+    fn shift(&self, code: KeyCode) -> KeyCode {
+        let shift = self.modifiers.get(Modifiers::SHIFT);
+        match code {
+            KeyCode::Char(c) if c.is_ascii_alphabetic() => {
+                if shift != self.modifiers.get(Modifiers::CAPSLOCK) {
+                    KeyCode::Char(c.to_ascii_uppercase())
+                } else {
+                    KeyCode::Char(c)
+                }
+            }
+            KeyCode::Char(c) if shift => KeyCode::Char(shifted(c)),
+            KeyCode::Tab if shift => KeyCode::BackTab,
+            code => code,
+        }
+    }
+
+    fn event(&self, kind: Kind, code: KeyCode) -> KeyEvent {
+        KeyEvent {
+            kind,
+            modifiers: self.modifiers,
+            code,
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Modifier {
+    fn get(self, modifiers: Modifiers) -> bool {
+        match self {
+            Self::Shift => modifiers.get(Modifiers::SHIFT),
+            Self::Ctrl => modifiers.get(Modifiers::CTRL),
+            Self::Alt => modifiers.get(Modifiers::ALT),
+            Self::Meta => modifiers.get(Modifiers::META),
+            Self::CapsLock => modifiers.get(Modifiers::CAPSLOCK),
+            Self::NumLock => modifiers.get(Modifiers::NUMLOCK),
+        }
+    }
+
+    fn set(self, modifiers: &mut Modifiers, value: bool) {
+        match self {
+            Self::Shift => modifiers.set(Modifiers::SHIFT, value),
+            Self::Ctrl => modifiers.set(Modifiers::CTRL, value),
+            Self::Alt => modifiers.set(Modifiers::ALT, value),
+            Self::Meta => modifiers.set(Modifiers::META, value),
+            Self::CapsLock => modifiers.set(Modifiers::CAPSLOCK, value),
+            Self::NumLock => modifiers.set(Modifiers::NUMLOCK, value),
+        };
+    }
+}
+
+/// Returns the key for a (non-extended) make code.
+fn key(code: u8) -> Option<Key> {
+    let char = |c| Some(Key::Code(KeyCode::Char(c)));
+    let keypad = |num, nav| Some(Key::Keypad { num, nav });
+    match code {
+        0x01 => Some(Key::Code(KeyCode::Esc)),
+        0x02..=0x0a => char((b'1' + code - 0x02) as char),
+        0x0b => char('0'),
+        0x0c => char('-'),
+        0x0d => char('='),
+        0x0e => Some(Key::Code(KeyCode::Backspace)),
+        0x0f => Some(Key::Code(KeyCode::Tab)),
+        0x10..=0x19 => char(b"qwertyuiop"[(code - 0x10) as usize] as char),
+        0x1a => char('['),
+        0x1b => char(']'),
+        0x1c => Some(Key::Code(KeyCode::Enter)),
+        0x1d => Some(Key::Modifier(Modifier::Ctrl)),
+        0x1e..=0x26 => char(b"asdfghjkl"[(code - 0x1e) as usize] as char),
+        0x27 => char(';'),
+        0x28 => char('\''),
+        0x29 => char('`'),
+        0x2a | 0x36 => Some(Key::Modifier(Modifier::Shift)),
+        0x2b => char('\\'),
+        0x2c..=0x32 => char(b"zxcvbnm"[(code - 0x2c) as usize] as char),
+        0x33 => char(','),
+        0x34 => char('.'),
+        0x35 => char('/'),
+        0x37 => char('*'),
+        0x38 => Some(Key::Modifier(Modifier::Alt)),
+        0x39 => char(' '),
+        0x3a => Some(Key::Lock(Modifier::CapsLock, None)),
+        0x3b..=0x44 => Some(Key::Code(KeyCode::F(code - 0x3b + 1))),
+        0x45 => Some(Key::Lock(Modifier::NumLock, Some(KeyCode::NumLock))),
+        0x47 => keypad('7', KeyCode::Home),
+        0x48 => keypad('8', KeyCode::Up),
+        0x49 => keypad('9', KeyCode::PageUp),
+        0x4a => char('-'),
+        0x4b => keypad('4', KeyCode::Left),
+        0x4c => keypad('5', KeyCode::KeypadBegin),
+        0x4d => keypad('6', KeyCode::Right),
+        0x4e => char('+'),
+        0x4f => keypad('1', KeyCode::End),
+        0x50 => keypad('2', KeyCode::Down),
+        0x51 => keypad('3', KeyCode::PageDown),
+        0x52 => keypad('0', KeyCode::Insert),
+        0x53 => keypad('.', KeyCode::Delete),
+        0x57 => Some(Key::Code(KeyCode::F(11))),
+        0x58 => Some(Key::Code(KeyCode::F(12))),
+        _ => None,
+    }
+}
+
+/// Returns the key for an extended (`0xE0`-prefixed) make code.
+fn extended_key(code: u8) -> Option<Key> {
+    let code = match code {
+        0x10 => KeyCode::Media(MediaKeyCode::TrackPrevious),
+        0x19 => KeyCode::Media(MediaKeyCode::TrackNext),
+        0x1c => KeyCode::Enter,
+        0x1d => return Some(Key::Modifier(Modifier::Ctrl)),
+        0x20 => KeyCode::Media(MediaKeyCode::MuteVolume),
+        0x22 => KeyCode::Media(MediaKeyCode::PlayPause),
+        0x24 => KeyCode::Media(MediaKeyCode::Stop),
+        0x2e => KeyCode::Media(MediaKeyCode::LowerVolume),
+        0x30 => KeyCode::Media(MediaKeyCode::RaiseVolume),
+        0x35 => KeyCode::Char('/'),
+        0x37 => KeyCode::PrintScreen,
+        0x38 => return Some(Key::Modifier(Modifier::Alt)),
+        0x47 => KeyCode::Home,
+        0x48 => KeyCode::Up,
+        0x49 => KeyCode::PageUp,
+        0x4b => KeyCode::Left,
+        0x4d => KeyCode::Right,
+        0x4f => KeyCode::End,
+        0x50 => KeyCode::Down,
+        0x51 => KeyCode::PageDown,
+        0x52 => KeyCode::Insert,
+        0x53 => KeyCode::Delete,
+        0x5b | 0x5c => return Some(Key::Modifier(Modifier::Meta)),
+        0x5d => KeyCode::Menu,
+        // `E0 2A` and `E0 AA` are "fake shifts" sent around Print Screen and
+        // the navigation keys, and everything else is unknown.
+        _ => return None,
+    };
+    Some(Key::Code(code))
+}
+
+/// Returns the character produced by a non-letter key when Shift is held.
+fn shifted(c: char) -> char {
+    match c {
+        '1' => '!',
+        '2' => '@',
+        '3' => '#',
+        '4' => '$',
+        '5' => '%',
+        '6' => '^',
+        '7' => '&',
+        '8' => '*',
+        '9' => '(',
+        '0' => ')',
+        '-' => '_',
+        '=' => '+',
+        '[' => '{',
+        ']' => '}',
+        ';' => ':',
+        '\'' => '"',
+        '`' => '~',
+        '\\' => '|',
+        ',' => '<',
+        '.' => '>',
+        '/' => '?',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> std::vec::Vec<KeyEvent> {
+        let mut decoder = Decoder::new();
+        bytes.iter().filter_map(|&b| decoder.feed(b)).collect()
+    }
+
+    fn codes(bytes: &[u8]) -> std::vec::Vec<(Kind, KeyCode)> {
+        decode(bytes)
+            .into_iter()
+            .map(|event| (event.kind, event.code))
+            .collect()
+    }
+
+    #[test]
+    fn press_and_release() {
+        // 'a' pressed, held (typematic repeat), and released.
+        assert_eq!(
+            codes(&[0x1e, 0x1e, 0x9e]),
+            [
+                (Kind::Pressed, KeyCode::Char('a')),
+                (Kind::Held, KeyCode::Char('a')),
+                (Kind::Released, KeyCode::Char('a')),
+            ]
+        );
+    }
+
+    #[test]
+    fn modifiers() {
+        // shift+a, shift+1, then a after releasing shift.
+        let events = decode(&[0x2a, 0x1e, 0x9e, 0x02, 0x82, 0xaa, 0x1e]);
+        let pressed: std::vec::Vec<_> = events
+            .iter()
+            .filter(|event| event.kind == Kind::Pressed)
+            .map(|event| (event.code, event.modifiers.get(Modifiers::SHIFT)))
+            .collect();
+        assert_eq!(
+            pressed,
+            [
+                (KeyCode::Char('A'), true),
+                (KeyCode::Char('!'), true),
+                (KeyCode::Char('a'), false),
+            ]
+        );
+
+        // right ctrl is extended, and sets the same modifier as left ctrl.
+        let events = decode(&[0xe0, 0x1d, 0x2e]);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].modifiers.get(Modifiers::CTRL));
+        assert_eq!(events[0].code, KeyCode::Char('c'));
+    }
+
+    #[test]
+    fn caps_lock() {
+        // caps lock toggles on press, and shift inverts it for letters only.
+        let bytes = [
+            0x3a, 0xba, // caps lock
+            0x1e, 0x9e, // a
+            0x2a, 0x1e, 0x9e, 0x02, 0x82, 0xaa, // shift+a, shift+1
+            0x3a, 0xba, // caps lock
+            0x1e, 0x9e, // a
+        ];
+        assert_eq!(
+            codes(&bytes)
+                .into_iter()
+                .filter(|(kind, _)| *kind == Kind::Pressed)
+                .map(|(_, code)| code)
+                .collect::<std::vec::Vec<_>>(),
+            [
+                KeyCode::Char('A'),
+                KeyCode::Char('a'),
+                KeyCode::Char('!'),
+                KeyCode::Char('a'),
+            ]
+        );
+    }
+
+    #[test]
+    fn extended() {
+        assert_eq!(
+            codes(&[0xe0, 0x48, 0xe0, 0xc8, 0xe0, 0x35]),
+            [
+                (Kind::Pressed, KeyCode::Up),
+                (Kind::Released, KeyCode::Up),
+                (Kind::Pressed, KeyCode::Char('/')),
+            ]
+        );
+
+        // keypad 8 is distinct from the extended up arrow, and depends on num
+        // lock.
+        assert_eq!(
+            codes(&[0x48, 0xc8, 0x45, 0xc5, 0x48]),
+            [
+                (Kind::Pressed, KeyCode::Up),
+                (Kind::Released, KeyCode::Up),
+                (Kind::Pressed, KeyCode::NumLock),
+                (Kind::Released, KeyCode::NumLock),
+                (Kind::Pressed, KeyCode::Char('8')),
+            ]
+        );
+    }
+
+    #[test]
+    fn print_screen() {
+        // print screen is wrapped in fake shifts, which are ignored.
+        assert_eq!(
+            codes(&[0xe0, 0x2a, 0xe0, 0x37, 0xe0, 0xb7, 0xe0, 0xaa]),
+            [
+                (Kind::Pressed, KeyCode::PrintScreen),
+                (Kind::Released, KeyCode::PrintScreen),
+            ]
+        );
+    }
+
+    #[test]
+    fn pause() {
+        let mut decoder = Decoder::new();
+        for &byte in &PAUSE[..PAUSE.len() - 1] {
+            assert_eq!(decoder.feed(byte), None);
+        }
+        let event = decoder.feed(PAUSE[PAUSE.len() - 1]).unwrap();
+        assert_eq!((event.kind, event.code), (Kind::Pressed, KeyCode::Pause));
+        // the pause sequence doesn't leave ctrl or num lock set.
+        assert_eq!(decoder.modifiers(), Modifiers::new());
+
+        // a broken pause sequence is abandoned, and decoding carries on.
+        assert_eq!(
+            codes(&[0xe1, 0x1d, 0x1e]),
+            [(Kind::Pressed, KeyCode::Char('a'))]
+        );
+    }
+
+    #[test]
+    fn ignored() {
+        // controller responses, unknown scancodes, and modifiers alone produce
+        // no events.
+        assert_eq!(codes(&[0xfa, 0xfe, 0x00, 0x55, 0xe0, 0x7f, 0x2a, 0x38]), []);
+    }
+}
@@ -0,0 +1,398 @@
+//! Decoding keyboard input sent by a terminal.
+//!
+//! When the only console is a serial port (such as when running under QEMU
+//! with `-serial stdio`), key presses arrive as the bytes a terminal sends for
+//! them, rather than as scancodes. This module decodes those bytes into the
+//! same [`KeyEvent`]s that [`scancode`](super::scancode) produces, so that
+//! anything reading keyboard input doesn't need to care where it came from.
+//!
+//! Printable ASCII characters are sent as themselves, and most control keys
+//! as a single control character: Ctrl+C is `0x03`, Enter is `\r`, and so
+//! on. Keys with no ASCII equivalent, such as the arrow keys, are sent as
+//! [ANSI escape sequences] starting with `ESC`, like `ESC [ A` for Up.
+//!
+//! A terminal only reports key presses, so every event is
+//! [`Kind::Pressed`]. Bytes which aren't ASCII, and escape sequences for
+//! keys which aren't recognized, are ignored.
+//!
+//! # The Escape key
+//!
+//! The Escape key sends a lone `ESC`, which is also how every escape
+//! sequence starts, and the rest of a sequence may arrive later (such as in
+//! a separate UART interrupt). The decoder can't tell the two apart until it
+//! sees the next byte, so it waits: [`Decoder::is_pending`] returns `true`
+//! while a sequence is incomplete, and if nothing else arrives within a short
+//! time, the caller should call [`Decoder::flush`] to decode what was
+//! received so far as a key press.
+//!
+//! `ESC` followed by a character that doesn't start a sequence is decoded as
+//! that character with [`Modifiers::ALT`], which is how most terminals send
+//! Alt (or Meta) key combinations.
+//!
+//! [ANSI escape sequences]: https://en.wikipedia.org/wiki/ANSI_escape_code#Terminal_input_sequences
+use super::key_event::{KeyCode, KeyEvent, Kind, Modifiers};
+
+const ESC: u8 = 0x1b;
+/// The maximum number of numeric parameters in a control sequence. Key
+/// sequences have at most two: a key number and a modifier.
+const MAX_PARAMS: usize = 2;
+
+/// A terminal input decoder.
+///
+/// Bytes received from the terminal are passed to [`Decoder::feed`], which
+/// returns a [`KeyEvent`] once a complete key has been received.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    state: State,
+    /// Set if the last byte was a carriage return, so that the line feed of
+    /// a CRLF pair isn't decoded as a second Enter.
+    after_cr: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// Received `ESC`.
+    Escape,
+    /// Received `ESC [`, and the parameters so far.
+    Csi {
+        params: [u16; MAX_PARAMS],
+        len: usize,
+    },
+    /// Received `ESC O`.
+    Ss3,
+}
+
+impl Decoder {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            after_cr: false,
+        }
+    }
+
+    /// Returns `true` if the decoder has received part of an escape sequence.
+    ///
+    /// If this is still `true` after a short time with no input, call
+    /// [`Decoder::flush`].
+    #[must_use]
+    pub fn is_pending(&self) -> bool {
+        self.state != State::Ground
+    }
+
+    /// Decode the next byte from the terminal, returning a [`KeyEvent`] if
+    /// it completes a key.
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        match self.state {
+            State::Ground => self.ground(byte, after_cr),
+            State::Escape => match byte {
+                b'[' => {
+                    self.state = State::Csi {
+                        params: [0; MAX_PARAMS],
+                        len: 0,
+                    };
+                    None
+                }
+                b'O' => {
+                    self.state = State::Ss3;
+                    None
+                }
+                // the Escape key, followed by the start of another sequence.
+                ESC => Some(press(KeyCode::Esc, Modifiers::new())),
+                _ => {
+                    self.state = State::Ground;
+                    let mut event = self.ground(byte, false)?;
+                    event.modifiers.set(Modifiers::ALT, true);
+                    Some(event)
+                }
+            },
+            State::Csi {
+                mut params,
+                mut len,
+            } => match byte {
+                b'0'..=b'9' => {
+                    len = len.max(1);
+                    // extra parameters are ignored.
+                    if let Some(param) = params.get_mut(len - 1) {
+                        *param = param
+                            .saturating_mul(10)
+                            .saturating_add(u16::from(byte - b'0'));
+                    }
+                    self.state = State::Csi { params, len };
+                    None
+                }
+                b';' => {
+                    len = len.max(1) + 1;
+                    self.state = State::Csi { params, len };
+                    None
+                }
+                // other parameter and intermediate bytes don't appear in key
+                // sequences, but don't end the sequence either.
+                0x20..=0x3f => None,
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    let code = if byte == b'~' {
+                        tilde_key(params[0])?
+                    } else {
+                        final_key(byte)?
+                    };
+                    Some(press(code, modifiers(params[1])))
+                }
+                // a control character can't be part of a sequence, so the
+                // sequence was garbled. drop it.
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Ss3 => {
+                self.state = State::Ground;
+                Some(press(final_key(byte)?, Modifiers::new()))
+            }
+        }
+    }
+
+    /// Decode an incomplete escape sequence, because no more input arrived.
+    ///
+    /// A lone `ESC` is the Escape key, and `ESC [` or `ESC O` are Alt+`[`
+    /// and Alt+`O`. Anything longer is discarded.
+    pub fn flush(&mut self) -> Option<KeyEvent> {
+        let state = core::mem::replace(&mut self.state, State::Ground);
+        let alt = Modifiers::new().with(Modifiers::ALT, true);
+        match state {
+            State::Ground => None,
+            State::Escape => Some(press(KeyCode::Esc, Modifiers::new())),
+            State::Csi { len: 0, .. } => Some(press(KeyCode::Char('['), alt)),
+            State::Csi { .. } => None,
+            State::Ss3 => Some(press(KeyCode::Char('O'), alt)),
+        }
+    }
+
+    fn ground(&mut self, byte: u8, after_cr: bool) -> Option<KeyEvent> {
+        let ctrl = Modifiers::new().with(Modifiers::CTRL, true);
+        let (code, modifiers) = match byte {
+            ESC => {
+                self.state = State::Escape;
+                return None;
+            }
+            b'\r' => {
+                self.after_cr = true;
+                (KeyCode::Enter, Modifiers::new())
+            }
+            b'\n' if after_cr => return None,
+            b'\n' => (KeyCode::Enter, Modifiers::new()),
+            b'\t' => (KeyCode::Tab, Modifiers::new()),
+            // terminals disagree on which of these Backspace sends.
+            0x08 | 0x7f => (KeyCode::Backspace, Modifiers::new()),
+            // Ctrl+Space, or Ctrl+@.
+            0x00 => (KeyCode::Char(' '), ctrl),
+            // Ctrl+A through Ctrl+Z.
+            0x01..=0x1a => (KeyCode::Char((b'a' + byte - 1) as char), ctrl),
+            // Ctrl+\, Ctrl+], Ctrl+^, and Ctrl+_.
+            0x1c..=0x1f => (KeyCode::Char((byte + 0x40) as char), ctrl),
+            _ if byte.is_ascii_uppercase() => (
+                KeyCode::Char(byte as char),
+                Modifiers::new().with(Modifiers::SHIFT, true),
+            ),
+            0x20..=0x7e => (KeyCode::Char(byte as char), Modifiers::new()),
+            _ => return None,
+        };
+        Some(press(code, modifiers))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn press(code: KeyCode, modifiers: Modifiers) -> KeyEvent {
+    KeyEvent {
+        kind: Kind::Pressed,
+        modifiers,
+        code,
+    }
+}
+
+/// Returns the key for the final byte of a `CSI` or `SS3` sequence.
+fn final_key(byte: u8) -> Option<KeyCode> {
+    Some(match byte {
+        b'A' => KeyCode::Up,
+        b'B' => KeyCode::Down,
+        b'C' => KeyCode::Right,
+        b'D' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        b'E' => KeyCode::KeypadBegin,
+        b'Z' => KeyCode::BackTab,
+        b'P'..=b'S' => KeyCode::F(byte - b'P' + 1),
+        _ => return None,
+    })
+}
+
+/// Returns the key for a `CSI <n> ~` sequence.
+fn tilde_key(n: u16) -> Option<KeyCode> {
+    Some(match n {
+        1 | 7 => KeyCode::Home,
+        2 => KeyCode::Insert,
+        3 => KeyCode::Delete,
+        4 | 8 => KeyCode::End,
+        5 => KeyCode::PageUp,
+        6 => KeyCode::PageDown,
+        11..=15 => KeyCode::F((n - 10) as u8),
+        17..=21 => KeyCode::F((n - 11) as u8),
+        23 | 24 => KeyCode::F((n - 12) as u8),
+        _ => return None,
+    })
+}
+
+/// Returns the modifiers encoded in a sequence's modifier parameter, which is
+/// one more than a bitmask of Shift (1), Alt (2), Ctrl (4), and Meta (8).
+fn modifiers(param: u16) -> Modifiers {
+    let bits = param.saturating_sub(1);
+    Modifiers::new()
+        .with(Modifiers::SHIFT, bits & 1 != 0)
+        .with(Modifiers::ALT, bits & 2 != 0)
+        .with(Modifiers::CTRL, bits & 4 != 0)
+        .with(Modifiers::META, bits & 8 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> std::vec::Vec<KeyEvent> {
+        let mut decoder = Decoder::new();
+        bytes.iter().filter_map(|&b| decoder.feed(b)).collect()
+    }
+
+    fn codes(bytes: &[u8]) -> std::vec::Vec<KeyCode> {
+        decode(bytes).into_iter().map(|event| event.code).collect()
+    }
+
+    #[test]
+    fn printable() {
+        let events = decode(b"hI!");
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| event.code)
+                .collect::<std::vec::Vec<_>>(),
+            [KeyCode::Char('h'), KeyCode::Char('I'), KeyCode::Char('!')]
+        );
+        assert!(events.iter().all(|event| event.kind == Kind::Pressed));
+        assert!(events[1].modifiers.get(Modifiers::SHIFT));
+        assert!(!events[0].modifiers.get(Modifiers::SHIFT));
+    }
+
+    #[test]
+    fn control_keys() {
+        // CR, LF, and CRLF are each a single Enter.
+        assert_eq!(
+            codes(b"a\rb\nc\r\nd"),
+            [
+                KeyCode::Char('a'),
+                KeyCode::Enter,
+                KeyCode::Char('b'),
+                KeyCode::Enter,
+                KeyCode::Char('c'),
+                KeyCode::Enter,
+                KeyCode::Char('d'),
+            ]
+        );
+        assert_eq!(
+            codes(b"\x7f\x08\t"),
+            [KeyCode::Backspace, KeyCode::Backspace, KeyCode::Tab]
+        );
+
+        let events = decode(b"\x03");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].code, KeyCode::Char('c'));
+        assert!(events[0].modifiers.get(Modifiers::CTRL));
+    }
+
+    #[test]
+    fn arrows() {
+        assert_eq!(
+            codes(b"\x1b[A\x1b[B\x1b[C\x1b[D\x1bOA"),
+            [
+                KeyCode::Up,
+                KeyCode::Down,
+                KeyCode::Right,
+                KeyCode::Left,
+                KeyCode::Up,
+            ]
+        );
+
+        // Ctrl+Right.
+        let events = decode(b"\x1b[1;5C");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].code, KeyCode::Right);
+        assert!(events[0].modifiers.get(Modifiers::CTRL));
+        assert!(!events[0].modifiers.get(Modifiers::SHIFT));
+    }
+
+    #[test]
+    fn tilde_sequences() {
+        assert_eq!(
+            codes(b"\x1b[3~\x1b[5~\x1b[15~\x1b[24~\x1bOP"),
+            [
+                KeyCode::Delete,
+                KeyCode::PageUp,
+                KeyCode::F(5),
+                KeyCode::F(12),
+                KeyCode::F(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_sequences() {
+        // the bytes of a sequence may arrive separately, with other work in
+        // between.
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.feed(ESC), None);
+        assert!(decoder.is_pending());
+        assert_eq!(decoder.feed(b'['), None);
+        assert!(decoder.is_pending());
+        assert_eq!(
+            decoder.feed(b'D').map(|event| event.code),
+            Some(KeyCode::Left)
+        );
+        assert!(!decoder.is_pending());
+
+        // CR and LF in separate reads are still one Enter.
+        assert_eq!(
+            decoder.feed(b'\r').map(|event| event.code),
+            Some(KeyCode::Enter)
+        );
+        assert_eq!(decoder.feed(b'\n'), None);
+    }
+
+    #[test]
+    fn escape_key() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.feed(ESC), None);
+        assert_eq!(decoder.flush().map(|event| event.code), Some(KeyCode::Esc));
+        assert!(!decoder.is_pending());
+        assert_eq!(decoder.flush(), None);
+
+        // Escape pressed twice.
+        assert_eq!(codes(b"\x1b\x1b[A"), [KeyCode::Esc, KeyCode::Up]);
+
+        // Alt+x.
+        let events = decode(b"\x1bx");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].code, KeyCode::Char('x'));
+        assert!(events[0].modifiers.get(Modifiers::ALT));
+    }
+
+    #[test]
+    fn ignored() {
+        // non-ASCII bytes, unknown sequences, and garbled sequences.
+        assert_eq!(codes(b"\xc3\xa9\x1b[99~\x1b[1\x03a"), [KeyCode::Char('a')]);
+    }
+}
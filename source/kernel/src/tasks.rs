@@ -0,0 +1,289 @@
+//! Tasks spawned by userspace.
+//!
+//! The platform implementation gives the kernel a fixed table of [`Entry`]
+//! points using [`Kernel::set_task_entries`]. Userspace spawns a task from
+//! one of them with a [`UserRequestBody::Spawn`] request, naming the entry
+//! point by its index in the table, and gets back a [`TaskHandle`] which it
+//! can query with [`UserRequestBody::TaskStatus`].
+//!
+//! The number of spawned tasks which may be running at once is limited by
+//! [`TaskSettings::max_tasks`]. See [`abi::syscall::tasks`] for how handles
+//! are reused.
+//!
+//! [`Kernel::set_task_entries`]: crate::Kernel::set_task_entries
+//! [`UserRequestBody::Spawn`]: abi::syscall::UserRequestBody::Spawn
+//! [`UserRequestBody::TaskStatus`]: abi::syscall::UserRequestBody::TaskStatus
+use crate::Kernel;
+use abi::syscall::{
+    tasks::{SpawnRequest, TaskHandle, TaskStatus, TaskStatusRequest},
+    SysCallError,
+};
+use futures::future::LocalBoxFuture;
+use maitake::{sync::blocking::Mutex, task::Task};
+use mnemos_alloc::containers::{Box, FixedVec};
+use mycelium_util::sync::InitOnce;
+use serde::{Deserialize, Serialize};
+
+/// An entry point which userspace may spawn tasks from.
+///
+/// The entry point is called with the argument from the
+/// [`SpawnRequest`], and returns the future for the task to run.
+pub type Entry = fn(arg: u32) -> LocalBoxFuture<'static, ()>;
+
+/// Settings for tasks spawned by userspace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskSettings {
+    /// The maximum number of tasks spawned by userspace which may be running
+    /// at once.
+    ///
+    /// Storage for this many tasks is allocated from the heap when the kernel
+    /// is created.
+    #[serde(default = "TaskSettings::default_max_tasks")]
+    pub max_tasks: usize,
+}
+
+/// The kernel's table of tasks spawned by userspace.
+pub(crate) struct UserTasks {
+    entries: InitOnce<&'static [Entry]>,
+    slots: Mutex<FixedVec<Slot>>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    /// The number of times a task has been spawned in this slot, minus one.
+    generation: u32,
+    running: bool,
+}
+
+// === impl TaskSettings ===
+
+impl TaskSettings {
+    pub const DEFAULT_MAX_TASKS: usize = 16;
+
+    const fn default_max_tasks() -> usize {
+        Self::DEFAULT_MAX_TASKS
+    }
+}
+
+impl Default for TaskSettings {
+    fn default() -> Self {
+        Self {
+            max_tasks: Self::DEFAULT_MAX_TASKS,
+        }
+    }
+}
+
+// === impl UserTasks ===
+
+impl UserTasks {
+    /// Returns a new task table, or `None` if its storage could not be
+    /// allocated.
+    pub(crate) fn try_new(settings: &TaskSettings) -> Option<Self> {
+        Some(Self {
+            entries: InitOnce::uninitialized(),
+            slots: Mutex::new(FixedVec::try_new(settings.max_tasks)?),
+        })
+    }
+
+    pub(crate) fn set_entries(&self, entries: &'static [Entry]) -> Result<(), &'static str> {
+        self.entries
+            .try_init(entries)
+            .map_err(|_| "Task entry points already set.")
+    }
+
+    /// Spawn a task from the entry point named by `req` on `kernel`.
+    pub(crate) fn spawn(
+        &'static self,
+        kernel: &'static Kernel,
+        req: SpawnRequest,
+    ) -> Result<TaskHandle, SysCallError> {
+        let entry = *self
+            .entries
+            .try_get()
+            .and_then(|entries| entries.get(req.entry as usize))
+            .ok_or(SysCallError::NotFound)?;
+
+        let handle = self.reserve()?;
+        let fut = entry(req.arg);
+        let task = Task::new(async move {
+            fut.await;
+            self.finish(handle);
+        });
+        match Box::try_new(task) {
+            Ok(task) => {
+                kernel.spawn_allocated(task.into_alloc_box());
+                Ok(handle)
+            }
+            Err(_) => {
+                // the handle was never returned, so nobody can observe the
+                // slot's generation having been used.
+                self.finish(handle);
+                Err(SysCallError::ResourceExhausted)
+            }
+        }
+    }
+
+    /// Returns the status of the task named by `req`.
+    pub(crate) fn status(&self, req: TaskStatusRequest) -> Result<TaskStatus, SysCallError> {
+        self.slots.with_lock(|slots| {
+            let slot = slots
+                .as_slice()
+                .get(req.handle.slot as usize)
+                .filter(|slot| slot.generation == req.handle.generation)
+                .ok_or(SysCallError::NotFound)?;
+            Ok(if slot.running {
+                TaskStatus::Running
+            } else {
+                TaskStatus::Finished
+            })
+        })
+    }
+
+    /// Reserve a slot for a new task.
+    ///
+    /// A finished task's slot is reused before a new slot is used, so the
+    /// table only grows when every slot is running. The finished slot with
+    /// the lowest generation is picked, to spread reuse across the slots.
+    fn reserve(&self) -> Result<TaskHandle, SysCallError> {
+        self.slots.with_lock(|slots| {
+            let reused = slots
+                .as_slice_mut()
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, slot)| !slot.running)
+                .min_by_key(|(_, slot)| slot.generation);
+            if let Some((idx, slot)) = reused {
+                slot.generation = slot.generation.wrapping_add(1);
+                slot.running = true;
+                return Ok(TaskHandle {
+                    slot: idx as u32,
+                    generation: slot.generation,
+                });
+            }
+
+            let idx = slots.len();
+            slots
+                .try_push(Slot {
+                    generation: 0,
+                    running: true,
+                })
+                .map_err(|_| SysCallError::ResourceExhausted)?;
+            Ok(TaskHandle {
+                slot: idx as u32,
+                generation: 0,
+            })
+        })
+    }
+
+    fn finish(&self, handle: TaskHandle) {
+        self.slots.with_lock(|slots| {
+            if let Some(slot) = slots.as_slice_mut().get_mut(handle.slot as usize) {
+                slot.running = false;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestKernel;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use futures::FutureExt;
+    use maitake::sync::WaitQueue;
+
+    static RAN: AtomicU32 = AtomicU32::new(0);
+    static RAN_WAIT: WaitQueue = WaitQueue::new();
+
+    static NEVER: WaitQueue = WaitQueue::new();
+
+    const ENTRIES: &[Entry] = &[run_once, run_forever];
+
+    fn run_once(arg: u32) -> LocalBoxFuture<'static, ()> {
+        async move {
+            RAN.store(arg, Ordering::SeqCst);
+            RAN_WAIT.wake_all();
+        }
+        .boxed_local()
+    }
+
+    fn run_forever(_: u32) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let _ = NEVER.wait().await;
+        }
+        .boxed_local()
+    }
+
+    #[test]
+    fn spawn_and_query() {
+        TestKernel::run(|k| async move {
+            k.set_task_entries(ENTRIES).unwrap();
+
+            let waiting = RAN_WAIT.wait();
+            let handle = k
+                .spawn_user_task(SpawnRequest { entry: 0, arg: 42 })
+                .unwrap();
+            assert_eq!(
+                k.user_task_status(TaskStatusRequest { handle }),
+                Ok(TaskStatus::Running)
+            );
+
+            waiting.await.unwrap();
+            assert_eq!(RAN.load(Ordering::SeqCst), 42);
+            assert_eq!(
+                k.user_task_status(TaskStatusRequest { handle }),
+                Ok(TaskStatus::Finished)
+            );
+
+            // spawning another task reuses the finished task's slot, so its
+            // handle becomes stale.
+            let next = k
+                .spawn_user_task(SpawnRequest { entry: 1, arg: 0 })
+                .unwrap();
+            assert_eq!(next.slot, handle.slot);
+            assert_ne!(next.generation, handle.generation);
+            assert_eq!(
+                k.user_task_status(TaskStatusRequest { handle }),
+                Err(SysCallError::NotFound)
+            );
+            assert_eq!(
+                k.user_task_status(TaskStatusRequest { handle: next }),
+                Ok(TaskStatus::Running)
+            );
+        });
+    }
+
+    #[test]
+    fn spawn_errors() {
+        TestKernel::run(|k| async move {
+            assert_eq!(
+                k.spawn_user_task(SpawnRequest { entry: 0, arg: 0 }),
+                Err(SysCallError::NotFound),
+                "no entry points have been set"
+            );
+            k.set_task_entries(ENTRIES).unwrap();
+            assert_eq!(
+                k.spawn_user_task(SpawnRequest { entry: 2, arg: 0 }),
+                Err(SysCallError::NotFound),
+            );
+
+            for _ in 0..TaskSettings::DEFAULT_MAX_TASKS {
+                k.spawn_user_task(SpawnRequest { entry: 1, arg: 0 })
+                    .unwrap();
+            }
+            assert_eq!(
+                k.spawn_user_task(SpawnRequest { entry: 1, arg: 0 }),
+                Err(SysCallError::ResourceExhausted),
+            );
+
+            let bogus = TaskHandle {
+                slot: TaskSettings::DEFAULT_MAX_TASKS as u32,
+                generation: 0,
+            };
+            assert_eq!(
+                k.user_task_status(TaskStatusRequest { handle: bogus }),
+                Err(SysCallError::NotFound)
+            );
+        });
+    }
+}
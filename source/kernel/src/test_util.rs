@@ -1,5 +1,7 @@
 use super::*;
 
+pub(crate) mod timer;
+
 use mnemos_alloc::heap::MnemosAlloc;
 use std::{
     future::Future,
@@ -39,7 +41,15 @@ impl TestKernel {
         // at least it means we never create a dangling pointer to it.
         let kernel = unsafe {
             NonNull::new(mnemos_alloc::containers::Box::into_raw(
-                Kernel::new(KernelSettings { max_drivers: 16 }, clock).unwrap(),
+                Kernel::new(
+                    KernelSettings {
+                        max_drivers: Some(16),
+                        latency: Default::default(),
+                        tasks: Default::default(),
+                    },
+                    clock,
+                )
+                .unwrap(),
             ))
             .expect("newly-allocated kernel mustn't be null!")
         };
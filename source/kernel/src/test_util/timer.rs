@@ -0,0 +1,225 @@
+//! Test support for deterministically exercising the `maitake` timer wheel.
+//!
+//! [`TestTimer`] wraps a [`Timer`] driven by a clock that never advances on its
+//! own, so the wheel only moves when a test calls
+//! [`TestTimer::force_advance`]. Every sleep scheduled through a [`TestTimer`]
+//! is tracked, which lets a test capture the wheel's state as a
+//! [`TimerSnapshot`] and later [restore](TestTimer::restore) it into a fresh
+//! timer to replay the same configuration.
+//!
+//! # Snapshot stability
+//!
+//! A [`TimerSnapshot`] is an in-memory value for use *within a single test
+//! binary*. It is expressed purely in timer ticks relative to the wheel's
+//! starting position, and records the tick duration it was taken with. It is
+//! deliberately not serializable: the format may change whenever the kernel's
+//! timer configuration changes, and no compatibility is promised between
+//! kernel versions. The only guarantee is that restoring a snapshot into a
+//! timer with the same tick duration reproduces the same pending deadlines at
+//! the same wheel position.
+use core::{future::Future, pin::Pin, task::Context};
+use maitake::time::{Clock, Sleep, Timer};
+use std::time::Duration;
+
+/// A [`Timer`] which only advances when explicitly told to.
+pub(crate) struct TestTimer {
+    timer: &'static Timer,
+    /// The number of ticks the wheel has been advanced since it was created.
+    now: u64,
+    /// Sleeps that have not yet completed, along with their deadlines (in
+    /// ticks since the wheel was created).
+    pending: Vec<(u64, Pin<Box<Sleep<'static>>>)>,
+}
+
+/// A captured timer wheel configuration.
+///
+/// See the [module-level documentation](self) for this type's stability
+/// guarantees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TimerSnapshot {
+    /// The duration of a single tick of the timer the snapshot was taken from.
+    pub(crate) tick_duration: Duration,
+    /// The wheel's position, in ticks since it was created.
+    pub(crate) now: u64,
+    /// The deadlines of all pending sleeps, in ticks since the wheel was
+    /// created, sorted in ascending order.
+    pub(crate) deadlines: Vec<u64>,
+}
+
+/// Errors returned by [`TestTimer::restore`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RestoreError {
+    /// The snapshot was taken from a timer with a different tick duration.
+    GranularityMismatch { snapshot: Duration, timer: Duration },
+    /// The timer has already been advanced past the snapshot's position, and
+    /// the wheel cannot be turned backwards.
+    AlreadyAdvanced { snapshot: u64, timer: u64 },
+}
+
+impl TestTimer {
+    pub(crate) fn new(tick_duration: Duration) -> Self {
+        let clock = Clock::new(tick_duration, || 0).named("CLOCK_TEST_FROZEN");
+        // leak the timer so that sleeps can borrow it for `'static`; the test
+        // kernel is leaked in the same way.
+        let timer = Box::leak(Box::new(Timer::new(clock)));
+        Self {
+            timer,
+            now: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the underlying [`Timer`].
+    pub(crate) fn timer(&self) -> &'static Timer {
+        self.timer
+    }
+
+    /// Schedule a sleep which completes `ticks` ticks from the wheel's current
+    /// position.
+    pub(crate) fn schedule(&mut self, ticks: u64) {
+        let mut sleep = Box::pin(self.timer.sleep_ticks(ticks));
+        // poll the sleep once so that it registers itself with the wheel.
+        if sleep.as_mut().poll(&mut noop_cx()).is_pending() {
+            self.pending.push((self.now + ticks, sleep));
+        }
+    }
+
+    /// Forcibly advance the wheel by `ticks` ticks, returning the number of
+    /// pending sleeps that completed.
+    pub(crate) fn force_advance(&mut self, ticks: u64) -> usize {
+        self.timer.force_advance_ticks(ticks);
+        self.now += ticks;
+        let before = self.pending.len();
+        self.pending
+            .retain_mut(|(_, sleep)| sleep.as_mut().poll(&mut noop_cx()).is_pending());
+        before - self.pending.len()
+    }
+
+    /// Returns the number of ticks until the next pending deadline, as
+    /// reported by the wheel.
+    pub(crate) fn ticks_to_next_deadline(&self) -> Option<u64> {
+        self.timer.turn().ticks_to_next_deadline()
+    }
+
+    /// Capture the wheel's current position and pending deadlines.
+    pub(crate) fn snapshot(&self) -> TimerSnapshot {
+        let mut deadlines = self
+            .pending
+            .iter()
+            .map(|&(deadline, _)| deadline)
+            .collect::<Vec<_>>();
+        deadlines.sort_unstable();
+        TimerSnapshot {
+            tick_duration: self.timer.clock().tick_duration(),
+            now: self.now,
+            deadlines,
+        }
+    }
+
+    /// Restore `snapshot` into this timer, advancing the wheel to the
+    /// snapshot's position and scheduling all of its pending deadlines.
+    ///
+    /// Deadlines already scheduled on this timer are left in place.
+    pub(crate) fn restore(&mut self, snapshot: &TimerSnapshot) -> Result<(), RestoreError> {
+        let tick_duration = self.timer.clock().tick_duration();
+        if tick_duration != snapshot.tick_duration {
+            return Err(RestoreError::GranularityMismatch {
+                snapshot: snapshot.tick_duration,
+                timer: tick_duration,
+            });
+        }
+
+        if self.now > snapshot.now {
+            return Err(RestoreError::AlreadyAdvanced {
+                snapshot: snapshot.now,
+                timer: self.now,
+            });
+        }
+
+        if snapshot.now > self.now {
+            self.force_advance(snapshot.now - self.now);
+        }
+
+        for &deadline in &snapshot.deadlines {
+            self.schedule(deadline - snapshot.now);
+        }
+
+        Ok(())
+    }
+}
+
+fn noop_cx() -> Context<'static> {
+    Context::from_waker(futures::task::noop_waker_ref())
+}
+
+#[test]
+fn snapshot_roundtrip() {
+    let mut timer = TestTimer::new(Duration::from_millis(1));
+    timer.schedule(10);
+    timer.schedule(100);
+    timer.schedule(1000);
+    assert_eq!(timer.force_advance(50), 1);
+
+    let snapshot = timer.snapshot();
+    assert_eq!(snapshot.now, 50);
+    assert_eq!(snapshot.deadlines, [100, 1000]);
+
+    let mut restored = TestTimer::new(Duration::from_millis(1));
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(restored.snapshot(), snapshot);
+    assert_eq!(
+        restored.ticks_to_next_deadline(),
+        timer.ticks_to_next_deadline()
+    );
+    assert_eq!(restored.ticks_to_next_deadline(), Some(50));
+}
+
+#[test]
+fn restored_deadlines_fire_on_time() {
+    let mut timer = TestTimer::new(Duration::from_millis(1));
+    timer.schedule(64);
+    timer.schedule(65);
+    let snapshot = timer.snapshot();
+
+    let mut restored = TestTimer::new(Duration::from_millis(1));
+    restored.restore(&snapshot).unwrap();
+
+    // advancing to exactly one tick before the first deadline must not fire
+    // anything...
+    assert_eq!(restored.force_advance(63), 0);
+    // ...but advancing exactly to it must not skip it.
+    assert_eq!(restored.force_advance(1), 1);
+    assert_eq!(restored.force_advance(1), 1);
+    assert_eq!(restored.ticks_to_next_deadline(), None);
+}
+
+#[test]
+fn restore_granularity_mismatch() {
+    let timer = TestTimer::new(Duration::from_millis(1));
+    let snapshot = timer.snapshot();
+
+    let mut other = TestTimer::new(Duration::from_micros(1));
+    assert_eq!(
+        other.restore(&snapshot),
+        Err(RestoreError::GranularityMismatch {
+            snapshot: Duration::from_millis(1),
+            timer: Duration::from_micros(1),
+        })
+    );
+}
+
+#[test]
+fn restore_after_advance() {
+    let timer = TestTimer::new(Duration::from_millis(1));
+    let snapshot = timer.snapshot();
+
+    let mut other = TestTimer::new(Duration::from_millis(1));
+    other.force_advance(5);
+    assert_eq!(
+        other.restore(&snapshot),
+        Err(RestoreError::AlreadyAdvanced {
+            snapshot: 0,
+            timer: 5
+        })
+    );
+}
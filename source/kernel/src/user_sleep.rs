@@ -0,0 +1,149 @@
+//! Sleeping on behalf of userspace.
+//!
+//! Userspace sleeps by sending a [`UserRequestBody::Sleep`] request, which
+//! is answered once the requested duration has elapsed. [`UserSleeps`]
+//! tracks the sleeps in progress by the nonce of the request which started
+//! them, so that a later [`UserRequestBody::CancelSleep`] can disarm the
+//! timer and answer the sleep early.
+//!
+//! [`UserRequestBody::Sleep`]: abi::syscall::UserRequestBody::Sleep
+//! [`UserRequestBody::CancelSleep`]: abi::syscall::UserRequestBody::CancelSleep
+use abi::syscall::time::{CancelSleepRequest, SleepError, SleepRequest};
+use core::pin::pin;
+use futures::future::{select, Either};
+use maitake::{
+    sync::wait_map::{WaitMap, WakeOutcome},
+    time::{Duration, Timer},
+};
+
+/// The sleeps requested by userspace which are in progress.
+pub struct UserSleeps {
+    /// Each sleep in progress waits here, keyed by its request's nonce, to be
+    /// woken if it's cancelled.
+    cancel: WaitMap<u32, ()>,
+}
+
+impl UserSleeps {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            cancel: WaitMap::new(),
+        }
+    }
+
+    /// Sleep on `timer` for the duration requested by `req`, which was sent
+    /// with `nonce`.
+    ///
+    /// This completes when the duration has elapsed, or with
+    /// [`SleepError::Cancelled`] if the sleep is [cancelled](Self::cancel)
+    /// first. Dropping this future disarms the timer.
+    pub async fn sleep(
+        &self,
+        timer: &Timer,
+        nonce: u32,
+        req: SleepRequest,
+    ) -> Result<(), SleepError> {
+        let mut cancelled = pin!(self.cancel.wait(nonce));
+        // register the nonce before arming the timer, so that a cancellation
+        // which arrives straight away isn't missed.
+        cancelled
+            .as_mut()
+            .enqueue()
+            .await
+            .map_err(|_| SleepError::Rejected)?;
+
+        let sleep = pin!(timer.sleep(Duration::from_micros(req.micros)));
+        match select(sleep, cancelled).await {
+            Either::Left(((), _)) => Ok(()),
+            // dropping the sleep future disarms its timer.
+            Either::Right((_, _)) => Err(SleepError::Cancelled),
+        }
+    }
+
+    /// Cancel the sleep started by the request with the nonce in `req`.
+    ///
+    /// Returns `true` if a sleep was cancelled, or `false` if there is no
+    /// sleep in progress with that nonce.
+    pub fn cancel(&self, req: CancelSleepRequest) -> bool {
+        matches!(self.cancel.wake(&req.nonce, ()), WakeOutcome::Woke)
+    }
+}
+
+impl Default for UserSleeps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::timer::TestTimer;
+    use core::{
+        future::Future,
+        task::{Context, Poll},
+    };
+    use futures::task::noop_waker_ref;
+    use maitake::time::Clock;
+    use std::time::{Instant, SystemTime};
+
+    #[test]
+    fn sleep_elapses() {
+        let clock = Clock::new(Duration::from_micros(1), || {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_micros() as u64
+        })
+        .named("CLOCK_SYSTEMTIME_NOW");
+        let timer = Timer::new(clock);
+        let sleeps = UserSleeps::new();
+        let duration = Duration::from_millis(20);
+
+        let start = Instant::now();
+        let mut sleep = pin!(sleeps.sleep(
+            &timer,
+            1,
+            SleepRequest {
+                micros: duration.as_micros() as u64,
+            },
+        ));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let res = loop {
+            if let Poll::Ready(res) = sleep.as_mut().poll(&mut cx) {
+                break res;
+            }
+            timer.turn();
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        let elapsed = start.elapsed();
+
+        assert_eq!(res, Ok(()));
+        assert!(
+            elapsed >= duration,
+            "slept for {elapsed:?}, but asked for {duration:?}"
+        );
+    }
+
+    #[test]
+    fn cancel_disarms_timer() {
+        let timer = TestTimer::new(Duration::from_millis(1));
+        let sleeps = UserSleeps::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut sleep = pin!(sleeps.sleep(timer.timer(), 7, SleepRequest { micros: 100_000 }));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        assert!(timer.ticks_to_next_deadline().is_some());
+
+        // cancelling a different nonce does nothing.
+        assert!(!sleeps.cancel(CancelSleepRequest { nonce: 8 }));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+
+        assert!(sleeps.cancel(CancelSleepRequest { nonce: 7 }));
+        assert_eq!(
+            sleep.as_mut().poll(&mut cx),
+            Poll::Ready(Err(SleepError::Cancelled))
+        );
+        assert_eq!(timer.ticks_to_next_deadline(), None);
+    }
+}
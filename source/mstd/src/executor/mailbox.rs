@@ -37,119 +37,1458 @@
 use core::{
     cell::UnsafeCell,
     mem::MaybeUninit,
-    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    pin::pin,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
 };
 
 use abi::{
     bbqueue_ipc::framed::{FrameConsumer, FrameProducer},
+    frame::{self, FrameError},
     syscall::{
-        KernelMsg, KernelResponse, KernelResponseBody, UserRequest, UserRequestBody,
-        UserRequestHeader,
+        console::ConsoleWriteRequest,
+        hello::{HelloError, HelloRequest, ABI_VERSION},
+        time::{CancelSleepRequest, SleepRequest},
+        DriverKind, KernelMsg, KernelResponse, KernelResponseBody, SysCallError, UserRequest,
+        UserRequestBody, UserRequestHeader, NO_RESPONSE_NONCE,
     },
 };
+#[cfg(any(test, feature = "test-util"))]
+use abi::{bbqueue_ipc::BBBuffer, syscall::KernelResponseHeader};
+use futures_util::future::{select, Either};
+use heapless::{Deque, LinearMap, Vec};
 use maitake::sync::{
-    wait_map::{self, WaitMap},
-    WaitQueue,
+    blocking::Mutex,
+    spin::Spinlock,
+    wait_map::{WaitMap, WakeOutcome},
+    Mutex as AsyncMutex, WaitCell, WaitQueue,
 };
 
+use crate::executor::{
+    notification::{Notification, Notifications, Subscription},
+    time::Alarm,
+};
+
+/// The default mailbox, whose rings are set with [`MailBox::set_rings`] when
+/// the executor is initialized.
 pub static MAILBOX: MailBox = MailBox::new();
 
-// TODO: There's a bit of mutexing going on here. `send_wait` and `recv_wait` BOTH have
-pub struct MailBox {
+/// The default maximum serialized size of a message sent to the kernel, in
+/// bytes. See [`MailBox::with_max_msg_size`].
+pub const DEFAULT_MAX_MSG_SIZE: usize = 128;
+
+/// The maximum number of responses that may be held in the early arrivals
+/// buffer. See [`MailBox::poll`].
+const EARLY_CAPACITY: usize = 8;
+
+/// The maximum number of services that may have ordered delivery enabled. See
+/// [`MailBox::set_ordered`].
+const MAX_ORDERED_SERVICES: usize = 4;
+
+/// The maximum number of outstanding requests to a single ordered service.
+const ORDERED_MAX_PENDING: usize = 16;
+
+/// The maximum number of out-of-order responses held for a single ordered
+/// service.
+const ORDERED_MAX_HELD: usize = 4;
+
+/// The default maximum number of [`PendingResponse`]s that may exist at once
+/// for a [`MailBox`]. See [`MailBox::submit`].
+pub const DEFAULT_MAX_SUBMITTED: usize = 16;
+
+/// The number of low bits of a nonce which count requests. The bits above
+/// them hold the request's [`Priority`].
+const NONCE_COUNTER_BITS: u32 = 30;
+const NONCE_COUNTER_MASK: u32 = (1 << NONCE_COUNTER_BITS) - 1;
+
+/// The maximum number of responses [`MailBox::poll`] holds back while it
+/// delivers more urgent ones. See [`Priority`].
+const POLL_BATCH: usize = 16;
+
+/// How urgently a request should be handled, relative to other requests from
+/// the same mailbox.
+///
+/// There is a single `u2k` ring, and the kernel handles requests in the order
+/// they're written to it, so priority can't reorder requests once they are
+/// sent. Instead, priority decides who goes first whenever the mailbox has to
+/// choose:
+///
+/// - When the `u2k` ring is full, a sender waiting for room is never passed
+///   by a lower-priority sender. A burst of low-priority requests (such as
+///   serial logging) therefore can't keep a high-priority request out of
+///   the ring once it's waiting.
+/// - When [`MailBox::poll`] drains several responses at once, it delivers
+///   the responses to higher-priority requests first, so their waiters are
+///   woken, and run, ahead of the rest.
+///
+/// The priority of a request is encoded in the top bits of its nonce, so
+/// responses can be prioritized without keeping track of every request.
+/// [`MailBox::send`], [`MailBox::request`], and [`MailBox::submit`] infer a
+/// priority from the request with [`Priority::of`];
+/// [`MailBox::send_with_priority`] and [`MailBox::request_with_priority`]
+/// set one explicitly.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Bulk traffic, such as serial output, which may be delayed.
+    Low = 0,
+    /// The default for most requests.
+    Normal = 1,
+    /// Latency-sensitive requests, such as timers.
+    High = 2,
+}
+
+/// Errors returned by the [`MailBox`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MailboxError {
+    /// The kernel did not respond before the timeout elapsed.
+    Timeout,
+    /// The serialized message is larger than the mailbox's maximum message
+    /// size, so it was not sent.
+    MessageTooLarge {
+        /// The serialized size of the message, in bytes.
+        len: usize,
+        /// The mailbox's maximum message size, in bytes.
+        max: usize,
+    },
+    /// The kernel's response to the request could not be decoded. See
+    /// [`MailBox::poll`].
+    Decode,
+    /// The kernel rejected the request before it reached a driver.
+    Kernel(SysCallError),
+    /// The kernel doesn't support the ABI version this mailbox was built
+    /// with. See [`MailBox::handshake`].
+    AbiMismatch {
+        /// The ABI version this mailbox was built with.
+        ours: u32,
+        /// The ABI version the kernel supports.
+        kernel: u32,
+    },
+    /// The kernel stopped answering [keepalive](MailBox::keepalive) pings, so
+    /// the request was abandoned.
+    ConnectionLost,
+    /// The request could not be sent, or no response could be received.
+    Failed,
+}
+
+/// A response routed to a request's waiter: either the response body, or the
+/// reason it could not be delivered.
+type Response = Result<KernelResponseBody, MailboxError>;
+
+/// The userspace end of the mailbox rings.
+///
+/// `N` is the number of [`MailBox::submit`]ted requests which may be
+/// awaiting a response at once. Each one reserves an entry in a map of this
+/// size before it is sent, and `submit` waits for a free entry once they are
+/// all in use. Applications which keep more requests in flight can use a
+/// mailbox with a larger `N`; it defaults to [`DEFAULT_MAX_SUBMITTED`].
+pub struct MailBox<const N: usize = DEFAULT_MAX_SUBMITTED> {
     nonce: AtomicU32,
-    inhibit_send: AtomicBool,
+    /// The serialized size of the smallest message waiting for room in the
+    /// `u2k` ring, or `usize::MAX` if no messages are waiting.
+    min_blocked: AtomicUsize,
+    /// Tasks waiting for room in the `u2k` ring.
     send_wait: WaitQueue,
-    recv_wait: WaitMap<u32, KernelResponseBody>,
+    /// The number of tasks of each [`Priority`] waiting in `send_wait`.
+    blocked: [AtomicUsize; Priority::COUNT],
+    /// Tasks waiting on a response, keyed by the nonce of their request.
+    ///
+    /// Each in-flight request has its own entry in this map, so when a
+    /// response arrives, [`MailBox::poll`] wakes only the task waiting on that
+    /// response's nonce, and hands the response directly to it. Other waiters
+    /// are not woken, and there is no shared map of received responses for
+    /// them to re-scan.
+    recv_wait: WaitMap<u32, Response>,
+    /// Responses that arrived while no task was waiting on their nonce.
+    ///
+    /// This map, `submitted`, and `ordered` are guarded by spinlocks, which
+    /// wait rather than fail when they're contended, so [`MailBox::poll`] and
+    /// a sender running on another core never skip work because the other
+    /// holds the lock. Each lock is only held for a few map operations, and
+    /// never across an `await`.
+    early: Mutex<LinearMap<u32, Response, EARLY_CAPACITY>, Spinlock>,
+    /// Responses to [`MailBox::submit`]ted requests, keyed by nonce. Each
+    /// [`PendingResponse`] owns an entry, which is `None` until the response
+    /// arrives.
+    submitted: Mutex<LinearMap<u32, Option<Response>, N>, Spinlock>,
+    /// Tasks waiting for a free entry in `submitted`.
+    submit_wait: WaitQueue,
+    /// Per-service state for services with ordered delivery enabled.
+    ordered: Mutex<LinearMap<DriverKind, OrderedQueue, MAX_ORDERED_SERVICES>, Spinlock>,
+    /// Requests waiting for room in an ordered service's queue.
+    ordered_wait: WaitQueue,
+    /// Woken by [`MailBox::notify_k2u`] when the kernel writes to the `k2u`
+    /// ring, to wake [`MailBox::run`].
+    k2u_ready: WaitCell,
+    /// Unsolicited messages from the kernel.
+    notifications: Notifications,
+    /// The maximum serialized size of a message sent to the kernel.
+    max_msg_size: usize,
+    /// Counters reported by [`MailBox::metrics`].
+    sent: AtomicUsize,
+    received: AtomicUsize,
+    decode_errors: AtomicUsize,
+    checksum_errors: AtomicUsize,
+    dropped: AtomicUsize,
+    unhandled: AtomicUsize,
+    in_flight: AtomicUsize,
     rings: OnceRings,
+    /// The ABI version negotiated with the kernel, or 0 if the handshake
+    /// hasn't succeeded yet.
+    abi_version: AtomicU32,
+    /// Cleared by [`MailBox::keepalive`] when the kernel stops responding.
+    alive: AtomicBool,
+    /// The outcome of the ABI version handshake, or `None` if it hasn't
+    /// finished yet. Held while the handshake is in progress, so that only
+    /// one task performs it.
+    handshake: AsyncMutex<Option<Result<u32, MailboxError>>>,
 }
 
-impl MailBox {
+/// A snapshot of a [`MailBox`]'s traffic counters, returned by
+/// [`MailBox::metrics`].
+///
+/// All counts except `in_flight` are totals since the mailbox was created,
+/// and wrap on overflow.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct MailboxMetrics {
+    /// The number of messages written to the `u2k` ring.
+    pub sent: usize,
+    /// The number of responses read from the `k2u` ring.
+    pub received: usize,
+    /// The number of messages from the kernel which could not be decoded.
+    pub decode_errors: usize,
+    /// The number of messages from the kernel whose checksum didn't match
+    /// their contents.
+    pub checksum_errors: usize,
+    /// The number of responses discarded from the early arrivals buffer
+    /// because it was full.
+    pub dropped: usize,
+    /// The number of well-formed messages from the kernel which the mailbox
+    /// doesn't handle, and discarded, such as
+    /// [`KernelMsg::Dealloc`](abi::syscall::KernelMsg::Dealloc).
+    pub unhandled: usize,
+    /// The number of requests currently waiting for a response. If this never
+    /// returns to zero, responses are being lost.
+    pub in_flight: usize,
+}
+
+/// Responses released by an [`OrderedQueue`], in submission order.
+type Released = Vec<(u32, Response), ORDERED_MAX_PENDING>;
+
+/// Tracks the outstanding requests to a service with ordered delivery.
+struct OrderedQueue {
+    /// Nonces of requests still awaiting a response, in submission order.
+    pending: Deque<u32, ORDERED_MAX_PENDING>,
+    /// Responses that arrived before the response to an earlier request.
+    held: LinearMap<u32, Response, ORDERED_MAX_HELD>,
+}
+
+impl<const N: usize> MailBox<N> {
+    /// Returns a new mailbox with a maximum message size of
+    /// [`DEFAULT_MAX_MSG_SIZE`].
     pub const fn new() -> Self {
+        Self::with_max_msg_size(DEFAULT_MAX_MSG_SIZE)
+    }
+
+    /// Returns a new mailbox which sends messages of up to `max_msg_size`
+    /// bytes once serialized.
+    ///
+    /// Larger messages are rejected with [`MailboxError::MessageTooLarge`].
+    /// Each message is sent with a [`frame`] header, so the `u2k` ring must be
+    /// able to hold a frame of `max_msg_size` plus [`frame::HEADER_LEN`]
+    /// bytes, or sending will wait forever once the ring fills.
+    pub const fn with_max_msg_size(max_msg_size: usize) -> Self {
         Self {
             nonce: AtomicU32::new(0),
-            inhibit_send: AtomicBool::new(false),
+            min_blocked: AtomicUsize::new(usize::MAX),
             send_wait: WaitQueue::new(),
+            blocked: [const { AtomicUsize::new(0) }; Priority::COUNT],
             recv_wait: WaitMap::new(),
+            early: Mutex::new_with_raw_mutex(LinearMap::new(), Spinlock::new()),
+            submitted: Mutex::new_with_raw_mutex(LinearMap::new(), Spinlock::new()),
+            submit_wait: WaitQueue::new(),
+            ordered: Mutex::new_with_raw_mutex(LinearMap::new(), Spinlock::new()),
+            ordered_wait: WaitQueue::new(),
+            k2u_ready: WaitCell::new(),
+            notifications: Notifications::new(),
+            max_msg_size,
+            sent: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+            decode_errors: AtomicUsize::new(0),
+            checksum_errors: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            unhandled: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
             rings: OnceRings::new(),
+            abi_version: AtomicU32::new(0),
+            alive: AtomicBool::new(true),
+            handshake: AsyncMutex::new(None),
         }
     }
 
+    /// Returns the maximum serialized size of a message sent by this mailbox.
+    #[inline]
+    #[must_use]
+    pub fn max_msg_size(&self) -> usize {
+        self.max_msg_size
+    }
+
+    /// Returns a new mailbox which communicates over `rings`, with a maximum
+    /// message size of [`DEFAULT_MAX_MSG_SIZE`].
+    ///
+    /// Unlike the [`MAILBOX`] static, a mailbox created this way is ready to
+    /// use immediately, and several may exist at once, each with its own ring
+    /// pair.
+    #[must_use]
+    pub fn with_rings(rings: Rings) -> Self {
+        let mailbox = Self::new();
+        mailbox.rings.set(rings);
+        mailbox
+    }
+
+    /// Set the rings of a mailbox created without them, such as [`MAILBOX`].
+    ///
+    /// # Panics
+    ///
+    /// If the mailbox's rings have already been set.
     pub fn set_rings(&self, rings: Rings) {
         self.rings.set(rings);
     }
 
+    /// Drain all pending messages from the kernel, waking the tasks waiting
+    /// on any responses.
+    ///
+    /// Responses are handed directly to their waiters through `recv_wait`,
+    /// rather than being buffered in a bounded map until the waiter runs, so
+    /// this always drains the entire `k2u` ring: a slow or starved waiter can
+    /// never cause responses to back up in the ring.
+    ///
+    /// Each frame is decoded once, and released as soon as it has been
+    /// decoded. The decoded response is moved to its waiter, never copied
+    /// into an intermediate buffer. Waiters don't borrow the frame itself:
+    /// frames must be released in the order they were written, so one waiter
+    /// holding on to its frame would stop every later frame from being
+    /// reused. Bulk data doesn't travel through the ring anyway, since it is
+    /// sent as a [`ByteBoxWire`](abi::syscall::ByteBoxWire) referring to the
+    /// shared heap.
+    ///
+    /// A response whose nonce has no waiter yet is an *early arrival*, and is
+    /// retained in a small buffer keyed by nonce, which
+    /// [`MailBox::request`] checks before parking. The buffer holds at most
+    /// `EARLY_CAPACITY` responses; when it is full, the response with the
+    /// oldest nonce is evicted to make room. Responses to requests that will
+    /// never be waited on (because they were sent with [`MailBox::send`], or
+    /// the requester was dropped) are eventually evicted this way.
+    ///
+    /// Each message's [`frame`] header is checked before it is decoded. A
+    /// message whose checksum doesn't match was corrupted in the ring: it is
+    /// logged and discarded, and counted in [`MailBox::checksum_errors`]. Its
+    /// nonce can't be trusted either, so no waiter is told about it, and the
+    /// request it answered will only complete if it has a timeout.
+    ///
+    /// A message which cannot be decoded, or whose header is malformed, is
+    /// logged and discarded, and counted in [`MailBox::decode_errors`]. If the
+    /// message's nonce can still be read, the request waiting on it completes
+    /// with [`MailboxError::Decode`], rather than waiting for a response which
+    /// will never arrive.
+    ///
+    /// A [`KernelMsg::Dealloc`] is logged and discarded, and counted in
+    /// [`MailboxMetrics::unhandled`], since userspace doesn't give the kernel
+    /// any byte boxes to hand back yet. So is a response with
+    /// [`NO_RESPONSE_NONCE`], since nothing ever waits for one.
+    ///
+    /// Responses to [`Priority::High`] requests are delivered as soon as they
+    /// are read. Others are held back until the ring is drained (or up to
+    /// `POLL_BATCH` of them have been read), and then delivered in priority
+    /// order, so that the most urgent waiters are woken first.
     pub fn poll(&self) {
         let rings = self.rings.get();
+        let mut deferred = Vec::<(u32, Response), POLL_BATCH>::new();
+        let mut respond = |nonce: u32, body: Response| {
+            if nonce == NO_RESPONSE_NONCE {
+                tracing::warn!("ignoring response to a message which expects none");
+                self.unhandled.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            if Priority::of_nonce(nonce) == Priority::High {
+                self.dispatch(nonce, body);
+                return;
+            }
+            if deferred.is_full() {
+                self.dispatch_deferred(&mut deferred);
+            }
+            // can't fail: we just made room.
+            let _ = deferred.push((nonce, body));
+        };
 
         while let Some(msg) = rings.k2u.read() {
-            match postcard::from_bytes::<KernelMsg>(&msg) {
+            let payload = match frame::open(&msg) {
+                Ok(payload) => payload,
+                Err(error @ FrameError::ChecksumMismatch { .. }) => {
+                    tracing::warn!(len = msg.len(), ?error, "corrupted message from kernel");
+                    self.checksum_errors.fetch_add(1, Ordering::Relaxed);
+                    msg.release();
+                    continue;
+                }
+                Err(error) => {
+                    tracing::warn!(len = msg.len(), ?error, "malformed frame from kernel");
+                    self.decode_errors.fetch_add(1, Ordering::Relaxed);
+                    msg.release();
+                    continue;
+                }
+            };
+            match postcard::from_bytes::<KernelMsg>(payload) {
+                Ok(KernelMsg::Response(KernelResponse {
+                    header,
+                    body: KernelResponseBody::Error(error),
+                })) => {
+                    self.received.fetch_add(1, Ordering::Relaxed);
+                    respond(header.nonce, Err(MailboxError::Kernel(error)));
+                }
                 Ok(KernelMsg::Response(KernelResponse { header, body })) => {
-                    // Attempt to wake a relevant waiting task, OR drop the response
-                    self.recv_wait.wake(&header.nonce, body);
+                    self.received.fetch_add(1, Ordering::Relaxed);
+                    respond(header.nonce, Ok(body));
+                }
+                Ok(KernelMsg::Timestamp(timestamp)) => {
+                    self.notifications
+                        .deliver(Notification::Timestamp(timestamp));
                 }
-                Ok(_) => todo!(),
-                Err(_) => {
-                    // todo: print something? Relax this panic later with a graceful
-                    // warning
-                    panic!("Decoded bad message from kernel?");
+                Ok(KernelMsg::Dealloc(wire)) => {
+                    // nothing in userspace hands byte boxes to the kernel yet,
+                    // so there's nothing to free it into.
+                    tracing::warn!(
+                        ptr = wire.ptr,
+                        len = wire.len,
+                        "ignoring dealloc request from kernel",
+                    );
+                    self.unhandled.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(error) => {
+                    let nonce = peek_nonce(payload);
+                    tracing::warn!(
+                        ?nonce,
+                        len = payload.len(),
+                        %error,
+                        "could not decode message from kernel",
+                    );
+                    self.decode_errors.fetch_add(1, Ordering::Relaxed);
+                    if let Some(nonce) = nonce {
+                        respond(nonce, Err(MailboxError::Decode));
+                    }
                 }
             }
 
             msg.release();
         }
+        self.dispatch_deferred(&mut deferred);
 
-        if self.inhibit_send.load(Ordering::Acquire) && rings.u2k.grant(128).is_ok() {
-            self.inhibit_send.store(false, Ordering::Release);
+        // if there's now room for the smallest blocked message, wake the
+        // blocked senders. any which still don't fit will record their size
+        // again and go back to waiting.
+        let min_blocked = self.min_blocked.load(Ordering::Acquire);
+        if min_blocked != usize::MAX && rings.u2k.grant(min_blocked).is_ok() {
+            self.min_blocked.store(usize::MAX, Ordering::Release);
             self.send_wait.wake_all();
         }
     }
 
-    async fn send_inner(&'static self, nonce: u32, msg: UserRequestBody) -> Result<(), ()> {
-        let rings = self.rings.get();
-        let outgoing = UserRequest {
-            header: UserRequestHeader { nonce },
-            body: msg,
-        };
+    /// Drain messages from the kernel whenever they arrive, forever.
+    ///
+    /// This calls [`MailBox::poll`] each time [`MailBox::notify_k2u`] is
+    /// called, so that nothing else needs to poll the mailbox. Spawn this as
+    /// a task, and arrange for `notify_k2u` to be called when the kernel
+    /// signals that it has written to the `k2u` ring.
+    ///
+    /// The `bbqueue_ipc` consumer can't tell us when a frame becomes
+    /// available, since it is written from the other side of the
+    /// kernel/userspace boundary, so the kernel's signal is the only wakeup.
+    /// A notification which arrives while the ring is being drained is
+    /// remembered, so no frame is left in the ring until the next one.
+    pub async fn run(&self) {
+        loop {
+            self.poll();
+            if self.k2u_ready.wait().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Wake [`MailBox::run`] to drain the `k2u` ring.
+    ///
+    /// This should be called whenever the kernel signals that it has written
+    /// to the ring. Calling it spuriously is harmless.
+    pub fn notify_k2u(&self) {
+        self.k2u_ready.wake();
+    }
+
+    /// Returns the number of messages from the kernel which could not be
+    /// decoded, and were discarded.
+    #[must_use]
+    pub fn decode_errors(&self) -> usize {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns `false` if [`MailBox::keepalive`] found that the kernel
+    /// stopped responding.
+    ///
+    /// Once the connection is lost, every request fails with
+    /// [`MailboxError::ConnectionLost`].
+    #[must_use]
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+
+    /// Check that the kernel is still responding, forever, or until it
+    /// stops.
+    ///
+    /// Whenever `interval` passes without a response from the kernel, this
+    /// sends it a [`UserRequestBody::Ping`]. If no `Pong` arrives within
+    /// `timeout`, the connection is considered lost: [`MailBox::is_alive`]
+    /// returns `false`, every outstanding request fails with
+    /// [`MailboxError::ConnectionLost`], as does every later one, and this
+    /// returns. While other responses keep arriving, no pings are sent.
+    ///
+    /// Spawn this as a task, alongside [`MailBox::run`], to turn a kernel (or
+    /// driver) that silently stopped responding into an error, rather than
+    /// requests which wait forever.
+    pub async fn keepalive(&self, interval: Duration, timeout: Duration) {
+        let mut seq = 0u32;
+        let mut seen = self.received.load(Ordering::Relaxed);
+        while self.is_alive() {
+            Alarm::after(interval).await;
+            let received = self.received.load(Ordering::Relaxed);
+            if received != seen {
+                seen = received;
+                continue;
+            }
 
-        // Wait for a successful send
+            seq = seq.wrapping_add(1);
+            match self
+                .request_timeout(UserRequestBody::Ping(seq), timeout)
+                .await
+            {
+                Ok(KernelResponseBody::Pong(pong)) if pong == seq => {}
+                Ok(response) => {
+                    tracing::warn!(seq, ?response, "unexpected response to keepalive ping");
+                }
+                Err(MailboxError::Timeout) => {
+                    tracing::error!(seq, ?timeout, "kernel stopped responding, connection lost");
+                    self.connection_lost();
+                }
+                Err(error) => {
+                    tracing::warn!(seq, ?error, "keepalive ping failed");
+                }
+            }
+            seen = self.received.load(Ordering::Relaxed);
+        }
+    }
+
+    /// Mark the connection as lost, and wake every task waiting on the
+    /// mailbox, so that they fail with [`MailboxError::ConnectionLost`].
+    fn connection_lost(&self) {
+        self.alive.store(false, Ordering::Release);
+        self.recv_wait.close();
+        self.send_wait.close();
+        self.submit_wait.close();
+        self.ordered_wait.close();
+    }
+
+    /// Returns the error for a wait which failed because its queue was
+    /// closed.
+    fn closed_error(&self) -> MailboxError {
+        if self.is_alive() {
+            MailboxError::Failed
+        } else {
+            MailboxError::ConnectionLost
+        }
+    }
+
+    /// Returns the number of messages from the kernel which were discarded
+    /// because their checksum didn't match their contents.
+    #[must_use]
+    pub fn checksum_errors(&self) -> usize {
+        self.checksum_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the mailbox's traffic counters.
+    ///
+    /// The counters are updated independently, so a snapshot taken while
+    /// messages are being sent or received may be slightly inconsistent.
+    #[must_use]
+    pub fn metrics(&self) -> MailboxMetrics {
+        MailboxMetrics {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            checksum_errors: self.checksum_errors.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            unhandled: self.unhandled.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Subscribe to unsolicited [`Notification`]s from the kernel.
+    ///
+    /// Only one subscription may exist at a time. Returns `None` if there is
+    /// already a subscriber. See the [`notification`] module for details.
+    ///
+    /// [`notification`]: crate::executor::notification
+    pub fn subscribe(&self) -> Option<Subscription<'_>> {
+        self.notifications.subscribe()
+    }
+
+    /// Returns the number of notifications which were dropped because no one
+    /// was subscribed, or the subscriber had fallen behind.
+    #[must_use]
+    pub fn dropped_notifications(&self) -> usize {
+        self.notifications.dropped()
+    }
+
+    /// Enable or disable ordered delivery for responses from `kind`.
+    ///
+    /// By default, responses are delivered as soon as they arrive, so the
+    /// responses to several requests to the same service may complete in any
+    /// order. When ordered delivery is enabled for a service, responses to
+    /// [`MailBox::request`]s to that service are delivered in the order the
+    /// requests were sent. A response which arrives before the response to
+    /// an earlier request is held until the earlier response is delivered.
+    ///
+    /// At most `ORDERED_MAX_PENDING` requests to an ordered service may be
+    /// outstanding at once; further requests wait for an earlier one to
+    /// complete before they are sent. At most `ORDERED_MAX_HELD` responses
+    /// are held per service. If a response arrives while the held buffer is
+    /// full, the mailbox stops waiting for the oldest outstanding response:
+    /// the held responses behind it are released in order, and that response
+    /// is delivered without ordering whenever it does arrive.
+    ///
+    /// Disabling ordered delivery releases any held responses immediately.
+    /// Returns an error if ordered delivery is already enabled for
+    /// `MAX_ORDERED_SERVICES` other services.
+    #[allow(clippy::result_unit_err)]
+    pub fn set_ordered(&self, kind: DriverKind, ordered: bool) -> Result<(), ()> {
+        let mut released = Released::new();
+        {
+            let mut services = self.ordered.lock();
+            if ordered {
+                if !services.contains_key(&kind) {
+                    let queue = OrderedQueue {
+                        pending: Deque::new(),
+                        held: LinearMap::new(),
+                    };
+                    services.insert(kind, queue).map_err(drop)?;
+                }
+                return Ok(());
+            }
+
+            if let Some(mut queue) = services.remove(&kind) {
+                queue.release_all(&mut released);
+            }
+        }
+
+        self.deliver_released(released);
+        self.ordered_wait.wake_all();
+        Ok(())
+    }
+
+    /// Dispatch the responses deferred by [`MailBox::poll`], most urgent
+    /// first, and in the order they arrived within each priority.
+    fn dispatch_deferred(&self, deferred: &mut Vec<(u32, Response), POLL_BATCH>) {
+        let mut low = Vec::<(u32, Response), POLL_BATCH>::new();
+        for (nonce, body) in core::mem::take(deferred) {
+            if Priority::of_nonce(nonce) == Priority::Normal {
+                self.dispatch(nonce, body);
+            } else {
+                // can't fail: `low` is as big as `deferred`.
+                let _ = low.push((nonce, body));
+            }
+        }
+        for (nonce, body) in low {
+            self.dispatch(nonce, body);
+        }
+    }
+
+    /// Route a response from the kernel to its waiter, holding it back first
+    /// if it belongs to an ordered service and arrived out of order.
+    fn dispatch(&self, nonce: u32, body: Response) {
+        let mut released = Released::new();
+        {
+            let mut services = self.ordered.lock();
+            let queue = services
+                .values_mut()
+                .find(|queue| queue.pending.iter().any(|&n| n == nonce));
+            match queue {
+                Some(queue) => queue.arrived(nonce, body, &mut released),
+                None => {
+                    drop(services);
+                    self.deliver(nonce, body);
+                    return;
+                }
+            }
+        }
+
+        if !released.is_empty() {
+            self.deliver_released(released);
+            self.ordered_wait.wake_all();
+        }
+    }
+
+    fn deliver_released(&self, released: Released) {
+        for (nonce, body) in released {
+            self.deliver(nonce, body);
+        }
+    }
+
+    fn deliver(&self, nonce: u32, body: Response) {
+        // Hold the lock on `submitted` while waking, so that a
+        // `PendingResponse` can't start waiting between a failed wake and its
+        // response being stored.
+        let mut submitted = self.submitted.lock();
+
+        // Attempt to wake a relevant waiting task, OR hold on to the response
+        // until its requester gets around to it.
+        if let WakeOutcome::NoMatch(body) = self.recv_wait.wake(&nonce, body) {
+            match submitted.get_mut(&nonce) {
+                Some(slot) => *slot = Some(body),
+                None => {
+                    drop(submitted);
+                    self.stash_early(nonce, body);
+                }
+            }
+        }
+    }
+
+    /// If `kind` has ordered delivery enabled, add `nonce` to the end of its
+    /// queue, waiting for room if the queue is full.
+    ///
+    /// Returns `true` if the nonce was queued.
+    async fn reserve_ordered(&self, kind: DriverKind, nonce: u32) -> Result<bool, ()> {
         loop {
-            if !self.inhibit_send.load(Ordering::Acquire) {
-                // TODO: Max Size
-                if let Ok(mut wgr) = rings.u2k.grant(128) {
-                    let used = postcard::to_slice(&outgoing, &mut wgr).map_err(drop)?.len();
-                    wgr.commit(used);
-                    break;
-                } else {
-                    // Inhibit further sending until there is room, in order to prevent
-                    // starving waiters
-                    self.inhibit_send.store(true, Ordering::Release);
+            {
+                let mut services = self.ordered.lock();
+                let Some(queue) = services.get_mut(&kind) else {
+                    return Ok(false);
+                };
+                if queue.pending.push_back(nonce).is_ok() {
+                    return Ok(true);
                 }
             }
-            self.send_wait.wait().await.map_err(drop)?;
+            self.ordered_wait.wait().await.map_err(drop)?;
+        }
+    }
+
+    /// Remove a nonce queued by [`MailBox::reserve_ordered`] whose request
+    /// was never sent, so that later responses aren't held waiting for it.
+    fn unreserve_ordered(&self, kind: DriverKind, nonce: u32) {
+        let mut released = Released::new();
+        {
+            let mut services = self.ordered.lock();
+            let Some(queue) = services.get_mut(&kind) else {
+                return;
+            };
+            queue.remove(nonce);
+            queue.release(&mut released);
+        }
+
+        self.deliver_released(released);
+        self.ordered_wait.wake_all();
+    }
+
+    /// Retain a response that arrived before anyone was waiting for it.
+    fn stash_early(&self, nonce: u32, body: Response) {
+        let next = self.nonce.load(Ordering::Acquire);
+        let mut early = self.early.lock();
+        if early.len() == early.capacity() {
+            // evict the response with the oldest nonce. nonces wrap, so
+            // "oldest" is the one furthest behind the next nonce to be issued,
+            // ignoring the priority bits.
+            let oldest = early
+                .keys()
+                .copied()
+                .max_by_key(|&n| next.wrapping_sub(n) & NONCE_COUNTER_MASK)
+                .expect("a full map is not empty");
+            early.remove(&oldest);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        // we just made room, so this can't fail.
+        let _ = early.insert(nonce, body);
+    }
+
+    /// Take the response for `nonce`, if it arrived before we started
+    /// waiting for it.
+    fn take_early(&self, nonce: u32) -> Option<Response> {
+        self.early.lock().remove(&nonce)
+    }
+
+    async fn send_inner(
+        &self,
+        nonce: u32,
+        msg: UserRequestBody,
+        priority: Priority,
+    ) -> Result<(), MailboxError> {
+        if !self.is_alive() {
+            return Err(MailboxError::ConnectionLost);
+        }
+        let (outgoing, len) = self.outgoing(nonce, msg)?;
+
+        // Wait for a successful send.
+        //
+        // A message is sent as soon as there's room for it in the ring, even
+        // if a larger message is still waiting for room, so small messages
+        // are never held up behind a large one. However, a message never
+        // passes a higher-priority message that's waiting for room.
+        if !self.higher_blocked(priority) && self.try_write(&outgoing, len)? {
+            return Ok(());
+        }
+
+        let mut blocked = None;
+        loop {
+            // Register with `send_wait` before checking for room again, so
+            // that if `poll` makes room between the check and the wait, its
+            // wakeup isn't missed.
+            let mut wait = pin!(self.send_wait.wait());
+            let _ = wait.as_mut().subscribe();
+
+            // Tell `poll` how much room we need before it's worth waking us.
+            self.min_blocked.fetch_min(len, Ordering::AcqRel);
+            blocked.get_or_insert_with(|| Blocked::new(self, priority));
+
+            if !self.higher_blocked(priority) && self.try_write(&outgoing, len)? {
+                break;
+            }
+
+            wait.await.map_err(|_| self.closed_error())?;
         }
 
         Ok(())
     }
 
+    /// Returns `true` if a sender with a higher priority than `priority` is
+    /// waiting for room in the `u2k` ring.
+    fn higher_blocked(&self, priority: Priority) -> bool {
+        self.blocked[priority as usize + 1..]
+            .iter()
+            .any(|blocked| blocked.load(Ordering::Acquire) > 0)
+    }
+
+    /// Build the message to send for `msg`, returning it with the length of
+    /// the frame it will be sent in.
+    fn outgoing(
+        &self,
+        nonce: u32,
+        msg: UserRequestBody,
+    ) -> Result<(UserRequest, usize), MailboxError> {
+        let outgoing = UserRequest {
+            header: UserRequestHeader { nonce },
+            body: msg,
+        };
+
+        let len =
+            postcard::serialize_with_flavor(&outgoing, postcard::ser_flavors::Size::default())
+                .map_err(|_| MailboxError::Failed)?;
+        if len > self.max_msg_size {
+            return Err(MailboxError::MessageTooLarge {
+                len,
+                max: self.max_msg_size,
+            });
+        }
+        Ok((outgoing, frame::frame_len(len)))
+    }
+
+    /// Write `outgoing` to the `u2k` ring if there's room for it, returning
+    /// `false` if there isn't.
+    fn try_write(&self, outgoing: &UserRequest, len: usize) -> Result<bool, MailboxError> {
+        let Ok(mut wgr) = self.rings.get().u2k.grant(len) else {
+            return Ok(false);
+        };
+        let used = postcard::to_slice(outgoing, &mut wgr[frame::HEADER_LEN..])
+            .map_err(|_| MailboxError::Failed)?
+            .len();
+        let len = frame::frame_len(used);
+        frame::seal(&mut wgr[..len]);
+        wgr.commit(len);
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Returns a fresh nonce for a request with the given `priority`.
+    ///
+    /// The top bits of [`NO_RESPONSE_NONCE`] don't match any priority, so it
+    /// is never returned.
+    fn next_nonce(&self, priority: Priority) -> u32 {
+        let count = self.nonce.fetch_add(1, Ordering::AcqRel) & NONCE_COUNTER_MASK;
+        count | ((priority as u32) << NONCE_COUNTER_BITS)
+    }
+
+    /// Set the counter that the next request's nonce is made from.
+    ///
+    /// Nonces are normally issued in sequence, starting from 0, so a test
+    /// would have to send a billion requests to see the counter wrap. This
+    /// lets it skip straight to the nonces it's interested in. The request's
+    /// [`Priority`] is still encoded in the top bits of its nonce, so only
+    /// the low `NONCE_COUNTER_BITS` bits of `count` are used.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_next_nonce(&self, count: u32) {
+        self.nonce
+            .store(count & NONCE_COUNTER_MASK, Ordering::Release);
+    }
+
     /// Send a message to the kernel without waiting for a response
-    pub async fn send(&'static self, msg: UserRequestBody) -> Result<(), ()> {
-        let nonce = self.nonce.fetch_add(1, Ordering::AcqRel);
-        self.send_inner(nonce, msg).await
+    ///
+    /// The kernel still responds to the message, and the response is held as
+    /// an early arrival until it is evicted (see [`MailBox::poll`]). For
+    /// messages whose response is never needed, prefer
+    /// [`MailBox::send_oneshot`].
+    pub async fn send(&self, msg: UserRequestBody) -> Result<(), MailboxError> {
+        let priority = Priority::of(&msg);
+        self.send_with_priority(msg, priority).await
+    }
+
+    /// Send a message to the kernel with the given [`Priority`], without
+    /// waiting for a response.
+    ///
+    /// See [`MailBox::send`].
+    pub async fn send_with_priority(
+        &self,
+        msg: UserRequestBody,
+        priority: Priority,
+    ) -> Result<(), MailboxError> {
+        self.handshake().await?;
+        let nonce = self.next_nonce(priority);
+        self.send_inner(nonce, msg, priority).await
+    }
+
+    /// Send a message to the kernel which expects no response.
+    ///
+    /// The message is sent with [`NO_RESPONSE_NONCE`], so the kernel doesn't
+    /// reply to it. This returns as soon as the message has been written to
+    /// the `u2k` ring, and uses no space in the early arrivals buffer.
+    pub async fn send_oneshot(&self, msg: UserRequestBody) -> Result<(), MailboxError> {
+        self.handshake().await?;
+        let priority = Priority::of(&msg);
+        self.send_inner(NO_RESPONSE_NONCE, msg, priority).await
     }
 
     /// Send a message to the kernel, waiting for a response
-    pub async fn request(&'static self, msg: UserRequestBody) -> Result<KernelResponseBody, ()> {
-        let nonce = self.nonce.fetch_add(1, Ordering::AcqRel);
+    ///
+    /// # Cancellation Safety
+    ///
+    /// This future is cancellation safe. The request's nonce is registered in
+    /// `recv_wait` by a [`wait_map::Wait`](maitake::sync::wait_map::Wait) future, which removes the nonce
+    /// from the map when it is dropped. If this future is dropped after the
+    /// request has been sent, but before the response arrives, the response
+    /// has no waiter when it eventually arrives, and [`MailBox::poll`] holds
+    /// it as an early arrival until it is evicted. No slot is leaked, and the
+    /// response can never be delivered to a different caller, since nonces
+    /// are never reused.
+    ///
+    /// # Backpressure
+    ///
+    /// The number of in-flight requests is not limited by the mailbox. Each
+    /// waiter's entry in `recv_wait` lives in its own future, rather than in
+    /// a fixed-size map, and [`MailBox::poll`] always drains the whole `k2u`
+    /// ring, so no number of outstanding requests can stop responses from
+    /// being received. Requests are only held back by a lack of room in the
+    /// `u2k` ring, or by an ordered service's queue (see
+    /// [`MailBox::set_ordered`]).
+    pub async fn request(&self, msg: UserRequestBody) -> Result<KernelResponseBody, MailboxError> {
+        let priority = Priority::of(&msg);
+        self.request_with_priority(msg, priority).await
+    }
+
+    /// Send a message to the kernel with the given [`Priority`], waiting for
+    /// a response.
+    ///
+    /// See [`MailBox::request`].
+    pub async fn request_with_priority(
+        &self,
+        msg: UserRequestBody,
+        priority: Priority,
+    ) -> Result<KernelResponseBody, MailboxError> {
+        self.handshake().await?;
+        self.request_inner(msg, priority).await
+    }
+
+    /// Returns the ABI version negotiated with the kernel, or `None` if the
+    /// [handshake](MailBox::handshake) hasn't succeeded yet.
+    #[must_use]
+    pub fn abi_version(&self) -> Option<u32> {
+        match self.abi_version.load(Ordering::Acquire) {
+            0 => None,
+            version => Some(version),
+        }
+    }
+
+    /// Negotiate the ABI version with the kernel, returning the negotiated
+    /// version.
+    ///
+    /// The first message sent by the mailbox performs the handshake
+    /// automatically, and fails if it does, so calling this is optional.
+    /// Calling it during startup reports a mismatched kernel immediately,
+    /// rather than at the first request.
+    ///
+    /// If the kernel doesn't support [`ABI_VERSION`], this returns
+    /// [`MailboxError::AbiMismatch`], and so does every later attempt to use
+    /// the mailbox. If the handshake fails for any other reason, it is
+    /// retried the next time the mailbox is used.
+    pub async fn handshake(&self) -> Result<u32, MailboxError> {
+        if let Some(version) = self.abi_version() {
+            return Ok(version);
+        }
+
+        let mut handshake = self.handshake.lock().await;
+        if let Some(res) = *handshake {
+            return res;
+        }
+        let res = self.hello(ABI_VERSION).await;
+        match res {
+            Ok(version) => {
+                self.abi_version.store(version, Ordering::Release);
+                *handshake = Some(res);
+            }
+            Err(MailboxError::AbiMismatch { kernel, .. }) => {
+                tracing::error!(
+                    ours = ABI_VERSION,
+                    kernel,
+                    "kernel does not support this program's syscall ABI version!"
+                );
+                *handshake = Some(res);
+            }
+            Err(_) => {}
+        }
+        res
+    }
+
+    /// Send a [`UserRequestBody::Hello`] with `abi_version`.
+    async fn hello(&self, abi_version: u32) -> Result<u32, MailboxError> {
+        let response = self
+            .request_inner(
+                UserRequestBody::Hello(HelloRequest { abi_version }),
+                Priority::High,
+            )
+            .await?;
+        match response {
+            KernelResponseBody::Hello(Ok(resp)) => Ok(resp.abi_version),
+            KernelResponseBody::Hello(Err(HelloError::AbiMismatch { supported })) => {
+                Err(MailboxError::AbiMismatch {
+                    ours: abi_version,
+                    kernel: supported,
+                })
+            }
+            _ => Err(MailboxError::Failed),
+        }
+    }
+
+    async fn request_inner(
+        &self,
+        msg: UserRequestBody,
+        priority: Priority,
+    ) -> Result<KernelResponseBody, MailboxError> {
+        let nonce = self.next_nonce(priority);
 
         // Start listening for the response BEFORE we send the request
-        let mut rx = core::pin::pin!(MAILBOX.recv_wait.wait(nonce));
-        rx.as_mut().enqueue().await.map_err(drop)?;
-        self.send_inner(nonce, msg).await?;
+        let mut rx = core::pin::pin!(self.recv_wait.wait(nonce));
+        rx.as_mut()
+            .enqueue()
+            .await
+            .map_err(|_| self.closed_error())?;
+        let _in_flight = InFlight::new(&self.in_flight);
+
+        self.send_request(nonce, msg).await?;
+
+        // If the response was delivered before we were registered, it's in
+        // the early arrivals buffer rather than `recv_wait`. Check for it
+        // before parking.
+        if let Some(response) = self.take_early(nonce) {
+            return response;
+        }
+
+        rx.await.map_err(|_| self.closed_error())?
+    }
+
+    /// Send a message to the kernel, returning a [`PendingResponse`] which
+    /// can be used to wait for the response later.
+    ///
+    /// This returns once the request has been sent, so a single task can
+    /// submit several requests before waiting for any of their responses,
+    /// and can then wait for them in any order. A response which arrives
+    /// before it is waited for is held by the mailbox until its
+    /// `PendingResponse` waits for it, or is dropped.
+    ///
+    /// At most `N` `PendingResponse`s may exist at once; if
+    /// there are already that many, this waits for one to be dropped before
+    /// sending the request.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// Dropping this future, or the returned `PendingResponse`, has the same
+    /// effect as dropping a [`MailBox::request`] future: the response is
+    /// discarded when it arrives.
+    pub async fn submit(
+        &self,
+        msg: UserRequestBody,
+    ) -> Result<PendingResponse<'_, N>, MailboxError> {
+        self.handshake().await?;
+        let nonce = self.next_nonce(Priority::of(&msg));
+
+        // Reserve a slot for the response BEFORE we send the request.
+        loop {
+            if self.submitted.lock().insert(nonce, None).is_ok() {
+                break;
+            }
+            self.submit_wait
+                .wait()
+                .await
+                .map_err(|_| self.closed_error())?;
+        }
+        let pending = PendingResponse {
+            mailbox: self,
+            nonce,
+            _in_flight: InFlight::new(&self.in_flight),
+        };
+
+        self.send_request(nonce, msg).await?;
+        Ok(pending)
+    }
+
+    /// Send a request with a registered nonce, queueing it first if its
+    /// service has ordered delivery enabled.
+    async fn send_request(&self, nonce: u32, msg: UserRequestBody) -> Result<(), MailboxError> {
+        let kind = msg.driver_kind();
+        let ordered = self
+            .reserve_ordered(kind, nonce)
+            .await
+            .map_err(|_| self.closed_error())?;
+        if ordered {
+            // If we don't get as far as sending the request, don't leave the
+            // service's later responses waiting on it.
+            let guard = Unreserve {
+                mailbox: self,
+                kind,
+                nonce,
+            };
+            let sent = self.send_inner(nonce, msg, Priority::of_nonce(nonce)).await;
+            if sent.is_ok() {
+                core::mem::forget(guard);
+            }
+            sent
+        } else {
+            self.send_inner(nonce, msg, Priority::of_nonce(nonce)).await
+        }
+    }
+
+    /// Send a message to the kernel, waiting up to `timeout` for a response.
+    ///
+    /// If no response arrives in time, this returns
+    /// [`MailboxError::Timeout`]. The request's nonce is unregistered when
+    /// this returns, so a response that arrives later is never delivered to
+    /// another caller.
+    pub async fn request_timeout(
+        &self,
+        msg: UserRequestBody,
+        timeout: Duration,
+    ) -> Result<KernelResponseBody, MailboxError> {
+        let request = pin!(self.request(msg));
+        let alarm = pin!(Alarm::after(timeout));
+        match select(request, alarm).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), _)) => Err(MailboxError::Timeout),
+        }
+    }
+
+    /// Sleep for `duration`, by asking the kernel to respond once it has
+    /// elapsed.
+    ///
+    /// Unlike an [`Alarm`], this doesn't depend on the executor's own timer,
+    /// so it can be used before the timer is running.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// If this future is dropped while the kernel is sleeping, it sends a
+    /// [`UserRequestBody::CancelSleep`] request, so that the kernel disarms
+    /// its timer rather than keeping it until the sleep would have ended. If
+    /// there's no room in the `u2k` ring for the cancellation, the kernel's
+    /// sleep runs to completion, and its response is discarded as usual.
+    pub async fn sleep(&self, duration: Duration) -> Result<(), MailboxError> {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let pending = self
+            .submit(UserRequestBody::Sleep(SleepRequest { micros }))
+            .await?;
+        let cancel = CancelSleep {
+            mailbox: self,
+            nonce: pending.nonce(),
+        };
+        let response = pending.await_response().await;
+        core::mem::forget(cancel);
+
+        match response? {
+            KernelResponseBody::Sleep(Ok(())) => Ok(()),
+            _ => Err(MailboxError::Failed),
+        }
+    }
+
+    /// Write `bytes` to the kernel's console, returning once the kernel has
+    /// written all of them.
+    ///
+    /// Each request carries at most
+    /// [`CONSOLE_WRITE_MAX`](abi::syscall::console::CONSOLE_WRITE_MAX) bytes,
+    /// so longer writes are split across several requests. The kernel only
+    /// responds to each one once its sinks have room for it, so this also
+    /// waits out any backpressure from a slow serial port.
+    pub async fn console_write(&self, mut bytes: &[u8]) -> Result<(), MailboxError> {
+        while !bytes.is_empty() {
+            let (req, _) = ConsoleWriteRequest::new(bytes);
+            match self.request(UserRequestBody::ConsoleWrite(req)).await? {
+                KernelResponseBody::ConsoleWrite(Ok(resp)) if resp.written > 0 => {
+                    let written = (resp.written as usize).min(bytes.len());
+                    bytes = &bytes[written..];
+                }
+                _ => return Err(MailboxError::Failed),
+            }
+        }
+        Ok(())
+    }
+}
 
-        rx.await.map_err(drop)
+impl<const N: usize> Default for MailBox<N> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+impl OrderedQueue {
+    /// Record the arrival of the response to `nonce`, and release any
+    /// responses that are now in order.
+    fn arrived(&mut self, nonce: u32, body: Response, released: &mut Released) {
+        let mut arrival = (nonce, body);
+        loop {
+            if self.pending.front() == Some(&arrival.0) {
+                self.pending.pop_front();
+                // can't fail: at most one response is released per pending nonce.
+                let _ = released.push(arrival);
+                self.release(released);
+                return;
+            }
+
+            match self.held.insert(arrival.0, arrival.1) {
+                Ok(_) => {
+                    self.release(released);
+                    return;
+                }
+                // The held buffer is full. Stop waiting for the oldest
+                // outstanding response, and try again.
+                Err(rejected) => {
+                    self.pending.pop_front();
+                    self.release(released);
+                    arrival = rejected;
+                }
+            }
+        }
+    }
+
+    /// Release held responses from the front of the queue, until we reach a
+    /// request whose response has not arrived yet.
+    fn release(&mut self, released: &mut Released) {
+        while let Some(&front) = self.pending.front() {
+            let Some(body) = self.held.remove(&front) else {
+                return;
+            };
+            self.pending.pop_front();
+            let _ = released.push((front, body));
+        }
+    }
+
+    /// Release all held responses, in order, without waiting for any that
+    /// have not arrived yet.
+    fn release_all(&mut self, released: &mut Released) {
+        while let Some(front) = self.pending.pop_front() {
+            if let Some(body) = self.held.remove(&front) {
+                let _ = released.push((front, body));
+            }
+        }
+    }
+
+    fn remove(&mut self, nonce: u32) {
+        for _ in 0..self.pending.len() {
+            if let Some(n) = self.pending.pop_front() {
+                if n != nonce {
+                    let _ = self.pending.push_back(n);
+                }
+            }
+        }
+    }
+}
+
+/// A request sent with [`MailBox::submit`], whose response has not been
+/// received yet.
+///
+/// Dropping a `PendingResponse` discards the response.
+#[must_use = "the response is discarded if a `PendingResponse` is dropped"]
+pub struct PendingResponse<'mailbox, const N: usize = DEFAULT_MAX_SUBMITTED> {
+    mailbox: &'mailbox MailBox<N>,
+    nonce: u32,
+    _in_flight: InFlight<'mailbox>,
+}
+
+impl<const N: usize> PendingResponse<'_, N> {
+    /// Returns the nonce of the request.
+    #[inline]
+    #[must_use]
+    pub fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    /// Wait for the response to the request.
+    pub async fn await_response(self) -> Result<KernelResponseBody, MailboxError> {
+        let mailbox = self.mailbox;
+        let mut rx = pin!(mailbox.recv_wait.wait(self.nonce));
+        rx.as_mut()
+            .enqueue()
+            .await
+            .map_err(|_| mailbox.closed_error())?;
+
+        // If the response arrived before we started waiting, it's in our
+        // slot. `deliver` holds this lock while waking, so if it isn't there,
+        // it will be delivered to `rx`.
+        let early = mailbox
+            .submitted
+            .lock()
+            .get_mut(&self.nonce)
+            .and_then(Option::take);
+        if let Some(response) = early {
+            return response;
+        }
+
+        rx.await.map_err(|_| mailbox.closed_error())?
+    }
+}
+
+impl<const N: usize> Drop for PendingResponse<'_, N> {
+    fn drop(&mut self) {
+        self.mailbox.submitted.lock().remove(&self.nonce);
+        self.mailbox.submit_wait.wake();
+    }
+}
+
+// === impl Priority ===
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    /// Returns the default priority for `msg`.
+    ///
+    /// Timer and handshake requests are [`Priority::High`], serial port
+    /// traffic and console output are [`Priority::Low`], and everything else
+    /// is [`Priority::Normal`].
+    #[must_use]
+    pub fn of(msg: &UserRequestBody) -> Self {
+        match msg {
+            UserRequestBody::Sleep(_)
+            | UserRequestBody::CancelSleep(_)
+            | UserRequestBody::Hello(_)
+            | UserRequestBody::Ping(_) => Self::High,
+            UserRequestBody::Serial(_) | UserRequestBody::ConsoleWrite(_) => Self::Low,
+            _ => Self::Normal,
+        }
+    }
+
+    /// Returns the priority encoded in a nonce returned by
+    /// [`MailBox::next_nonce`].
+    fn of_nonce(nonce: u32) -> Self {
+        match nonce >> NONCE_COUNTER_BITS {
+            2 => Self::High,
+            1 => Self::Normal,
+            _ => Self::Low,
+        }
+    }
+}
+
+/// Counts a sender in [`MailBox::blocked`] while it waits for room in the
+/// `u2k` ring.
+struct Blocked<'mailbox, const N: usize> {
+    mailbox: &'mailbox MailBox<N>,
+    priority: Priority,
+}
+
+impl<'mailbox, const N: usize> Blocked<'mailbox, N> {
+    fn new(mailbox: &'mailbox MailBox<N>, priority: Priority) -> Self {
+        mailbox.blocked[priority as usize].fetch_add(1, Ordering::AcqRel);
+        Self { mailbox, priority }
+    }
+}
+
+impl<const N: usize> Drop for Blocked<'_, N> {
+    fn drop(&mut self) {
+        let blocked = &self.mailbox.blocked[self.priority as usize];
+        // if this was the last sender blocked at this priority, lower
+        // priority senders may have been waiting for it to go first.
+        if blocked.fetch_sub(1, Ordering::AcqRel) == 1 && self.priority != Priority::Low {
+            self.mailbox.send_wait.wake_all();
+        }
+    }
+}
+
+/// Counts a request in [`MailboxMetrics::in_flight`] until it is dropped.
+struct InFlight<'mailbox>(&'mailbox AtomicUsize);
+
+impl<'mailbox> InFlight<'mailbox> {
+    fn new(in_flight: &'mailbox AtomicUsize) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Unqueues an ordered request's nonce if it is dropped before the request is
+/// sent.
+struct Unreserve<'mailbox, const N: usize> {
+    mailbox: &'mailbox MailBox<N>,
+    kind: DriverKind,
+    nonce: u32,
+}
+
+impl<const N: usize> Drop for Unreserve<'_, N> {
+    fn drop(&mut self) {
+        self.mailbox.unreserve_ordered(self.kind, self.nonce);
+    }
+}
+
+/// Cancels the kernel's side of a [`MailBox::sleep`] if it is dropped before
+/// the sleep completes.
+struct CancelSleep<'mailbox, const N: usize> {
+    mailbox: &'mailbox MailBox<N>,
+    nonce: u32,
+}
+
+impl<const N: usize> Drop for CancelSleep<'_, N> {
+    fn drop(&mut self) {
+        let msg = UserRequestBody::CancelSleep(CancelSleepRequest { nonce: self.nonce });
+        // `drop` can't wait for room in the ring, so this is best effort.
+        if let Ok((outgoing, len)) = self.mailbox.outgoing(NO_RESPONSE_NONCE, msg) {
+            let _ = self.mailbox.try_write(&outgoing, len);
+        }
+    }
+}
+
+/// Read the nonce of a `KernelMsg::Response` which could not be fully decoded.
+///
+/// Returns `None` if the message isn't a response, or is too mangled to tell.
+fn peek_nonce(bytes: &[u8]) -> Option<u32> {
+    // `KernelMsg::Response` is the third variant, and its header (containing
+    // just the nonce) is serialized first.
+    const RESPONSE_VARIANT: u32 = 2;
+
+    let (variant, rest) = postcard::take_from_bytes::<u32>(bytes).ok()?;
+    if variant != RESPONSE_VARIANT {
+        return None;
+    }
+    let (nonce, _) = postcard::take_from_bytes::<u32>(rest).ok()?;
+    Some(nonce)
+}
+
 unsafe impl Sync for OnceRings {}
 
 struct OnceRings {
@@ -184,4 +1523,930 @@ pub struct Rings {
     pub k2u: FrameConsumer<'static>,
 }
 
-// impl Ma
+/// The kernel's ends of a pair of [`Rings`] returned by
+/// [`Rings::in_memory`], for tests which play the kernel.
+///
+/// See the `round_trip` test in this module for an example.
+#[cfg(any(test, feature = "test-util"))]
+pub struct KernelRings {
+    pub u2k: FrameConsumer<'static>,
+    pub k2u: FrameProducer<'static>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl KernelRings {
+    /// Returns the next request written to the `u2k` ring, or `None` if the
+    /// ring is empty.
+    ///
+    /// # Panics
+    ///
+    /// If the request's frame is corrupt, or it can't be decoded.
+    pub fn recv(&self) -> Option<UserRequest> {
+        let msg = self.u2k.read()?;
+        let payload = frame::open(&msg).expect("request frame must be valid");
+        let req = postcard::from_bytes(payload).expect("request must decode");
+        msg.release();
+        Some(req)
+    }
+
+    /// Write `msg` to the `k2u` ring, sealed as the kernel would.
+    ///
+    /// This doesn't call [`MailBox::poll`], so a test can write several
+    /// messages before they are read.
+    ///
+    /// # Panics
+    ///
+    /// If there's no room for `msg` in the ring.
+    pub fn send(&self, msg: &KernelMsg) {
+        let len = postcard::serialize_with_flavor(msg, postcard::ser_flavors::Size::default())
+            .expect("message must serialize");
+        let mut wgr = self
+            .k2u
+            .grant(frame::frame_len(len))
+            .expect("no room in the k2u ring");
+        postcard::to_slice(msg, &mut wgr[frame::HEADER_LEN..]).expect("message must serialize");
+        frame::seal(&mut wgr[..frame::frame_len(len)]);
+        wgr.commit(frame::frame_len(len));
+    }
+
+    /// Respond to the request with `nonce`.
+    pub fn respond(&self, nonce: u32, body: KernelResponseBody) {
+        self.send(&KernelMsg::Response(KernelResponse {
+            header: KernelResponseHeader { nonce },
+            body,
+        }));
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Rings {
+    /// Returns rings backed by `u2k_buf` and `k2u_buf`, along with the ends
+    /// the kernel would normally hold, so that a test can play the kernel.
+    ///
+    /// The buffers must be large enough to hold a frame of the mailbox's
+    /// maximum message size.
+    pub fn in_memory(
+        u2k: &'static mut BBBuffer,
+        u2k_buf: &'static mut [u8],
+        k2u: &'static mut BBBuffer,
+        k2u_buf: &'static mut [u8],
+    ) -> (Self, KernelRings) {
+        // Safety: we have the only references to the buffers and their
+        // storage, and each ring has one producer and one consumer.
+        unsafe {
+            u2k.initialize(u2k_buf.as_mut_ptr(), u2k_buf.len());
+            k2u.initialize(k2u_buf.as_mut_ptr(), k2u_buf.len());
+            let u2k: *mut BBBuffer = u2k;
+            let k2u: *mut BBBuffer = k2u;
+            let rings = Self {
+                u2k: BBBuffer::take_framed_producer(u2k),
+                k2u: BBBuffer::take_framed_consumer(k2u),
+            };
+            let kernel = KernelRings {
+                u2k: BBBuffer::take_framed_consumer(u2k),
+                k2u: BBBuffer::take_framed_producer(k2u),
+            };
+            (rings, kernel)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::time::{CHRONOS, CURRENT_TIME};
+    use abi::syscall::hello::HelloResponse;
+    use core::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use futures::task::noop_waker_ref;
+    use std::sync::{Mutex as StdMutex, MutexGuard, PoisonError};
+
+    /// The size of each in-memory ring.
+    const RING_LEN: usize = 1024;
+
+    /// Returns a pair of in-memory rings, and the kernel's ends of them.
+    fn rings() -> (Rings, KernelRings) {
+        fn ring() -> (&'static mut BBBuffer, &'static mut [u8]) {
+            (
+                Box::leak(Box::new(BBBuffer::new())),
+                Box::leak(std::vec![0; RING_LEN].into_boxed_slice()),
+            )
+        }
+        let (u2k, u2k_buf) = ring();
+        let (k2u, k2u_buf) = ring();
+        Rings::in_memory(u2k, u2k_buf, k2u, k2u_buf)
+    }
+
+    /// Returns a mailbox using in-memory rings, and the kernel's ends of them.
+    fn mailbox<const N: usize>() -> (&'static MailBox<N>, KernelRings) {
+        let (rings, kernel) = rings();
+        (Box::leak(Box::new(MailBox::with_rings(rings))), kernel)
+    }
+
+    /// Returns a mailbox which has completed the ABI handshake.
+    fn connected<const N: usize>() -> (&'static MailBox<N>, KernelRings) {
+        let (mailbox, kernel) = mailbox::<N>();
+        complete_handshake(mailbox, &kernel);
+        (mailbox, kernel)
+    }
+
+    /// Perform the ABI handshake for `mailbox`, playing the kernel.
+    fn complete_handshake<const N: usize>(mailbox: &MailBox<N>, kernel: &KernelRings) {
+        let mut handshake = Box::pin(mailbox.handshake());
+        assert!(poll_once(handshake.as_mut()).is_pending());
+        let hello = kernel.recv().expect("the handshake should be sent");
+        assert!(matches!(hello.body, UserRequestBody::Hello(_)));
+        kernel.respond(
+            hello.header.nonce,
+            KernelResponseBody::Hello(Ok(HelloResponse {
+                abi_version: ABI_VERSION,
+            })),
+        );
+        mailbox.poll();
+        assert_eq!(poll_once(handshake.as_mut()), Poll::Ready(Ok(ABI_VERSION)));
+    }
+
+    /// Poll `fut` once, with a waker that does nothing.
+    fn poll_once<F: Future + ?Sized>(fut: Pin<&mut F>) -> Poll<F::Output> {
+        fut.poll(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    /// Take exclusive use of the executor's clock, which is global, for the
+    /// rest of a test.
+    fn clock() -> MutexGuard<'static, ()> {
+        static CLOCK: StdMutex<()> = StdMutex::new(());
+        CLOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Move the executor's clock forward by `by`, waking any expired alarms.
+    fn advance(by: Duration) {
+        *CURRENT_TIME.borrow_mut().unwrap() += u64::try_from(by.as_micros()).unwrap();
+        CHRONOS.poll();
+    }
+
+    /// Write a frame holding `payload` to the `k2u` ring, corrupting its
+    /// checksum if `corrupt` is set.
+    fn send_raw(kernel: &KernelRings, payload: &[u8], corrupt: bool) {
+        let len = frame::frame_len(payload.len());
+        let mut wgr = kernel.k2u.grant(len).expect("no room in the k2u ring");
+        wgr[frame::HEADER_LEN..len].copy_from_slice(payload);
+        frame::seal(&mut wgr[..len]);
+        if corrupt {
+            wgr[len - 1] ^= 0xff;
+        }
+        wgr.commit(len);
+    }
+
+    #[test]
+    fn metrics() {
+        let (mailbox, kernel) = connected::<4>();
+        // the handshake's request and response.
+        assert_eq!(
+            mailbox.metrics(),
+            MailboxMetrics {
+                sent: 1,
+                received: 1,
+                ..Default::default()
+            }
+        );
+
+        let mut first = Box::pin(mailbox.request(UserRequestBody::Ping(1)));
+        let mut second = Box::pin(mailbox.request(UserRequestBody::Ping(2)));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(poll_once(second.as_mut()).is_pending());
+        let metrics = mailbox.metrics();
+        assert_eq!(metrics.sent, 3);
+        assert_eq!(metrics.in_flight, 2);
+
+        let first_req = kernel.recv().expect("the first request should be sent");
+        let second_req = kernel.recv().expect("the second request should be sent");
+        kernel.respond(first_req.header.nonce, KernelResponseBody::Pong(1));
+        send_raw(&kernel, &[0xff; 4], false);
+        send_raw(&kernel, b"corrupted", true);
+        kernel.send(&KernelMsg::Dealloc(abi::syscall::ByteBoxWire {
+            ptr: 0,
+            len: 0,
+        }));
+        mailbox.poll();
+        assert!(matches!(
+            poll_once(first.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+        drop(first);
+
+        let metrics = mailbox.metrics();
+        assert_eq!(metrics.received, 2);
+        assert_eq!(metrics.decode_errors, 1);
+        assert_eq!(metrics.checksum_errors, 1);
+        assert_eq!(metrics.unhandled, 1);
+        assert_eq!(metrics.in_flight, 1);
+
+        // once its requester is gone, the second response is an early arrival,
+        // as are the responses to requests sent without waiting. the early
+        // arrivals buffer holds `EARLY_CAPACITY` of them.
+        drop(second);
+        assert_eq!(mailbox.metrics().in_flight, 0);
+        kernel.respond(second_req.header.nonce, KernelResponseBody::Pong(2));
+        for seq in 0..EARLY_CAPACITY as u32 {
+            let mut send = Box::pin(mailbox.send(UserRequestBody::Ping(seq)));
+            assert_eq!(poll_once(send.as_mut()), Poll::Ready(Ok(())));
+            let req = kernel.recv().expect("the request should be sent");
+            kernel.respond(req.header.nonce, KernelResponseBody::Pong(seq));
+        }
+        mailbox.poll();
+
+        assert_eq!(
+            mailbox.metrics(),
+            MailboxMetrics {
+                sent: 3 + EARLY_CAPACITY,
+                received: 3 + EARLY_CAPACITY,
+                decode_errors: 1,
+                checksum_errors: 1,
+                dropped: 1,
+                unhandled: 1,
+                in_flight: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn request_timeout() {
+        let _clock = clock();
+        let (mailbox, kernel) = connected::<4>();
+
+        let mut req =
+            Box::pin(mailbox.request_timeout(UserRequestBody::Ping(1), Duration::from_millis(10)));
+        assert!(poll_once(req.as_mut()).is_pending());
+        let sent = kernel.recv().expect("the request should be sent");
+
+        advance(Duration::from_millis(10));
+        assert!(matches!(
+            poll_once(req.as_mut()),
+            Poll::Ready(Err(MailboxError::Timeout))
+        ));
+        drop(req);
+        assert_eq!(mailbox.metrics().in_flight, 0);
+
+        // the timed out request's response arrives late, and isn't delivered
+        // to the next request.
+        let mut next = Box::pin(mailbox.request(UserRequestBody::Ping(2)));
+        assert!(poll_once(next.as_mut()).is_pending());
+        let next_sent = kernel.recv().expect("the next request should be sent");
+        kernel.respond(sent.header.nonce, KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert!(poll_once(next.as_mut()).is_pending());
+
+        kernel.respond(next_sent.header.nonce, KernelResponseBody::Pong(2));
+        mailbox.poll();
+        assert!(matches!(
+            poll_once(next.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(2)))
+        ));
+    }
+
+    #[test]
+    fn ordered_delivery() {
+        let (mailbox, kernel) = connected::<4>();
+        // pings are handled by the kernel's `Hello` driver.
+        mailbox
+            .set_ordered(DriverKind::Hello, true)
+            .expect("ordered delivery should be enabled");
+
+        // two requests answered out of order, one left unanswered, enough
+        // behind it to fill the held buffer, and one more to overflow it.
+        const REQUESTS: u32 = ORDERED_MAX_HELD as u32 + 4;
+        let mut requests = (0..REQUESTS)
+            .map(|seq| Box::pin(mailbox.request(UserRequestBody::Ping(seq))))
+            .collect::<std::vec::Vec<_>>();
+        let mut nonces = std::vec::Vec::new();
+        for req in &mut requests {
+            assert!(poll_once(req.as_mut()).is_pending());
+            nonces.push(
+                kernel
+                    .recv()
+                    .expect("the request should be sent")
+                    .header
+                    .nonce,
+            );
+        }
+
+        // the second response arrives first, and is held until the first one
+        // arrives.
+        kernel.respond(nonces[1], KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert!(poll_once(requests[1].as_mut()).is_pending());
+        kernel.respond(nonces[0], KernelResponseBody::Pong(0));
+        mailbox.poll();
+        assert!(matches!(
+            poll_once(requests[0].as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(0)))
+        ));
+        assert!(matches!(
+            poll_once(requests[1].as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+
+        // with the third response outstanding, fill the held buffer with the
+        // responses behind it.
+        for seq in 3..REQUESTS - 1 {
+            kernel.respond(nonces[seq as usize], KernelResponseBody::Pong(seq));
+        }
+        mailbox.poll();
+        for req in &mut requests[3..] {
+            assert!(poll_once(req.as_mut()).is_pending());
+        }
+
+        // when one more arrives, the mailbox stops waiting for the third
+        // response, and releases everything behind it in order.
+        let last = REQUESTS - 1;
+        kernel.respond(nonces[last as usize], KernelResponseBody::Pong(last));
+        mailbox.poll();
+        for seq in 3..REQUESTS {
+            assert!(matches!(
+                poll_once(requests[seq as usize].as_mut()),
+                Poll::Ready(Ok(KernelResponseBody::Pong(n))) if n == seq
+            ));
+        }
+
+        // the third response is delivered unordered whenever it arrives.
+        assert!(poll_once(requests[2].as_mut()).is_pending());
+        kernel.respond(nonces[2], KernelResponseBody::Pong(2));
+        mailbox.poll();
+        assert!(matches!(
+            poll_once(requests[2].as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(2)))
+        ));
+    }
+
+    /// Send requests from several threads while `poll` runs on another, as
+    /// if they were on different cores, and check that every response
+    /// reaches its requester.
+    #[test]
+    fn concurrent_requests() {
+        const SENDERS: u32 = 4;
+        const REQUESTS: u32 = 250;
+
+        let (mailbox, kernel) = connected::<4>();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            // the kernel answers each ping with the same sequence number.
+            scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    match kernel.recv() {
+                        Some(UserRequest {
+                            header,
+                            body: UserRequestBody::Ping(seq),
+                        }) => kernel.respond(header.nonce, KernelResponseBody::Pong(seq)),
+                        Some(req) => panic!("unexpected request: {req:?}"),
+                        None => std::thread::yield_now(),
+                    }
+                }
+            });
+            scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    mailbox.poll();
+                    std::thread::yield_now();
+                }
+            });
+
+            let senders = (0..SENDERS)
+                .map(|sender| {
+                    scope.spawn(move || {
+                        for i in 0..REQUESTS {
+                            let seq = sender * REQUESTS + i;
+                            let response = futures::executor::block_on(
+                                mailbox.request(UserRequestBody::Ping(seq)),
+                            );
+                            assert!(
+                                matches!(response, Ok(KernelResponseBody::Pong(n)) if n == seq),
+                                "ping {seq} got {response:?}"
+                            );
+                        }
+                    })
+                })
+                .collect::<std::vec::Vec<_>>();
+            let results = senders
+                .into_iter()
+                .map(|sender| sender.join())
+                .collect::<std::vec::Vec<_>>();
+            done.store(true, Ordering::Release);
+            for result in results {
+                result.expect("sender thread panicked");
+            }
+        });
+
+        let metrics = mailbox.metrics();
+        assert_eq!(metrics.sent, 1 + (SENDERS * REQUESTS) as usize);
+        assert_eq!(metrics.received, metrics.sent);
+        assert_eq!(metrics.dropped, 0);
+        assert_eq!(metrics.in_flight, 0);
+    }
+
+    #[test]
+    fn sleep() {
+        let (mailbox, kernel) = connected::<4>();
+        let mut sleep = Box::pin(mailbox.sleep(Duration::from_millis(10)));
+        assert!(poll_once(sleep.as_mut()).is_pending());
+        let req = kernel.recv().expect("the sleep request should be sent");
+        assert!(matches!(
+            req.body,
+            UserRequestBody::Sleep(SleepRequest { micros: 10_000 })
+        ));
+
+        // the sleep only ends when the kernel says so.
+        mailbox.poll();
+        assert!(poll_once(sleep.as_mut()).is_pending());
+        kernel.respond(req.header.nonce, KernelResponseBody::Sleep(Ok(())));
+        mailbox.poll();
+        assert_eq!(poll_once(sleep.as_mut()), Poll::Ready(Ok(())));
+        drop(sleep);
+        assert!(kernel.recv().is_none(), "a finished sleep isn't cancelled");
+    }
+
+    #[test]
+    fn sleep_cancelled() {
+        let (mailbox, kernel) = connected::<4>();
+        let mut sleep = Box::pin(mailbox.sleep(Duration::from_millis(10)));
+        assert!(poll_once(sleep.as_mut()).is_pending());
+        let req = kernel.recv().expect("the sleep request should be sent");
+        drop(sleep);
+
+        let cancel = kernel.recv().expect("the cancellation should be sent");
+        assert_eq!(cancel.header.nonce, NO_RESPONSE_NONCE);
+        assert!(matches!(
+            cancel.body,
+            UserRequestBody::CancelSleep(CancelSleepRequest { nonce }) if nonce == req.header.nonce
+        ));
+        assert_eq!(mailbox.metrics().in_flight, 0);
+    }
+
+    #[test]
+    fn abi_mismatch() {
+        let (mailbox, kernel) = mailbox::<4>();
+        let mismatch = MailboxError::AbiMismatch {
+            ours: ABI_VERSION,
+            kernel: ABI_VERSION + 1,
+        };
+
+        // the first request waits for the handshake, which the kernel rejects.
+        let mut ping = Box::pin(mailbox.request(UserRequestBody::Ping(1)));
+        assert!(poll_once(ping.as_mut()).is_pending());
+        let hello = kernel.recv().expect("the handshake should be sent");
+        assert!(matches!(
+            hello.body,
+            UserRequestBody::Hello(HelloRequest {
+                abi_version: ABI_VERSION
+            })
+        ));
+        kernel.respond(
+            hello.header.nonce,
+            KernelResponseBody::Hello(Err(HelloError::AbiMismatch {
+                supported: ABI_VERSION + 1,
+            })),
+        );
+        mailbox.poll();
+        assert!(matches!(poll_once(ping.as_mut()), Poll::Ready(Err(ref e)) if *e == mismatch));
+        assert_eq!(mailbox.abi_version(), None);
+        assert!(kernel.recv().is_none(), "the request isn't sent");
+
+        // and the mismatch sticks, without asking the kernel again.
+        let mut send = Box::pin(mailbox.send(UserRequestBody::Ping(2)));
+        assert_eq!(poll_once(send.as_mut()), Poll::Ready(Err(mismatch)));
+        let mut handshake = Box::pin(mailbox.handshake());
+        assert_eq!(poll_once(handshake.as_mut()), Poll::Ready(Err(mismatch)));
+        assert!(kernel.recv().is_none());
+    }
+
+    #[test]
+    fn submit_backpressure() {
+        let (mailbox, kernel) = connected::<1>();
+        let mut submit = Box::pin(mailbox.submit(UserRequestBody::Ping(1)));
+        let Poll::Ready(Ok(first)) = poll_once(submit.as_mut()) else {
+            panic!("the first request should be submitted");
+        };
+        assert!(kernel.recv().is_some());
+
+        // the only slot is taken, so the second request waits for it.
+        let mut submit = Box::pin(mailbox.submit(UserRequestBody::Ping(2)));
+        assert!(poll_once(submit.as_mut()).is_pending());
+        assert!(poll_once(submit.as_mut()).is_pending());
+        assert!(kernel.recv().is_none(), "the request isn't sent yet");
+        assert_eq!(mailbox.metrics().in_flight, 1);
+
+        drop(first);
+        let Poll::Ready(Ok(second)) = poll_once(submit.as_mut()) else {
+            panic!("the second request should be submitted");
+        };
+        let req = kernel.recv().expect("the second request should be sent");
+        assert!(matches!(req.body, UserRequestBody::Ping(2)));
+        assert_eq!(mailbox.metrics().in_flight, 1);
+
+        kernel.respond(req.header.nonce, KernelResponseBody::Pong(2));
+        mailbox.poll();
+        let mut response = Box::pin(second.await_response());
+        assert!(matches!(
+            poll_once(response.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(2)))
+        ));
+    }
+
+    #[test]
+    fn send_backpressure() {
+        let (mailbox, kernel) = connected::<4>();
+        let send = |msg| Box::pin(mailbox.send_with_priority(msg, Priority::Normal));
+
+        // fill the `u2k` ring with small messages, until one has to wait.
+        let mut sent = 0;
+        loop {
+            let mut ping = send(UserRequestBody::Ping(0));
+            if poll_once(ping.as_mut()).is_pending() {
+                break;
+            }
+            sent += 1;
+        }
+
+        // a large message waits for room...
+        let (write, _) =
+            ConsoleWriteRequest::new(&[b'x'; abi::syscall::console::CONSOLE_WRITE_MAX]);
+        let mut large = send(UserRequestBody::ConsoleWrite(write));
+        assert!(poll_once(large.as_mut()).is_pending());
+
+        // ...but once the kernel has read one small message, another one fits,
+        // and isn't held up behind the large one, which still doesn't fit.
+        assert!(kernel.recv().is_some());
+        let mut small = send(UserRequestBody::Ping(1));
+        assert_eq!(poll_once(small.as_mut()), Poll::Ready(Ok(())));
+        assert!(poll_once(large.as_mut()).is_pending());
+
+        for _ in 1..sent {
+            let req = kernel.recv().expect("the small messages should be sent");
+            assert!(matches!(req.body, UserRequestBody::Ping(0)));
+        }
+        let req = kernel
+            .recv()
+            .expect("the last small message should be sent");
+        assert!(matches!(req.body, UserRequestBody::Ping(1)));
+        assert!(kernel.recv().is_none());
+
+        // once the ring has been drained, the large message is sent too.
+        mailbox.poll();
+        assert_eq!(poll_once(large.as_mut()), Poll::Ready(Ok(())));
+        let req = kernel.recv().expect("the large message should be sent");
+        assert!(matches!(req.body, UserRequestBody::ConsoleWrite(_)));
+    }
+
+    #[test]
+    fn nonce_wraparound() {
+        let (mailbox, kernel) = connected::<4>();
+        let high = (Priority::High as u32) << NONCE_COUNTER_BITS;
+
+        // the last nonce before the counter wraps, and the first one after.
+        mailbox.set_next_nonce(u32::MAX);
+        let submit = |seq| {
+            let mut submit = Box::pin(mailbox.submit(UserRequestBody::Ping(seq)));
+            match poll_once(submit.as_mut()) {
+                Poll::Ready(Ok(pending)) => pending,
+                _ => panic!("request {seq} should be submitted"),
+            }
+        };
+        let last = submit(1);
+        let first = submit(2);
+        assert_eq!(last.nonce(), high | NONCE_COUNTER_MASK);
+        assert_eq!(first.nonce(), high);
+
+        let last_req = kernel.recv().expect("the first request should be sent");
+        let first_req = kernel.recv().expect("the second request should be sent");
+        assert_eq!(last_req.header.nonce, last.nonce());
+        assert_eq!(first_req.header.nonce, first.nonce());
+
+        // answer the requests in the opposite order.
+        kernel.respond(first.nonce(), KernelResponseBody::Pong(2));
+        kernel.respond(last.nonce(), KernelResponseBody::Pong(1));
+        mailbox.poll();
+        let mut last = Box::pin(last.await_response());
+        let mut first = Box::pin(first.await_response());
+        assert!(matches!(
+            poll_once(last.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+        assert!(matches!(
+            poll_once(first.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(2)))
+        ));
+    }
+
+    #[test]
+    fn nonce_never_no_response() {
+        let (mailbox, _kernel) = mailbox::<4>();
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            // wrap the counter itself, not just the bits of it that are used.
+            mailbox.nonce.store(u32::MAX - 2, Ordering::Release);
+            for _ in 0..6 {
+                let nonce = mailbox.next_nonce(priority);
+                assert_ne!(nonce, NO_RESPONSE_NONCE);
+                assert_eq!(Priority::of_nonce(nonce), priority);
+            }
+        }
+    }
+
+    /// A request's full round trip through a fresh mailbox, including the
+    /// handshake, with [`MailBox::poll`] run by hand.
+    #[test]
+    fn round_trip() {
+        let (mailbox, kernel) = mailbox::<4>();
+
+        // send a request, and check what the kernel receives.
+        let mut sleep = Box::pin(mailbox.sleep(Duration::from_millis(10)));
+        assert!(poll_once(sleep.as_mut()).is_pending());
+        let hello = kernel.recv().expect("the handshake is sent first");
+        kernel.respond(
+            hello.header.nonce,
+            KernelResponseBody::Hello(Ok(HelloResponse {
+                abi_version: ABI_VERSION,
+            })),
+        );
+        mailbox.poll();
+
+        assert!(poll_once(sleep.as_mut()).is_pending());
+        let req = kernel.recv().expect("the sleep request is sent");
+        assert!(matches!(
+            req.body,
+            UserRequestBody::Sleep(SleepRequest { micros: 10_000 })
+        ));
+        assert!(kernel.recv().is_none());
+
+        // answer it, and check that the future resolves.
+        kernel.respond(req.header.nonce, KernelResponseBody::Sleep(Ok(())));
+        mailbox.poll();
+        assert_eq!(poll_once(sleep.as_mut()), Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn keepalive() {
+        let _clock = clock();
+        let (mailbox, kernel) = connected::<4>();
+        let interval = Duration::from_millis(10);
+        let mut keepalive = Box::pin(mailbox.keepalive(interval, interval));
+        assert!(poll_once(keepalive.as_mut()).is_pending());
+
+        // the kernel never answers this.
+        let mut submit = Box::pin(mailbox.submit(UserRequestBody::Ping(100)));
+        let Poll::Ready(Ok(pending)) = poll_once(submit.as_mut()) else {
+            panic!("the request should be submitted");
+        };
+        assert!(kernel.recv().is_some());
+
+        // the kernel answers the first ping...
+        advance(interval);
+        assert!(poll_once(keepalive.as_mut()).is_pending());
+        let ping = kernel.recv().expect("a ping should be sent");
+        assert!(matches!(ping.body, UserRequestBody::Ping(1)));
+        kernel.respond(ping.header.nonce, KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert!(poll_once(keepalive.as_mut()).is_pending());
+        assert!(mailbox.is_alive());
+
+        // ...but not the second.
+        advance(interval);
+        assert!(poll_once(keepalive.as_mut()).is_pending());
+        let ping = kernel.recv().expect("a ping should be sent");
+        assert!(matches!(ping.body, UserRequestBody::Ping(2)));
+        advance(interval);
+        assert_eq!(poll_once(keepalive.as_mut()), Poll::Ready(()));
+        assert!(!mailbox.is_alive());
+
+        let mut response = Box::pin(pending.await_response());
+        assert!(matches!(
+            poll_once(response.as_mut()),
+            Poll::Ready(Err(MailboxError::ConnectionLost))
+        ));
+        let mut send = Box::pin(mailbox.send(UserRequestBody::Ping(3)));
+        assert_eq!(
+            poll_once(send.as_mut()),
+            Poll::Ready(Err(MailboxError::ConnectionLost))
+        );
+        assert!(kernel.recv().is_none());
+    }
+
+    #[test]
+    fn message_too_large() {
+        let (rings, kernel) = rings();
+        let mailbox: &'static MailBox<4> = Box::leak(Box::new(MailBox::with_max_msg_size(16)));
+        mailbox.set_rings(rings);
+        complete_handshake(mailbox, &kernel);
+
+        // a small request fits...
+        let mut small = Box::pin(mailbox.request(UserRequestBody::Ping(1)));
+        assert!(poll_once(small.as_mut()).is_pending());
+
+        // ...but a full console write doesn't, and is rejected without being
+        // sent.
+        let (write, _) =
+            ConsoleWriteRequest::new(&[b'x'; abi::syscall::console::CONSOLE_WRITE_MAX]);
+        let mut large = Box::pin(mailbox.request(UserRequestBody::ConsoleWrite(write)));
+        assert!(matches!(
+            poll_once(large.as_mut()),
+            Poll::Ready(Err(MailboxError::MessageTooLarge { max: 16, .. }))
+        ));
+        drop(large);
+
+        let req = kernel.recv().expect("the small request should be sent");
+        assert!(matches!(req.body, UserRequestBody::Ping(1)));
+        assert!(kernel.recv().is_none(), "the large request isn't sent");
+        kernel.respond(req.header.nonce, KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert!(matches!(
+            poll_once(small.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+    }
+
+    #[test]
+    fn independent_mailboxes() {
+        let (first, first_kernel) = connected::<4>();
+        let (second, second_kernel) = connected::<4>();
+
+        let mut first_req = Box::pin(first.request(UserRequestBody::Ping(1)));
+        let mut second_req = Box::pin(second.request(UserRequestBody::Ping(2)));
+        assert!(poll_once(first_req.as_mut()).is_pending());
+        assert!(poll_once(second_req.as_mut()).is_pending());
+
+        // each request is only sent on its own mailbox's ring.
+        let first_sent = first_kernel
+            .recv()
+            .expect("the first request should be sent");
+        assert!(matches!(first_sent.body, UserRequestBody::Ping(1)));
+        assert!(first_kernel.recv().is_none());
+        let second_sent = second_kernel
+            .recv()
+            .expect("the second request should be sent");
+        assert!(matches!(second_sent.body, UserRequestBody::Ping(2)));
+        assert!(second_kernel.recv().is_none());
+
+        // the second mailbox's response arrives while the first is still
+        // waiting for its own, and doesn't need the first to make progress.
+        second_kernel.respond(second_sent.header.nonce, KernelResponseBody::Pong(2));
+        second.poll();
+        assert!(matches!(
+            poll_once(second_req.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(2)))
+        ));
+        first.poll();
+        assert!(poll_once(first_req.as_mut()).is_pending());
+
+        first_kernel.respond(first_sent.header.nonce, KernelResponseBody::Pong(1));
+        first.poll();
+        assert!(matches!(
+            poll_once(first_req.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+        assert_eq!(first.metrics().received, 2);
+        assert_eq!(second.metrics().received, 2);
+    }
+
+    #[test]
+    fn priority_not_starved() {
+        let (mailbox, kernel) = connected::<4>();
+        let send_low =
+            |seq| Box::pin(mailbox.send_with_priority(UserRequestBody::Ping(seq), Priority::Low));
+
+        // flood the `u2k` ring with low priority messages, until they have to
+        // wait for room.
+        let mut sent = 0;
+        let mut low = loop {
+            let mut low = send_low(0);
+            if poll_once(low.as_mut()).is_pending() {
+                break low;
+            }
+            sent += 1;
+        };
+
+        // a high priority request waits for room too...
+        let mut high = Box::pin(mailbox.request(UserRequestBody::Ping(1)));
+        assert!(poll_once(high.as_mut()).is_pending());
+        for _ in 0..sent {
+            assert!(kernel.recv().is_some());
+        }
+        assert!(kernel.recv().is_none());
+
+        // ...but once there's room, it goes first, even if the low priority
+        // sender is woken first.
+        mailbox.poll();
+        assert!(poll_once(low.as_mut()).is_pending());
+        assert!(poll_once(high.as_mut()).is_pending());
+        assert_eq!(poll_once(low.as_mut()), Poll::Ready(Ok(())));
+        let high_req = kernel
+            .recv()
+            .expect("the high priority request should be sent");
+        assert!(matches!(high_req.body, UserRequestBody::Ping(1)));
+        let low_req = kernel
+            .recv()
+            .expect("the low priority message should be sent");
+        assert!(matches!(low_req.body, UserRequestBody::Ping(0)));
+
+        kernel.respond(low_req.header.nonce, KernelResponseBody::Pong(0));
+        kernel.respond(high_req.header.nonce, KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert!(matches!(
+            poll_once(high.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+    }
+
+    #[test]
+    fn early_arrival() {
+        let (mailbox, kernel) = connected::<4>();
+        mailbox.set_next_nonce(10);
+        let nonce = ((Priority::High as u32) << NONCE_COUNTER_BITS) | 10;
+
+        // the response is read before its request has registered a waiter, so
+        // it's held as an early arrival...
+        kernel.respond(nonce, KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert_eq!(mailbox.metrics().dropped, 0);
+
+        // ...and the request picks it up as soon as it has been sent.
+        let mut req = Box::pin(mailbox.request(UserRequestBody::Ping(1)));
+        assert!(matches!(
+            poll_once(req.as_mut()),
+            Poll::Ready(Ok(KernelResponseBody::Pong(1)))
+        ));
+        let sent = kernel.recv().expect("the request should be sent");
+        assert_eq!(sent.header.nonce, nonce);
+        assert!(mailbox.take_early(nonce).is_none());
+    }
+
+    #[test]
+    fn early_arrivals_overflow() {
+        let (mailbox, kernel) = connected::<4>();
+
+        // one more unclaimed response than the early arrivals buffer holds.
+        let mut nonces = std::vec::Vec::new();
+        for seq in 0..=EARLY_CAPACITY as u32 {
+            let mut send = Box::pin(mailbox.send(UserRequestBody::Ping(seq)));
+            assert_eq!(poll_once(send.as_mut()), Poll::Ready(Ok(())));
+            let req = kernel.recv().expect("the request should be sent");
+            kernel.respond(req.header.nonce, KernelResponseBody::Pong(seq));
+            nonces.push(req.header.nonce);
+        }
+        mailbox.poll();
+
+        // the oldest is evicted to make room for the newest.
+        assert_eq!(mailbox.metrics().dropped, 1);
+        assert!(mailbox.take_early(nonces[0]).is_none());
+        for (seq, &nonce) in (0..).zip(&nonces).skip(1) {
+            assert!(matches!(
+                mailbox.take_early(nonce),
+                Some(Ok(KernelResponseBody::Pong(n))) if n == seq
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_error() {
+        let (mailbox, kernel) = connected::<4>();
+        let mut req = Box::pin(mailbox.request(UserRequestBody::Ping(1)));
+        assert!(poll_once(req.as_mut()).is_pending());
+        let sent = kernel.recv().expect("the request should be sent");
+
+        // a response whose nonce can be read, but whose body is cut short.
+        const RESPONSE_VARIANT: u32 = 2;
+        let mut payload = [0; 8];
+        let len = postcard::to_slice(&(RESPONSE_VARIANT, sent.header.nonce), &mut payload)
+            .expect("the header must serialize")
+            .len();
+        payload[len] = 0xff;
+        send_raw(&kernel, &payload[..=len], false);
+        mailbox.poll();
+
+        assert!(matches!(
+            poll_once(req.as_mut()),
+            Poll::Ready(Err(MailboxError::Decode))
+        ));
+        assert_eq!(mailbox.metrics().decode_errors, 1);
+    }
+
+    #[test]
+    fn send_oneshot() {
+        let (mailbox, kernel) = connected::<4>();
+        let mut send = Box::pin(mailbox.send_oneshot(UserRequestBody::Ping(1)));
+        assert_eq!(poll_once(send.as_mut()), Poll::Ready(Ok(())));
+        assert_eq!(mailbox.metrics().in_flight, 0);
+        let req = kernel.recv().expect("the message should be sent");
+        assert_eq!(req.header.nonce, NO_RESPONSE_NONCE);
+        assert!(matches!(req.body, UserRequestBody::Ping(1)));
+
+        // if the kernel replies anyway, the reply is discarded, rather than
+        // taking up room in the early arrivals buffer.
+        kernel.respond(NO_RESPONSE_NONCE, KernelResponseBody::Pong(1));
+        mailbox.poll();
+        assert!(mailbox.take_early(NO_RESPONSE_NONCE).is_none());
+        let metrics = mailbox.metrics();
+        assert_eq!(metrics.unhandled, 1);
+        assert_eq!(metrics.dropped, 0);
+        assert_eq!(metrics.in_flight, 0);
+    }
+}
@@ -0,0 +1,191 @@
+//! Unsolicited messages from the kernel.
+//!
+//! Most messages from the kernel are responses to a request, and are routed
+//! to the requester by nonce. The kernel may also send messages which are not
+//! a response to any request. Messages are tagged by their [`KernelMsg`]
+//! variant: a [`KernelMsg::Response`] is always a response, and the other
+//! variants which carry information for the application are unsolicited
+//! [`Notification`]s.
+//!
+//! Notifications are delivered to at most one [`Subscription`] at a time,
+//! created by [`MailBox::subscribe`]. A subscription buffers up to
+//! `NOTIFICATION_CAPACITY` notifications that haven't been taken yet. A
+//! notification is dropped if no one is subscribed when it arrives, or if the
+//! subscription's buffer is full; [`MailBox::dropped_notifications`] counts
+//! how many notifications have been dropped.
+//!
+//! Subscribers wait on their own [`WaitCell`], rather than the wait map used
+//! for responses, so a flood of notifications never delays the delivery of
+//! responses to their requesters.
+//!
+//! [`KernelMsg`]: abi::syscall::KernelMsg
+//! [`KernelMsg::Response`]: abi::syscall::KernelMsg::Response
+//! [`MailBox::subscribe`]: super::mailbox::MailBox::subscribe
+//! [`MailBox::dropped_notifications`]: super::mailbox::MailBox::dropped_notifications
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::stream::Stream;
+use heapless::Deque;
+use maitake::sync::{blocking::Mutex, spin::Spinlock, WaitCell};
+
+/// The maximum number of notifications buffered for a subscriber.
+const NOTIFICATION_CAPACITY: usize = 8;
+
+/// A message sent by the kernel which is not a response to a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// The kernel's current timestamp.
+    Timestamp(u64),
+}
+
+/// A stream of [`Notification`]s, returned by
+/// [`MailBox::subscribe`](super::mailbox::MailBox::subscribe).
+///
+/// Dropping the subscription unsubscribes, discarding any notifications which
+/// have not been taken yet.
+pub struct Subscription<'mailbox> {
+    notifications: &'mailbox Notifications,
+}
+
+pub(crate) struct Notifications {
+    subscribed: AtomicBool,
+    queue: Mutex<Deque<Notification, NOTIFICATION_CAPACITY>, Spinlock>,
+    wait: WaitCell,
+    dropped: AtomicUsize,
+}
+
+impl Notifications {
+    pub(crate) const fn new() -> Self {
+        Self {
+            subscribed: AtomicBool::new(false),
+            queue: Mutex::new_with_raw_mutex(Deque::new(), Spinlock::new()),
+            wait: WaitCell::new(),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> Option<Subscription<'_>> {
+        if self.subscribed.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Subscription {
+            notifications: self,
+        })
+    }
+
+    /// Deliver a notification to the subscriber, or drop it if there is no
+    /// subscriber or its buffer is full.
+    pub(crate) fn deliver(&self, notification: Notification) {
+        let queued = {
+            // check `subscribed` while holding the lock, so that we can't race
+            // with a subscription being dropped.
+            let mut queue = self.queue.lock();
+            self.subscribed.load(Ordering::Acquire) && queue.push_back(notification).is_ok()
+        };
+        if queued {
+            self.wait.wake();
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for Subscription<'_> {
+    type Item = Notification;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(notification) = self.notifications.queue.lock().pop_front() {
+                return Poll::Ready(Some(notification));
+            }
+
+            // if a notification is delivered after we checked the queue, but
+            // before we registered our waker, the cell remembers the wakeup,
+            // and this returns `Ready` so that we check again.
+            match self.notifications.wait.poll_wait(cx) {
+                Poll::Ready(Ok(())) => continue,
+                Poll::Ready(Err(_)) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        // discard anything left over, so that the next subscriber doesn't see
+        // notifications that arrived before it subscribed.
+        let mut queue = self.notifications.queue.lock();
+        self.notifications
+            .subscribed
+            .store(false, Ordering::Release);
+        queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    /// Take the next notification from `sub`, if one is buffered.
+    fn next(sub: &mut Subscription<'_>) -> Poll<Option<Notification>> {
+        Pin::new(sub).poll_next(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn unsubscribed() {
+        let notifications = Notifications::new();
+        notifications.deliver(Notification::Timestamp(1));
+        assert_eq!(notifications.dropped(), 1);
+
+        // a later subscriber doesn't see it.
+        let mut sub = notifications.subscribe().expect("no one is subscribed");
+        assert!(notifications.subscribe().is_none(), "only one subscriber");
+        assert_eq!(next(&mut sub), Poll::Pending);
+
+        // nor do notifications delivered after unsubscribing reach the next
+        // subscriber.
+        drop(sub);
+        notifications.deliver(Notification::Timestamp(2));
+        assert_eq!(notifications.dropped(), 2);
+        let mut sub = notifications.subscribe().expect("no one is subscribed");
+        assert_eq!(next(&mut sub), Poll::Pending);
+    }
+
+    #[test]
+    fn full_buffer() {
+        let notifications = Notifications::new();
+        let mut sub = notifications.subscribe().expect("no one is subscribed");
+        for timestamp in 0..=NOTIFICATION_CAPACITY as u64 {
+            notifications.deliver(Notification::Timestamp(timestamp));
+        }
+        // the last one didn't fit.
+        assert_eq!(notifications.dropped(), 1);
+
+        for timestamp in 0..NOTIFICATION_CAPACITY as u64 {
+            assert_eq!(
+                next(&mut sub),
+                Poll::Ready(Some(Notification::Timestamp(timestamp)))
+            );
+        }
+        assert_eq!(next(&mut sub), Poll::Pending);
+
+        // once there's room again, notifications are buffered.
+        notifications.deliver(Notification::Timestamp(100));
+        assert_eq!(
+            next(&mut sub),
+            Poll::Ready(Some(Notification::Timestamp(100)))
+        );
+        assert_eq!(notifications.dropped(), 1);
+    }
+}
@@ -101,7 +101,7 @@ impl Eq for Alarmed {}
 
 impl PartialOrd for Alarmed {
     fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        self.alarm.partial_cmp(&other.alarm)
+        Some(self.cmp(other))
     }
 }
 
@@ -129,7 +129,7 @@ impl Chronos {
         let curr_tick = *CURRENT_TIME.borrow().unwrap();
 
         let mut inner = self.inner.borrow_mut().unwrap();
-        if let Some(alm) = inner.shorts.get(0) {
+        if let Some(alm) = inner.shorts.first() {
             if alm.alarm.tick > curr_tick {
                 // Nothing to wake, we're done
                 return;
@@ -1,14 +1,14 @@
 #![doc = include_str!("../README.md")]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-// TODO(AJM): This is not currently functional.
+/// Common between the Kernel and Userspace
+pub use abi;
 
-// /// Common between the Kernel and Userspace
-// pub use abi;
+pub mod executor;
+pub mod serial;
+pub mod utils;
 
-// pub mod executor;
-// pub mod serial;
-// pub mod utils;
+// TODO(AJM): The entry point and panic handler are not currently functional.
 
 // // The user must provide a `no_mangle` entrypoint.
 // extern "Rust" {
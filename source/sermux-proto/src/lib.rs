@@ -29,6 +29,8 @@ pub enum WellKnown {
     PseudoKeyboard = 2,
     /// A bidirectional for binary encoded tracing messages
     BinaryTracing = 3,
+    /// An output-only channel for text written to the kernel's console
+    Console = 4,
 
     /// A bidirectional interactive forth shell (1/4)
     ForthShell0 = 10,
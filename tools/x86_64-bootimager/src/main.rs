@@ -14,6 +14,7 @@ fn main() -> miette::Result<()> {
     match cmd {
         Some(Subcommand::Build) => Ok(()),
         Some(Subcommand::Qemu(opts)) => opts.run_qemu(bootimage_path, &builder.bootloader),
+        Some(Subcommand::Test(opts)) => opts.run_tests(bootimage_path, &builder.bootloader),
         None => qemu::Options::default().run_qemu(bootimage_path, &builder.bootloader),
     }
 }
@@ -47,4 +48,9 @@ enum Subcommand {
     /// This is the default subcommand.
     #[clap(alias = "run")]
     Qemu(qemu::Options),
+    /// Build a mnemOS boot image (if needed) and run its boot tests in a QEMU
+    /// virtual machine, exiting with an error if any of them fail.
+    ///
+    /// The kernel must be built with the `boot-tests` feature.
+    Test(qemu::TestOptions),
 }
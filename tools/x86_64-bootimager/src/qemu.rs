@@ -2,7 +2,12 @@ use crate::{BootLogLevel, BootMode, BootloaderOptions};
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, ValueHint};
 use miette::{Context, IntoDiagnostic};
-use std::io::{self, BufRead, BufReader, Read, Write};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Child, ExitStatus},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 #[derive(Clone, Debug, Parser)]
 pub struct Options {
@@ -48,6 +53,25 @@ pub struct Options {
     pub qemu_args: Vec<String>,
 }
 
+/// Options for running the kernel's boot tests in QEMU.
+#[derive(Clone, Debug, Parser)]
+pub struct TestOptions {
+    /// How long to wait for the boot tests to finish, in seconds, before
+    /// killing QEMU and failing.
+    #[clap(long, default_value_t = 60)]
+    pub timeout: u64,
+
+    #[clap(flatten)]
+    pub qemu: Options,
+}
+
+/// A running QEMU VM.
+struct Vm {
+    qemu: Child,
+    crowtty: Option<JoinHandle<miette::Result<()>>>,
+    tag: libcrowtty::LogTag,
+}
+
 impl Default for Options {
     fn default() -> Self {
         Self {
@@ -84,9 +108,38 @@ impl Options {
         bootimage_path: impl AsRef<Utf8Path>,
         boot_opts: &BootloaderOptions,
     ) -> miette::Result<()> {
-        use std::process::Stdio;
+        let Vm {
+            mut qemu,
+            crowtty,
+            tag,
+        } = self.spawn(bootimage_path.as_ref(), boot_opts, &[])?;
 
-        let bootimage_path = bootimage_path.as_ref();
+        let stderr = qemu.stderr.take().expect("QEMU should have piped stderr");
+        echo_stderr(tag, stderr);
+
+        let status = qemu
+            .wait()
+            .into_diagnostic()
+            .context("QEMU child process failed")?;
+
+        if !status.success() {
+            return Err(miette::miette!("QEMU exited with {status}"));
+        }
+
+        if let Some(crowtty) = crowtty {
+            crowtty.join().unwrap()?;
+        }
+
+        Ok(())
+    }
+
+    fn spawn(
+        self,
+        bootimage_path: &Utf8Path,
+        boot_opts: &BootloaderOptions,
+        extra_args: &[&str],
+    ) -> miette::Result<Vm> {
+        use std::process::Stdio;
 
         tracing::info!(qemu = %self.qemu_path, args = ?self.qemu_args, "Booting mnemOS VM");
 
@@ -96,6 +149,7 @@ impl Options {
         } else {
             cmd.args(Options::default_args());
         }
+        cmd.args(extra_args);
 
         cmd.arg("-drive")
             .arg(format!("format=raw,file={bootimage_path}"));
@@ -128,7 +182,7 @@ impl Options {
             .context("failed to spawn QEMU child process")?;
 
         let tag = libcrowtty::LogTag::serial().verbose(self.crowtty_verbose);
-        let crowtty_thread = if crowtty_enabled {
+        let crowtty = if crowtty_enabled {
             let stdin = qemu.stdin.take().expect("QEMU should have piped stdin");
             let stdout = qemu.stdout.take().expect("QEMU should have piped stdout");
             let boot_log = boot_opts.boot_log;
@@ -151,35 +205,103 @@ impl Options {
             None
         };
 
-        let qemu_stderr = {
-            let stderr = qemu.stderr.take().expect("QEMU should have piped stderr");
-            BufReader::new(stderr)
+        Ok(Vm { qemu, crowtty, tag })
+    }
+}
+
+// === impl TestOptions ===
+
+impl TestOptions {
+    /// The `isa-debug-exit` device the kernel's `boot-tests` feature exits
+    /// QEMU through. This must match `QEMU_DEBUG_EXIT_PORT` in the kernel.
+    const DEBUG_EXIT_ARGS: &'static [&'static str] = &[
+        "-device",
+        "isa-debug-exit,iobase=0xf4,iosize=0x04",
+        "-display",
+        "none",
+        "-no-reboot",
+    ];
+    /// QEMU's exit status when the kernel reports that every test passed.
+    const SUCCESS: i32 = (0x10 << 1) | 1;
+    /// QEMU's exit status when the kernel reports a failed test, or a panic.
+    const FAILURE: i32 = (0x11 << 1) | 1;
+
+    /// Boot a kernel built with the `boot-tests` feature in QEMU, and wait for
+    /// it to report the result of its tests.
+    pub fn run_tests(
+        self,
+        bootimage_path: impl AsRef<Utf8Path>,
+        boot_opts: &BootloaderOptions,
+    ) -> miette::Result<()> {
+        let timeout = Duration::from_secs(self.timeout);
+        let Vm { mut qemu, tag, .. } =
+            self.qemu
+                .spawn(bootimage_path.as_ref(), boot_opts, Self::DEBUG_EXIT_ARGS)?;
+
+        let stderr = qemu.stderr.take().expect("QEMU should have piped stderr");
+        std::thread::Builder::new()
+            .name("qemu-stderr".to_string())
+            .spawn(move || echo_stderr(tag, stderr))
+            .unwrap();
+
+        // crowtty is left running: it stops on its own once QEMU has exited,
+        // and its output doesn't change the result.
+        let Some(status) = wait_timeout(&mut qemu, timeout)? else {
+            return Err(miette::miette!(
+                "boot tests timed out after {}s",
+                timeout.as_secs()
+            ));
         };
-        let qemu_tag = tag.named("QEMU");
-        for line in qemu_stderr.lines() {
-            match line {
-                Ok(line) => eprintln!("{qemu_tag} {line}"),
-                Err(error) => {
-                    tracing::warn!(%error, "failed to read from QEMU stderr");
-                    break;
-                }
+        match status.code() {
+            Some(Self::SUCCESS) => {
+                tracing::info!("boot tests passed");
+                Ok(())
             }
+            Some(Self::FAILURE) => Err(miette::miette!("boot tests failed")),
+            _ => Err(miette::miette!(
+                "QEMU exited without reporting a test result ({status})"
+            )),
         }
+    }
+}
 
-        let status = qemu
-            .wait()
+/// Wait for `qemu` to exit, killing it and returning `None` if it hasn't
+/// exited after `timeout`.
+fn wait_timeout(qemu: &mut Child, timeout: Duration) -> miette::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = qemu
+            .try_wait()
             .into_diagnostic()
-            .context("QEMU child process failed")?;
-
-        if !status.success() {
-            return Err(miette::miette!("QEMU exited with {status}"));
+            .context("QEMU child process failed")?
+        {
+            return Ok(Some(status));
         }
 
-        if let Some(crowtty) = crowtty_thread {
-            crowtty.join().unwrap()?;
+        if Instant::now() >= deadline {
+            tracing::warn!("boot tests timed out, killing QEMU");
+            qemu.kill()
+                .into_diagnostic()
+                .context("failed to kill QEMU child process")?;
+            let _ = qemu.wait();
+            return Ok(None);
         }
 
-        Ok(())
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Echo QEMU's stderr until it exits.
+fn echo_stderr(tag: libcrowtty::LogTag, stderr: std::process::ChildStderr) {
+    let qemu_tag = tag.named("QEMU");
+    for line in BufReader::new(stderr).lines() {
+        match line {
+            Ok(line) => eprintln!("{qemu_tag} {line}"),
+            Err(error) => {
+                tracing::warn!(%error, "failed to read from QEMU stderr");
+                break;
+            }
+        }
     }
 }
 